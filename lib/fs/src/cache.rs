@@ -0,0 +1,212 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use lru::LruCache;
+
+use net::ipfs::{self, Cid, Client, Error};
+use net::unixfs::Node;
+
+// Counters of a block cache, to tell how well it works and how big it should be.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Blocks dropped to make room for others.
+    pub evictions: u64,
+    // Total size of the cached blocks.
+    pub bytes: u64,
+    pub entries: u64,
+}
+
+// LRU cache of raw blocks keyed by CID, bounded by the total size of the cached blocks.
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    // Read by stats without taking the lock. The size and count of the blocks are copied from
+    // inner whenever they change.
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    bytes: AtomicU64,
+    entries: AtomicU64,
+}
+
+struct Inner {
+    blocks: LruCache<Cid, Bytes>,
+    size: usize,
+}
+
+impl BlockCache {
+    // Cache holding at most capacity bytes. A zero capacity disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                blocks: LruCache::unbounded(),
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, cid: &Cid) -> Option<Bytes> {
+        let block = self.inner.lock().unwrap().blocks.get(cid).cloned();
+        match block {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        block
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            entries: self.entries.load(Ordering::Relaxed),
+        }
+    }
+
+    // Insert a block, evicting the least recently used ones until it fits.
+    pub fn insert(&self, cid: Cid, block: Bytes) {
+        if block.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = inner.blocks.put(cid, block.clone()) {
+            inner.size -= old.len();
+        }
+        inner.size += block.len();
+        while inner.size > self.capacity {
+            match inner.blocks.pop_lru() {
+                Some((_, evicted)) => {
+                    inner.size -= evicted.len();
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
+        self.bytes.store(inner.size as u64, Ordering::Relaxed);
+        self.entries
+            .store(inner.blocks.len() as u64, Ordering::Relaxed);
+    }
+}
+
+// IPFS client whose block fetches go through a cache shared by all its clones.
+#[derive(Clone)]
+pub struct CachedClient {
+    client: Client,
+    cache: Arc<BlockCache>,
+    // Whether fetched blocks are checked against their CID before being used.
+    verify: bool,
+}
+
+impl CachedClient {
+    pub fn new(client: Client, capacity: usize) -> Self {
+        Self {
+            client,
+            cache: Arc::new(BlockCache::new(capacity)),
+            verify: true,
+        }
+    }
+
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub async fn get_block(&self, cid: &Cid) -> Result<Bytes, Error> {
+        let metrics = self.client.metrics();
+        if let Some(block) = self.cache.get(cid) {
+            if let Some(metrics) = metrics {
+                metrics.cache_hits.inc();
+            }
+            return Ok(block);
+        }
+        if let Some(metrics) = metrics {
+            metrics.cache_misses.inc();
+        }
+        let block = self.client.get_block(cid).await?;
+        if self.verify {
+            ipfs::verify_block(cid, &block)?;
+        }
+        self.cache.insert(*cid, block.clone());
+        Ok(block)
+    }
+
+    pub async fn get_node(&self, cid: &Cid) -> Result<Node, Error> {
+        let block = self.get_block(cid).await?;
+        Node::decode(cid, block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cid(n: u8) -> Cid {
+        // CIDv0 of a fake sha2-256 digest.
+        let mut bytes = vec![0x12, 0x20];
+        bytes.extend([n; 32]);
+        Cid::try_from(bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = BlockCache::new(10);
+        cache.insert(cid(1), Bytes::from_static(b"aaaa"));
+        cache.insert(cid(2), Bytes::from_static(b"bbbb"));
+        // Touch the first block so the second one becomes the least recently used.
+        assert!(cache.get(&cid(1)).is_some());
+        cache.insert(cid(3), Bytes::from_static(b"cccc"));
+
+        assert!(cache.get(&cid(1)).is_some());
+        assert!(cache.get(&cid(2)).is_none());
+        assert!(cache.get(&cid(3)).is_some());
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = BlockCache::new(10);
+        assert!(cache.get(&cid(1)).is_none());
+        cache.insert(cid(1), Bytes::from_static(b"aaaa"));
+        assert!(cache.get(&cid(1)).is_some());
+        assert!(cache.get(&cid(1)).is_some());
+        assert!(cache.get(&cid(2)).is_none());
+        cache.insert(cid(2), Bytes::from_static(b"bbbb"));
+        // Makes room by dropping the first block.
+        cache.insert(cid(3), Bytes::from_static(b"cccc"));
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                evictions: 1,
+                bytes: 8,
+                entries: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_skips_blocks_over_capacity() {
+        let cache = BlockCache::new(2);
+        cache.insert(cid(1), Bytes::from_static(b"too big"));
+        assert!(cache.get(&cid(1)).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use std::io;
+
+use wasmer_wasix::FsError;
+
+use net::ipfs::Error;
+
+// Map an IPFS client error to the FsError reported to the guest, so the errno it sees tells
+// missing content apart from malformed paths and network failures.
+pub fn fs_error(e: &Error) -> FsError {
+    match e {
+        Error::NotFound(_) | Error::NotFoundLocally(_) => FsError::EntryNotFound,
+        Error::Cid(_) => FsError::InvalidInput,
+        Error::Timeout(_) => FsError::TimedOut,
+        Error::Unreachable(..) => FsError::ConnectionRefused,
+        Error::Api(_) | Error::Decode(_) | Error::Corrupt(_) | Error::Gateway(_) => {
+            FsError::IOError
+        }
+    }
+}
+
+// Same as fs_error, for errors surfaced through the file I/O traits.
+pub fn io_error(e: Error) -> io::Error {
+    let kind = match &e {
+        Error::NotFound(_) | Error::NotFoundLocally(_) => io::ErrorKind::NotFound,
+        Error::Cid(_) => io::ErrorKind::InvalidInput,
+        Error::Timeout(_) => io::ErrorKind::TimedOut,
+        Error::Unreachable(..) => io::ErrorKind::ConnectionRefused,
+        Error::Corrupt(_) => io::ErrorKind::InvalidData,
+        Error::Api(_) | Error::Decode(_) | Error::Gateway(_) => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_error() {
+        let not_found = Error::NotFound("merkledag: not found".to_owned());
+        assert_eq!(fs_error(&not_found), FsError::EntryNotFound);
+
+        let timeout = Error::Timeout("context deadline exceeded".to_owned());
+        assert_eq!(fs_error(&timeout), FsError::TimedOut);
+
+        let cid = net::ipfs::Cid::try_from("!notacid").unwrap_err();
+        assert_eq!(fs_error(&Error::Cid(cid)), FsError::InvalidInput);
+
+        let decode = Error::Decode("truncated varint".to_owned());
+        assert_eq!(fs_error(&decode), FsError::IOError);
+    }
+
+    #[test]
+    fn test_io_error() {
+        let e = io_error(Error::NotFound("merkledag: not found".to_owned()));
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        let e = io_error(Error::Timeout("context deadline exceeded".to_owned()));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// IPNS resolutions cached for a fixed TTL, so long-lived guests pick up new records once the
+// cached ones expire.
+pub struct IpnsCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl IpnsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Path the name resolved to, unless the resolution has expired.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(name) {
+            Some((target, resolved_at)) if resolved_at.elapsed() < self.ttl => Some(target.clone()),
+            Some(_) => {
+                entries.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, name: String, target: String) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(name, (target, Instant::now()));
+    }
+}
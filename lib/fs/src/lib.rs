@@ -1,34 +1,505 @@
-use bytes::BufMut;
-use futures::executor::block_on;
 use futures::future::BoxFuture;
-use futures::TryStreamExt;
+use std::cmp::min;
+use std::collections::BTreeMap;
 use std::fmt;
-use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::future::Future;
+use std::io::{self, SeekFrom, Write};
 use std::marker::{Send, Sync};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use std::sync::{Arc, RwLock};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tracing::instrument;
 
 use wasmer_wasix::{virtual_fs, FsError};
 
-use net::ipfs::Client;
+use net::ipfs::{Cid, Client, Progress};
+use net::unixfs::{self, Node, NodeType};
+
+mod cache;
+mod error;
+mod ipns;
+mod overlay;
+mod path;
+mod pool;
+mod sniff;
+mod stream;
+mod write;
+
+pub use cache::CacheStats;
+
+use cache::CachedClient;
+use error::fs_error;
+use ipns::IpnsCache;
+use overlay::{Entry, Overlay};
+use path::IpfsPath;
+use pool::FetchPool;
+use stream::BlockStream;
+use write::Writer;
 
 const IPFS_PATH: &str = "/ipfs";
+const IPNS_PATH: &str = "/ipns";
+
+// How long IPNS resolutions are reused before the name is resolved again.
+const DEFAULT_IPNS_TTL: Duration = Duration::from_secs(60);
+
+// How many bytes from the start of a file are looked at to guess its content type.
+const SNIFF_LEN: u64 = 512;
+
+// How many symlinks are followed while resolving a path before giving up on it.
+const MAX_SYMLINKS: usize = 32;
 
+// How many blocks streaming reads fetch concurrently ahead of the read position.
+const DEFAULT_READ_AHEAD: usize = 4;
+
+// Threads the fetches of blocking calls are made on, unless set otherwise.
+const DEFAULT_FETCH_THREADS: usize = 4;
+
+// Size of the chunks copy_reference moves from the source file to the destination.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+// Filesystem over IPFS and IPNS. Clones are cheap and share the block cache, resolved IPNS
+// names, the overlay of written files and the mount table, so a file written through one clone
+// can be read through any other, from any thread. Settings like the timeout are copied, and
+// changing them on a clone leaves the others as they are.
+#[derive(Clone)]
 pub struct IpfsFs {
-    client: Client,
+    client: CachedClient,
+    ipns: Arc<IpnsCache>,
+    // Files written through the filesystem.
+    overlay: Arc<Overlay>,
+    // Deadline for the fetches made while serving a single call, e.g. an open.
+    timeout: Option<Duration>,
+    read_ahead: usize,
+    // Filesystems mounted under the tree, by mount point.
+    mounts: Arc<RwLock<BTreeMap<PathBuf, Arc<dyn virtual_fs::FileSystem + Send + Sync>>>>,
+    // Whether calls the filesystem can't carry out fail with errors programs tolerate rather
+    // than Unsupported.
+    compat: bool,
+    // Told how much of each file opened for reading was fetched.
+    progress: Option<Progress>,
+    // Where the fetches of calls like open are made while the calling thread waits.
+    pool: Arc<FetchPool>,
 }
 
 impl IpfsFs {
     pub fn new(client: Client) -> IpfsFs {
-        return IpfsFs { client: client };
+        Self::with_cache(client, 0)
+    }
+
+    // Filesystem over a daemon that was checked to be up, so a mount fails early with the
+    // address of the daemon rather than on the first file the guest opens.
+    pub async fn connect(client: Client) -> Result<IpfsFs, net::ipfs::Error> {
+        client.health_check().await?;
+        Ok(Self::new(client))
+    }
+
+    // Filesystem caching up to capacity bytes of fetched blocks, shared by all its open files.
+    pub fn with_cache(client: Client, capacity: usize) -> IpfsFs {
+        IpfsFs {
+            client: CachedClient::new(client, capacity),
+            ipns: Arc::new(IpnsCache::new(DEFAULT_IPNS_TTL)),
+            overlay: Arc::new(Overlay::default()),
+            timeout: None,
+            read_ahead: DEFAULT_READ_AHEAD,
+            mounts: Arc::default(),
+            compat: false,
+            progress: None,
+            pool: Arc::new(FetchPool::new(DEFAULT_FETCH_THREADS)),
+        }
+    }
+
+    // Set how long IPNS names stay resolved to the same path before being resolved again. The
+    // filesystem then stops sharing resolved names with the clones made before.
+    pub fn with_ipns_ttl(mut self, ttl: Duration) -> IpfsFs {
+        self.ipns = Arc::new(IpnsCache::new(ttl));
+        self
+    }
+
+    // Give up on fetches that take longer than timeout and fail the call with TimedOut.
+    pub fn with_timeout(mut self, timeout: Duration) -> IpfsFs {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Set how many blocks streaming reads fetch concurrently ahead of the read position. Each
+    // open file buffers at most that many blocks.
+    pub fn with_read_ahead(mut self, read_ahead: usize) -> IpfsFs {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    // Set whether fetched blocks are checked against their CID, which they are by default. Only
+    // turn it off for a trusted local daemon, as the guest then gets whatever data it returns.
+    pub fn with_verify(mut self, verify: bool) -> IpfsFs {
+        self.client = self.client.with_verify(verify);
+        self
+    }
+
+    // Counters of the block cache shared by the open files of the filesystem.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.client.stats()
+    }
+
+    // Set whether calls the filesystem can't carry out are softened for programs that treat
+    // Unsupported as fatal. Creating a mount point fails with AlreadyExists, writing under
+    // /ipns with PermissionDenied, and flushing a read-only file or unlinking an open one
+    // succeeds without doing anything. Off by default.
+    pub fn with_compat(mut self, compat: bool) -> IpfsFs {
+        self.compat = compat;
+        self
+    }
+
+    // Set how many threads make the fetches of blocking calls, e.g. opens. Calls fetch
+    // concurrently whatever the number, which only bounds the threads driving the requests.
+    pub fn with_fetch_threads(mut self, threads: usize) -> IpfsFs {
+        self.pool = Arc::new(FetchPool::new(threads));
+        self
+    }
+
+    // Report to progress how many bytes of the files opened for reading are fetched, as their
+    // blocks arrive. The size of a file is known as soon as it is opened.
+    pub fn with_progress(mut self, progress: Progress) -> IpfsFs {
+        self.progress = Some(progress);
+        self
     }
 
     pub fn path(&self) -> PathBuf {
         return PathBuf::from(IPFS_PATH);
     }
+
+    pub fn ipns_path(&self) -> PathBuf {
+        PathBuf::from(IPNS_PATH)
+    }
+
+    // Guess the MIME type of the file at path from its first bytes and its extension. Only the
+    // first block of the file is fetched.
+    pub fn content_type(&self, path: &Path) -> virtual_fs::Result<Option<String>> {
+        let path_str = path::to_str(path)?;
+        let node = self.resolve_following(path_str)?;
+        if node.is_dir() {
+            return Err(FsError::NotAFile);
+        }
+        let head = self.block_on_fetch(async {
+            let get_node = |cid| async move { self.client.get_node(&cid).await };
+            unixfs::read_range(&node, 0, SNIFF_LEN, get_node)
+                .await
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    fs_error(&e)
+                })
+        })?;
+        Ok(sniff::content_type(path, &head).map(str::to_owned))
+    }
+
+    // Store the files written through the filesystem as a UnixFS directory tree and return the
+    // CID of its root, so the snapshot can be pinned.
+    pub fn root_cid(&self) -> Result<Cid, net::ipfs::Error> {
+        self.pool.block_on(self.overlay.put(self.client.client()))
+    }
+
+    // Snapshot the files written through the filesystem like root_cid, and pin the snapshot
+    // recursively so the daemon keeps it.
+    pub fn pin_root(&self) -> Result<Cid, net::ipfs::Error> {
+        self.pool.block_on(async {
+            let client = self.client.client();
+            let cid = self.overlay.put(client).await?;
+            client.pin(&cid, true).await?;
+            Ok(cid)
+        })
+    }
+
+    // Wait for a fetch made on the fetch pool. Once the timeout elapses the fetch is dropped
+    // along with anything it downloaded so far.
+    fn block_on_fetch<T: Send>(
+        &self,
+        fetch: impl Future<Output = virtual_fs::Result<T>> + Send,
+    ) -> virtual_fs::Result<T> {
+        self.pool.block_on(self.fetch_with_timeout(fetch))
+    }
+
+    async fn fetch_with_timeout<T>(
+        &self,
+        fetch: impl Future<Output = virtual_fs::Result<T>>,
+    ) -> virtual_fs::Result<T> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fetch)
+                .await
+                .unwrap_or(Err(FsError::TimedOut)),
+            None => fetch.await,
+        }
+    }
+
+    // Resolve an IPFS path to its UnixFS node, following the named links under the root CID
+    // one segment at a time.
+    fn resolve_node(&self, path: &str) -> virtual_fs::Result<Node> {
+        self.block_on_fetch(self.resolve(path))
+    }
+
+    async fn resolve(&self, path: &str) -> virtual_fs::Result<Node> {
+        let ipfs_path = match path.strip_prefix(IPNS_PATH) {
+            Some(name_path) => IpfsPath::parse(&self.resolve_ipns(name_path).await?)?,
+            None => {
+                // Files written through the filesystem shadow the IPFS tree.
+                if let Some(Entry::File { cid, .. }) = self.overlay_entry(path) {
+                    return self.fetch_node(&cid).await;
+                }
+                IpfsPath::parse(path)?
+            }
+        };
+        let mut node = self.fetch_node(&ipfs_path.root).await?;
+        for segment in &ipfs_path.segments {
+            if !node.is_dir() {
+                return Err(FsError::BaseNotDirectory);
+            }
+            let get_node = |cid| async move { self.client.get_node(&cid).await };
+            let link = unixfs::dir_entry(&node, segment, get_node)
+                .await
+                .map_err(|e| {
+                    tracing::error!("{}", e);
+                    fs_error(&e)
+                })?;
+            let cid = link.ok_or(FsError::EntryNotFound)?.cid;
+            node = self.fetch_node(&cid).await?;
+        }
+        Ok(node)
+    }
+
+    // Filesystem mounted at the longest prefix of path, along with the mount point and the path
+    // relative to the mounted filesystem.
+    fn mount_for(
+        &self,
+        path: &Path,
+    ) -> Option<(
+        Arc<dyn virtual_fs::FileSystem + Send + Sync>,
+        PathBuf,
+        PathBuf,
+    )> {
+        let mounts = self.mounts.read().unwrap();
+        let (mount_point, fs) = mounts
+            .iter()
+            .filter(|(mount_point, _)| path.starts_with(mount_point))
+            .max_by_key(|(mount_point, _)| mount_point.components().count())?;
+        let rest = path
+            .strip_prefix(mount_point)
+            .expect("path is under mount point");
+        Some((fs.clone(), mount_point.clone(), Path::new("/").join(rest)))
+    }
+
+    // Resolve a path to its UnixFS node, following symlinks until it gets to something else.
+    fn resolve_following(&self, path: &str) -> virtual_fs::Result<Node> {
+        let mut path = path.to_owned();
+        for _ in 0..MAX_SYMLINKS {
+            let node = self.resolve_node(&path)?;
+            if node.typ != NodeType::Symlink {
+                return Ok(node);
+            }
+            path = path::link_target(&path, &symlink_target(&node)?);
+        }
+        Err(FsError::InvalidInput)
+    }
+
+    // Metadata of the entry at path. Symlinks are reported as such unless follow is set, in
+    // which case the metadata of what they point to is reported instead.
+    fn stat(&self, path: &Path, follow: bool) -> virtual_fs::Result<virtual_fs::Metadata> {
+        if let Some((fs, _, path)) = self.mount_for(path) {
+            return match follow {
+                true => fs.metadata(&path),
+                false => fs.symlink_metadata(&path),
+            };
+        }
+        // The mount points themselves are directories without a CID of their own.
+        if path == Path::new(IPFS_PATH) || path == Path::new(IPNS_PATH) {
+            let mut md = virtual_fs::Metadata::default();
+            md.ft = virtual_fs::FileType::new_dir();
+            return Ok(md);
+        }
+        let path_str = path::to_str(path)?;
+        let md = match self.overlay_entry(path_str) {
+            Some(entry) => entry_metadata(&entry),
+            None if follow => node_metadata(&self.resolve_following(path_str)?),
+            None => node_metadata(&self.resolve_node(path_str)?),
+        };
+        Ok(self.with_times(path_str, md))
+    }
+
+    // Entry written through the filesystem at path, if any. IPNS paths are never written to.
+    fn overlay_entry(&self, path: &str) -> Option<Entry> {
+        if path.starts_with(IPNS_PATH) {
+            return None;
+        }
+        self.overlay.get(&path::segments(path))
+    }
+
+    fn with_times(&self, path: &str, mut md: virtual_fs::Metadata) -> virtual_fs::Metadata {
+        let times = self.overlay.times(&path::segments(path));
+        md.accessed = times.accessed;
+        md.modified = times.modified;
+        md.created = times.created;
+        md
+    }
+
+    // Check that path can be removed from the overlay. Entries that only exist in IPFS are
+    // immutable.
+    fn removable(&self, path: &Path) -> virtual_fs::Result<Entry> {
+        let path_str = path::to_str(path)?;
+        if path::segments(path_str).is_empty() {
+            return Err(FsError::PermissionDenied);
+        }
+        if let Some(entry) = self.overlay_entry(path_str) {
+            return Ok(entry);
+        }
+        match self.metadata(path) {
+            Ok(_) => Err(FsError::PermissionDenied),
+            Err(FsError::InvalidInput) => Err(FsError::EntryNotFound),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Translate a '/<name>/<path>' path under the IPNS mount to the IPFS path the name
+    // currently resolves to.
+    async fn resolve_ipns(&self, name_path: &str) -> virtual_fs::Result<String> {
+        let name_path = name_path.trim_start_matches('/');
+        let (name, rest) = name_path.split_once('/').unwrap_or((name_path, ""));
+        if name.is_empty() {
+            return Err(FsError::EntryNotFound);
+        }
+
+        let target = match self.ipns.get(name) {
+            Some(target) => target,
+            None => {
+                let target = self.client.client().resolve_name(name).await.map_err(|e| {
+                    tracing::error!("failed to resolve /ipns/{}: {}", name, e);
+                    FsError::EntryNotFound
+                })?;
+                self.ipns.insert(name.to_owned(), target.clone());
+                target
+            }
+        };
+        Ok(format!("{}/{}", target.trim_end_matches('/'), rest))
+    }
+
+    // Read the whole contents of a file node.
+    fn read_all(&self, node: Node) -> virtual_fs::Result<Vec<u8>> {
+        self.block_on_fetch(async {
+            let get_node = |cid| async move { self.client.get_node(&cid).await };
+            let contents = unixfs::read_range(&node, 0, node.size(), get_node).await;
+            let contents = contents.map_err(|e| {
+                tracing::error!("{}", e);
+                fs_error(&e)
+            })?;
+            Ok(contents.to_vec())
+        })
+    }
+
+    // Open a file for writing. Its current contents are loaded in memory and written back to
+    // IPFS when the file is flushed.
+    fn open_writable(
+        &self,
+        path: &str,
+        conf: &virtual_fs::OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn virtual_fs::VirtualFile + Send + Sync + 'static>> {
+        let segments = path::segments(path);
+        if path.starts_with(IPNS_PATH) || segments.is_empty() {
+            return match (self.compat, segments.is_empty()) {
+                (false, _) => Err(FsError::Unsupported),
+                (true, true) => Err(FsError::NotAFile),
+                (true, false) => Err(FsError::PermissionDenied),
+            };
+        }
+
+        if let Some(Entry::Dir(_)) = self.overlay_entry(path) {
+            return Err(FsError::NotAFile);
+        }
+        let existing = match self.resolve_node(path) {
+            Ok(node) if node.is_dir() => return Err(FsError::NotAFile),
+            Ok(node) => Some(node),
+            Err(FsError::EntryNotFound) | Err(FsError::InvalidInput) => None,
+            Err(e) => return Err(e),
+        };
+        // New and truncated files must be written back even if nothing is written to them.
+        let changed = existing.is_none() || conf.truncate();
+        let contents = match existing {
+            Some(_) if conf.create_new() => return Err(FsError::AlreadyExists),
+            Some(node) if !conf.truncate() => self.read_all(node)?,
+            Some(_) => Vec::new(),
+            None if conf.create() || conf.create_new() => Vec::new(),
+            None => return Err(FsError::EntryNotFound),
+        };
+
+        let mut writer = Writer::new(
+            self.client.client().clone(),
+            self.overlay.clone(),
+            self.pool.clone(),
+            segments,
+            conf.append(),
+        );
+        if changed {
+            writer.mark_dirty();
+        }
+        let mut ipfs_file = IpfsFile::new(path.to_owned(), contents);
+        ipfs_file.readable = conf.read();
+        ipfs_file.writer = Some(writer);
+        ipfs_file.overlay = self.overlay.clone();
+        ipfs_file.compat = self.compat;
+        Ok(Box::new(ipfs_file))
+    }
+
+    async fn fetch_node(&self, cid: &Cid) -> virtual_fs::Result<Node> {
+        self.client.get_node(cid).await.map_err(|e| {
+            tracing::error!("{}", e);
+            fs_error(&e)
+        })
+    }
+}
+
+// Metadata of a UnixFS node. Files report the UnixFS file size and directories their number
+// of links. The links of sharded directories lead to buckets rather than entries, and counting
+// the entries would take fetching every shard, so they report 0.
+fn node_metadata(node: &Node) -> virtual_fs::Metadata {
+    if node.typ == NodeType::HamtShard {
+        dir_metadata(0)
+    } else if node.is_dir() {
+        dir_metadata(node.links.len() as u64)
+    } else if node.typ == NodeType::Symlink {
+        let mut md = virtual_fs::Metadata::default();
+        md.ft = virtual_fs::FileType {
+            symlink: true,
+            ..Default::default()
+        };
+        md.len = node.data.len() as u64;
+        md
+    } else {
+        file_metadata(node.size())
+    }
+}
+
+// Target of a UnixFS symlink, stored as its data.
+fn symlink_target(node: &Node) -> virtual_fs::Result<String> {
+    String::from_utf8(node.data.to_vec()).map_err(|_| FsError::InvalidInput)
+}
+
+fn entry_metadata(entry: &Entry) -> virtual_fs::Metadata {
+    match entry {
+        Entry::File { size, .. } => file_metadata(*size),
+        Entry::Dir(dir) => dir_metadata(dir.len() as u64),
+    }
+}
+
+fn dir_metadata(len: u64) -> virtual_fs::Metadata {
+    let mut md = virtual_fs::Metadata::default();
+    md.ft = virtual_fs::FileType::new_dir();
+    md.len = len;
+    md
+}
+
+fn file_metadata(len: u64) -> virtual_fs::Metadata {
+    let mut md = virtual_fs::Metadata::default();
+    md.ft = virtual_fs::FileType::new_file();
+    md.len = len;
+    md
 }
 
 // We need to implement Debug to ble able to implement the other traits.
@@ -41,71 +512,223 @@ impl fmt::Debug for IpfsFs {
 impl virtual_fs::FileSystem for IpfsFs {
     #[instrument(level = "trace", skip_all, fields(?path), ret)]
     fn readlink(&self, path: &Path) -> virtual_fs::Result<PathBuf> {
-        Err(FsError::Unsupported)
+        if let Some((fs, _, path)) = self.mount_for(path) {
+            return fs.readlink(&path);
+        }
+        let path_str = path::to_str(path)?;
+        if self.overlay_entry(path_str).is_some() {
+            return Err(FsError::InvalidInput);
+        }
+        let node = self.resolve_node(path_str)?;
+        if node.typ != NodeType::Symlink {
+            return Err(FsError::InvalidInput);
+        }
+        Ok(PathBuf::from(symlink_target(&node)?))
     }
 
-    // TODO reconsider the whole function.
     #[instrument(level = "trace", skip_all, fields(?path), ret)]
     fn read_dir(&self, path: &Path) -> virtual_fs::Result<virtual_fs::ReadDir> {
-        let path_str = path.to_str().ok_or(FsError::EntryNotFound)?;
-        let files_request = block_on(self.client.ls(path_str));
-        match files_request {
-            Ok(files) => {
-                let dir_entries = files
-                    .iter()
-                    .map(|file| {
-                        let path_buf = PathBuf::from(file);
-                        let metadata = self.metadata(path_buf.as_path());
-                        virtual_fs::DirEntry {
-                            path: path_buf,
-                            metadata: metadata,
-                        }
-                    })
-                    .collect();
-                Ok(virtual_fs::ReadDir::new(dir_entries))
-            }
-            Err(e) => {
-                tracing::error!("{}", e);
-                Err(FsError::EntryNotFound) // TODO reconsider error
-            }
+        if let Some((fs, mount_point, path)) = self.mount_for(path) {
+            // Entries are listed relative to the mounted filesystem.
+            let entries = fs.read_dir(&path)?.map(|entry| {
+                let mut entry = entry?;
+                let rest = entry.path.strip_prefix("/").unwrap_or(&entry.path);
+                entry.path = mount_point.join(rest);
+                Ok(entry)
+            });
+            return Ok(virtual_fs::ReadDir::new(
+                entries.collect::<virtual_fs::Result<_>>()?,
+            ));
         }
+        let path_str = path::to_str(path)?;
+        let overlay = match self.overlay_entry(path_str) {
+            Some(Entry::Dir(dir)) => Some(dir),
+            Some(Entry::File { .. }) => return Err(FsError::BaseNotDirectory),
+            None => None,
+        };
+        // Directories created through the filesystem may not exist in IPFS.
+        let node = match self.resolve_node(path_str) {
+            Ok(node) if !node.is_dir() => return Err(FsError::BaseNotDirectory),
+            Ok(node) => Some(node),
+            Err(FsError::EntryNotFound) | Err(FsError::InvalidInput) if overlay.is_some() => None,
+            Err(e) => return Err(e),
+        };
+        // The entries of sharded directories are spread across the shards under their node.
+        let links = match node {
+            Some(node) => self.block_on_fetch(async {
+                let get_node = |cid| async move { self.client.get_node(&cid).await };
+                unixfs::dir_entries(&node, get_node).await.map_err(|e| {
+                    tracing::error!("{}", e);
+                    fs_error(&e)
+                })
+            })?,
+            None => Vec::new(),
+        };
+
+        // Each named link of the directory node is an entry, unless the overlay shadows it. The
+        // children are fetched concurrently, as listing needs the metadata of all of them.
+        let shadowed = |name: &str| overlay.as_ref().is_some_and(|dir| dir.contains(name));
+        let entries = links
+            .iter()
+            .filter(|link| !shadowed(&link.name))
+            .map(|link| async {
+                let metadata = match self.client.get_node(&link.cid).await {
+                    Ok(child) => Ok(node_metadata(&child)),
+                    Err(e) => {
+                        tracing::error!("{}", e);
+                        Err(fs_error(&e))
+                    }
+                };
+                let path = path.join(&link.name);
+                let metadata = metadata.map(|md| self.with_times(&path.to_string_lossy(), md));
+                virtual_fs::DirEntry { path, metadata }
+            });
+        let mut dir_entries =
+            self.block_on_fetch(async { Ok(futures::future::join_all(entries).await) })?;
+        for (name, entry) in overlay.iter().flat_map(|dir| dir.entries()) {
+            let path = path.join(name);
+            let metadata = self.with_times(&path.to_string_lossy(), entry_metadata(entry));
+            dir_entries.push(virtual_fs::DirEntry {
+                path,
+                metadata: Ok(metadata),
+            });
+        }
+        Ok(virtual_fs::ReadDir::new(dir_entries))
     }
 
     #[instrument(level = "trace", skip_all, fields(?path), ret)]
     fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
-        Err(FsError::Unsupported)
+        if let Some((fs, _, path)) = self.mount_for(path) {
+            return fs.create_dir(&path);
+        }
+        let path_str = path::to_str(path)?;
+        let segments = path::segments(path_str);
+        if path_str.starts_with(IPNS_PATH) || segments.is_empty() {
+            // The mount points always exist, so e.g. 'mkdir -p' gets past them.
+            return match (self.compat, segments.is_empty()) {
+                (false, _) => Err(FsError::Unsupported),
+                (true, true) => Err(FsError::AlreadyExists),
+                (true, false) => Err(FsError::PermissionDenied),
+            };
+        }
+        match self.metadata(path) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::EntryNotFound) | Err(FsError::InvalidInput) => {}
+            Err(e) => return Err(e),
+        }
+        // Entries right under the mount point have no parent to check.
+        if segments.len() > 1 {
+            let parent = path.parent().ok_or(FsError::EntryNotFound)?;
+            match self.metadata(parent) {
+                Ok(md) if md.is_dir() => {}
+                Ok(_) => return Err(FsError::BaseNotDirectory),
+                Err(FsError::InvalidInput) => return Err(FsError::EntryNotFound),
+                Err(e) => return Err(e),
+            }
+        }
+        self.overlay.insert_dir(&segments)
     }
 
     #[instrument(level = "trace", skip_all, fields(?path), ret)]
     fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
-        Err(FsError::Unsupported)
+        if let Some((fs, _, path)) = self.mount_for(path) {
+            return fs.remove_dir(&path);
+        }
+        match self.removable(path)? {
+            Entry::Dir(dir) if !dir.is_empty() => Err(FsError::DirectoryNotEmpty),
+            Entry::Dir(_) => {
+                let path_str = path::to_str(path)?;
+                self.overlay.remove(&path::segments(path_str));
+                Ok(())
+            }
+            Entry::File { .. } => Err(FsError::BaseNotDirectory),
+        }
     }
 
     #[instrument(level = "trace", skip_all, fields(?from, ?to), ret)]
     fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
-        Box::pin(async { Err(FsError::Unsupported) })
+        Box::pin(async move {
+            match (self.mount_for(from), self.mount_for(to)) {
+                (None, None) => {}
+                (Some((fs, from_mount, from)), Some((_, to_mount, to)))
+                    if from_mount == to_mount =>
+                {
+                    return fs.rename(&from, &to).await
+                }
+                // Entries can't be moved from one filesystem to another.
+                _ => return Err(FsError::Unsupported),
+            }
+
+            let from_str = path::to_str(from)?;
+            let to_str = path::to_str(to)?;
+            let (from_segments, to_segments) = (path::segments(from_str), path::segments(to_str));
+            if from_str.starts_with(IPNS_PATH)
+                || to_str.starts_with(IPNS_PATH)
+                || from_segments.is_empty()
+                || to_segments.is_empty()
+            {
+                return Err(FsError::Unsupported);
+            }
+
+            // Only entries written through the filesystem can be moved, the IPFS tree is
+            // immutable.
+            let Some(entry) = self.overlay_entry(from_str) else {
+                return match self.fetch_with_timeout(self.resolve(from_str)).await {
+                    Ok(_) => Err(FsError::PermissionDenied),
+                    Err(FsError::InvalidInput) => Err(FsError::EntryNotFound),
+                    Err(e) => Err(e),
+                };
+            };
+            if from_segments == to_segments {
+                return Ok(());
+            }
+            if to_segments.starts_with(&from_segments) {
+                return Err(FsError::InvalidInput);
+            }
+
+            // Whether the target is a directory and whether it is empty, if it exists.
+            let target = match self.overlay_entry(to_str) {
+                Some(Entry::Dir(dir)) => Some((true, dir.is_empty())),
+                Some(Entry::File { .. }) => Some((false, true)),
+                None => match self.fetch_with_timeout(self.resolve(to_str)).await {
+                    Ok(node) => Some((node.is_dir(), node.links.is_empty())),
+                    Err(FsError::EntryNotFound) | Err(FsError::InvalidInput) => None,
+                    Err(e) => return Err(e),
+                },
+            };
+            match (&entry, target) {
+                (Entry::Dir(_), Some((false, _))) => return Err(FsError::BaseNotDirectory),
+                (Entry::Dir(_), Some((true, false))) => return Err(FsError::DirectoryNotEmpty),
+                (Entry::File { .. }, Some((true, _))) => return Err(FsError::NotAFile),
+                _ => {}
+            }
+            self.overlay.rename(&from_segments, &to_segments)
+        })
     }
 
     #[instrument(level = "trace", skip_all, fields(?path), ret)]
     fn metadata(&self, path: &Path) -> virtual_fs::Result<virtual_fs::Metadata> {
-        let mut md = virtual_fs::Metadata::default();
-        if path.ends_with("/") {
-            md.ft = virtual_fs::FileType::new_dir();
-        } else {
-            md.ft = virtual_fs::FileType::new_file();
-        }
-        Ok(md)
+        self.stat(path, true)
     }
 
     #[instrument(level = "trace", skip_all, fields(?path), ret)]
     fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<virtual_fs::Metadata> {
-        self.metadata(path)
-        // Err(FsError::Unsupported)
+        self.stat(path, false)
     }
 
     #[instrument(level = "trace", skip_all, fields(?path), ret)]
     fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
-        Err(FsError::Unsupported)
+        if let Some((fs, _, path)) = self.mount_for(path) {
+            return fs.remove_file(&path);
+        }
+        match self.removable(path)? {
+            Entry::File { .. } => {
+                let path_str = path::to_str(path)?;
+                self.overlay.remove(&path::segments(path_str));
+                Ok(())
+            }
+            Entry::Dir(_) => Err(FsError::NotAFile),
+        }
     }
 
     #[instrument(level = "trace", skip_all, fields(), ret)]
@@ -119,10 +742,19 @@ impl virtual_fs::FileSystem for IpfsFs {
     fn mount(
         &self,
         _name: String,
-        _path: &Path,
-        _fs: Box<dyn virtual_fs::FileSystem + Send + Sync>,
+        path: &Path,
+        fs: Box<dyn virtual_fs::FileSystem + Send + Sync>,
     ) -> virtual_fs::Result<()> {
-        Err(FsError::Unsupported)
+        let mut mounts = self.mounts.write().unwrap();
+        // Mounts can't be nested, so each path belongs to a single filesystem.
+        let overlaps = mounts
+            .keys()
+            .any(|mount_point| mount_point.starts_with(path) || path.starts_with(mount_point));
+        if overlaps {
+            return Err(FsError::AlreadyExists);
+        }
+        mounts.insert(path.to_owned(), Arc::from(fs));
+        Ok(())
     }
 }
 
@@ -133,21 +765,34 @@ impl virtual_fs::FileOpener for IpfsFs {
         path: &Path,
         conf: &virtual_fs::OpenOptionsConfig,
     ) -> virtual_fs::Result<Box<dyn virtual_fs::VirtualFile + Send + Sync + 'static>> {
-        let path_str = path.to_str().ok_or(FsError::EntryNotFound)?;
-        let bytes_future = self.client.get_file(path_str);
+        if let Some((fs, _, path)) = self.mount_for(path) {
+            return fs.new_open_options().options(conf.clone()).open(path);
+        }
+        let path_str = path::to_str(path)?;
 
-        let bytes = block_on(async {
-            bytes_future
-                .map_ok(|chunk| chunk.to_vec())
-                .try_concat()
-                .await
-        });
+        if conf.write() || conf.append() || conf.create() || conf.create_new() || conf.truncate() {
+            return self.open_writable(path_str, conf);
+        }
 
-        let ipfs_file = match bytes {
-            Ok(b) => IpfsFile::new(path_str.to_owned(), b),
-            Err(_) => return Err(FsError::IOError), // TODO: use a proper error.
-        };
+        // Only the root node is fetched here, file blocks are streamed as the guest reads.
+        let node = self.resolve_following(path_str)?;
+        if node.is_dir() {
+            return Err(FsError::NotAFile);
+        }
 
+        let mut ipfs_file = IpfsFile::streaming(
+            path_str.to_owned(),
+            self.client.clone(),
+            node,
+            self.read_ahead,
+        );
+        if let (Some(progress), Source::Streaming(stream)) = (&self.progress, &mut ipfs_file.source)
+        {
+            stream.set_progress(progress.clone());
+        }
+        ipfs_file.readable = conf.read();
+        ipfs_file.overlay = self.overlay.clone();
+        ipfs_file.compat = self.compat;
         Ok(Box::new(ipfs_file))
     }
 }
@@ -157,19 +802,109 @@ impl virtual_fs::FileOpener for IpfsFs {
 // unsafe impl Sync for IpfsFs {}
 
 pub struct IpfsFile {
-    // bytes: Vec<u8>,
     path: String,
-    size: usize,
-    cursor: Cursor<Vec<u8>>,
+    size: u64,
+    pos: u64,
+    // Whether the file was opened for reading.
+    readable: bool,
+    source: Source,
+    // Set for files opened for writing, which are written back to IPFS when flushed.
+    writer: Option<Writer>,
+    // Overlay of the filesystem the file was opened from, holding its times.
+    overlay: Arc<Overlay>,
+    // Whether the filesystem the file was opened from is in compatibility mode.
+    compat: bool,
+    // Memory mapped into the file, which write_from_mmap copies regions of.
+    mmap: Option<Mmap>,
+}
+
+// Memory a guest builds its output in, shared with the files it's mapped into.
+pub type Mmap = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+// Where the contents of an IpfsFile are read from.
+enum Source {
+    // The whole file is held in memory.
+    Buffered(Vec<u8>),
+    // Blocks are fetched from IPFS as reads advance through the file.
+    Streaming(BlockStream),
 }
 
 impl IpfsFile {
     #[instrument(level = "trace", skip_all, fields(?bytes), ret)]
     pub fn new(path: String, bytes: Vec<u8>) -> IpfsFile {
         IpfsFile {
-            path: path,
-            size: bytes.len(),
-            cursor: Cursor::new(bytes),
+            path,
+            size: bytes.len() as u64,
+            pos: 0,
+            readable: true,
+            source: Source::Buffered(bytes),
+            writer: None,
+            overlay: Arc::new(Overlay::default()),
+            compat: false,
+            mmap: None,
+        }
+    }
+
+    // File whose blocks are fetched lazily from the UnixFS DAG under root. The size is taken
+    // from the root node, so it is known without downloading anything else.
+    #[instrument(level = "trace", skip_all, fields(?path), ret)]
+    pub fn streaming(
+        path: String,
+        client: CachedClient,
+        root: Node,
+        read_ahead: usize,
+    ) -> IpfsFile {
+        IpfsFile {
+            path,
+            size: root.size(),
+            pos: 0,
+            readable: true,
+            source: Source::Streaming(BlockStream::new(client, root, read_ahead)),
+            writer: None,
+            overlay: Arc::new(Overlay::default()),
+            compat: false,
+            mmap: None,
+        }
+    }
+
+    // Map mmap into the file, so regions of it can be written with write_from_mmap.
+    pub fn with_mmap(mut self, mmap: Mmap) -> IpfsFile {
+        self.mmap = Some(mmap);
+        self
+    }
+
+    // Write buf at the current position of a file opened for writing.
+    fn write_buffered(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (Some(writer), Source::Buffered(bytes)) = (self.writer.as_mut(), &mut self.source)
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                FsError::Unsupported,
+            ));
+        };
+
+        if writer.append {
+            self.pos = bytes.len() as u64;
+        }
+        // Writes past the end extend the file, zero-filling any gap.
+        let start = self.pos as usize;
+        let end = start + buf.len();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        self.size = bytes.len() as u64;
+        writer.mark_dirty();
+        Ok(buf.len())
+    }
+}
+
+impl Drop for IpfsFile {
+    fn drop(&mut self) {
+        // Don't lose writes the guest never flushed.
+        if let (Some(writer), Source::Buffered(bytes)) = (self.writer.as_mut(), &self.source) {
+            writer.flush_on_drop(bytes, &self.path);
         }
     }
 }
@@ -183,31 +918,61 @@ impl fmt::Debug for IpfsFile {
 impl AsyncRead for IpfsFile {
     #[instrument(level = "trace", skip_all, fields(?cx, ?buf), ret)]
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        if usize::try_from(self.cursor.position()).unwrap() < self.size {
-            buf.put(self.cursor.get_mut().as_slice());
+        let this = self.get_mut();
+        if !this.readable {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file not opened for reading",
+            )));
+        }
+        // Leave the buffer untouched to signal EOF.
+        if this.pos >= this.size {
+            return Poll::Ready(Ok(()));
         }
+
+        let available = match &mut this.source {
+            Source::Buffered(bytes) => &bytes[this.pos as usize..],
+            Source::Streaming(stream) => ready!(stream.poll_at(cx, this.pos))?,
+        };
+        // Copy at most buf.remaining() bytes and advance the position by the amount read.
+        let n = min(buf.remaining(), available.len());
+        buf.put_slice(&available[..n]);
+        this.pos += n as u64;
         Poll::Ready(Ok(()))
     }
 }
 
-// TODO
 impl AsyncSeek for IpfsFile {
     #[instrument(level = "trace", skip_all, fields(?position), ret)]
     fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
-        let res = self.cursor.seek(position);
-        match res {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+        // Resolve the target offset against the file size rather than the buffered bytes.
+        let (base, offset) = match position {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(());
+            }
+            SeekFrom::End(n) => (self.size, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        match base.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
         }
     }
 
     #[instrument(level = "trace", skip_all, fields(?cx), ret)]
     fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
-        Poll::Ready(Ok(self.cursor.position()))
+        Poll::Ready(Ok(self.pos))
     }
 }
 
@@ -218,26 +983,26 @@ impl AsyncWrite for IpfsFile {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        Poll::Ready(Err(io::Error::new(
-            io::ErrorKind::Unsupported,
-            FsError::Unsupported,
-        )))
+        Poll::Ready(self.get_mut().write_buffered(buf))
     }
 
     #[instrument(level = "trace", skip_all, fields(?cx), ret)]
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Poll::Ready(Err(io::Error::new(
-            io::ErrorKind::Unsupported,
-            FsError::Unsupported,
-        )))
+        let this = self.get_mut();
+        match (this.writer.as_mut(), &this.source) {
+            (Some(writer), Source::Buffered(bytes)) => writer.poll_flush(cx, bytes),
+            // Read-only files have nothing to flush.
+            _ if this.compat => Poll::Ready(Ok(())),
+            _ => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                FsError::Unsupported,
+            ))),
+        }
     }
 
     #[instrument(level = "trace", skip_all, fields(?cx), ret)]
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Poll::Ready(Err(io::Error::new(
-            io::ErrorKind::Unsupported,
-            FsError::Unsupported,
-        )))
+        self.poll_flush(cx)
     }
 }
 
@@ -248,38 +1013,52 @@ impl AsyncWrite for IpfsFile {
 impl virtual_fs::VirtualFile for IpfsFile {
     #[instrument(level = "trace", skip_all, fields(), ret)]
     fn last_accessed(&self) -> u64 {
-        0
+        self.overlay.times(&path::segments(&self.path)).accessed
     }
 
     #[instrument(level = "trace", skip_all, fields(), ret)]
     fn last_modified(&self) -> u64 {
-        0
+        self.overlay.times(&path::segments(&self.path)).modified
     }
 
     #[instrument(level = "trace", skip_all, fields(), ret)]
     fn created_time(&self) -> u64 {
-        0
+        self.overlay.times(&path::segments(&self.path)).created
     }
 
     #[allow(unused_variables)]
     #[instrument(level = "trace", skip_all, fields(?atime, ?mtime), ret)]
     fn set_times(&mut self, atime: Option<u64>, mtime: Option<u64>) -> virtual_fs::Result<()> {
-        Err(FsError::Unsupported)
+        // Blocks are immutable, so times are tracked per path in the overlay.
+        self.overlay
+            .set_times(&path::segments(&self.path), atime, mtime);
+        Ok(())
     }
 
     #[instrument(level = "trace", skip_all, fields(), ret)]
     fn size(&self) -> u64 {
-        self.size as u64
+        self.size
     }
 
     #[instrument(level = "trace", skip_all, fields(?new_size), ret)]
     fn set_len(&mut self, new_size: u64) -> virtual_fs::Result<()> {
-        Err(FsError::Unsupported)
+        let (Some(writer), Source::Buffered(bytes)) = (self.writer.as_mut(), &mut self.source)
+        else {
+            return Err(FsError::Unsupported);
+        };
+        bytes.resize(new_size as usize, 0);
+        self.size = new_size;
+        writer.mark_dirty();
+        Ok(())
     }
 
     #[instrument(level = "trace", skip_all, fields(), ret)]
     fn unlink(&mut self) -> virtual_fs::Result<()> {
-        Err(FsError::Unsupported)
+        match self.compat {
+            // The file stays where it is, and readable while open.
+            true => Ok(()),
+            false => Err(FsError::Unsupported),
+        }
     }
 
     #[instrument(level = "trace", skip_all, fields(), ret)]
@@ -287,14 +1066,32 @@ impl virtual_fs::VirtualFile for IpfsFile {
         true
     }
 
+    // Special fds stand for the stdio of the host, which wasix reads and writes directly, so
+    // IPFS files have none. Guests polling a file wait on poll_read_ready instead, which only
+    // wakes them once the block at the read position is fetched.
     #[instrument(level = "trace", skip_all, fields(), ret)]
     fn get_special_fd(&self) -> Option<u32> {
         None
     }
 
-    #[instrument(level = "trace", skip_all, fields(?_offset, ?_len), ret)]
-    fn write_from_mmap(&mut self, _offset: u64, _len: u64) -> std::io::Result<()> {
-        Err(std::io::ErrorKind::Unsupported.into())
+    #[instrument(level = "trace", skip_all, fields(?offset, ?len), ret)]
+    fn write_from_mmap(&mut self, offset: u64, len: u64) -> std::io::Result<()> {
+        // Writes the region straight from the mapped memory at the current position, like write
+        // would, so only files opened for writing with memory mapped into them support it.
+        let Some(mmap) = self.mmap.clone() else {
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+        let mapped = (*mmap).as_ref();
+        let region = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(len).ok())
+            .and_then(|(start, len)| mapped.get(start..start.checked_add(len)?));
+        let Some(region) = region else {
+            let mapped = mapped.len();
+            let msg = format!("{len} bytes at {offset} are past the {mapped} bytes mapped");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        };
+        self.write_buffered(region).map(|_| ())
     }
 
     #[instrument(level = "trace", skip_all, fields(?src), ret)]
@@ -302,24 +1099,805 @@ impl virtual_fs::VirtualFile for IpfsFile {
         &mut self,
         mut src: Box<dyn virtual_fs::VirtualFile + Send + Sync + 'static>,
     ) -> BoxFuture<'_, std::io::Result<()>> {
+        // Copies into files opened for writing, which are written back to IPFS when flushed.
+        // If the source fails midway, what was copied until then stays in the file.
         Box::pin(async move {
-            let bytes_written = tokio::io::copy(&mut src, self).await?;
-            tracing::trace!(bytes_written, "Copying file into host filesystem",);
+            let mut chunk = vec![0; COPY_CHUNK_SIZE];
+            let mut bytes_written = 0u64;
+            loop {
+                let n = src.read(&mut chunk).await.map_err(|e| {
+                    let path = &self.path;
+                    let msg = format!("copying into {path} after {bytes_written} bytes: {e}");
+                    io::Error::new(e.kind(), msg)
+                })?;
+                if n == 0 {
+                    break;
+                }
+                // Short writes are retried until the whole chunk is in.
+                self.write_all(&chunk[..n]).await?;
+                bytes_written += n as u64;
+            }
+            tracing::trace!(bytes_written, path = %self.path, "copied file");
             Ok(())
         })
     }
 
     #[instrument(level = "trace", skip_all, fields(?cx), ret)]
     fn poll_read_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
-        if self.size == 0 && self.cursor.position() == 0 {
+        // Report the bytes that can be read without waiting on the network, and wait for the
+        // block at the current position to be fetched if it isn't yet.
+        let this = self.get_mut();
+        if !this.readable || this.pos >= this.size {
             return Poll::Ready(Ok(0));
         }
-        let left: usize = self.size - usize::try_from(self.cursor.position()).unwrap() - 1;
-        Poll::Ready(Ok(left))
+        match &mut this.source {
+            Source::Buffered(_) => Poll::Ready(Ok((this.size - this.pos) as usize)),
+            Source::Streaming(stream) => stream.poll_at(cx, this.pos).map_ok(|data| data.len()),
+        }
     }
 
     #[instrument(level = "trace", skip_all, fields(?cx), ret)]
     fn poll_write_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
-        Poll::Ready(Ok(0))
+        // Writes go to the in-memory contents, which can grow up to the maximum Vec size.
+        match (&self.writer, &self.source) {
+            (Some(_), Source::Buffered(bytes)) => {
+                Poll::Ready(Ok((isize::MAX as usize).saturating_sub(bytes.len())))
+            }
+            _ => Poll::Ready(Ok(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use net::testing::Daemon;
+    use tokio::io::AsyncSeekExt;
+    use virtual_fs::{FileSystem, VirtualFile};
+
+    #[test]
+    fn test_open_write_flags() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let fs = IpfsFs::new(client);
+
+        // Paths that aren't under a CID are only looked up in the overlay, so no daemon is
+        // needed to find out they don't exist.
+        let mut write = fs.new_open_options();
+        write.write(true);
+        assert_eq!(
+            write.open("/ipfs/scratch.txt").unwrap_err(),
+            FsError::EntryNotFound
+        );
+
+        let mut append = fs.new_open_options();
+        append.append(true);
+        assert_eq!(
+            append.open("/ipfs/scratch.txt").unwrap_err(),
+            FsError::EntryNotFound
+        );
+
+        // IPNS names can't be written to.
+        let mut create = fs.new_open_options();
+        create.create(true);
+        assert_eq!(
+            create.open("/ipns/example.com/file").unwrap_err(),
+            FsError::Unsupported
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_stats() {
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let a = client.add_bytes(Bytes::from_static(b"a")).await.unwrap();
+        let b = client.add_bytes(Bytes::from_static(b"b")).await.unwrap();
+        // Room for a single one of the files, which are a block of a byte each.
+        let fs = IpfsFs::with_cache(client, 1);
+        // Opens fetch blocks with blocking calls.
+        let open = |cid: &Cid| {
+            let path = format!("/ipfs/{cid}");
+            tokio::task::block_in_place(|| fs.new_open_options().read(true).open(path).unwrap());
+            fs.cache_stats()
+        };
+
+        let fresh = open(&a);
+        assert!(fresh.misses > 0);
+        assert_eq!(fresh.entries, 1);
+        assert_eq!(daemon.requests("block/get"), 1);
+
+        // Opening the same file again is served from the cache...
+        let repeated = open(&a);
+        assert_eq!(repeated.misses, fresh.misses);
+        assert!(repeated.hits > fresh.hits);
+        assert_eq!(daemon.requests("block/get"), 1);
+
+        // ...unlike opening another one, which makes room for its block.
+        let other = open(&b);
+        assert!(other.misses > repeated.misses);
+        assert_eq!(other.evictions, 1);
+        assert_eq!(other.entries, 1);
+        assert_eq!(other.bytes, 1);
+
+        // The first file was dropped to make room, so it is fetched again.
+        let evicted = open(&a);
+        assert!(evicted.misses > other.misses);
+        assert_eq!(evicted.evictions, 2);
+        assert_eq!(daemon.requests("block/get"), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_open_cached() {
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let cid = client
+            .add_bytes(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        let fs = IpfsFs::with_cache(client, 1 << 20);
+        let path = format!("/ipfs/{cid}");
+
+        for _ in 0..2 {
+            let mut file = tokio::task::block_in_place(|| {
+                fs.new_open_options().read(true).open(&path).unwrap()
+            });
+            let mut read = String::new();
+            file.read_to_string(&mut read).await.unwrap();
+            assert_eq!(read, "hello");
+        }
+        // The second open is served from the cache rather than the daemon.
+        assert_eq!(daemon.requests("block/get"), 1);
+    }
+
+    // Store a directory of two files, a.txt and b.txt, through client.
+    async fn add_dir(client: &Client) -> Cid {
+        let mut links = Vec::new();
+        for (name, data) in [("a.txt", &b"hello"[..]), ("b.txt", &b"hi"[..])] {
+            let (cid, tsize) = client.add_file_sized(data).await.unwrap();
+            let name = name.to_owned();
+            links.push(unixfs::Link { cid, name, tsize });
+        }
+        client.put_directory(links).await.unwrap().0
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_dir() {
+        let daemon = Daemon::start();
+        let dir = add_dir(&daemon.client()).await;
+        let fs = IpfsFs::new(daemon.client());
+
+        tokio::task::block_in_place(|| {
+            assert_eq!(
+                dir_names(&fs, &format!("/ipfs/{dir}")),
+                vec!["a.txt", "b.txt"]
+            );
+            assert_eq!(
+                fs.read_dir(Path::new(&format!("/ipfs/{dir}/a.txt")))
+                    .unwrap_err(),
+                FsError::BaseNotDirectory
+            );
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_metadata() {
+        let daemon = Daemon::start();
+        let dir = add_dir(&daemon.client()).await;
+        let fs = IpfsFs::new(daemon.client());
+        let metadata = |path: &str| tokio::task::block_in_place(|| fs.metadata(Path::new(path)));
+
+        let file = metadata(&format!("/ipfs/{dir}/a.txt")).unwrap();
+        assert!(file.is_file());
+        assert_eq!(file.len, 5);
+
+        // Directories are as long as the links they have.
+        let md = metadata(&format!("/ipfs/{dir}")).unwrap();
+        assert!(md.is_dir());
+        assert_eq!(md.len, 2);
+
+        assert_eq!(
+            metadata(&format!("/ipfs/{dir}/missing")).unwrap_err(),
+            FsError::EntryNotFound
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ipns_ttl() {
+        const TTL: Duration = Duration::from_millis(200);
+
+        async fn read(fs: &IpfsFs, path: &str) -> virtual_fs::Result<String> {
+            let mut file =
+                tokio::task::block_in_place(|| fs.new_open_options().read(true).open(path))?;
+            let mut read = String::new();
+            file.read_to_string(&mut read).await.unwrap();
+            Ok(read)
+        }
+
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let a = client.add_bytes(Bytes::from_static(b"a")).await.unwrap();
+        let b = client.add_bytes(Bytes::from_static(b"b")).await.unwrap();
+        let fs = IpfsFs::new(client).with_ipns_ttl(TTL);
+
+        daemon.set_name("example.com", Some(&format!("/ipfs/{a}")));
+        assert_eq!(read(&fs, "/ipns/example.com").await.unwrap(), "a");
+
+        // The name is republished, but stays resolved to the former path until the TTL is up.
+        daemon.set_name("example.com", Some(&format!("/ipfs/{b}")));
+        assert_eq!(read(&fs, "/ipns/example.com").await.unwrap(), "a");
+        assert_eq!(daemon.requests("name/resolve"), 1);
+        tokio::time::sleep(TTL).await;
+        assert_eq!(read(&fs, "/ipns/example.com").await.unwrap(), "b");
+        assert_eq!(daemon.requests("name/resolve"), 2);
+
+        // Names that don't resolve have no entries under them.
+        assert_eq!(
+            read(&fs, "/ipns/missing.example.com").await.unwrap_err(),
+            FsError::EntryNotFound
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_timeout() {
+        use std::time::Instant;
+
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let cid = client
+            .add_bytes(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        // The daemon stalls well past the timeout.
+        daemon.set_delay(Duration::from_secs(30));
+        let fs = IpfsFs::new(client).with_timeout(Duration::from_millis(100));
+
+        let start = Instant::now();
+        let open = tokio::task::block_in_place(|| {
+            fs.new_open_options()
+                .read(true)
+                .open(format!("/ipfs/{cid}"))
+        });
+        assert_eq!(open.unwrap_err(), FsError::TimedOut);
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "took {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_concurrent_opens() {
+        use std::convert::Infallible;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Instant;
+
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response};
+
+        const DELAY: Duration = Duration::from_millis(300);
+        const OPENS: u8 = 4;
+
+        // Daemon answering every block request after DELAY, recording how many it answers at
+        // once.
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let daemon = tokio::runtime::Runtime::new().unwrap();
+        let addr = daemon.block_on(async {
+            let make_service = make_service_fn({
+                let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+                move |_| {
+                    let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                            let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_in_flight.fetch_max(n, Ordering::SeqCst);
+                            let in_flight = in_flight.clone();
+                            async move {
+                                tokio::time::sleep(DELAY).await;
+                                in_flight.fetch_sub(1, Ordering::SeqCst);
+                                Ok::<_, Infallible>(Response::new(Body::from("data")))
+                            }
+                        }))
+                    }
+                }
+            });
+            let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+            addr
+        });
+
+        let daemon_addr = format!("/ip4/127.0.0.1/tcp/{}", addr.port());
+        let client = Client::new(daemon_addr.parse().unwrap());
+        // The daemon serves the same data for every CID.
+        let fs = IpfsFs::new(client).with_verify(false).with_fetch_threads(1);
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for i in 0..OPENS {
+                let fs = &fs;
+                scope.spawn(move || {
+                    let hash = cid::multihash::Multihash::wrap(0x12, &[i; 32]).unwrap();
+                    let path = format!("/ipfs/{}", Cid::new_v1(unixfs::RAW, hash));
+                    fs.new_open_options().read(true).open(path).unwrap();
+                });
+            }
+        });
+
+        // Even with a single fetch thread, the opens wait on the daemon together rather than
+        // one after the other.
+        assert!(start.elapsed() < DELAY * 2, "took {:?}", start.elapsed());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), OPENS as usize);
+    }
+
+    // What an off-the-shelf program may do with its files: make sure its directories exist,
+    // open a log, flush an input it only read and unlink it once done.
+    fn guest_calls(
+        fs: &IpfsFs,
+    ) -> (
+        virtual_fs::Result<()>,
+        virtual_fs::Result<()>,
+        Result<(), io::ErrorKind>,
+        virtual_fs::Result<()>,
+    ) {
+        let mkdir = fs.create_dir(Path::new("/ipfs"));
+        let log = fs
+            .new_open_options()
+            .append(true)
+            .create(true)
+            .open("/ipns/example.com/log")
+            .map(drop);
+        let mut input = IpfsFile::new("/ipfs/input.txt".to_owned(), b"input".to_vec());
+        input.compat = fs.compat;
+        let flush = block_on(input.flush()).map_err(|e| e.kind());
+        (mkdir, log, flush, input.unlink())
+    }
+
+    #[test]
+    fn test_compat() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let strict = IpfsFs::new(client.clone());
+        assert_eq!(
+            guest_calls(&strict),
+            (
+                Err(FsError::Unsupported),
+                Err(FsError::Unsupported),
+                Err(io::ErrorKind::Unsupported),
+                Err(FsError::Unsupported)
+            )
+        );
+
+        let compat = IpfsFs::new(client).with_compat(true);
+        assert_eq!(
+            guest_calls(&compat),
+            (
+                Err(FsError::AlreadyExists),
+                Err(FsError::PermissionDenied),
+                Ok(()),
+                Ok(())
+            )
+        );
+    }
+
+    fn dir_names(fs: &IpfsFs, path: &str) -> Vec<String> {
+        let entries = fs.read_dir(Path::new(path)).unwrap();
+        entries
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_create_and_remove() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let fs = IpfsFs::new(client);
+
+        fs.create_dir(Path::new("/ipfs/scratch")).unwrap();
+        assert!(fs.metadata(Path::new("/ipfs/scratch")).unwrap().is_dir());
+        assert!(dir_names(&fs, "/ipfs/scratch").is_empty());
+        assert_eq!(
+            fs.create_dir(Path::new("/ipfs/scratch")),
+            Err(FsError::AlreadyExists)
+        );
+        assert_eq!(
+            fs.create_dir(Path::new("/ipfs/missing/dir")),
+            Err(FsError::EntryNotFound)
+        );
+
+        // Bind the file directly, writing it back would need a daemon.
+        let cid = Cid::try_from("QmeeLUVdiSTTKQqhWqsffYDtNvvvcTfJdotkNyi1KDEJtQ").unwrap();
+        let file = path::segments("/ipfs/scratch/file.txt");
+        fs.overlay.insert_file(&file, cid, 5, 5).unwrap();
+        assert_eq!(dir_names(&fs, "/ipfs/scratch"), vec!["file.txt"]);
+        assert_eq!(
+            fs.remove_dir(Path::new("/ipfs/scratch")),
+            Err(FsError::DirectoryNotEmpty)
+        );
+
+        fs.remove_file(Path::new("/ipfs/scratch/file.txt")).unwrap();
+        assert!(dir_names(&fs, "/ipfs/scratch").is_empty());
+        fs.remove_dir(Path::new("/ipfs/scratch")).unwrap();
+        assert!(dir_names(&fs, "/ipfs").is_empty());
+        assert_eq!(
+            fs.remove_dir(Path::new("/ipfs/scratch")),
+            Err(FsError::EntryNotFound)
+        );
+    }
+
+    #[test]
+    fn test_rename() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let fs = IpfsFs::new(client);
+        let cid = Cid::try_from("QmeeLUVdiSTTKQqhWqsffYDtNvvvcTfJdotkNyi1KDEJtQ").unwrap();
+        fs.overlay
+            .insert_file(&path::segments("/ipfs/a/file.txt"), cid, 5, 5)
+            .unwrap();
+        fs.create_dir(Path::new("/ipfs/b")).unwrap();
+
+        // Within a directory.
+        block_on(fs.rename(
+            Path::new("/ipfs/a/file.txt"),
+            Path::new("/ipfs/a/moved.txt"),
+        ))
+        .unwrap();
+        assert_eq!(dir_names(&fs, "/ipfs/a"), vec!["moved.txt"]);
+
+        // Across directories, creating the missing ones.
+        block_on(fs.rename(
+            Path::new("/ipfs/a/moved.txt"),
+            Path::new("/ipfs/b/c/file.txt"),
+        ))
+        .unwrap();
+        assert!(dir_names(&fs, "/ipfs/a").is_empty());
+        assert_eq!(dir_names(&fs, "/ipfs/b/c"), vec!["file.txt"]);
+        assert_eq!(fs.metadata(Path::new("/ipfs/b/c/file.txt")).unwrap().len, 5);
+
+        assert_eq!(
+            block_on(fs.rename(Path::new("/ipfs/a/missing"), Path::new("/ipfs/b/x"))),
+            Err(FsError::EntryNotFound)
+        );
+        // A directory can't replace a file, nor a file a directory.
+        assert_eq!(
+            block_on(fs.rename(Path::new("/ipfs/a"), Path::new("/ipfs/b/c/file.txt"))),
+            Err(FsError::BaseNotDirectory)
+        );
+        assert_eq!(
+            block_on(fs.rename(Path::new("/ipfs/b/c/file.txt"), Path::new("/ipfs/a"))),
+            Err(FsError::NotAFile)
+        );
+
+        // Nor can a file be a parent of the target, which leaves both entries as they were.
+        assert_eq!(
+            block_on(fs.rename(Path::new("/ipfs/a"), Path::new("/ipfs/b/c/file.txt/a"))),
+            Err(FsError::BaseNotDirectory)
+        );
+        assert!(fs.metadata(Path::new("/ipfs/a")).unwrap().is_dir());
+        assert!(fs
+            .metadata(Path::new("/ipfs/b/c/file.txt"))
+            .unwrap()
+            .is_file());
+        assert_eq!(dir_names(&fs, "/ipfs/b/c"), vec!["file.txt"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_root_tsizes() {
+        let daemon = Daemon::start();
+        // Small chunks, so the file is a tree of blocks and its root links to the leaves.
+        let client = daemon.client().with_chunk_size(4);
+        let (cid, tsize) = client.add_file_sized(&b"hello world"[..]).await.unwrap();
+        let fs = IpfsFs::new(client);
+        fs.overlay
+            .insert_file(&path::segments("/ipfs/a/file.txt"), cid, 11, tsize)
+            .unwrap();
+
+        let root = tokio::task::block_in_place(|| fs.root_cid()).unwrap();
+        let root = fs.client.get_node(&root).await.unwrap();
+        let a = fs.client.get_node(&root.links[0].cid).await.unwrap();
+        let file = fs.client.get_node(&a.links[0].cid).await.unwrap();
+
+        // Links record the cumulative size of the blocks under them, down to the leaves.
+        assert!(tsize > 11);
+        assert_eq!(file.dag_size(), tsize);
+        assert_eq!(a.links[0].tsize, tsize);
+        assert_eq!(root.links[0].tsize, a.dag_size());
+    }
+
+    #[test]
+    fn test_set_times() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let fs = IpfsFs::new(client);
+        let cid = Cid::try_from("QmeeLUVdiSTTKQqhWqsffYDtNvvvcTfJdotkNyi1KDEJtQ").unwrap();
+        fs.overlay
+            .insert_file(&path::segments("/ipfs/file.txt"), cid, 5, 5)
+            .unwrap();
+
+        let mut file = IpfsFile::new("/ipfs/file.txt".to_owned(), b"hello".to_vec());
+        file.overlay = fs.overlay.clone();
+        let created = file.created_time();
+        assert!(created > 0);
+
+        file.set_times(Some(1_000), Some(2_000)).unwrap();
+        assert_eq!(file.last_accessed(), 1_000);
+        assert_eq!(file.last_modified(), 2_000);
+        assert_eq!(file.created_time(), created);
+
+        // Only the given times change, and they are visible through the filesystem.
+        file.set_times(None, Some(3_000)).unwrap();
+        let md = fs.metadata(Path::new("/ipfs/file.txt")).unwrap();
+        assert_eq!((md.accessed, md.modified), (1_000, 3_000));
+    }
+
+    #[test]
+    fn test_mount() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let fs = IpfsFs::new(client);
+        let tmp = virtual_fs::mem_fs::FileSystem::default();
+        fs.mount("tmp".to_owned(), Path::new("/tmp"), Box::new(tmp))
+            .unwrap();
+        assert_eq!(
+            fs.mount(
+                "nested".to_owned(),
+                Path::new("/tmp/nested"),
+                Box::new(virtual_fs::mem_fs::FileSystem::default())
+            ),
+            Err(FsError::AlreadyExists)
+        );
+
+        // Writes land in the mounted filesystem, without reaching the overlay or a daemon.
+        let mut file = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/tmp/scratch.txt")
+            .unwrap();
+        block_on(file.write_all(b"hello")).unwrap();
+        drop(file);
+
+        assert_eq!(fs.metadata(Path::new("/tmp/scratch.txt")).unwrap().len, 5);
+        assert_eq!(dir_names(&fs, "/tmp"), vec!["scratch.txt"]);
+        assert!(fs
+            .overlay
+            .get(&path::segments("/tmp/scratch.txt"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_clone() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let fs = IpfsFs::new(client);
+        let clone = fs.clone();
+        let tmp = virtual_fs::mem_fs::FileSystem::default();
+        clone
+            .mount("tmp".to_owned(), Path::new("/tmp"), Box::new(tmp))
+            .unwrap();
+        clone.create_dir(Path::new("/ipfs/scratch")).unwrap();
+
+        // Written through one clone on another thread, read through the other.
+        std::thread::spawn(move || {
+            let mut file = clone
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .open("/tmp/scratch.txt")
+                .unwrap();
+            block_on(file.write_all(b"hello")).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let mut file = fs
+            .new_open_options()
+            .read(true)
+            .open("/tmp/scratch.txt")
+            .unwrap();
+        let mut read = String::new();
+        block_on(file.read_to_string(&mut read)).unwrap();
+        assert_eq!(read, "hello");
+        assert!(fs.metadata(Path::new("/ipfs/scratch")).unwrap().is_dir());
+
+        // Settings stay with the handle they were changed on.
+        let timeout = fs.clone().with_timeout(Duration::from_secs(1));
+        assert_eq!(timeout.timeout, Some(Duration::from_secs(1)));
+        assert_eq!(fs.timeout, None);
+        assert_eq!(dir_names(&timeout, "/ipfs"), vec!["scratch"]);
+    }
+
+    #[test]
+    fn test_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let fs = IpfsFs::new(client);
+        let path = Path::new(OsStr::from_bytes(b"/ipfs/\xff\xfe"));
+
+        assert_eq!(
+            fs.new_open_options().open(path).unwrap_err(),
+            FsError::InvalidInput
+        );
+        assert_eq!(fs.metadata(path).unwrap_err(), FsError::InvalidInput);
+        assert_eq!(fs.read_dir(path).unwrap_err(), FsError::InvalidInput);
+    }
+
+    #[test]
+    fn test_symlink_metadata() {
+        let link = Node {
+            typ: NodeType::Symlink,
+            ..Node::file(Bytes::from_static(b"../lib/libc.so"))
+        };
+        let md = node_metadata(&link);
+        assert!(md.ft.symlink);
+        assert_eq!(md.len, 14);
+        assert_eq!(symlink_target(&link).unwrap(), "../lib/libc.so");
+    }
+
+    #[test]
+    fn test_sharded_dir_metadata() {
+        // Links to an entry and to a shard holding any number of others.
+        let cid = Cid::try_from("QmeeLUVdiSTTKQqhWqsffYDtNvvvcTfJdotkNyi1KDEJtQ").unwrap();
+        let link = |name: &str| unixfs::Link {
+            cid,
+            name: name.to_owned(),
+            tsize: 0,
+        };
+        let shard = Node {
+            typ: NodeType::HamtShard,
+            ..Node::directory(vec![link("0Aa.txt"), link("1F")])
+        };
+        let md = node_metadata(&shard);
+        assert!(md.ft.dir);
+        assert_eq!(md.len, 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_in_chunks() -> io::Result<()> {
+        let content: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let mut file = IpfsFile::new("/ipfs/test".to_owned(), content.clone());
+
+        // Read with a buffer that doesn't divide the file size to cross chunk boundaries.
+        let mut read = Vec::new();
+        let mut chunk = [0u8; 7];
+        loop {
+            let n = file.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(read, content);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_seek_then_read() -> io::Result<()> {
+        let content: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+        let mut file = IpfsFile::new("/ipfs/test".to_owned(), content.clone());
+
+        let pos = file.seek(SeekFrom::Start(2048)).await?;
+        assert_eq!(pos, 2048);
+        let mut buf = [0u8; 16];
+        file.read_exact(&mut buf).await?;
+        assert_eq!(&buf[..], &content[2048..2064]);
+
+        let pos = file.seek(SeekFrom::End(-16)).await?;
+        assert_eq!(pos, 4080);
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).await?;
+        assert_eq!(&rest[..], &content[4080..]);
+
+        assert!(file.seek(SeekFrom::Current(-5000)).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_reference() {
+        let content: Vec<u8> = (0..=255u8).cycle().take(3 * COPY_CHUNK_SIZE / 2).collect();
+        let src = IpfsFile::new("/ipfs/src.bin".to_owned(), content.clone());
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let overlay = Arc::new(Overlay::default());
+        let segments = path::segments("/ipfs/dst.bin");
+        let mut dst = IpfsFile::new("/ipfs/dst.bin".to_owned(), Vec::new());
+        let pool = Arc::new(FetchPool::new(1));
+        dst.writer = Some(Writer::new(client, overlay, pool, segments, false));
+
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_writer(logs.clone())
+            .without_time()
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            block_on(dst.copy_reference(Box::new(src))).unwrap();
+        });
+
+        let Source::Buffered(copied) = &dst.source else {
+            unreachable!();
+        };
+        assert_eq!(copied, &content);
+        assert_eq!(dst.size(), content.len() as u64);
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let logged = format!("bytes_written={}", content.len());
+        assert!(logs.contains(&logged), "{logs}");
+        // Nothing to write back without a daemon.
+        dst.writer = None;
+    }
+
+    #[test]
+    fn test_write_from_mmap() {
+        let mapped: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let overlay = Arc::new(Overlay::default());
+        let segments = path::segments("/ipfs/out.bin");
+        let mut file = IpfsFile::new("/ipfs/out.bin".to_owned(), b"head".to_vec())
+            .with_mmap(Arc::new(mapped.clone()));
+        let pool = Arc::new(FetchPool::new(1));
+        file.writer = Some(Writer::new(client, overlay, pool, segments, false));
+
+        // Regions are written at the position, overwriting and then extending the file.
+        block_on(file.seek(SeekFrom::Start(2))).unwrap();
+        file.write_from_mmap(100, 300).unwrap();
+        file.write_from_mmap(0, 0).unwrap();
+        file.write_from_mmap(900, 100).unwrap();
+        assert_eq!(file.size(), 402);
+
+        block_on(file.seek(SeekFrom::Start(0))).unwrap();
+        let mut read = Vec::new();
+        block_on(file.read_to_end(&mut read)).unwrap();
+        assert_eq!(&read[..2], b"he");
+        assert_eq!(read[2..302], mapped[100..400]);
+        assert_eq!(read[302..], mapped[900..]);
+
+        for (offset, len) in [(1000, 1), (999, 2), (u64::MAX, 1), (1, u64::MAX)] {
+            let e = file.write_from_mmap(offset, len).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(file.size(), 402);
+
+        // Nothing to write back without a daemon.
+        file.writer = None;
+        let e = file.write_from_mmap(0, 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        let mut unmapped = IpfsFile::new("/ipfs/a.txt".to_owned(), Vec::new());
+        let e = unmapped.write_from_mmap(0, 0).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn test_drop_unflushed() {
+        // A daemon that accepts connections and never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = Client::new(format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap());
+        let segments = path::segments("/ipfs/out.txt");
+        let mut file = IpfsFile::new("/ipfs/out.txt".to_owned(), Vec::new());
+        let pool = Arc::new(FetchPool::new(1));
+        file.writer = Some(Writer::new(client, Arc::default(), pool, segments, false));
+        file.write_all(b"hello").await.unwrap();
+
+        // Dropped on the only thread of the runtime, the file leaves the write-back to it
+        // instead of waiting for a reply the runtime would have to drive.
+        drop(file);
+        tokio::task::yield_now().await;
+    }
+
+    // Log lines written by a subscriber, for the test to read back.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for Logs {
+        type Writer = Logs;
+
+        fn make_writer(&'a self) -> Logs {
+            self.clone()
+        }
     }
 }
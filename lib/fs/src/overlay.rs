@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+use wasmer_wasix::FsError;
+
+use net::ipfs::{Cid, Client, Error};
+use net::unixfs::{Link, Node};
+
+// Entries written through the filesystem, layered over the immutable IPFS tree. Only the
+// path bindings change, the blocks they point to are never modified.
+pub struct Overlay {
+    root: Mutex<Dir>,
+    // When the filesystem was created, reported as the times of paths that were never touched.
+    mounted: u64,
+    // Times of the paths that were written or had their times set.
+    times: Mutex<HashMap<Vec<String>, Times>>,
+}
+
+// Access, modification and creation times, in nanoseconds since the epoch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Times {
+    pub accessed: u64,
+    pub modified: u64,
+    pub created: u64,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Self {
+            root: Mutex::default(),
+            mounted: now(),
+            times: Mutex::default(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Dir {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Dir {
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.entries.iter()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn remove(&mut self, segments: &[String]) -> Option<Entry> {
+        let (name, parents) = segments.split_last()?;
+        let mut dir = self;
+        for segment in parents {
+            match dir.entries.get_mut(segment)? {
+                Entry::Dir(d) => dir = d,
+                Entry::File { .. } => return None,
+            }
+        }
+        dir.entries.remove(name)
+    }
+
+    // Bind new to the path, creating the missing parent directories. Parents that are files
+    // are left as they are and fail the insertion, which then changes nothing: the directories
+    // it creates all come after the last existing parent.
+    fn insert(&mut self, segments: &[String], new: Entry) -> Result<(), FsError> {
+        let Some((name, parents)) = segments.split_last() else {
+            return Ok(());
+        };
+        let mut dir = self;
+        for segment in parents {
+            let entry = dir
+                .entries
+                .entry(segment.clone())
+                .or_insert_with(|| Entry::Dir(Dir::default()));
+            match entry {
+                Entry::Dir(d) => dir = d,
+                Entry::File { .. } => return Err(FsError::BaseNotDirectory),
+            }
+        }
+        dir.entries.insert(name.clone(), new);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub enum Entry {
+    // Files are size bytes long, stored in blocks adding up to tsize bytes, which links to them
+    // record.
+    File { cid: Cid, size: u64, tsize: u64 },
+    Dir(Dir),
+}
+
+impl Overlay {
+    pub fn get(&self, segments: &[String]) -> Option<Entry> {
+        let root = self.root.lock().unwrap();
+        let Some((name, parents)) = segments.split_last() else {
+            return Some(Entry::Dir(root.clone()));
+        };
+        let mut dir = &*root;
+        for segment in parents {
+            match dir.entries.get(segment)? {
+                Entry::Dir(d) => dir = d,
+                Entry::File { .. } => return None,
+            }
+        }
+        dir.entries.get(name).cloned()
+    }
+
+    // Bind a file to the path, creating the missing parent directories.
+    pub fn insert_file(
+        &self,
+        segments: &[String],
+        cid: Cid,
+        size: u64,
+        tsize: u64,
+    ) -> Result<(), FsError> {
+        self.insert(segments, Entry::File { cid, size, tsize })
+    }
+
+    // Create an empty directory at the path, along with the missing parent directories.
+    pub fn insert_dir(&self, segments: &[String]) -> Result<(), FsError> {
+        self.insert(segments, Entry::Dir(Dir::default()))
+    }
+
+    // Unbind the path, returning the entry it was bound to.
+    pub fn remove(&self, segments: &[String]) -> Option<Entry> {
+        let entry = self.root.lock().unwrap().remove(segments)?;
+        let mut times = self.times.lock().unwrap();
+        times.retain(|path, _| !path.starts_with(segments));
+        Some(entry)
+    }
+
+    // Move the entry bound to from over to, creating the missing parent directories. Both
+    // paths change at once, so the entry is never visible at both or neither.
+    pub fn rename(&self, from: &[String], to: &[String]) -> Result<(), FsError> {
+        let mut root = self.root.lock().unwrap();
+        let entry = root.remove(from).ok_or(FsError::EntryNotFound)?;
+        if let Err(e) = root.insert(to, entry.clone()) {
+            // Put the entry back where it was, which can't fail as it was just there.
+            root.insert(from, entry)?;
+            return Err(e);
+        }
+
+        // The times of the entry and everything under it move along.
+        let mut times = self.times.lock().unwrap();
+        let moved: Vec<_> = times
+            .iter()
+            .filter(|(path, _)| path.starts_with(from))
+            .map(|(path, t)| (path.clone(), *t))
+            .collect();
+        times.retain(|path, _| !path.starts_with(from) && !path.starts_with(to));
+        for (path, t) in moved {
+            let mut new_path = to.to_vec();
+            new_path.extend_from_slice(&path[from.len()..]);
+            times.insert(new_path, t);
+        }
+        Ok(())
+    }
+
+    fn insert(&self, segments: &[String], new: Entry) -> Result<(), FsError> {
+        self.root.lock().unwrap().insert(segments, new)?;
+        let now = now();
+        let mut times = self.times.lock().unwrap();
+        times
+            .entry(segments.to_vec())
+            .and_modify(|t| t.modified = now)
+            .or_insert(Times {
+                accessed: now,
+                modified: now,
+                created: now,
+            });
+        Ok(())
+    }
+
+    pub fn times(&self, segments: &[String]) -> Times {
+        let times = self.times.lock().unwrap();
+        times.get(segments).copied().unwrap_or(Times {
+            accessed: self.mounted,
+            modified: self.mounted,
+            created: self.mounted,
+        })
+    }
+
+    pub fn set_times(&self, segments: &[String], accessed: Option<u64>, modified: Option<u64>) {
+        let mut t = self.times(segments);
+        t.accessed = accessed.unwrap_or(t.accessed);
+        t.modified = modified.unwrap_or(t.modified);
+        self.times.lock().unwrap().insert(segments.to_vec(), t);
+    }
+
+    // Store the overlay as a UnixFS directory tree and return the CID of its root.
+    pub async fn put(&self, client: &Client) -> Result<Cid, Error> {
+        let root = self.root.lock().unwrap().clone();
+        Ok(put_dir(client, &root).await?.0)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+// Store dir and the directories under it, and return its CID with the cumulative size of its
+// blocks.
+fn put_dir<'a>(client: &'a Client, dir: &'a Dir) -> BoxFuture<'a, Result<(Cid, u64), Error>> {
+    Box::pin(async move {
+        let mut links = Vec::with_capacity(dir.entries.len());
+        for (name, entry) in &dir.entries {
+            let (cid, tsize) = match entry {
+                Entry::File { cid, tsize, .. } => (*cid, *tsize),
+                Entry::Dir(d) => put_dir(client, d).await?,
+            };
+            links.push(Link {
+                cid,
+                name: name.clone(),
+                tsize,
+            });
+        }
+        let node = Node::directory(links);
+        Ok((client.put_node(&node).await?, node.dag_size()))
+    })
+}
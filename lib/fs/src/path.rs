@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use wasmer_wasix::FsError;
+
+use net::ipfs::Cid;
+
+use crate::IPFS_PATH;
+
+// IPFS path split into its root CID and the UnixFS path under it.
+#[derive(Debug, PartialEq)]
+pub struct IpfsPath {
+    pub root: Cid,
+    pub segments: Vec<String>,
+}
+
+impl IpfsPath {
+    // Parse a '/ipfs/<cid>/<path>' path. The CID may be a base58 CIDv0 or a multibase encoded
+    // CIDv1, anything else is rejected as invalid input.
+    pub fn parse(path: &str) -> Result<Self, FsError> {
+        let rest = path.strip_prefix(IPFS_PATH).unwrap_or(path);
+        let mut segments = rest.split('/').filter(|s| !s.is_empty() && *s != ".");
+        let root = segments.next().ok_or(FsError::InvalidInput)?;
+        let root = Cid::try_from(root).map_err(|e| {
+            tracing::debug!("invalid root CID {root}: {e}");
+            FsError::InvalidInput
+        })?;
+        Ok(Self {
+            root,
+            segments: segments.map(str::to_owned).collect(),
+        })
+    }
+}
+
+// Path as a string. IPFS names are UTF-8, so paths that aren't can't be resolved and are
+// rejected as invalid rather than reported missing.
+pub fn to_str(path: &Path) -> Result<&str, FsError> {
+    path.to_str().ok_or(FsError::InvalidInput)
+}
+
+// Segments of a path under the IPFS mount, used as overlay keys.
+pub fn segments(path: &str) -> Vec<String> {
+    let rest = path.strip_prefix(IPFS_PATH).unwrap_or(path);
+    rest.split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .map(str::to_owned)
+        .collect()
+}
+
+// Path a symlink at link points to. Relative targets are resolved against the directory
+// holding the link.
+pub fn link_target(link: &str, target: &str) -> String {
+    if target.starts_with('/') {
+        return target.to_owned();
+    }
+    let mut segments: Vec<&str> = link.split('/').filter(|s| !s.is_empty()).collect();
+    segments.pop();
+    for segment in target.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cid_v0() {
+        let path = IpfsPath::parse("/ipfs/QmeeLUVdiSTTKQqhWqsffYDtNvvvcTfJdotkNyi1KDEJtQ").unwrap();
+        assert_eq!(path.root.version(), cid::Version::V0);
+        assert!(path.segments.is_empty());
+    }
+
+    #[test]
+    fn test_parse_cid_v1_with_segments() {
+        let path = IpfsPath::parse(
+            "/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/dir/file.txt",
+        )
+        .unwrap();
+        assert_eq!(path.root.version(), cid::Version::V1);
+        assert_eq!(path.segments, vec!["dir", "file.txt"]);
+    }
+
+    #[test]
+    fn test_parse_unknown_base() {
+        assert_eq!(
+            IpfsPath::parse("/ipfs/!notacid/file"),
+            Err(FsError::InvalidInput)
+        );
+        assert_eq!(IpfsPath::parse("/ipfs"), Err(FsError::InvalidInput));
+    }
+
+    #[test]
+    fn test_link_target() {
+        assert_eq!(
+            link_target("/ipfs/cid/usr/lib/libc.so", "libc.so.6"),
+            "/ipfs/cid/usr/lib/libc.so.6"
+        );
+        assert_eq!(
+            link_target("/ipfs/cid/bin/sh", "../usr/./bin/dash"),
+            "/ipfs/cid/usr/bin/dash"
+        );
+        assert_eq!(
+            link_target("/ipfs/cid/latest", "/ipfs/other"),
+            "/ipfs/other"
+        );
+    }
+}
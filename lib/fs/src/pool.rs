@@ -0,0 +1,91 @@
+use std::future::Future;
+use std::sync::OnceLock;
+
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+
+// Runtime the blocking calls of the filesystem make their fetches on, so the requests and
+// timers they depend on keep being driven by threads of its own whatever thread the guest made
+// the call on. The threads are only started on the first fetch.
+pub struct FetchPool {
+    threads: usize,
+    runtime: OnceLock<Runtime>,
+}
+
+impl FetchPool {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            runtime: OnceLock::new(),
+        }
+    }
+
+    fn handle(&self) -> &Handle {
+        let runtime = self.runtime.get_or_init(|| {
+            Builder::new_multi_thread()
+                .worker_threads(self.threads)
+                .thread_name("ipfs-fetch")
+                .enable_all()
+                .build()
+                .expect("error starting the fetch pool")
+        });
+        runtime.handle()
+    }
+
+    // Wait for fetch, blocking the calling thread without holding up the tasks of the runtime
+    // it may belong to. Workers of a multi-threaded runtime hand their tasks over to the other
+    // workers while they wait, and calls made from within a single-threaded runtime, which has
+    // no other worker to hand them to, wait for fetch on a thread of their own.
+    pub fn block_on<F>(&self, fetch: F) -> F::Output
+    where
+        F: Future + Send,
+        F::Output: Send,
+    {
+        let handle = self.handle();
+        match Handle::try_current().map(|current| current.runtime_flavor()) {
+            Ok(RuntimeFlavor::MultiThread) => {
+                tokio::task::block_in_place(|| handle.block_on(fetch))
+            }
+            Ok(_) => std::thread::scope(|scope| {
+                let waiting = scope.spawn(|| handle.block_on(fetch));
+                waiting
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            }),
+            Err(_) => handle.block_on(fetch),
+        }
+    }
+}
+
+impl Drop for FetchPool {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks until its threads stop, which panics when the last clone
+        // of the filesystem is dropped within another runtime.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_block_on() {
+        let pool = FetchPool::new(1);
+        let sleep = || async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            7
+        };
+        assert_eq!(pool.block_on(sleep()), 7);
+
+        // From the workers of either kind of runtime, including one without timers.
+        let current = Builder::new_current_thread().build().unwrap();
+        assert_eq!(current.block_on(async { pool.block_on(sleep()) }), 7);
+        let multi = Builder::new_multi_thread().build().unwrap();
+        let start = Instant::now();
+        assert_eq!(multi.block_on(async { pool.block_on(sleep()) }), 7);
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+}
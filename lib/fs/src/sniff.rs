@@ -0,0 +1,106 @@
+use std::path::Path;
+
+// Magic bytes at the start of the file, and the MIME type they identify.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"%PDF-", "application/pdf"),
+    (b"\0asm", "application/wasm"),
+    (b"PK\x03\x04", "application/zip"),
+];
+
+// MIME type by file extension, for contents the signatures don't recognize.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("css", "text/css"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("md", "text/markdown"),
+    ("mp4", "video/mp4"),
+    ("svg", "image/svg+xml"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("webp", "image/webp"),
+];
+
+// Guess the MIME type of a file from the start of its contents, falling back to the extension
+// of its path.
+pub fn content_type(path: &Path, head: &[u8]) -> Option<&'static str> {
+    sniff(head).or_else(|| by_extension(path))
+}
+
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    for (magic, mime) in SIGNATURES {
+        if head.starts_with(magic) {
+            return Some(mime);
+        }
+    }
+    if looks_like_json(head) {
+        return Some("application/json");
+    }
+    None
+}
+
+// JSON documents start with an object or an array and are UTF-8 throughout, although the head
+// may end in the middle of a character.
+fn looks_like_json(head: &[u8]) -> bool {
+    let start = head.iter().position(|b| !b.is_ascii_whitespace());
+    if !matches!(start.map(|i| head[i]), Some(b'{') | Some(b'[')) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+fn by_extension(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == ext)
+        .map(|(_, mime)| *mime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_headers() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(content_type(Path::new("/ipfs/x"), png), Some("image/png"));
+
+        let wasm = b"\0asm\x01\0\0\0";
+        assert_eq!(
+            content_type(Path::new("/ipfs/x"), wasm),
+            Some("application/wasm")
+        );
+
+        let json = b"  {\"name\": \"ww\"";
+        assert_eq!(
+            content_type(Path::new("/ipfs/x"), json),
+            Some("application/json")
+        );
+
+        // The contents win over the extension.
+        assert_eq!(
+            content_type(Path::new("/ipfs/x/archive.txt"), b"\x1f\x8b\x08"),
+            Some("application/gzip")
+        );
+    }
+
+    #[test]
+    fn test_unknown() {
+        let blob = b"\x13\x37\xbe\xef";
+        assert_eq!(content_type(Path::new("/ipfs/x"), blob), None);
+        assert_eq!(
+            content_type(Path::new("/ipfs/x/notes.TXT"), blob),
+            Some("text/plain")
+        );
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+
+use net::ipfs::{Cid, Error, Progress};
+use net::unixfs::Node;
+
+use crate::cache::CachedClient;
+use crate::error::io_error;
+
+// Leaf data and the offset of its first byte within the file.
+type Block = (u64, Bytes);
+
+// Source of the nodes a BlockStream fetches.
+pub trait NodeSource: Clone + Send + Sync + 'static {
+    fn get_node(&self, cid: Cid) -> BoxFuture<'static, Result<Node, Error>>;
+}
+
+impl NodeSource for CachedClient {
+    fn get_node(&self, cid: Cid) -> BoxFuture<'static, Result<Node, Error>> {
+        let client = self.clone();
+        Box::pin(async move { client.get_node(&cid).await })
+    }
+}
+
+// Node of the file DAG that is yet to be read, along with the range of file data under it.
+struct Pending {
+    start: u64,
+    size: u64,
+    fetch: Fetch,
+}
+
+enum Fetch {
+    Queued(Cid),
+    // The mutex is only there to make the stream Sync and is never locked.
+    InFlight(Mutex<BoxFuture<'static, Result<Node, Error>>>),
+    Done(Node),
+}
+
+// Fetches the leaf blocks of a UnixFS file on demand. Sequential reads fetch up to read_ahead
+// nodes past the current position concurrently, and hand them out in file order.
+pub struct BlockStream<S = CachedClient> {
+    source: S,
+    root: Arc<Node>,
+    read_ahead: usize,
+    // Most recently read block.
+    block: Option<Block>,
+    // Nodes left to read, in file order. Only the first read_ahead ones are ever fetched, which
+    // bounds the number of blocks held in memory.
+    pending: VecDeque<Pending>,
+    // Told the bytes of the nodes fetched so far, if set.
+    progress: Option<Progress>,
+    fetched: u64,
+}
+
+impl<S: NodeSource> BlockStream<S> {
+    pub fn new(source: S, root: Node, read_ahead: usize) -> Self {
+        Self {
+            source,
+            root: Arc::new(root),
+            read_ahead: read_ahead.max(1),
+            block: None,
+            pending: VecDeque::new(),
+            progress: None,
+            fetched: 0,
+        }
+    }
+
+    // Report the bytes fetched to progress as nodes arrive, starting with the data of the root,
+    // which was fetched before the stream was made. Bytes fetched again after seeking back are
+    // counted again, up to the size of the file.
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.fetched = self.root.data.len() as u64;
+        progress(self.fetched, Some(self.root.size()));
+        self.progress = Some(progress);
+    }
+
+    // Offset of pos within the current block, if the block holds it.
+    fn offset_in_block(&self, pos: u64) -> Option<usize> {
+        let (start, data) = self.block.as_ref()?;
+        if pos >= *start && pos < start + data.len() as u64 {
+            return Some((pos - start) as usize);
+        }
+        None
+    }
+
+    // Poll for the file bytes starting at pos, up to the end of the block that holds them.
+    pub fn poll_at(&mut self, cx: &mut Context<'_>, pos: u64) -> Poll<io::Result<&[u8]>> {
+        loop {
+            if let Some(offset) = self.offset_in_block(pos) {
+                let (_, data) = self.block.as_ref().expect("block holds pos");
+                return Poll::Ready(Ok(&data[offset..]));
+            }
+
+            // Skip the nodes the position moved past, and start over from the root if it moved
+            // back or out of what is left to read.
+            while let Some(next) = self.pending.front() {
+                if next.start + next.size > pos {
+                    break;
+                }
+                self.pending.pop_front();
+            }
+            if self.pending.front().map_or(true, |next| next.start > pos) {
+                let root = self.root.clone();
+                self.pending.clear();
+                self.expand(&root, 0, pos);
+                if self.offset_in_block(pos).is_some() {
+                    continue;
+                }
+                if self.pending.is_empty() {
+                    return Poll::Ready(Err(io_error(Error::Decode(format!(
+                        "offset {pos} is past the end of the file"
+                    )))));
+                }
+            }
+
+            if let Err(e) = self.poll_fetches(cx) {
+                self.pending.clear();
+                return Poll::Ready(Err(io_error(e)));
+            }
+            let Some(Pending {
+                start,
+                fetch: Fetch::Done(_),
+                ..
+            }) = self.pending.front()
+            else {
+                return Poll::Pending;
+            };
+            let start = *start;
+            let Some(Pending {
+                fetch: Fetch::Done(node),
+                ..
+            }) = self.pending.pop_front()
+            else {
+                unreachable!()
+            };
+            if node.links.is_empty() && start + node.data.len() as u64 <= pos {
+                self.pending.clear();
+                return Poll::Ready(Err(io_error(Error::Decode(format!(
+                    "block at offset {start} is shorter than its declared size"
+                )))));
+            }
+            self.expand(&node, start, pos);
+        }
+    }
+
+    // Read a node whose data starts at start: its inline data becomes the current block and
+    // its children overlapping the rest of the file from pos are queued in front of the others.
+    fn expand(&mut self, node: &Node, start: u64, pos: u64) {
+        // File nodes may carry data inline before the data of their children.
+        if !node.data.is_empty() {
+            self.block = Some((start, node.data.clone()));
+        }
+
+        let mut child_start = start + node.data.len() as u64;
+        let mut children = Vec::new();
+        for (i, link) in node.links.iter().enumerate() {
+            let size = node.blocksizes.get(i).copied().unwrap_or(link.tsize);
+            if child_start + size > pos {
+                children.push(Pending {
+                    start: child_start,
+                    size,
+                    fetch: Fetch::Queued(link.cid),
+                });
+            }
+            child_start += size;
+        }
+        for child in children.into_iter().rev() {
+            self.pending.push_front(child);
+        }
+    }
+
+    // Start fetching the nodes within the read-ahead window and poll the ones in flight.
+    fn poll_fetches(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        for pending in self.pending.iter_mut().take(self.read_ahead) {
+            if let Fetch::Queued(cid) = pending.fetch {
+                pending.fetch = Fetch::InFlight(Mutex::new(self.source.get_node(cid)));
+            }
+            if let Fetch::InFlight(fetch) = &mut pending.fetch {
+                let fetch = fetch.get_mut().unwrap_or_else(|e| e.into_inner());
+                if let Poll::Ready(result) = fetch.as_mut().poll(cx) {
+                    let node = result?;
+                    if let Some(progress) = &self.progress {
+                        let total = self.root.size();
+                        self.fetched = total.min(self.fetched + node.data.len() as u64);
+                        progress(self.fetched, Some(total));
+                    }
+                    pending.fetch = Fetch::Done(node);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::future::poll_fn;
+    use futures::task::ArcWake;
+    use net::unixfs::Link;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Serves nodes from memory, recording the nodes requested and how many fetches are in
+    // flight at once.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        nodes: Arc<HashMap<Cid, Node>>,
+        requested: Arc<Mutex<Vec<Cid>>>,
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    impl NodeSource for Recorder {
+        fn get_node(&self, cid: Cid) -> BoxFuture<'static, Result<Node, Error>> {
+            self.requested.lock().unwrap().push(cid);
+            let this = self.clone();
+            Box::pin(async move {
+                let n = this.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                this.max_in_flight.fetch_max(n, Ordering::SeqCst);
+                // Stay in flight until polled again.
+                let mut yielded = false;
+                poll_fn(|cx| {
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+                this.in_flight.fetch_sub(1, Ordering::SeqCst);
+                let node = this.nodes.get(&cid).cloned();
+                node.ok_or_else(|| Error::NotFound(cid.to_string()))
+            })
+        }
+    }
+
+    // Source serving a file of eight leaves of 4 bytes, along with its root and contents.
+    fn file() -> (Recorder, Node, Vec<u8>) {
+        let mut nodes = HashMap::new();
+        let mut links = Vec::new();
+        let mut contents = Vec::new();
+        for i in 0..8u8 {
+            let data = Bytes::from(vec![i; 4]);
+            // CIDv0 of a fake sha2-256 digest, the nodes are served from memory by CID.
+            let mut hash = vec![0x12, 0x20];
+            hash.extend([i; 32]);
+            let cid = Cid::try_from(hash.as_slice()).unwrap();
+            contents.extend_from_slice(&data);
+            nodes.insert(cid, Node::file(data));
+            links.push(Link {
+                cid,
+                name: String::new(),
+                tsize: 4,
+            });
+        }
+        let source = Recorder {
+            nodes: Arc::new(nodes),
+            ..Default::default()
+        };
+        (source, Node::file_root(links), contents)
+    }
+
+    fn read_all<S: NodeSource>(stream: &mut BlockStream<S>, len: usize) -> Vec<u8> {
+        let mut read = Vec::new();
+        while read.len() < len {
+            let pos = read.len() as u64;
+            let data = block_on(poll_fn(|cx| {
+                stream.poll_at(cx, pos).map_ok(|data| data.to_vec())
+            }))
+            .unwrap();
+            read.extend_from_slice(&data);
+        }
+        read
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let (source, root, contents) = file();
+        let mut stream = BlockStream::new(source.clone(), root, 4);
+
+        let read = read_all(&mut stream, contents.len());
+        assert_eq!(read, contents);
+        assert_eq!(source.max_in_flight.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_read_head() {
+        let (source, root, contents) = file();
+        let leaves: Vec<Cid> = root.links.iter().map(|link| link.cid).collect();
+        let mut stream = BlockStream::new(source.clone(), root, 2);
+
+        // Reading the first bytes only fetches the leaves within read ahead of them.
+        let data = block_on(poll_fn(|cx| {
+            stream.poll_at(cx, 0).map_ok(|data| data.to_vec())
+        }))
+        .unwrap();
+        assert_eq!(data, contents[..4]);
+        assert_eq!(*source.requested.lock().unwrap(), leaves[..2]);
+    }
+
+    #[test]
+    fn test_progress() {
+        let (source, root, contents) = file();
+        let mut stream = BlockStream::new(source, root, 4);
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        stream.set_progress({
+            let reports = reports.clone();
+            Arc::new(move |fetched, total| reports.lock().unwrap().push((fetched, total)))
+        });
+
+        assert_eq!(read_all(&mut stream, contents.len()), contents);
+        let reports = reports.lock().unwrap();
+        // Once for the root, and once for each leaf.
+        assert_eq!(reports.len(), 9);
+        assert_eq!(reports[0], (0, Some(32)));
+        assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(reports.last(), Some(&(32, Some(32))));
+    }
+
+    // Source whose fetches never complete.
+    #[derive(Clone)]
+    struct Stalled;
+
+    impl NodeSource for Stalled {
+        fn get_node(&self, _: Cid) -> BoxFuture<'static, Result<Node, Error>> {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[test]
+    fn test_pending_while_fetching() {
+        let leaf = Link {
+            cid: Cid::try_from("QmeeLUVdiSTTKQqhWqsffYDtNvvvcTfJdotkNyi1KDEJtQ").unwrap(),
+            name: String::new(),
+            tsize: 4,
+        };
+        let root = Node {
+            data: Bytes::from_static(b"head"),
+            ..Node::file_root(vec![leaf])
+        };
+        let mut stream = BlockStream::new(Stalled, root, 1);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // Inline data is there without fetching anything, the linked block never arrives.
+        assert!(matches!(
+            stream.poll_at(&mut cx, 1),
+            Poll::Ready(Ok(b"ead"))
+        ));
+        assert!(stream.poll_at(&mut cx, 4).is_pending());
+        assert!(stream.poll_at(&mut cx, 4).is_pending());
+    }
+
+    // Source whose only fetch completes once its node is sent through the gate.
+    #[derive(Clone)]
+    struct Gated(Arc<Mutex<Option<oneshot::Receiver<Node>>>>);
+
+    impl NodeSource for Gated {
+        fn get_node(&self, cid: Cid) -> BoxFuture<'static, Result<Node, Error>> {
+            let gate = self.0.lock().unwrap().take();
+            Box::pin(async move {
+                let closed = || Error::NotFound(cid.to_string());
+                gate.ok_or_else(closed)?.await.map_err(|_| closed())
+            })
+        }
+    }
+
+    // Counts the times it is woken.
+    #[derive(Default)]
+    struct Wakes(AtomicUsize);
+
+    impl ArcWake for Wakes {
+        fn wake_by_ref(wakes: &Arc<Self>) {
+            wakes.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_wakes_when_block_arrives() {
+        let leaf = Link {
+            cid: Cid::try_from("QmeeLUVdiSTTKQqhWqsffYDtNvvvcTfJdotkNyi1KDEJtQ").unwrap(),
+            name: String::new(),
+            tsize: 4,
+        };
+        let (arrive, gate) = oneshot::channel();
+        let source = Gated(Arc::new(Mutex::new(Some(gate))));
+        let mut stream = BlockStream::new(source, Node::file_root(vec![leaf]), 1);
+        let wakes = Arc::new(Wakes::default());
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // A guest polling the file waits for the block without being woken to spin...
+        assert!(stream.poll_at(&mut cx, 0).is_pending());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+
+        // ...until the block arrives, which it can then read.
+        arrive
+            .send(Node::file(Bytes::from_static(b"data")))
+            .unwrap();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            stream.poll_at(&mut cx, 0),
+            Poll::Ready(Ok(b"data"))
+        ));
+    }
+}
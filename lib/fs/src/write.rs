@@ -0,0 +1,132 @@
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use tokio::runtime::Handle;
+
+use net::ipfs::Client;
+
+use crate::error::io_error;
+use crate::overlay::Overlay;
+use crate::pool::FetchPool;
+
+// Writes the contents of a file opened for writing back to IPFS, and binds the resulting CID
+// to the file path in the overlay.
+pub struct Writer {
+    client: Client,
+    overlay: Arc<Overlay>,
+    // Runs the write-backs of files dropped outside of any runtime.
+    pool: Arc<FetchPool>,
+    segments: Vec<String>,
+    // Whether writes always go to the end of the file.
+    pub append: bool,
+    // Whether the contents changed since they were last written back.
+    dirty: bool,
+    // In-flight write-back. The mutex is only there to make the writer Sync and is never locked.
+    flush: Option<Mutex<BoxFuture<'static, io::Result<()>>>>,
+}
+
+impl Writer {
+    pub fn new(
+        client: Client,
+        overlay: Arc<Overlay>,
+        pool: Arc<FetchPool>,
+        segments: Vec<String>,
+        append: bool,
+    ) -> Self {
+        Self {
+            client,
+            overlay,
+            pool,
+            segments,
+            append,
+            dirty: false,
+            flush: None,
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    // Write the contents back if they changed since the last write-back.
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>, contents: &[u8]) -> Poll<io::Result<()>> {
+        if self.flush.is_none() {
+            if !self.dirty {
+                return Poll::Ready(Ok(()));
+            }
+            let write = write_back(
+                self.client.clone(),
+                self.overlay.clone(),
+                self.segments.clone(),
+                Bytes::copy_from_slice(contents),
+            );
+            self.flush = Some(Mutex::new(Box::pin(write)));
+            self.dirty = false;
+        }
+
+        let flush = self
+            .flush
+            .as_mut()
+            .expect("flush is in flight")
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        let result = ready!(flush.as_mut().poll(cx));
+        self.flush = None;
+        if let Err(e) = result {
+            self.dirty = true;
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Write back the pending changes of a file dropped without having been flushed, logging
+    // failures against path. Drop can't wait for the write-back on a thread of a runtime, whose
+    // requests are driven by that same runtime, so there it runs as a task of the runtime, and
+    // the file keeps its previous contents until the task is done. Only threads outside of any
+    // runtime wait for it, on the fetch pool.
+    pub fn flush_on_drop(&mut self, contents: &[u8], path: &str) {
+        if !self.dirty && self.flush.is_none() {
+            return;
+        }
+        self.dirty = false;
+        self.flush = None;
+        let write = write_back(
+            self.client.clone(),
+            self.overlay.clone(),
+            self.segments.clone(),
+            Bytes::copy_from_slice(contents),
+        );
+        let path = path.to_owned();
+        let logged = async move {
+            if let Err(e) = write.await {
+                tracing::error!("failed to write back {path}: {e}");
+            }
+        };
+        match Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(logged);
+            }
+            Err(_) => self.pool.block_on(logged),
+        }
+    }
+}
+
+async fn write_back(
+    client: Client,
+    overlay: Arc<Overlay>,
+    segments: Vec<String>,
+    contents: Bytes,
+) -> io::Result<()> {
+    let size = contents.len() as u64;
+    let (cid, tsize) = client
+        .add_file_sized(&contents[..])
+        .await
+        .map_err(io_error)?;
+    // Fails if a parent of the file was replaced by a file since it was opened.
+    overlay.insert_file(&segments, cid, size, tsize)?;
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::task::{Context, Poll};
+
+use libp2p::core::transport::PortUse;
+use libp2p::core::Endpoint;
+use libp2p::swarm::{
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+
+// Which peers may connect to the node.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum AccessPolicy {
+    // Any peer.
+    #[default]
+    Open,
+    // Only these peers.
+    Allow(HashSet<PeerId>),
+    // Any peer but these.
+    Deny(HashSet<PeerId>),
+}
+
+impl AccessPolicy {
+    pub fn permits(&self, peer: &PeerId) -> bool {
+        match self {
+            AccessPolicy::Open => true,
+            AccessPolicy::Allow(peers) => peers.contains(peer),
+            AccessPolicy::Deny(peers) => !peers.contains(peer),
+        }
+    }
+}
+
+// Reason a connection was refused.
+#[derive(Debug)]
+struct Denied(PeerId);
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "peer {} is not allowed to connect", self.0)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+// Closes the connections of the peers the policy doesn't permit as soon as the peer is known,
+// once the connection is secured and before any protocol runs over it.
+pub struct Behaviour {
+    policy: AccessPolicy,
+}
+
+impl Behaviour {
+    pub fn new(policy: AccessPolicy) -> Self {
+        Self { policy }
+    }
+
+    pub fn policy(&self) -> &AccessPolicy {
+        &self.policy
+    }
+
+    // Apply policy to the connections established from now on. Existing connections are left
+    // for the caller to close.
+    pub fn set_policy(&mut self, policy: AccessPolicy) {
+        self.policy = policy;
+    }
+
+    fn check(&self, peer: PeerId) -> Result<(), ConnectionDenied> {
+        match self.policy.permits(&peer) {
+            true => Ok(()),
+            false => Err(ConnectionDenied::new(Denied(peer))),
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: Option<PeerId>,
+        _: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        // Don't even dial peers known to be refused.
+        if let Some(peer) = peer {
+            self.check(peer)?;
+        }
+        Ok(Vec::new())
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(peer)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(peer)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        assert!(AccessPolicy::Open.permits(&a));
+        let allow = AccessPolicy::Allow([a].into());
+        assert!(allow.permits(&a) && !allow.permits(&b));
+        let deny = AccessPolicy::Deny([a].into());
+        assert!(!deny.permits(&a) && deny.permits(&b));
+    }
+}
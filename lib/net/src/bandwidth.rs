@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::{
+    StreamMuxer, StreamMuxerBox, StreamMuxerEvent, StreamMuxerExt, SubstreamBox,
+};
+use libp2p::PeerId;
+use prometheus_client::metrics::counter::Counter;
+
+use crate::metrics::Metrics;
+
+// Protocol the bytes of streams are counted under when they end before one is negotiated, or
+// when what they start with isn't a negotiation.
+pub const UNKNOWN_PROTOCOL: &str = "unknown";
+
+// Longest negotiation message read before giving up on the stream being negotiated.
+const MAX_NEGOTIATION_MESSAGE: usize = 1024;
+
+// Bytes carried over the streams of the connections to a peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+// Counters of the bytes read and written on streams, shared by the streams they count.
+#[derive(Default)]
+struct Counters {
+    inbound: Counter,
+    outbound: Counter,
+}
+
+// Counters of a connected peer, by the protocols negotiated on its streams.
+#[derive(Default)]
+struct Peer {
+    connections: usize,
+    protocols: HashMap<String, Arc<Counters>>,
+}
+
+// Bytes carried to and from each connected peer, by the protocols negotiated on the streams
+// they went over, like /meshsub/1.1.0 or /ww/rpc. Counting is an atomic update of
+// counters looked up once per stream, which are those of metrics when there are metrics, so
+// the endpoint serves them as well. A peer is forgotten, and its series removed from the
+// metrics, once its last connection closes, so they don't pile up as peers come and go.
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    metrics: Option<Metrics>,
+    peers: Arc<Mutex<HashMap<PeerId, Peer>>>,
+}
+
+impl Bandwidth {
+    pub fn new(metrics: Option<Metrics>) -> Self {
+        Self {
+            metrics,
+            peers: Arc::default(),
+        }
+    }
+
+    // Traffic with each connected peer, by protocol, since it connected.
+    pub fn report(&self) -> HashMap<(PeerId, String), Traffic> {
+        let peers = self.peers.lock().unwrap();
+        let traffic = peers.iter().flat_map(|(peer, counters)| {
+            counters.protocols.iter().map(|(protocol, counters)| {
+                let traffic = Traffic {
+                    inbound: counters.inbound.get(),
+                    outbound: counters.outbound.get(),
+                };
+                ((*peer, protocol.clone()), traffic)
+            })
+        });
+        traffic.collect()
+    }
+
+    fn connected(&self, peer: PeerId) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(peer).or_default().connections += 1;
+    }
+
+    fn disconnected(&self, peer: PeerId) {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(counters) = peers.get_mut(&peer) else {
+            return;
+        };
+        counters.connections -= 1;
+        if counters.connections > 0 {
+            return;
+        }
+        let counters = peers.remove(&peer).unwrap_or_default();
+        if let Some(metrics) = &self.metrics {
+            for protocol in counters.protocols.keys() {
+                metrics.remove_peer_bytes(&peer, protocol);
+            }
+        }
+    }
+
+    // Counters of the streams to peer negotiated for protocol. Streams that outlive the
+    // connections to their peer count on counters of their own, reported nowhere.
+    fn counters(&self, peer: PeerId, protocol: &str) -> Arc<Counters> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(counters) = peers.get_mut(&peer) else {
+            return Arc::default();
+        };
+        let counters = counters
+            .protocols
+            .entry(protocol.to_owned())
+            .or_insert_with(|| {
+                let counters = match &self.metrics {
+                    Some(metrics) => Counters {
+                        inbound: metrics.peer_bytes(&peer, protocol, "in"),
+                        outbound: metrics.peer_bytes(&peer, protocol, "out"),
+                    },
+                    None => Counters::default(),
+                };
+                Arc::new(counters)
+            });
+        counters.clone()
+    }
+
+    // Count the bytes of the streams muxer opens and accepts on a connection to peer.
+    pub(crate) fn meter(&self, peer: PeerId, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        self.connected(peer);
+        StreamMuxerBox::new(Muxer {
+            inner: muxer,
+            bandwidth: self.clone(),
+            peer,
+        })
+    }
+}
+
+// Reads the protocol of a stream off the multistream-select messages its listening side sends:
+// the multistream header, "na" for each protocol it turns down, then the protocol it accepts.
+// Each message is a varint length followed by as many bytes, the last of which is a newline.
+#[derive(Default)]
+struct Negotiation {
+    buf: Vec<u8>,
+}
+
+impl Negotiation {
+    // Take in bytes sent by the listening side, returning the protocol once it is known.
+    fn feed(&mut self, bytes: &[u8]) -> Option<String> {
+        self.buf.extend_from_slice(bytes);
+        loop {
+            let (len, header) = match varint(&self.buf) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => return None,
+                Err(()) => return Some(UNKNOWN_PROTOCOL.to_owned()),
+            };
+            if len > MAX_NEGOTIATION_MESSAGE {
+                return Some(UNKNOWN_PROTOCOL.to_owned());
+            }
+            let Some(message) = self.buf.get(header..header + len) else {
+                return None;
+            };
+            let line = message.strip_suffix(b"\n").map(std::str::from_utf8);
+            match line {
+                Some(Ok("/multistream/1.0.0" | "na")) => {}
+                Some(Ok(protocol)) => return Some(protocol.to_owned()),
+                _ => return Some(UNKNOWN_PROTOCOL.to_owned()),
+            }
+            self.buf.drain(..header + len);
+        }
+    }
+}
+
+// Decode the unsigned varint buf starts with into its value and length, or None if buf ends
+// before it does.
+fn varint(buf: &[u8]) -> Result<Option<(usize, usize)>, ()> {
+    let mut value = 0;
+    for (i, byte) in buf.iter().enumerate().take(9) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    match buf.len() < 9 {
+        true => Ok(None),
+        false => Err(()),
+    }
+}
+
+struct Muxer {
+    inner: StreamMuxerBox,
+    bandwidth: Bandwidth,
+    peer: PeerId,
+}
+
+impl Muxer {
+    fn metered(&self, stream: SubstreamBox, remote_listens: bool) -> Metered<SubstreamBox> {
+        Metered::new(stream, self.bandwidth.clone(), self.peer, remote_listens)
+    }
+}
+
+impl StreamMuxer for Muxer {
+    type Substream = Metered<SubstreamBox>;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let stream = ready!(this.inner.poll_inbound_unpin(cx))?;
+        Poll::Ready(Ok(this.metered(stream, false)))
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let stream = ready!(this.inner.poll_outbound_unpin(cx))?;
+        Poll::Ready(Ok(this.metered(stream, true)))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().inner.poll_close_unpin(cx)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        self.get_mut().inner.poll_unpin(cx)
+    }
+}
+
+impl Drop for Muxer {
+    fn drop(&mut self) {
+        self.bandwidth.disconnected(self.peer);
+    }
+}
+
+// How far the stream got in counting its bytes.
+enum Tally {
+    // Bytes read and written while the protocol is being negotiated.
+    Negotiating {
+        negotiation: Negotiation,
+        inbound: u64,
+        outbound: u64,
+    },
+    Counting(Arc<Counters>),
+}
+
+// Stream counting the bytes read from and written to it under the protocol negotiated on it.
+// Bytes carried before the protocol is known are counted under it once it is.
+struct Metered<S> {
+    inner: S,
+    bandwidth: Bandwidth,
+    peer: PeerId,
+    // Whether the other end listens, so the protocol is read off what is read rather than
+    // written.
+    remote_listens: bool,
+    tally: Tally,
+}
+
+impl<S> Metered<S> {
+    fn new(inner: S, bandwidth: Bandwidth, peer: PeerId, remote_listens: bool) -> Self {
+        Self {
+            inner,
+            bandwidth,
+            peer,
+            remote_listens,
+            tally: Tally::Negotiating {
+                negotiation: Negotiation::default(),
+                inbound: 0,
+                outbound: 0,
+            },
+        }
+    }
+
+    fn count(&mut self, bytes: &[u8], inbound: bool) {
+        let n = bytes.len() as u64;
+        let protocol = match &mut self.tally {
+            Tally::Counting(counters) => {
+                match inbound {
+                    true => counters.inbound.inc_by(n),
+                    false => counters.outbound.inc_by(n),
+                };
+                return;
+            }
+            Tally::Negotiating {
+                negotiation,
+                inbound: read,
+                outbound: written,
+            } => {
+                *(if inbound { read } else { written }) += n;
+                if inbound != self.remote_listens {
+                    return;
+                }
+                match negotiation.feed(bytes) {
+                    Some(protocol) => protocol,
+                    None => return,
+                }
+            }
+        };
+        self.settle(&protocol);
+    }
+
+    // Count the bytes carried so far under protocol, and those carried from then on.
+    fn settle(&mut self, protocol: &str) {
+        let counters = self.bandwidth.counters(self.peer, protocol);
+        if let Tally::Negotiating {
+            inbound, outbound, ..
+        } = &self.tally
+        {
+            counters.inbound.inc_by(*inbound);
+            counters.outbound.inc_by(*outbound);
+        }
+        self.tally = Tally::Counting(counters);
+    }
+}
+
+impl<S> Drop for Metered<S> {
+    fn drop(&mut self) {
+        if let Tally::Negotiating {
+            inbound, outbound, ..
+        } = self.tally
+        {
+            if inbound + outbound > 0 {
+                self.settle(UNKNOWN_PROTOCOL);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.count(&buf[..read], true);
+        Poll::Ready(Ok(read))
+    }
+
+    fn poll_read_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.inner).poll_read_vectored(cx, bufs))?;
+        let mut left = read;
+        for buf in bufs.iter() {
+            let n = left.min(buf.len());
+            self.count(&buf[..n], true);
+            left -= n;
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.count(&buf[..written], false);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, bufs))?;
+        let mut left = written;
+        for buf in bufs {
+            let n = left.min(buf.len());
+            self.count(&buf[..n], false);
+            left -= n;
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+
+    // A multistream-select message carrying line.
+    fn message(line: &str) -> Vec<u8> {
+        let mut message = vec![line.len() as u8 + 1];
+        message.extend_from_slice(line.as_bytes());
+        message.push(b'\n');
+        message
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let sent = [
+            message("/multistream/1.0.0"),
+            message("na"),
+            message("/meshsub/1.1.0"),
+        ]
+        .concat();
+        // However the messages are split.
+        for at in 0..sent.len() {
+            let mut negotiation = Negotiation::default();
+            let (head, tail) = sent.split_at(at);
+            let protocol = negotiation.feed(head).or_else(|| negotiation.feed(tail));
+            assert_eq!(protocol.as_deref(), Some("/meshsub/1.1.0"));
+        }
+
+        let mut negotiation = Negotiation::default();
+        assert_eq!(
+            negotiation.feed(b"\x03abc").as_deref(),
+            Some(UNKNOWN_PROTOCOL)
+        );
+    }
+
+    #[test]
+    fn test_metered() {
+        let metrics = Metrics::new();
+        let bandwidth = Bandwidth::new(Some(metrics.clone()));
+        let peer = PeerId::random();
+        bandwidth.connected(peer);
+
+        // A stream opened to the peer, which accepts /meshsub/1.1.0 and then sends 100 bytes.
+        let accepted = [message("/multistream/1.0.0"), message("/meshsub/1.1.0")].concat();
+        let negotiated = accepted.len() as u64;
+        let contents = Cursor::new([accepted, vec![7; 100]].concat());
+        let mut stream = Metered::new(contents, bandwidth.clone(), peer, true);
+        let mut read = Vec::new();
+        block_on(stream.read_to_end(&mut read)).unwrap();
+        block_on(stream.write_all(&[1; 50])).unwrap();
+        // A stream accepted from the peer, carrying something else than a negotiation.
+        let mut stream = Metered::new(Cursor::new(Vec::new()), bandwidth.clone(), peer, false);
+        block_on(stream.write_all(&[2; 25])).unwrap();
+
+        let meshsub = Traffic {
+            inbound: negotiated + 100,
+            outbound: 50,
+        };
+        let unknown = Traffic {
+            inbound: 0,
+            outbound: 25,
+        };
+        assert_eq!(
+            bandwidth.report(),
+            HashMap::from([
+                ((peer, "/meshsub/1.1.0".to_owned()), meshsub),
+                ((peer, UNKNOWN_PROTOCOL.to_owned()), unknown),
+            ])
+        );
+        let encoded = metrics.encode();
+        let labels = format!("peer=\"{peer}\",protocol=\"/meshsub/1.1.0\"");
+        assert!(encoded.contains(&format!("{{{labels},direction=\"out\"}} 50")));
+
+        // The peer is forgotten with its last connection.
+        bandwidth.connected(peer);
+        bandwidth.disconnected(peer);
+        assert_eq!(bandwidth.report().len(), 2);
+        bandwidth.disconnected(peer);
+        assert!(bandwidth.report().is_empty());
+        assert!(!metrics.encode().contains(&peer.to_string()));
+    }
+}
@@ -0,0 +1,248 @@
+use bytes::Bytes;
+use cid::Cid;
+
+use crate::ipfs::Error;
+
+// CBOR tag of CIDs in DAG-CBOR.
+const CID_TAG: u64 = 42;
+
+// Contents of a CARv1 archive.
+pub struct Car {
+    pub roots: Vec<Cid>,
+    pub blocks: Vec<(Cid, Bytes)>,
+}
+
+impl Car {
+    // Parse a CARv1 archive: a varint framed DAG-CBOR header followed by varint framed
+    // sections, each holding the CID of a block and its data.
+    pub fn parse(car: Bytes) -> Result<Self, Error> {
+        let mut reader = Reader { buf: &car };
+        let header = reader.frame().map_err(|e| decode_error("header", e))?;
+        let roots = parse_header(header)?;
+
+        let mut blocks = Vec::new();
+        while !reader.buf.is_empty() {
+            let section = reader
+                .frame()
+                .map_err(|e| decode_error(&format!("block {}", blocks.len()), e))?;
+            let mut cursor = section;
+            let cid = Cid::read_bytes(&mut cursor)
+                .map_err(|e| Error::Decode(format!("car block {}: {e}", blocks.len())))?;
+            let data = car.slice_ref(cursor);
+            blocks.push((cid, data));
+        }
+        Ok(Self { roots, blocks })
+    }
+}
+
+fn decode_error(what: &str, e: Error) -> Error {
+    match e {
+        Error::Decode(msg) => Error::Decode(format!("car {what}: {msg}")),
+        e => e,
+    }
+}
+
+fn parse_header(header: &[u8]) -> Result<Vec<Cid>, Error> {
+    let mut cbor = Cbor { buf: header };
+    let entries = cbor.expect(5, "header map")?;
+    let mut roots = None;
+    let mut version = None;
+    for _ in 0..entries {
+        let len = cbor.expect(3, "header key")?;
+        match cbor.take(len as usize)? {
+            b"roots" => {
+                let count = cbor.expect(4, "roots array")?;
+                let mut cids = Vec::new();
+                for _ in 0..count {
+                    cids.push(cbor.cid()?);
+                }
+                roots = Some(cids);
+            }
+            b"version" => version = Some(cbor.expect(0, "version")?),
+            _ => cbor.skip()?,
+        }
+    }
+    match version {
+        Some(1) => {}
+        Some(v) => return Err(Error::Decode(format!("unsupported car version {v}"))),
+        None => return Err(Error::Decode("car header without version".to_owned())),
+    }
+    roots.ok_or_else(|| Error::Decode("car header without roots".to_owned()))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value: u64 = 0;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(Error::Decode("truncated varint".to_owned()))
+    }
+
+    // Read a varint length prefixed frame.
+    fn frame(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.varint()? as usize;
+        if len > self.buf.len() {
+            return Err(Error::Decode(format!(
+                "truncated frame, expected {len} bytes but only {} left",
+                self.buf.len()
+            )));
+        }
+        let (frame, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(frame)
+    }
+}
+
+// Minimal CBOR reader, enough for the DAG-CBOR header of CAR files.
+struct Cbor<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Cbor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > self.buf.len() {
+            return Err(Error::Decode("truncated header".to_owned()));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    // Read the major type and argument of the next item.
+    fn head(&mut self) -> Result<(u8, u64), Error> {
+        let initial = self.take(1)?[0];
+        let arg = match initial & 0x1f {
+            n @ 0..=23 => u64::from(n),
+            24 => u64::from(self.take(1)?[0]),
+            25 => u64::from(u16::from_be_bytes(self.take(2)?.try_into().unwrap())),
+            26 => u64::from(u32::from_be_bytes(self.take(4)?.try_into().unwrap())),
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => {
+                return Err(Error::Decode(
+                    "indefinite length items in header".to_owned(),
+                ))
+            }
+        };
+        Ok((initial >> 5, arg))
+    }
+
+    fn expect(&mut self, major: u8, what: &str) -> Result<u64, Error> {
+        match self.head()? {
+            (m, arg) if m == major => Ok(arg),
+            (m, _) => Err(Error::Decode(format!(
+                "expected {what} in header, got major type {m}"
+            ))),
+        }
+    }
+
+    // DAG-CBOR CIDs are tagged byte strings prefixed with the identity multibase.
+    fn cid(&mut self) -> Result<Cid, Error> {
+        if self.expect(6, "cid tag")? != CID_TAG {
+            return Err(Error::Decode("root is not a cid".to_owned()));
+        }
+        let len = self.expect(2, "cid bytes")?;
+        match self.take(len as usize)? {
+            [0, cid @ ..] => Ok(Cid::try_from(cid)?),
+            _ => Err(Error::Decode(
+                "cid without identity multibase prefix".to_owned(),
+            )),
+        }
+    }
+
+    fn skip(&mut self) -> Result<(), Error> {
+        let (major, arg) = self.head()?;
+        match major {
+            2 | 3 => {
+                self.take(arg as usize)?;
+            }
+            4 => {
+                for _ in 0..arg {
+                    self.skip()?;
+                }
+            }
+            5 => {
+                for _ in 0..arg * 2 {
+                    self.skip()?;
+                }
+            }
+            6 => self.skip()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::unixfs::RAW;
+    use multihash_codetable::{Code, MultihashDigest};
+
+    fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn frame(buf: &mut Vec<u8>, data: &[u8]) {
+        put_varint(buf, data.len() as u64);
+        buf.extend_from_slice(data);
+    }
+
+    // CAR holding the given blocks, with the first one as its root.
+    pub(crate) fn build_car(blocks: &[(Cid, &[u8])]) -> Vec<u8> {
+        let root = blocks[0].0.to_bytes();
+        let mut header = vec![0xa2, 0x65];
+        header.extend_from_slice(b"roots");
+        header.extend([0x81, 0xd8, 0x2a, 0x58, root.len() as u8 + 1, 0x00]);
+        header.extend_from_slice(&root);
+        header.push(0x67);
+        header.extend_from_slice(b"version");
+        header.push(0x01);
+
+        let mut car = Vec::new();
+        frame(&mut car, &header);
+        for (cid, data) in blocks {
+            let mut section = cid.to_bytes();
+            section.extend_from_slice(data);
+            frame(&mut car, &section);
+        }
+        car
+    }
+
+    #[test]
+    fn test_parse_car() {
+        let a = Cid::new_v1(RAW, Code::Sha2_256.digest(b"hello"));
+        let b = Cid::new_v1(RAW, Code::Sha2_256.digest(b"world"));
+        let car = build_car(&[(a, b"hello"), (b, b"world")]);
+
+        let car = Car::parse(Bytes::from(car)).unwrap();
+        assert_eq!(car.roots, vec![a]);
+        assert_eq!(car.blocks.len(), 2);
+        assert_eq!(car.blocks[0], (a, Bytes::from_static(b"hello")));
+        assert_eq!(car.blocks[1], (b, Bytes::from_static(b"world")));
+    }
+
+    #[test]
+    fn test_parse_truncated_car() {
+        let a = Cid::new_v1(RAW, Code::Sha2_256.digest(b"hello"));
+        let mut car = build_car(&[(a, b"hello")]);
+        car.truncate(car.len() - 2);
+
+        match Car::parse(Bytes::from(car)) {
+            Err(Error::Decode(msg)) => assert!(msg.contains("block 0"), "{msg}"),
+            _ => panic!("expected a decode error"),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// Bytes of the window the rolling hash of the content-defined chunker is computed over.
+const WINDOW: usize = 32;
+
+// Values the bytes entering and leaving the window are hashed to, drawn with splitmix64 from a
+// fixed seed so boundaries, and therefore CIDs, are the same on every node.
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x7777_7777_7777_7777;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = (z ^ (z >> 31)) as u32;
+        i += 1;
+    }
+    table
+}
+
+// How files are split into leaves when added. The CID of a file depends on the chunker as much
+// as on its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chunker {
+    // Leaves of this many bytes, but the last one. Inserting a byte shifts every leaf after it,
+    // so an edited file shares no leaves past the edit with the original.
+    Fixed(usize),
+    // Leaves cut where a buzhash of the last WINDOW bytes has its low bits unset, so that
+    // boundaries follow the contents and an edit only changes the leaves around it. Leaves are
+    // between min and max bytes, and avg bytes past min on average, which is a power of two.
+    Buzhash { min: usize, avg: usize, max: usize },
+}
+
+impl Default for Chunker {
+    // Matches the default kubo chunker.
+    fn default() -> Self {
+        Chunker::Fixed(256 * 1024)
+    }
+}
+
+impl Chunker {
+    // Content-defined chunker with the sizes of the kubo buzhash chunker.
+    pub fn buzhash() -> Self {
+        Chunker::Buzhash {
+            min: 128 * 1024,
+            avg: 128 * 1024,
+            max: 512 * 1024,
+        }
+    }
+
+    // Chunker with its sizes made valid: at least a byte, WINDOW bytes for the minimum of the
+    // buzhash chunker, a power of two average and a maximum no smaller than the minimum.
+    pub(crate) fn normalized(self) -> Self {
+        match self {
+            Chunker::Fixed(size) => Chunker::Fixed(size.max(1)),
+            Chunker::Buzhash { min, avg, max } => {
+                let min = min.max(WINDOW);
+                Chunker::Buzhash {
+                    min,
+                    avg: avg.max(1).next_power_of_two(),
+                    max: max.max(min),
+                }
+            }
+        }
+    }
+
+    // Most bytes a leaf holds.
+    pub fn max_len(&self) -> usize {
+        match *self {
+            Chunker::Fixed(size) => size,
+            Chunker::Buzhash { max, .. } => max,
+        }
+    }
+
+    // Length of the first leaf of data, which starts a leaf and holds max_len bytes unless it
+    // ends the file.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        let len = data.len().min(self.max_len());
+        let Chunker::Buzhash { min, avg, .. } = *self else {
+            return len;
+        };
+        if len <= min {
+            return len;
+        }
+        let mask = avg as u32 - 1;
+        let mut hash = 0u32;
+        for &byte in &data[min - WINDOW..min] {
+            hash = hash.rotate_left(1) ^ TABLE[byte as usize];
+        }
+        for i in min..len {
+            if hash & mask == 0 {
+                return i;
+            }
+            let out = TABLE[data[i - WINDOW] as usize].rotate_left(WINDOW as u32);
+            hash = hash.rotate_left(1) ^ out ^ TABLE[data[i] as usize];
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    use rand::{Rng, SeedableRng};
+
+    fn leaves<'a>(chunker: &Chunker, mut data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut leaves = Vec::new();
+        while !data.is_empty() {
+            let (leaf, rest) = data.split_at(chunker.cut(data));
+            leaves.push(leaf);
+            data = rest;
+        }
+        leaves
+    }
+
+    // Share of the leaves of edited also found in original.
+    fn shared(chunker: &Chunker, original: &[u8], edited: &[u8]) -> f64 {
+        let original: HashSet<_> = leaves(chunker, original).into_iter().collect();
+        let edited = leaves(chunker, edited);
+        let found = edited
+            .iter()
+            .filter(|leaf| original.contains(*leaf))
+            .count();
+        found as f64 / edited.len() as f64
+    }
+
+    #[test]
+    fn test_insertion() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let original: Vec<u8> = (0..1 << 20).map(|_| rng.gen()).collect();
+        let mut edited = vec![0xff];
+        edited.extend_from_slice(&original);
+
+        let buzhash = Chunker::Buzhash {
+            min: 4096,
+            avg: 8192,
+            max: 65536,
+        }
+        .normalized();
+        let fixed = Chunker::Fixed(8192);
+        for chunker in [buzhash, fixed] {
+            let leaves = leaves(&chunker, &original);
+            assert_eq!(leaves.concat(), original);
+            assert!(leaves.iter().all(|leaf| leaf.len() <= chunker.max_len()));
+        }
+        assert!(shared(&buzhash, &original, &edited) > 0.9);
+        assert_eq!(shared(&fixed, &original, &edited), 0.0);
+    }
+
+    #[test]
+    fn test_normalized() {
+        let chunker = Chunker::Buzhash {
+            min: 0,
+            avg: 3000,
+            max: 10,
+        };
+        assert_eq!(
+            chunker.normalized(),
+            Chunker::Buzhash {
+                min: WINDOW,
+                avg: 4096,
+                max: WINDOW,
+            }
+        );
+        assert_eq!(Chunker::Fixed(0).normalized(), Chunker::Fixed(1));
+    }
+}
@@ -0,0 +1,90 @@
+use bytes::Bytes;
+use cid::Cid;
+pub use ipld_core::ipld::Ipld;
+
+use crate::ipfs::Error;
+
+// Multicodec of dag-cbor encoded blocks.
+pub const DAG_CBOR: u64 = 0x71;
+// Multicodec of dag-json encoded blocks.
+pub const DAG_JSON: u64 = 0x0129;
+
+// Encode node as a block of codec. Both codecs are deterministic, with map keys sorted, so
+// equal nodes always encode to the same block and get the same CID.
+pub fn encode(node: &Ipld, codec: u64) -> Result<Bytes, Error> {
+    let encoded = match codec {
+        DAG_CBOR => serde_ipld_dagcbor::to_vec(node).map_err(|e| e.to_string()),
+        DAG_JSON => serde_ipld_dagjson::to_vec(node).map_err(|e| e.to_string()),
+        _ => return Err(Error::Decode(format!("unsupported codec 0x{codec:x}"))),
+    };
+    encoded
+        .map(Bytes::from)
+        .map_err(|e| Error::Decode(format!("encoding node: {e}")))
+}
+
+// Decode the block of cid, whose codec it is encoded with.
+pub fn decode(cid: &Cid, block: &[u8]) -> Result<Ipld, Error> {
+    let decoded = match cid.codec() {
+        DAG_CBOR => serde_ipld_dagcbor::from_slice(block).map_err(|e| e.to_string()),
+        DAG_JSON => serde_ipld_dagjson::from_slice(block).map_err(|e| e.to_string()),
+        codec => return Err(Error::Decode(format!("unsupported codec 0x{codec:x}"))),
+    };
+    decoded.map_err(|e| Error::Decode(format!("decoding {cid}: {e}")))
+}
+
+// CIDs node links to, anywhere in it, in the order they appear.
+pub fn links(node: &Ipld) -> Vec<Cid> {
+    let mut links = Vec::new();
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        match node {
+            Ipld::Link(cid) => links.push(*cid),
+            Ipld::List(list) => stack.extend(list.iter().rev()),
+            Ipld::Map(map) => stack.extend(map.values().rev()),
+            _ => {}
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use multihash_codetable::{Code, MultihashDigest};
+
+    use crate::unixfs;
+
+    fn node() -> (Ipld, Cid) {
+        let link = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(b"leaf"));
+        let inner = BTreeMap::from([
+            ("link".to_owned(), Ipld::Link(link)),
+            ("size".to_owned(), Ipld::Integer(4)),
+        ]);
+        let node = BTreeMap::from([
+            ("name".to_owned(), Ipld::String("node".to_owned())),
+            ("inner".to_owned(), Ipld::Map(inner)),
+            (
+                "tags".to_owned(),
+                Ipld::List(vec![Ipld::Bool(true), Ipld::Null]),
+            ),
+        ]);
+        (Ipld::Map(node), link)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let (node, link) = node();
+        for codec in [DAG_CBOR, DAG_JSON] {
+            let block = encode(&node, codec).unwrap();
+            let cid = Cid::new_v1(codec, Code::Sha2_256.digest(&block));
+            let decoded = decode(&cid, &block).unwrap();
+            assert_eq!(decoded, node);
+            assert_eq!(links(&decoded), vec![link]);
+            // Encoding what was decoded gives the same block back.
+            assert_eq!(encode(&decoded, codec).unwrap(), block);
+        }
+        assert!(encode(&node, unixfs::DAG_PB).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use libp2p::kad::{
+    self, store, GetProvidersOk, GetRecordOk, QueryId, QueryResult, Quorum, Record, RecordKey,
+};
+use libp2p::PeerId;
+use tokio::sync::{mpsc, oneshot};
+
+// Largest value a record may hold. Matches what the record stores of the other nodes accept.
+pub const MAX_VALUE_SIZE: usize = 65 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    // The value is larger than the DHT accepts.
+    ValueTooLarge(usize),
+    // Fewer nodes than the quorum stored or returned the record.
+    QuorumFailed { got: usize, quorum: usize },
+    // No node holds a record under the key.
+    NotFound,
+    // The query didn't complete in time.
+    Timeout,
+    // The local record store refused the record.
+    Store(String),
+    // The swarm was shut down before the query completed.
+    Stopped,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ValueTooLarge(size) => {
+                write!(
+                    f,
+                    "value of {size} bytes exceeds the limit of {MAX_VALUE_SIZE}"
+                )
+            }
+            Error::QuorumFailed { got, quorum } => {
+                write!(f, "quorum not met: {got} of {quorum} nodes")
+            }
+            Error::NotFound => write!(f, "record not found"),
+            Error::Timeout => write!(f, "query timed out"),
+            Error::Store(msg) => write!(f, "record store: {msg}"),
+            Error::Stopped => write!(f, "swarm stopped"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<store::Error> for Error {
+    fn from(e: store::Error) -> Self {
+        Error::Store(e.to_string())
+    }
+}
+
+// How many nodes a quorum stands for.
+fn count(quorum: Quorum) -> usize {
+    match quorum {
+        Quorum::One => 1,
+        Quorum::Majority => kad::K_VALUE.get() / 2 + 1,
+        Quorum::All => kad::K_VALUE.get(),
+        Quorum::N(n) => n.get(),
+    }
+}
+
+struct Get {
+    quorum: usize,
+    // Value of the first record found, and how many were found so far.
+    value: Option<Vec<u8>>,
+    found: usize,
+    reply: oneshot::Sender<Result<Vec<u8>, Error>>,
+}
+
+// Queries of the swarm to the DHT that callers are waiting on.
+#[derive(Default)]
+pub(crate) struct Queries {
+    puts: HashMap<QueryId, (usize, oneshot::Sender<Result<(), Error>>)>,
+    gets: HashMap<QueryId, Get>,
+    provides: HashMap<QueryId, oneshot::Sender<Result<(), Error>>>,
+    // Providers found so far, and where to send those found next.
+    providers: HashMap<QueryId, (HashSet<PeerId>, mpsc::UnboundedSender<PeerId>)>,
+}
+
+impl Queries {
+    pub fn put<S: store::RecordStore>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        quorum: Quorum,
+        reply: oneshot::Sender<Result<(), Error>>,
+    ) {
+        if value.len() > MAX_VALUE_SIZE {
+            let _ = reply.send(Err(Error::ValueTooLarge(value.len())));
+            return;
+        }
+        match kad.put_record(Record::new(RecordKey::new(&key), value), quorum) {
+            Ok(id) => {
+                self.puts.insert(id, (count(quorum), reply));
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e.into()));
+            }
+        }
+    }
+
+    pub fn get<S: store::RecordStore>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        key: Vec<u8>,
+        quorum: Quorum,
+        reply: oneshot::Sender<Result<Vec<u8>, Error>>,
+    ) {
+        let id = kad.get_record(RecordKey::new(&key));
+        let get = Get {
+            quorum: count(quorum),
+            value: None,
+            found: 0,
+            reply,
+        };
+        self.gets.insert(id, get);
+    }
+
+    pub fn start_providing<S: store::RecordStore>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        key: Vec<u8>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    ) {
+        match kad.start_providing(RecordKey::new(&key)) {
+            Ok(id) => {
+                self.provides.insert(id, reply);
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e.into()));
+            }
+        }
+    }
+
+    pub fn get_providers<S: store::RecordStore>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        key: Vec<u8>,
+        providers: mpsc::UnboundedSender<PeerId>,
+    ) {
+        let id = kad.get_providers(RecordKey::new(&key));
+        self.providers.insert(id, (HashSet::new(), providers));
+    }
+
+    // Advance the query the event is about, replying to its caller once it is done.
+    pub fn on_event<S: store::RecordStore>(
+        &mut self,
+        kad: &mut kad::Behaviour<S>,
+        event: &kad::Event,
+    ) {
+        let kad::Event::OutboundQueryProgressed {
+            id, result, step, ..
+        } = event
+        else {
+            return;
+        };
+        match result {
+            QueryResult::PutRecord(result) => {
+                let Some((quorum, reply)) = self.puts.remove(id) else {
+                    return;
+                };
+                let result = match result {
+                    Ok(_) => Ok(()),
+                    Err(kad::PutRecordError::QuorumFailed { success, .. }) => {
+                        Err(Error::QuorumFailed {
+                            got: success.len(),
+                            quorum,
+                        })
+                    }
+                    Err(kad::PutRecordError::Timeout { .. }) => Err(Error::Timeout),
+                };
+                let _ = reply.send(result);
+            }
+            QueryResult::GetRecord(result) => {
+                let Some(get) = self.gets.get_mut(id) else {
+                    return;
+                };
+                if let Ok(GetRecordOk::FoundRecord(found)) = result {
+                    get.found += 1;
+                    get.value.get_or_insert_with(|| found.record.value.clone());
+                }
+                let done = get.found >= get.quorum;
+                if !done && !step.last() {
+                    return;
+                }
+                let get = self.gets.remove(id).unwrap();
+                let result = match (get.value, result) {
+                    (Some(value), _) if done => Ok(value),
+                    (Some(_), _) => Err(Error::QuorumFailed {
+                        got: get.found,
+                        quorum: get.quorum,
+                    }),
+                    (None, Err(kad::GetRecordError::Timeout { .. })) => Err(Error::Timeout),
+                    (None, _) => Err(Error::NotFound),
+                };
+                let _ = get.reply.send(result);
+                if let Some(mut query) = kad.query_mut(id) {
+                    query.finish();
+                }
+            }
+            QueryResult::StartProviding(result) => {
+                let Some(reply) = self.provides.remove(id) else {
+                    return;
+                };
+                let _ = reply.send(result.as_ref().map(|_| ()).map_err(|_| Error::Timeout));
+            }
+            QueryResult::GetProviders(result) => {
+                let Some((seen, sender)) = self.providers.get_mut(id) else {
+                    return;
+                };
+                let mut closed = false;
+                if let Ok(GetProvidersOk::FoundProviders { providers, .. }) = result {
+                    for provider in providers {
+                        if seen.insert(*provider) {
+                            closed |= sender.send(*provider).is_err();
+                        }
+                    }
+                }
+                // Dropping the sender ends the stream of providers.
+                if closed || step.last() {
+                    self.providers.remove(id);
+                }
+                if closed {
+                    if let Some(mut query) = kad.query_mut(id) {
+                        query.finish();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_count() {
+        assert_eq!(count(Quorum::One), 1);
+        assert_eq!(count(Quorum::N(NonZeroUsize::new(3).unwrap())), 3);
+        assert_eq!(count(Quorum::Majority), kad::K_VALUE.get() / 2 + 1);
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use hickory_resolver::TokioAsyncResolver;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+
+// Most lookups resolving a single address may take, so dnsaddr records pointing at each other
+// can't keep resolution going forever.
+const MAX_LOOKUPS: usize = 32;
+
+// Records found for a name, and how long they may be cached for.
+pub struct Answer<T> {
+    pub records: Vec<T>,
+    pub ttl: Duration,
+}
+
+// Looks names up in the DNS.
+pub trait Resolver: Send + Sync {
+    // IPv4 and IPv6 addresses of host.
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<IpAddr>, String>>;
+    // TXT records of name, each with its strings joined.
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Answer<String>, String>>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    // Looking the host up failed.
+    Lookup(String, String),
+    // The host has no record the address can be resolved with.
+    NoRecords(String),
+    // Resolving the host took more than MAX_LOOKUPS lookups.
+    TooManyLookups(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Lookup(host, msg) => write!(f, "resolving {host}: {msg}"),
+            Error::NoRecords(host) => write!(f, "resolving {host}: no matching address"),
+            Error::TooManyLookups(host) => {
+                write!(f, "resolving {host}: more than {MAX_LOOKUPS} lookups")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Resolver configured like the system's, created on first use so building one doesn't read
+// the configuration of the system.
+#[derive(Default)]
+pub struct SystemResolver(OnceLock<TokioAsyncResolver>);
+
+impl SystemResolver {
+    fn resolver(&self) -> &TokioAsyncResolver {
+        self.0.get_or_init(|| {
+            TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+                tracing::warn!("no DNS configuration on the system, using defaults: {e}");
+                TokioAsyncResolver::tokio(Default::default(), Default::default())
+            })
+        })
+    }
+}
+
+fn ttl(valid_until: Instant) -> Duration {
+    valid_until.saturating_duration_since(Instant::now())
+}
+
+impl Resolver for SystemResolver {
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<IpAddr>, String>> {
+        Box::pin(async move {
+            let lookup = self
+                .resolver()
+                .lookup_ip(host)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Answer {
+                records: lookup.iter().collect(),
+                ttl: ttl(lookup.valid_until()),
+            })
+        })
+    }
+
+    fn lookup_txt<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Answer<String>, String>> {
+        Box::pin(async move {
+            let lookup = self
+                .resolver()
+                .txt_lookup(name)
+                .await
+                .map_err(|e| e.to_string())?;
+            let records = lookup.iter().map(|txt| {
+                let strings = txt.txt_data().iter();
+                strings.map(|data| String::from_utf8_lossy(data)).collect()
+            });
+            Ok(Answer {
+                records: records.collect(),
+                ttl: ttl(lookup.valid_until()),
+            })
+        })
+    }
+}
+
+// Answers still valid, by name, along with when they expire.
+#[derive(Default)]
+struct Cache {
+    ips: HashMap<String, (Vec<IpAddr>, Instant)>,
+    txts: HashMap<String, (Vec<String>, Instant)>,
+}
+
+fn cached<T: Clone>(answers: &HashMap<String, (Vec<T>, Instant)>, name: &str) -> Option<Vec<T>> {
+    let (records, expiry) = answers.get(name)?;
+    (*expiry > Instant::now()).then(|| records.clone())
+}
+
+// Resolves the dns, dns4, dns6 and dnsaddr components of multiaddrs to the addresses the
+// transports dial, caching answers for as long as their TTL. Clones share the cache.
+#[derive(Clone)]
+pub struct Dns {
+    resolver: Arc<dyn Resolver>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Self::new(SystemResolver::default())
+    }
+}
+
+impl fmt::Debug for Dns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dns").finish_non_exhaustive()
+    }
+}
+
+// Host named by a component of a multiaddr, if it names one.
+fn host<'a>(protocol: &'a Protocol<'_>) -> Option<&'a str> {
+    match protocol {
+        Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host) => Some(host.as_ref()),
+        Protocol::Dnsaddr(host) => Some(host.as_ref()),
+        _ => None,
+    }
+}
+
+impl Dns {
+    pub fn new(resolver: impl Resolver + 'static) -> Self {
+        Self {
+            resolver: Arc::new(resolver),
+            cache: Arc::default(),
+        }
+    }
+
+    // Whether addr names a host that needs resolving before addr can be dialed.
+    pub fn names_host(addr: &Multiaddr) -> bool {
+        addr.iter().any(|p| host(&p).is_some())
+    }
+
+    // Addresses addr resolves to, in the order of the records, or addr itself if it names no
+    // host. The records of a dnsaddr host are resolved in turn, and only those ending like addr
+    // does, with the ID of the same peer for instance, are kept.
+    pub async fn resolve(&self, addr: &Multiaddr) -> Result<Vec<Multiaddr>, Error> {
+        let mut pending = VecDeque::from([addr.clone()]);
+        let mut resolved = Vec::new();
+        let mut lookups = 0;
+        while let Some(addr) = pending.pop_front() {
+            let protocols: Vec<Protocol> = addr.iter().collect();
+            let Some(i) = protocols.iter().position(|p| host(p).is_some()) else {
+                resolved.push(addr);
+                continue;
+            };
+            let (head, rest) = (&protocols[..i], &protocols[i + 1..]);
+            let name = host(&protocols[i]).unwrap_or_default();
+            lookups += 1;
+            if lookups > MAX_LOOKUPS {
+                return Err(Error::TooManyLookups(name.to_owned()));
+            }
+            let lookup = |e| Error::Lookup(name.to_owned(), e);
+            let found: Vec<Multiaddr> = match &protocols[i] {
+                Protocol::Dnsaddr(_) => {
+                    let records = self
+                        .txts(&format!("_dnsaddr.{name}"))
+                        .await
+                        .map_err(lookup)?;
+                    records
+                        .iter()
+                        .filter_map(|record| record.strip_prefix("dnsaddr=")?.parse().ok())
+                        .filter(|record: &Multiaddr| {
+                            let record: Vec<Protocol> = record.iter().collect();
+                            record.ends_with(rest)
+                        })
+                        .collect()
+                }
+                protocol => {
+                    let ips = self.ips(name).await.map_err(lookup)?;
+                    ips.into_iter()
+                        .filter(|ip| match protocol {
+                            Protocol::Dns4(_) => ip.is_ipv4(),
+                            Protocol::Dns6(_) => ip.is_ipv6(),
+                            _ => true,
+                        })
+                        .map(|ip| {
+                            let ip = match ip {
+                                IpAddr::V4(ip) => Protocol::Ip4(ip),
+                                IpAddr::V6(ip) => Protocol::Ip6(ip),
+                            };
+                            let protocols = head.iter().cloned().chain([ip]);
+                            protocols.chain(rest.iter().cloned()).collect()
+                        })
+                        .collect()
+                }
+            };
+            if found.is_empty() {
+                return Err(Error::NoRecords(name.to_owned()));
+            }
+            // Ahead of the others, so the addresses keep the order of the records.
+            for addr in found.into_iter().rev() {
+                pending.push_front(addr);
+            }
+        }
+        Ok(resolved)
+    }
+
+    async fn ips(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let hit = cached(&self.cache.lock().unwrap().ips, host);
+        if let Some(ips) = hit {
+            return Ok(ips);
+        }
+        let answer = self.resolver.lookup_ip(host).await?;
+        let expiry = Instant::now() + answer.ttl;
+        let mut cache = self.cache.lock().unwrap();
+        cache
+            .ips
+            .insert(host.to_owned(), (answer.records.clone(), expiry));
+        Ok(answer.records)
+    }
+
+    async fn txts(&self, name: &str) -> Result<Vec<String>, String> {
+        let hit = cached(&self.cache.lock().unwrap().txts, name);
+        if let Some(txts) = hit {
+            return Ok(txts);
+        }
+        let answer = self.resolver.lookup_txt(name).await?;
+        let expiry = Instant::now() + answer.ttl;
+        let mut cache = self.cache.lock().unwrap();
+        cache
+            .txts
+            .insert(name.to_owned(), (answer.records.clone(), expiry));
+        Ok(answer.records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::executor::block_on;
+    use libp2p::PeerId;
+
+    // Resolver answering from maps, counting the lookups it answers.
+    #[derive(Default)]
+    struct Stub {
+        ips: HashMap<String, Vec<IpAddr>>,
+        txts: HashMap<String, Vec<String>>,
+        ttl: Duration,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl Stub {
+        fn answer<T: Clone>(&self, records: Option<&Vec<T>>) -> Result<Answer<T>, String> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            let records = records.ok_or("no such name")?.clone();
+            Ok(Answer {
+                records,
+                ttl: self.ttl,
+            })
+        }
+    }
+
+    impl Resolver for Stub {
+        fn lookup_ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Answer<IpAddr>, String>> {
+            Box::pin(async move { self.answer(self.ips.get(host)) })
+        }
+
+        fn lookup_txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<Answer<String>, String>> {
+            Box::pin(async move { self.answer(self.txts.get(name)) })
+        }
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<Multiaddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_dnsaddr() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let records = [
+            format!("dnsaddr=/ip4/192.0.2.1/tcp/4001/p2p/{a}"),
+            format!("dnsaddr=/dns6/b.example/udp/4001/quic-v1/p2p/{b}"),
+            "dnsaddr=not a multiaddr".to_owned(),
+            "v=spf1 -all".to_owned(),
+        ];
+        let stub = Stub {
+            txts: HashMap::from([("_dnsaddr.bootstrap.example".to_owned(), records.to_vec())]),
+            ips: HashMap::from([(
+                "b.example".to_owned(),
+                vec!["192.0.2.2".parse().unwrap(), "2001:db8::2".parse().unwrap()],
+            )]),
+            ..Default::default()
+        };
+        let dns = Dns::new(stub);
+
+        let bootstrap: Multiaddr = "/dnsaddr/bootstrap.example".parse().unwrap();
+        assert!(Dns::names_host(&bootstrap));
+        let expected = addrs(&[
+            &format!("/ip4/192.0.2.1/tcp/4001/p2p/{a}"),
+            &format!("/ip6/2001:db8::2/udp/4001/quic-v1/p2p/{b}"),
+        ]);
+        assert_eq!(block_on(dns.resolve(&bootstrap)).unwrap(), expected);
+        // Only the records of the peer the address names.
+        let only_a = bootstrap.with(Protocol::P2p(a));
+        assert_eq!(block_on(dns.resolve(&only_a)).unwrap(), expected[..1]);
+
+        let concrete: Multiaddr = "/ip4/192.0.2.1/tcp/4001".parse().unwrap();
+        assert!(!Dns::names_host(&concrete));
+        assert_eq!(block_on(dns.resolve(&concrete)).unwrap(), vec![concrete]);
+    }
+
+    #[test]
+    fn test_dns() {
+        let ips = vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        let stub = Stub {
+            ips: HashMap::from([("host.example".to_owned(), ips)]),
+            ..Default::default()
+        };
+        let dns = Dns::new(stub);
+        let resolve = |addr: &str| block_on(dns.resolve(&addr.parse().unwrap()));
+
+        let both = addrs(&["/ip4/192.0.2.1/tcp/4001", "/ip6/2001:db8::1/tcp/4001"]);
+        assert_eq!(resolve("/dns/host.example/tcp/4001").unwrap(), both);
+        assert_eq!(resolve("/dns4/host.example/tcp/4001").unwrap(), both[..1]);
+        assert_eq!(resolve("/dns6/host.example/tcp/4001").unwrap(), both[1..]);
+
+        let missing = resolve("/dns4/missing.example/tcp/4001").unwrap_err();
+        assert_eq!(
+            missing,
+            Error::Lookup("missing.example".to_owned(), "no such name".to_owned())
+        );
+        assert!(missing.to_string().contains("missing.example"));
+    }
+
+    #[test]
+    fn test_cache() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let stub = |ttl| Stub {
+            ips: HashMap::from([(
+                "host.example".to_owned(),
+                vec!["192.0.2.1".parse().unwrap()],
+            )]),
+            ttl,
+            lookups: lookups.clone(),
+            ..Default::default()
+        };
+        let addr: Multiaddr = "/dns4/host.example/tcp/4001".parse().unwrap();
+
+        let dns = Dns::new(stub(Duration::from_secs(60)));
+        for _ in 0..3 {
+            block_on(dns.clone().resolve(&addr)).unwrap();
+        }
+        assert_eq!(lookups.swap(0, Ordering::SeqCst), 1);
+
+        // Answers that expired right away are looked up again.
+        let dns = Dns::new(stub(Duration::ZERO));
+        for _ in 0..3 {
+            block_on(dns.resolve(&addr)).unwrap();
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_lookup_loop() {
+        let stub = Stub {
+            txts: HashMap::from([(
+                "_dnsaddr.loop.example".to_owned(),
+                vec!["dnsaddr=/dnsaddr/loop.example".to_owned()],
+            )]),
+            ..Default::default()
+        };
+        let dns = Dns::new(stub);
+        let addr = "/dnsaddr/loop.example".parse().unwrap();
+        let error = block_on(dns.resolve(&addr)).unwrap_err();
+        assert_eq!(error, Error::TooManyLookups("loop.example".to_owned()));
+    }
+}
@@ -0,0 +1,117 @@
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_RANGE, RANGE};
+use hyper::{Body, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+
+use crate::ipfs::{fetch_each, verify_block, BlockSource, Cid, Error, Leaf};
+
+// HTTP gateway serving raw blocks, as specified by the trustless gateway API.
+pub struct Gateway {
+    url: String,
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Gateway {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+            client: hyper::Client::builder().build(HttpsConnector::new()),
+        }
+    }
+
+    // Fetch the bytes of leaves in the file under root with a single request for the range
+    // they span, which starts and ends on block boundaries, and split them back into blocks
+    // checked against their CIDs. None if the gateway answers with anything but that range.
+    async fn get_span(&self, root: &Cid, leaves: &[Leaf]) -> Result<Option<Vec<Bytes>>, Error> {
+        let (Some(first), Some(last)) = (leaves.first(), leaves.last()) else {
+            return Ok(Some(Vec::new()));
+        };
+        let (start, end) = (first.start, last.start + last.size);
+        let request = Request::get(format!("{}/ipfs/{root}", self.url))
+            .header(RANGE, format!("bytes={start}-{}", end - 1))
+            .body(Body::empty())
+            .map_err(|e| Error::Gateway(format!("{}: {e}", self.url)))?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| Error::Gateway(format!("{}: {e}", self.url)))?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            StatusCode::NOT_FOUND => {
+                return Err(Error::NotFound(format!("{root} not found on {}", self.url)))
+            }
+            // The whole file, the range was ignored.
+            StatusCode::OK => return Ok(None),
+            status => return Err(Error::Gateway(format!("{}: {status}", self.url))),
+        }
+        let content_range = response.headers().get(CONTENT_RANGE);
+        let expected = format!("bytes {start}-{}/", end - 1);
+        let aligned = content_range
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(&expected));
+        if !aligned {
+            return Ok(None);
+        }
+        let span = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| Error::Gateway(format!("{}: {e}", self.url)))?;
+        if span.len() as u64 != end - start {
+            return Ok(None);
+        }
+
+        let mut blocks = Vec::with_capacity(leaves.len());
+        for leaf in leaves {
+            let from = (leaf.start - start) as usize;
+            let block = span.slice(from..from + leaf.size as usize);
+            verify_block(&leaf.cid, &block)?;
+            blocks.push(block);
+        }
+        Ok(Some(blocks))
+    }
+}
+
+impl BlockSource for Gateway {
+    fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>> {
+        Box::pin(async move {
+            let request = Request::get(format!("{}/ipfs/{cid}?format=raw", self.url))
+                .header("Accept", "application/vnd.ipld.raw")
+                .body(Body::empty())
+                .map_err(|e| Error::Gateway(format!("{}: {e}", self.url)))?;
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| Error::Gateway(format!("{}: {e}", self.url)))?;
+            match response.status() {
+                StatusCode::OK => {}
+                StatusCode::NOT_FOUND => {
+                    return Err(Error::NotFound(format!("{cid} not found on {}", self.url)))
+                }
+                status => return Err(Error::Gateway(format!("{}: {status}", self.url))),
+            }
+            hyper::body::to_bytes(response.into_body())
+                .await
+                .map_err(|e| Error::Gateway(format!("{}: {e}", self.url)))
+        })
+    }
+
+    fn get_leaves<'a>(
+        &'a self,
+        root: &'a Cid,
+        leaves: &'a [Leaf],
+    ) -> BoxFuture<'a, Result<Vec<Bytes>, Error>> {
+        Box::pin(async move {
+            match self.get_span(root, leaves).await? {
+                Some(blocks) => Ok(blocks),
+                // Only whole blocks can be checked, so they are fetched one by one instead.
+                None => {
+                    tracing::debug!("{} didn't serve the range, fetching blocks", self.url);
+                    fetch_each(self, leaves).await
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use bytes::Bytes;
+use cid::Cid;
+use multihash_codetable::{Code, MultihashDigest};
+
+use crate::ipfs::Error;
+use crate::unixfs::{Link, Node, NodeType, DAG_PB};
+
+// Multihash code of the 64 bit murmur3 hash names are sharded by, the only one UnixFS uses.
+pub const MURMUR3_X64_64: u64 = 0x22;
+
+// Fanout of the shards go-unixfs builds.
+pub const DEFAULT_FANOUT: u64 = 256;
+
+// Layout of a HAMT-sharded directory, in which each node spreads the entries under it across
+// fanout buckets by log2(fanout) bits of the hash of their names, most significant first, taking
+// the next bits at every level. A link is named after its bucket, in fanout - 1 wide uppercase
+// hex, followed by the name of the entry it points to, or by nothing if it points to the shard
+// holding the entries of the bucket at the next level.
+struct Shard {
+    bits: u32,
+    width: usize,
+}
+
+impl Shard {
+    fn of(node: &Node) -> Result<Self, Error> {
+        if node.hash_type != Some(MURMUR3_X64_64) {
+            return Err(Error::Decode(format!(
+                "unsupported HAMT hash function {:?}",
+                node.hash_type
+            )));
+        }
+        let fanout = node.fanout.unwrap_or_default();
+        if !fanout.is_power_of_two() || !(2..=1 << 16).contains(&fanout) {
+            return Err(Error::Decode(format!("invalid HAMT fanout {fanout}")));
+        }
+        Ok(Self {
+            bits: fanout.trailing_zeros(),
+            width: format!("{:X}", fanout - 1).len(),
+        })
+    }
+
+    // Prefix of the links of the bucket name falls in at depth, or None past the last bits of
+    // its hash.
+    fn prefix(&self, name: &str, depth: u32) -> Option<String> {
+        let hash = u64::from_be_bytes(murmur3(name.as_bytes()));
+        let consumed = depth * self.bits;
+        if consumed + self.bits > 64 {
+            return None;
+        }
+        let bucket = (hash << consumed) >> (64 - self.bits);
+        Some(format!("{bucket:0width$X}", width = self.width))
+    }
+
+    // Entry named by link with its bucket prefix removed, or None if it links to a shard.
+    fn entry(&self, link: &Link) -> Result<Option<Link>, Error> {
+        match link.name.get(self.width..) {
+            Some("") => Ok(None),
+            Some(name) => Ok(Some(Link {
+                cid: link.cid,
+                name: name.to_owned(),
+                tsize: link.tsize,
+            })),
+            None => Err(Error::Decode(format!(
+                "invalid HAMT link name {}",
+                link.name
+            ))),
+        }
+    }
+}
+
+// Find the entry called name in the sharded directory under root, fetching only the shards on
+// the way to its bucket.
+pub async fn lookup<F, Fut>(root: &Node, name: &str, get_node: F) -> Result<Option<Link>, Error>
+where
+    F: Fn(Cid) -> Fut,
+    Fut: Future<Output = Result<Node, Error>>,
+{
+    let mut node = root.clone();
+    let mut depth = 0;
+    loop {
+        let shard = Shard::of(&node)?;
+        let Some(prefix) = shard.prefix(name, depth) else {
+            return Err(Error::Decode(format!(
+                "HAMT deeper than the hash of {name}"
+            )));
+        };
+        let Some(link) = node
+            .links
+            .iter()
+            .find(|link| link.name.starts_with(&prefix))
+        else {
+            return Ok(None);
+        };
+        match shard.entry(link)? {
+            Some(entry) if entry.name == name => return Ok(Some(entry)),
+            // The bucket holds a single entry, another one.
+            Some(_) => return Ok(None),
+            None => node = get_node(link.cid).await?,
+        }
+        if node.typ != NodeType::HamtShard {
+            return Err(Error::Decode(format!(
+                "HAMT link {prefix} isn't to a shard"
+            )));
+        }
+        depth += 1;
+    }
+}
+
+// Every entry of the sharded directory under root, in bucket order. The shards of each level
+// are fetched concurrently.
+pub async fn entries<F, Fut>(root: &Node, get_node: F) -> Result<Vec<Link>, Error>
+where
+    F: Fn(Cid) -> Fut,
+    Fut: Future<Output = Result<Node, Error>>,
+{
+    let mut entries = Vec::new();
+    let mut level = vec![root.clone()];
+    while !level.is_empty() {
+        let mut shards = Vec::new();
+        for node in &level {
+            let shard = Shard::of(node)?;
+            for link in &node.links {
+                match shard.entry(link)? {
+                    Some(entry) => entries.push(entry),
+                    None => shards.push(get_node(link.cid)),
+                }
+            }
+        }
+        level = futures::future::try_join_all(shards).await?;
+        if let Some(node) = level.iter().find(|node| node.typ != NodeType::HamtShard) {
+            return Err(Error::Decode(format!("HAMT link to a {:?} node", node.typ)));
+        }
+    }
+    Ok(entries)
+}
+
+// Root shard of a sharded directory holding entries, laid out the way go-unixfs lays them out,
+// with the blocks of the shards under it by CID, which are to be stored along with it. Buckets
+// holding a single entry link to it, and the others to a shard of their own at the next level.
+pub fn shard(entries: Vec<Link>, fanout: u64) -> Result<(Node, Vec<(Cid, Bytes)>), Error> {
+    let mut blocks = Vec::new();
+    let root = shard_at(entries, fanout, 0, &mut blocks)?;
+    Ok((root, blocks))
+}
+
+fn shard_at(
+    entries: Vec<Link>,
+    fanout: u64,
+    depth: u32,
+    blocks: &mut Vec<(Cid, Bytes)>,
+) -> Result<Node, Error> {
+    let mut node = Node::directory(Vec::new());
+    node.typ = NodeType::HamtShard;
+    node.hash_type = Some(MURMUR3_X64_64);
+    node.fanout = Some(fanout);
+    let layout = Shard::of(&node)?;
+    let mut buckets = BTreeMap::<String, Vec<Link>>::new();
+    for entry in entries {
+        let Some(prefix) = layout.prefix(&entry.name, depth) else {
+            return Err(Error::Decode(format!(
+                "HAMT deeper than the hash of {}",
+                entry.name
+            )));
+        };
+        buckets.entry(prefix).or_default().push(entry);
+    }
+
+    // Bit i of the bitfield, counting from the end, is set when bucket i is in use.
+    let mut bitfield = vec![0u8; (fanout as usize).div_ceil(8)];
+    let len = bitfield.len();
+    for (prefix, mut bucket) in buckets {
+        let index = usize::from_str_radix(&prefix, 16).expect("prefixes are hex");
+        bitfield[len - 1 - index / 8] |= 1 << (index % 8);
+        if bucket.len() == 1 {
+            let entry = bucket.pop().unwrap();
+            node.links.push(Link {
+                name: format!("{prefix}{}", entry.name),
+                ..entry
+            });
+            continue;
+        }
+        let child = shard_at(bucket, fanout, depth + 1, blocks)?;
+        let tsize = child.dag_size();
+        let block = Bytes::from(child.encode());
+        let cid = Cid::new_v1(DAG_PB, Code::Sha2_256.digest(&block));
+        node.links.push(Link {
+            cid,
+            name: prefix,
+            tsize,
+        });
+        blocks.push((cid, block));
+    }
+    node.data = Bytes::from(bitfield);
+    Ok(node)
+}
+
+// First half of the 128 bit x64 murmur3 hash of data with a zero seed, big-endian, which is
+// what go-unixfs hashes names with.
+fn murmur3(data: &[u8]) -> [u8; 8] {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+
+    fn fmix(mut k: u64) -> u64 {
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        k ^ (k >> 33)
+    }
+
+    let mix1 = |k: u64| k.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    let mix2 = |k: u64| k.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+    let word = |bytes: &[u8]| {
+        let mut word = [0; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        u64::from_le_bytes(word)
+    };
+
+    let (mut h1, mut h2) = (0u64, 0u64);
+    let mut blocks = data.chunks_exact(16);
+    for block in &mut blocks {
+        h1 ^= mix1(word(&block[..8]));
+        h1 = h1.rotate_left(27).wrapping_add(h2);
+        h1 = h1.wrapping_mul(5).wrapping_add(0x52dc_e729);
+        h2 ^= mix2(word(&block[8..]));
+        h2 = h2.rotate_left(31).wrapping_add(h1);
+        h2 = h2.wrapping_mul(5).wrapping_add(0x3849_5ab5);
+    }
+    let tail = blocks.remainder();
+    if tail.len() > 8 {
+        h2 ^= mix2(word(&tail[8..]));
+    }
+    if !tail.is_empty() {
+        h1 ^= mix1(word(&tail[..tail.len().min(8)]));
+    }
+
+    h1 ^= data.len() as u64;
+    h2 ^= data.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix(h1);
+    h2 = fmix(h2);
+    h1.wrapping_add(h2).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use futures::executor::block_on;
+
+    use crate::unixfs::RAW;
+
+    #[test]
+    fn test_murmur3() {
+        assert_eq!(murmur3(b""), [0; 8]);
+        assert_eq!(murmur3(b"hello"), 0xcbd8_a7b3_41bd_9b02u64.to_be_bytes());
+        let fox = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(murmur3(fox), 0xe34b_bc7b_bc07_1b6cu64.to_be_bytes());
+    }
+
+    #[test]
+    fn test_sharded_directory() {
+        let names: Vec<String> = (0..2000).map(|i| format!("file-{i}.txt")).collect();
+        let links = names.iter().map(|name| Link {
+            cid: Cid::new_v1(RAW, Code::Sha2_256.digest(name.as_bytes())),
+            name: name.clone(),
+            tsize: name.len() as u64,
+        });
+        let (root, stored) = shard(links.collect(), DEFAULT_FANOUT).unwrap();
+        let blocks: HashMap<Cid, Node> = stored
+            .into_iter()
+            .map(|(cid, block)| (cid, Node::decode(&cid, block).unwrap()))
+            .collect();
+        // Decoding what was encoded gives the same shard back.
+        let block = Bytes::from(root.encode());
+        let root = Node::decode(&Cid::new_v1(DAG_PB, Code::Sha2_256.digest(&block)), block);
+        let root = root.unwrap();
+        assert_eq!(root.typ, NodeType::HamtShard);
+
+        let fetched = std::cell::Cell::new(0);
+        let get_node = |cid: Cid| {
+            fetched.set(fetched.get() + 1);
+            let node = blocks.get(&cid).cloned();
+            async move { node.ok_or_else(|| Error::NotFound(cid.to_string())) }
+        };
+
+        // An entry of a shard right below the root, found by fetching only that shard.
+        let below = root.links.iter().find(|link| link.name.len() == 2).unwrap();
+        let link = blocks[&below.cid]
+            .links
+            .iter()
+            .find(|link| link.name.len() > 2);
+        let deep = &link.unwrap().name[2..];
+        // Links to shards record the size of all the blocks under them.
+        let under: u64 = blocks[&below.cid].links.iter().map(|link| link.tsize).sum();
+        let block = blocks[&below.cid].encode();
+        assert_eq!(below.tsize, block.len() as u64 + under);
+        let found = block_on(lookup(&root, deep, get_node)).unwrap().unwrap();
+        assert_eq!(found.name, deep);
+        assert_eq!(
+            found.cid,
+            Cid::new_v1(RAW, Code::Sha2_256.digest(deep.as_bytes()))
+        );
+        assert_eq!(fetched.get(), 1);
+        assert!(block_on(lookup(&root, "missing", get_node))
+            .unwrap()
+            .is_none());
+
+        let mut listed: Vec<String> = block_on(entries(&root, get_node))
+            .unwrap()
+            .into_iter()
+            .map(|link| link.name)
+            .collect();
+        listed.sort();
+        let mut expected = names.clone();
+        expected.sort();
+        assert_eq!(listed, expected);
+    }
+}
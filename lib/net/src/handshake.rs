@@ -0,0 +1,223 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use libp2p::core::transport::{DialOpts, ListenerId, TransportError, TransportEvent};
+use libp2p::core::Transport;
+use libp2p::Multiaddr;
+use tokio::runtime::{Handle, Runtime};
+
+// Runtime of its own for the tasks a transport spawns, apart from the event loop of the swarm.
+// QUIC connections are driven by tasks quinn spawns on whichever runtime it is called from, and
+// the libp2p-tls certificate verifier, with its Ed25519 checks, runs synchronously inside them.
+// rustls gives the verifier no way to wait, and libp2p-quic no way to swap it, so rather than
+// sending each check to the pool, the tasks running them are: a burst of handshakes then only
+// holds up the threads of the pool, and the event loop keeps going.
+pub struct HandshakePool {
+    // Only None while being dropped.
+    runtime: Option<Runtime>,
+}
+
+impl HandshakePool {
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads.max(1))
+            .thread_name("ww-handshake")
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            runtime: Some(runtime),
+        })
+    }
+
+    fn handle(&self) -> &Handle {
+        self.runtime.as_ref().expect("pool is running").handle()
+    }
+}
+
+impl Drop for HandshakePool {
+    fn drop(&mut self) {
+        // Swarms are dropped by their event loop, where waiting for the tasks of the pool to
+        // stop would block a runtime.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+// Transport whose calls, and the dials and upgrades they return, are made from the runtime of
+// a pool, so the tasks spawned along the way run there.
+pub struct Pooled<T> {
+    inner: T,
+    pool: Arc<HandshakePool>,
+}
+
+impl<T> Pooled<T> {
+    pub fn new(inner: T, pool: Arc<HandshakePool>) -> Self {
+        Self { inner, pool }
+    }
+}
+
+impl<T> Transport for Pooled<T>
+where
+    T: Transport + Unpin,
+    T::Dial: Unpin,
+    T::ListenerUpgrade: Unpin,
+{
+    type Output = T::Output;
+    type Error = T::Error;
+    type ListenerUpgrade = OnPool<T::ListenerUpgrade>;
+    type Dial = OnPool<T::Dial>;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        let _entered = self.pool.handle().enter();
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let _entered = self.pool.handle().enter();
+        let dial = self.inner.dial(addr, opts)?;
+        Ok(OnPool::new(dial, self.pool.clone()))
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        let this = self.get_mut();
+        let _entered = this.pool.handle().enter();
+        let pool = &this.pool;
+        Pin::new(&mut this.inner)
+            .poll(cx)
+            .map(|event| event.map_upgrade(|upgrade| OnPool::new(upgrade, pool.clone())))
+    }
+}
+
+// Future polled from the runtime of a pool, wherever it is awaited.
+pub struct OnPool<F> {
+    inner: F,
+    pool: Arc<HandshakePool>,
+}
+
+impl<F> OnPool<F> {
+    fn new(inner: F, pool: Arc<HandshakePool>) -> Self {
+        Self { inner, pool }
+    }
+}
+
+impl<F: Future + Unpin> Future for OnPool<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.get_mut();
+        let _entered = this.pool.handle().enter();
+        Pin::new(&mut this.inner).poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use futures::future::BoxFuture;
+    use libp2p::core::transport::PortUse;
+    use libp2p::core::Endpoint;
+
+    const STALL: Duration = Duration::from_millis(200);
+
+    // Transport whose dials spawn a task stalling its thread as long as a slow certificate check
+    // would, and resolve to the name of that thread.
+    struct Stalling;
+
+    impl Transport for Stalling {
+        type Output = Option<String>;
+        type Error = io::Error;
+        type ListenerUpgrade = BoxFuture<'static, io::Result<Option<String>>>;
+        type Dial = BoxFuture<'static, io::Result<Option<String>>>;
+
+        fn listen_on(
+            &mut self,
+            _: ListenerId,
+            addr: Multiaddr,
+        ) -> Result<(), TransportError<io::Error>> {
+            Err(TransportError::MultiaddrNotSupported(addr))
+        }
+
+        fn remove_listener(&mut self, _: ListenerId) -> bool {
+            false
+        }
+
+        fn dial(
+            &mut self,
+            _: Multiaddr,
+            _: DialOpts,
+        ) -> Result<Self::Dial, TransportError<io::Error>> {
+            let check = tokio::spawn(async {
+                thread::sleep(STALL);
+                thread::current().name().map(str::to_owned)
+            });
+            Ok(Box::pin(
+                async move { check.await.map_err(io::Error::other) },
+            ))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<TransportEvent<Self::ListenerUpgrade, io::Error>> {
+            Poll::Pending
+        }
+    }
+
+    // Every task of the test, the dials included, shares a single thread, as the tasks of a
+    // swarm do on a current-thread runtime.
+    #[tokio::test]
+    async fn test_handshakes_off_event_loop() {
+        const DIALS: usize = 4;
+        let pool = Arc::new(HandshakePool::new(DIALS).unwrap());
+        let mut transport = Pooled::new(Stalling, pool);
+        let dials: Vec<_> = (0..DIALS)
+            .map(|_| {
+                let opts = DialOpts {
+                    role: Endpoint::Dialer,
+                    port_use: PortUse::New,
+                };
+                tokio::spawn(transport.dial(Multiaddr::empty(), opts).unwrap())
+            })
+            .collect();
+
+        // Ticks standing in for the other events of the loop keep coming while the dials stall
+        // the threads they run on.
+        let start = Instant::now();
+        let mut slowest = Duration::ZERO;
+        while !dials.iter().all(|dial| dial.is_finished()) {
+            let tick = Instant::now();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            slowest = slowest.max(tick.elapsed());
+        }
+        assert!(slowest < STALL / 2, "event loop stalled for {slowest:?}");
+        // The dials stalled different threads of the pool at once.
+        assert!(start.elapsed() < STALL * 2, "took {:?}", start.elapsed());
+        for dial in dials {
+            let thread = dial.await.unwrap().unwrap();
+            assert_eq!(thread.as_deref(), Some("ww-handshake"));
+        }
+    }
+}
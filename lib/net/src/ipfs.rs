@@ -1,25 +1,455 @@
-use bytes::Bytes;
-use ipfs_api_backend_hyper::{Error, IpfsApi, IpfsClient, TryFromUri};
-use ipfs_api_prelude::BoxStream;
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::io::Cursor;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+pub use cid::Cid;
+use futures::future::{self, BoxFuture};
+use futures::{Stream, StreamExt, TryStreamExt};
+use hyper::client::HttpConnector;
+use hyper::{Body, Request, StatusCode};
+use ipfs_api_backend_hyper::{IpfsApi, IpfsClient, TryFromUri};
+use ipfs_api_prelude::{request, ApiError, BoxStream};
+use libp2p::multiaddr::Protocol;
 use libp2p::Multiaddr;
+use multihash_codetable::{Code, MultihashDigest};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::car::Car;
+use crate::chunker::Chunker;
+use crate::dag::{self, Ipld};
+use crate::gateway::Gateway;
+use crate::hamt;
+use crate::metrics::Metrics;
+use crate::unixfs::{self, Link, Node};
+
+// Most links a file node has before its leaves are grouped under another level of nodes, as
+// in the kubo balanced layout.
+const MAX_LINKS: usize = 174;
+
+// Estimated size of the links of a directory past which it is sharded, as kubo does. Links are
+// estimated at the length of their name and CID.
+const SHARDING_THRESHOLD: usize = 256 * 1024;
+
+// How many requests a batch fetch keeps in flight.
+const BATCH_CONCURRENCY: usize = 16;
+
+// How long the daemon has to answer a health check before it is considered unreachable.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Error {
+    // The requested content or name doesn't exist or couldn't be found.
+    NotFound(String),
+    // The daemon gave up on the request before completing it.
+    Timeout(String),
+    // The IPFS HTTP API returned an error.
+    Api(ipfs_api_backend_hyper::Error),
+    // A CID could not be parsed.
+    Cid(cid::Error),
+    // A block could not be decoded.
+    Decode(String),
+    // The data of a block doesn't hash to its CID.
+    Corrupt(Cid),
+    // An HTTP gateway failed to serve a block.
+    Gateway(String),
+    // The daemon at the address didn't answer.
+    Unreachable(Multiaddr, String),
+    // The block isn't in the local store, and the client is offline so it wasn't searched for.
+    NotFoundLocally(Cid),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound(msg) => write!(f, "not found: {msg}"),
+            Error::Timeout(msg) => write!(f, "timed out: {msg}"),
+            Error::Api(e) => write!(f, "ipfs api: {e}"),
+            Error::Cid(e) => write!(f, "invalid cid: {e}"),
+            Error::Decode(msg) => write!(f, "malformed block: {msg}"),
+            Error::Corrupt(cid) => write!(f, "block data doesn't match its cid {cid}"),
+            Error::Gateway(msg) => write!(f, "gateway: {msg}"),
+            Error::Unreachable(addr, msg) => write!(f, "daemon unreachable at {addr}: {msg}"),
+            Error::NotFoundLocally(cid) => write!(f, "{cid} not found locally (offline)"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    // Whether the error may go away by itself, so the request is worth retrying. Missing or
+    // corrupt content won't change by asking again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Timeout(_) | Error::Gateway(_) | Error::Unreachable(..) => true,
+            Error::Api(ipfs_api_backend_hyper::Error::Client(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+// Called as a file is fetched with the bytes fetched so far and the size of the file, which is
+// None until known.
+pub type Progress = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+// How failed block fetches are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Attempts made in total, including the first one.
+    pub max_attempts: u32,
+    // Wait before the first retry, doubled on each one after it up to max_backoff.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Errors that are retried, the others are returned right away.
+    pub retry_on: fn(&Error) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retry_on: Error::is_transient,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Policy making a single attempt.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry));
+        backoff.min(self.max_backoff)
+    }
+}
+
+impl From<ipfs_api_backend_hyper::Error> for Error {
+    fn from(e: ipfs_api_backend_hyper::Error) -> Self {
+        // The HTTP API reports every failure the same way, the message is all there is to tell
+        // missing content and timeouts apart from other errors.
+        let msg = e.to_string();
+        let lower = msg.to_lowercase();
+        if lower.contains("not found")
+            || lower.contains("could not find")
+            || lower.contains("no link named")
+            || lower.contains("could not resolve")
+        {
+            return Error::NotFound(msg);
+        }
+        if lower.contains("timed out") || lower.contains("deadline exceeded") {
+            return Error::Timeout(msg);
+        }
+        Error::Api(e)
+    }
+}
+
+impl From<cid::Error> for Error {
+    fn from(e: cid::Error) -> Self {
+        Error::Cid(e)
+    }
+}
+
+// Check that data hashes to the multihash of the CID it was fetched by.
+pub fn verify_block(cid: &Cid, data: &[u8]) -> Result<(), Error> {
+    let hash = cid.hash();
+    let code = Code::try_from(hash.code())
+        .map_err(|_| Error::Decode(format!("unsupported hash function 0x{:x}", hash.code())))?;
+    if code.digest(data).digest() != hash.digest() {
+        return Err(Error::Corrupt(*cid));
+    }
+    Ok(())
+}
+
+// Read up to size bytes, stopping short only at the end of the reader.
+async fn read_chunk(reader: &mut (impl AsyncRead + Unpin), size: usize) -> Result<Bytes, Error> {
+    let mut chunk = Vec::with_capacity(size);
+    while chunk.len() < size {
+        let mut limited = (&mut *reader).take((size - chunk.len()) as u64);
+        let n = limited
+            .read_to_end(&mut chunk)
+            .await
+            .map_err(|e| Error::Decode(format!("reading file: {e}")))?;
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(Bytes::from(chunk))
+}
+
+// CIDs a block links to.
+fn block_links(cid: &Cid, block: &Bytes) -> Result<Vec<Cid>, Error> {
+    match cid.codec() {
+        unixfs::RAW => Ok(Vec::new()),
+        dag::DAG_CBOR | dag::DAG_JSON => Ok(dag::links(&dag::decode(cid, block)?)),
+        _ => {
+            let node = Node::decode(cid, block.clone())?;
+            Ok(node.links.iter().map(|link| link.cid).collect())
+        }
+    }
+}
+
+// Raw leaf of a file and where its bytes are within the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Leaf {
+    pub cid: Cid,
+    pub start: u64,
+    pub size: u64,
+}
+
+// Somewhere raw blocks can be fetched from by CID.
+pub trait BlockSource: Send + Sync {
+    fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>>;
+
+    // Fetch leaves, which follow each other in the file under root, checked against their CIDs.
+    // Sources that can't do better fetch them one block at a time.
+    fn get_leaves<'a>(
+        &'a self,
+        _root: &'a Cid,
+        leaves: &'a [Leaf],
+    ) -> BoxFuture<'a, Result<Vec<Bytes>, Error>> {
+        Box::pin(fetch_each(self, leaves))
+    }
+}
+
+// Fetch the block of each leaf from source, checked against its CID.
+pub(crate) async fn fetch_each<S>(source: &S, leaves: &[Leaf]) -> Result<Vec<Bytes>, Error>
+where
+    S: BlockSource + ?Sized,
+{
+    future::try_join_all(leaves.iter().map(|leaf| async move {
+        let block = source.get_block(&leaf.cid).await?;
+        verify_block(&leaf.cid, &block)?;
+        Ok(block)
+    }))
+    .await
+}
+
+// Children of a node whose data starts at base overlapping range, or None if the inline data of
+// the node does, as it has no CID to be checked against.
+fn children_in_range(node: &Node, base: u64, range: &Range<u64>) -> Option<Vec<Leaf>> {
+    let inline_end = base + node.data.len() as u64;
+    if base < range.end && inline_end > range.start {
+        return None;
+    }
+    let mut start = inline_end;
+    let mut children = Vec::new();
+    for (i, link) in node.links.iter().enumerate() {
+        let size = node.blocksizes.get(i).copied().unwrap_or(link.tsize);
+        if start < range.end && start + size > range.start {
+            children.push(Leaf {
+                cid: link.cid,
+                start,
+                size,
+            });
+        }
+        start += size;
+    }
+    Some(children)
+}
+
+// Blocks held or found by the IPFS daemon.
+struct Daemon {
+    client: IpfsClient,
+}
+
+impl BlockSource for Daemon {
+    fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>> {
+        Box::pin(async move {
+            let block = self
+                .client
+                .block_get(&cid.to_string())
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat()
+                .await?;
+            Ok(Bytes::from(block))
+        })
+    }
+}
+
+// Blocks held by the IPFS daemon itself. Requests are made offline, so the daemon answers
+// right away from its blockstore instead of searching peers for missing blocks.
+struct LocalStore {
+    addr: Multiaddr,
+    url: String,
+    client: hyper::Client<HttpConnector>,
+}
+
+impl LocalStore {
+    fn new(addr: &Multiaddr) -> Self {
+        Self {
+            addr: addr.clone(),
+            url: api_url(addr).expect("error initializing IPFS client"),
+            client: hyper::Client::new(),
+        }
+    }
+}
+
+impl BlockSource for LocalStore {
+    fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>> {
+        Box::pin(async move {
+            let unreachable =
+                |e: hyper::Error| Error::Unreachable(self.addr.clone(), e.to_string());
+            let uri = format!("{}/api/v0/block/get?arg={cid}&offline=true", self.url);
+            let request = Request::post(uri)
+                .body(Body::empty())
+                .map_err(|e| Error::Decode(e.to_string()))?;
+            let response = self.client.request(request).await.map_err(unreachable)?;
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .map_err(unreachable)?;
+            if status == StatusCode::OK {
+                return Ok(body);
+            }
+            let message = String::from_utf8_lossy(&body).into_owned();
+            if message.contains("not found") {
+                return Err(Error::NotFoundLocally(*cid));
+            }
+            Err(ipfs_api_backend_hyper::Error::Api(ApiError { message, code: 0 }).into())
+        })
+    }
+}
+
+// File node over links, each with the size of the file data under it, and the size of the data
+// under the node. Links record the cumulative size of the blocks under them, as kubo does, while
+// the node records the size of the data under each.
+fn file_root(links: &[(Link, u64)]) -> (Node, u64) {
+    let sized = links.iter().map(|(link, size)| Link {
+        tsize: *size,
+        ..link.clone()
+    });
+    let mut node = Node::file_root(sized.collect());
+    for (link, (dag, _)) in node.links.iter_mut().zip(links) {
+        link.tsize = dag.tsize;
+    }
+    let size = node.size();
+    (node, size)
+}
+
+// Base URL of the HTTP API of the daemon listening on addr, e.g. /ip4/127.0.0.1/tcp/5001.
+fn api_url(addr: &Multiaddr) -> Option<String> {
+    let (mut host, mut port) = (None, None);
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(ip) => host = Some(ip.to_string()),
+            Protocol::Ip6(ip) => host = Some(format!("[{ip}]")),
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                host = Some(name.to_string())
+            }
+            Protocol::Tcp(p) => port = Some(p),
+            _ => {}
+        }
+    }
+    Some(format!("http://{}:{}", host?, port?))
+}
 
 // TODO rename and move to ipfs file
+#[derive(Clone)]
 pub struct Client {
+    addr: Multiaddr,
     client: IpfsClient,
+    pub(crate) local: Arc<dyn BlockSource>,
+    // Fetched from when the daemon doesn't come up with a block within gateway_delay.
+    gateways: Vec<Arc<dyn BlockSource>>,
+    gateway_delay: Duration,
+    retry: RetryPolicy,
+    // How added files are split into leaves.
+    chunker: Chunker,
+    // Where fetched bytes are counted, and the caches of the client count their hits.
+    metrics: Option<Metrics>,
+    // Whether blocks are only fetched from the blockstore of the daemon.
+    offline: bool,
 }
 
 impl Client {
     pub fn new(addr: Multiaddr) -> Self {
+        let client = IpfsClient::from_multiaddr_str(addr.to_string().as_str())
+            .expect("error initializing IPFS client");
         Self {
-            client: IpfsClient::from_multiaddr_str(addr.to_string().as_str())
-                .expect("error initializing IPFS client"),
+            local: Arc::new(Daemon {
+                client: client.clone(),
+            }),
+            addr,
+            client,
+            gateways: Vec::new(),
+            gateway_delay: Duration::ZERO,
+            retry: RetryPolicy::default(),
+            chunker: Chunker::default(),
+            metrics: None,
+            offline: false,
         }
     }
 
-    pub fn get_file(&self, path: &str) -> BoxStream<Bytes, Error> {
+    // Fall back to the HTTP gateways at the given URLs for blocks the daemon doesn't return
+    // within delay. The gateways are raced against the daemon and each other.
+    pub fn with_gateways(mut self, urls: &[String], delay: Duration) -> Self {
+        self.gateways = urls
+            .iter()
+            .map(|url| Arc::new(Gateway::new(url)) as Arc<dyn BlockSource>)
+            .collect();
+        self.gateway_delay = delay;
+        self
+    }
+
+    // Check that the daemon answers, so a missing daemon is reported up front rather than by
+    // the first request made to it. Gives up after HEALTH_CHECK_TIMEOUT.
+    pub async fn health_check(&self) -> Result<(), Error> {
+        let unreachable = |msg: String| Error::Unreachable(self.addr.clone(), msg);
+        match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, self.client.version()).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(unreachable(e.to_string())),
+            Err(_) => Err(unreachable(format!(
+                "no answer within {HEALTH_CHECK_TIMEOUT:?}"
+            ))),
+        }
+    }
+
+    pub fn get_file(&self, path: &str) -> BoxStream<Bytes, ipfs_api_backend_hyper::Error> {
         self.client.cat(path)
     }
 
+    // Same as get_file, reporting to progress as the file arrives. The size of the file is read
+    // from its root node before the contents are requested, and is None for paths that don't
+    // resolve to a UnixFS node.
+    pub fn get_file_with_progress(
+        &self,
+        path: &str,
+        progress: Progress,
+    ) -> BoxStream<Bytes, ipfs_api_backend_hyper::Error> {
+        let client = self.clone();
+        let path = path.to_owned();
+        progress(0, None);
+        let stream = futures::stream::once(async move {
+            let root = client.resolve_path(&path).await;
+            let total = root.ok().map(|root| root.size());
+            if total.is_some() {
+                progress(0, total);
+            }
+            let mut fetched = 0;
+            client.client.cat(&path).map_ok(move |chunk| {
+                fetched += chunk.len() as u64;
+                progress(fetched, total);
+                chunk
+            })
+        })
+        .flatten();
+        Box::new(Box::pin(stream))
+    }
+
     pub async fn ls(&self, path: &str) -> Result<Vec<String>, ipfs_api_backend_hyper::Error> {
         let files = self.client.ls(path).await;
         match files {
@@ -27,4 +457,1089 @@ impl Client {
             Err(e) => Err(e),
         }
     }
+
+    // Set the size of the leaves files are split into when added.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        self.with_chunker(Chunker::Fixed(chunk_size))
+    }
+
+    // Set how files are split into leaves when added, e.g. Chunker::buzhash() for leaves that
+    // files with insertions share with their originals.
+    pub fn with_chunker(mut self, chunker: Chunker) -> Self {
+        self.chunker = chunker.normalized();
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Set whether blocks are only fetched from the blockstore of the daemon. Offline, missing
+    // blocks fail right away with NotFoundLocally rather than being searched for on the network
+    // or fetched from gateways, so runs are reproducible and fetches don't wait on peers.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self.local = match offline {
+            true => Arc::new(LocalStore::new(&self.addr)),
+            false => Arc::new(Daemon {
+                client: self.client.clone(),
+            }),
+        };
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    // Fetch a single raw block, retrying transient failures as set by the retry policy.
+    pub async fn get_block(&self, cid: &Cid) -> Result<Bytes, Error> {
+        let result = self.retrying(cid, || self.fetch_block(cid)).await;
+        if let (Ok(block), Some(metrics)) = (&result, &self.metrics) {
+            metrics.ipfs_fetched_bytes.inc_by(block.len() as u64);
+        }
+        result
+    }
+
+    // Run fetch, which fetches what, until it succeeds, retrying transient failures as set by
+    // the retry policy.
+    async fn retrying<T, F>(
+        &self,
+        what: impl fmt::Display,
+        mut fetch: impl FnMut() -> F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match fetch().await {
+                Err(e) if attempt < self.retry.max_attempts && (self.retry.retry_on)(&e) => {
+                    let backoff = self.retry.backoff(attempt - 1);
+                    tracing::debug!("retrying {what} in {backoff:?} after attempt {attempt}: {e}");
+                    if !backoff.is_zero() {
+                        tokio::time::sleep(backoff).await;
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn fetch_block(&self, cid: &Cid) -> Result<Bytes, Error> {
+        let mut local = self.local.get_block(cid);
+        if self.gateways.is_empty() || self.offline {
+            return local.await;
+        }
+
+        let local = match tokio::time::timeout(self.gateway_delay, &mut local).await {
+            Ok(Ok(block)) => return Ok(block),
+            Ok(Err(e)) => {
+                tracing::debug!("falling back to gateways for {cid}: {e}");
+                None
+            }
+            Err(_) => {
+                tracing::debug!("falling back to gateways for {cid}: timed out");
+                Some(local)
+            }
+        };
+        // Gateways aren't trusted, so their blocks are always checked. Those that fail or
+        // return bad data are skipped in favor of the others.
+        let gateways = self.gateways.iter().map(|gateway| {
+            Box::pin(async move {
+                let block = gateway.get_block(cid).await?;
+                verify_block(cid, &block)?;
+                Ok(block)
+            }) as BoxFuture<'_, Result<Bytes, Error>>
+        });
+        let (block, _) = future::select_ok(local.into_iter().chain(gateways)).await?;
+        Ok(block)
+    }
+
+    // Fetch many blocks at once, yielding them as they arrive rather than in the given order.
+    // Each block succeeds or fails on its own.
+    pub fn get_blocks<'a>(
+        &'a self,
+        cids: &[Cid],
+    ) -> impl Stream<Item = Result<(Cid, Bytes), Error>> + 'a {
+        futures::stream::iter(cids.to_vec())
+            .map(move |cid| async move { Ok((cid, self.get_block(&cid).await?)) })
+            .buffer_unordered(BATCH_CONCURRENCY)
+    }
+
+    // Walk the DAG under root depth first, yielding each block once however many times it is
+    // linked to. Blocks that fail to fetch or decode are reported without ending the walk, but
+    // what is under them is skipped.
+    pub fn walk_dag(&self, root: Cid) -> impl Stream<Item = Result<(Cid, Bytes), Error>> + '_ {
+        let state = (vec![root], HashSet::new());
+        futures::stream::unfold(state, move |(mut stack, mut visited)| async move {
+            let cid = loop {
+                let cid = stack.pop()?;
+                if visited.insert(cid) {
+                    break cid;
+                }
+            };
+            let block = self.get_block(&cid).await;
+            let item = block.and_then(|block| {
+                let links = block_links(&cid, &block)?;
+                stack.extend(links.into_iter().rev());
+                Ok((cid, block))
+            });
+            Some((item, (stack, visited)))
+        })
+    }
+
+    // Fetch and decode the UnixFS node of a block.
+    pub async fn get_node(&self, cid: &Cid) -> Result<Node, Error> {
+        let block = self.get_block(cid).await?;
+        Node::decode(cid, block)
+    }
+
+    // Resolve an '/ipfs/<cid>/<path>' path to its node, following the named links under the
+    // root CID one segment at a time.
+    pub async fn resolve_path(&self, path: &str) -> Result<Node, Error> {
+        self.get_node(&self.resolve_cid(path).await?).await
+    }
+
+    // Resolve an '/ipfs/<cid>/<path>' path to the CID it points to. Only the nodes above it are
+    // fetched.
+    pub async fn resolve_cid(&self, path: &str) -> Result<Cid, Error> {
+        let path = path.strip_prefix("/ipfs").unwrap_or(path);
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+        let root = segments
+            .next()
+            .ok_or_else(|| Error::NotFound(format!("empty path {path}")))?;
+        let mut cid = Cid::try_from(root)?;
+        for segment in segments {
+            let node = self.get_node(&cid).await?;
+            let get_node = |cid| async move { self.get_node(&cid).await };
+            let link = unixfs::dir_entry(&node, segment, get_node).await?;
+            cid = link
+                .ok_or_else(|| Error::NotFound(format!("no link named {segment}")))?
+                .cid;
+        }
+        Ok(cid)
+    }
+
+    // Fetch len bytes of the file at path starting at offset. Only the blocks overlapping the
+    // range are fetched, and fewer bytes are returned if the range runs past the end of the file.
+    // With gateways, unless offline, files made of raw leaves are fetched from them with a
+    // single request for the leaves overlapping the range, each of which is checked against its
+    // CID.
+    pub async fn get_file_range(&self, path: &str, offset: u64, len: u64) -> Result<Bytes, Error> {
+        let cid = self.resolve_cid(path).await?;
+        let root = self.get_node(&cid).await?;
+        let range = offset..offset.saturating_add(len).min(root.size());
+        if !self.gateways.is_empty() && !self.offline && !range.is_empty() {
+            if let Some(leaves) = self.raw_leaves(&root, &range).await? {
+                return self.read_leaves(&cid, &leaves, &range).await;
+            }
+        }
+        unixfs::read_range(&root, offset, len, |cid| async move {
+            self.get_node(&cid).await
+        })
+        .await
+    }
+
+    // Raw leaves of the file under root overlapping range, in file order. Only the nodes above
+    // them are fetched. None if some of the range is held inline by a node rather than by a raw
+    // leaf.
+    async fn raw_leaves(
+        &self,
+        root: &Node,
+        range: &Range<u64>,
+    ) -> Result<Option<Vec<Leaf>>, Error> {
+        let Some(mut level) = children_in_range(root, 0, range) else {
+            return Ok(None);
+        };
+        while level.iter().any(|leaf| leaf.cid.codec() != unixfs::RAW) {
+            let mut next = Vec::new();
+            for leaf in level {
+                if leaf.cid.codec() == unixfs::RAW {
+                    next.push(leaf);
+                    continue;
+                }
+                let node = self.get_node(&leaf.cid).await?;
+                let Some(children) = children_in_range(&node, leaf.start, range) else {
+                    return Ok(None);
+                };
+                next.extend(children);
+            }
+            level = next;
+        }
+        Ok(Some(level))
+    }
+
+    // Fetch leaves, retrying transient failures like get_block, and cut range out of their data.
+    async fn read_leaves(
+        &self,
+        root: &Cid,
+        leaves: &[Leaf],
+        range: &Range<u64>,
+    ) -> Result<Bytes, Error> {
+        let what = format!("the leaves of {root}");
+        let fetch = || self.fetch_leaves(root, leaves);
+        let blocks = self.retrying(what, fetch).await?;
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        for (leaf, block) in leaves.iter().zip(&blocks) {
+            if block.len() as u64 != leaf.size {
+                return Err(Error::Decode(format!(
+                    "block {} is {} bytes instead of {}",
+                    leaf.cid,
+                    block.len(),
+                    leaf.size
+                )));
+            }
+            let from = range.start.saturating_sub(leaf.start).min(leaf.size) as usize;
+            let to = (range.end - leaf.start).min(leaf.size) as usize;
+            data.extend_from_slice(&block[from..to]);
+        }
+        if let Some(metrics) = &self.metrics {
+            let fetched = blocks.iter().map(|block| block.len() as u64).sum();
+            metrics.ipfs_fetched_bytes.inc_by(fetched);
+        }
+        Ok(Bytes::from(data))
+    }
+
+    // Fetch leaves like fetch_block fetches a block, from the daemon and then from the gateways
+    // as well if it doesn't come up with all of them within gateway_delay.
+    async fn fetch_leaves(&self, root: &Cid, leaves: &[Leaf]) -> Result<Vec<Bytes>, Error> {
+        let blocks = leaves.iter().map(|leaf| self.local.get_block(&leaf.cid));
+        let mut local: BoxFuture<'_, Result<Vec<Bytes>, Error>> =
+            Box::pin(future::try_join_all(blocks));
+        let local = match tokio::time::timeout(self.gateway_delay, &mut local).await {
+            Ok(Ok(blocks)) => return Ok(blocks),
+            Ok(Err(e)) => {
+                tracing::debug!("falling back to gateways for the leaves of {root}: {e}");
+                None
+            }
+            Err(_) => {
+                tracing::debug!("falling back to gateways for the leaves of {root}: timed out");
+                Some(local)
+            }
+        };
+        let gateways = self
+            .gateways
+            .iter()
+            .map(|gateway| gateway.get_leaves(root, leaves));
+        let (blocks, _) = future::select_ok(local.into_iter().chain(gateways)).await?;
+        Ok(blocks)
+    }
+
+    // Store a block and return its CIDv1. The CID is computed locally, so it only depends on
+    // the data and codec.
+    pub async fn put_block(&self, data: Bytes, codec: u64) -> Result<Cid, Error> {
+        let cid_codec = match codec {
+            unixfs::RAW => "raw",
+            unixfs::DAG_PB => "dag-pb",
+            dag::DAG_CBOR => "dag-cbor",
+            dag::DAG_JSON => "dag-json",
+            _ => return Err(Error::Decode(format!("unsupported codec 0x{codec:x}"))),
+        };
+        let cid = Cid::new_v1(codec, Code::Sha2_256.digest(&data));
+        let options = request::BlockPut {
+            mhtype: Some("sha2-256"),
+            cid_codec: Some(cid_codec),
+            ..Default::default()
+        };
+        self.client
+            .block_put_with_options(Cursor::new(data), options)
+            .await?;
+        Ok(cid)
+    }
+
+    // Store the blocks of a CARv1 archive and return the root CIDs declared in its header.
+    // Blocks are checked against their CID before anything is stored.
+    pub async fn import_car(&self, mut reader: impl AsyncRead + Unpin) -> Result<Vec<Cid>, Error> {
+        let mut car = Vec::new();
+        reader
+            .read_to_end(&mut car)
+            .await
+            .map_err(|e| Error::Decode(format!("reading car: {e}")))?;
+        let car = Car::parse(Bytes::from(car))?;
+        for (cid, data) in &car.blocks {
+            verify_block(cid, data)?;
+        }
+
+        for (cid, data) in car.blocks {
+            // Blocks are stored by multihash, so a CIDv0 block lands where its CIDv1 points.
+            let stored = self.put_block(data, cid.codec()).await?;
+            if stored.hash() != cid.hash() {
+                return Err(Error::Decode(format!(
+                    "block {cid} is not hashed with sha2-256"
+                )));
+            }
+        }
+        Ok(car.roots)
+    }
+
+    // Store a node as a dag-pb block.
+    pub async fn put_node(&self, node: &Node) -> Result<Cid, Error> {
+        self.put_block(Bytes::from(node.encode()), unixfs::DAG_PB)
+            .await
+    }
+
+    // Store a directory of entries and return its CID with the cumulative size of its blocks.
+    // Directories whose links outgrow SHARDING_THRESHOLD are sharded, as kubo shards them, so
+    // their blocks stay small enough to be exchanged.
+    pub async fn put_directory(&self, mut entries: Vec<Link>) -> Result<(Cid, u64), Error> {
+        let estimate: usize = entries
+            .iter()
+            .map(|link| link.name.len() + link.cid.encoded_len())
+            .sum();
+        if estimate <= SHARDING_THRESHOLD {
+            // UnixFS directories list their entries sorted by name.
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            let node = Node::directory(entries);
+            return Ok((self.put_node(&node).await?, node.dag_size()));
+        }
+        let (root, blocks) = hamt::shard(entries, hamt::DEFAULT_FANOUT)?;
+        for (_, block) in blocks {
+            self.put_block(block, unixfs::DAG_PB).await?;
+        }
+        Ok((self.put_node(&root).await?, root.dag_size()))
+    }
+
+    // Fetch and decode a dag-cbor or dag-json block. Links are decoded as Ipld::Link, so the
+    // nodes they point to can be fetched in turn.
+    pub async fn get_dag(&self, cid: &Cid) -> Result<Ipld, Error> {
+        let block = self.get_block(cid).await?;
+        dag::decode(cid, &block)
+    }
+
+    // Store node as a block of codec, dag::DAG_CBOR or dag::DAG_JSON, and return its CIDv1.
+    pub async fn put_dag(&self, node: &Ipld, codec: u64) -> Result<Cid, Error> {
+        self.put_block(dag::encode(node, codec)?, codec).await
+    }
+
+    // Store data as a UnixFS file split into raw leaves and return the CID of its root.
+    pub async fn add_bytes(&self, data: Bytes) -> Result<Cid, Error> {
+        self.add_file(&data[..]).await
+    }
+
+    // Store the contents of reader as a UnixFS file and return the CID of its root. Contents
+    // are split into raw leaves by the chunker, read and stored one at a time, so the CID only
+    // depends on the contents and the chunker.
+    pub async fn add_file(&self, reader: impl AsyncRead + Unpin) -> Result<Cid, Error> {
+        Ok(self.add_file_sized(reader).await?.0)
+    }
+
+    // Store the contents of reader as add_file does, and return the CID of its root with the
+    // cumulative size of the blocks of the file, which links to it record as their tsize.
+    pub async fn add_file_sized(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<(Cid, u64), Error> {
+        let max_len = self.chunker.max_len();
+        // Links to the nodes of a level, with the size of the file data under each.
+        let mut links = Vec::new();
+        // Read but not yet stored, which the next leaf starts with.
+        let mut pending = BytesMut::new();
+        loop {
+            let read = read_chunk(&mut reader, max_len - pending.len()).await?;
+            pending.extend_from_slice(&read);
+            if pending.is_empty() {
+                break;
+            }
+            let chunk = pending.split_to(self.chunker.cut(&pending)).freeze();
+            let size = chunk.len() as u64;
+            let cid = self.put_block(chunk, unixfs::RAW).await?;
+            let link = Link {
+                cid,
+                name: String::new(),
+                tsize: size,
+            };
+            links.push((link, size));
+        }
+
+        match links.len() {
+            0 => {
+                let node = Node::file(Bytes::new());
+                Ok((self.put_node(&node).await?, node.dag_size()))
+            }
+            // Single leaves are files on their own.
+            1 => Ok((links[0].0.cid, links[0].0.tsize)),
+            _ => {
+                while links.len() > MAX_LINKS {
+                    let mut parents = Vec::new();
+                    for group in links.chunks(MAX_LINKS) {
+                        let (node, size) = file_root(group);
+                        let tsize = node.dag_size();
+                        let cid = self.put_node(&node).await?;
+                        let link = Link {
+                            cid,
+                            name: String::new(),
+                            tsize,
+                        };
+                        parents.push((link, size));
+                    }
+                    links = parents;
+                }
+                let (node, _) = file_root(&links);
+                Ok((self.put_node(&node).await?, node.dag_size()))
+            }
+        }
+    }
+
+    // Pin cid so the daemon keeps it through garbage collection, along with everything it
+    // links to if recursive. Pinning content that is already pinned succeeds.
+    pub async fn pin(&self, cid: &Cid, recursive: bool) -> Result<(), Error> {
+        match self.client.pin_add(&cid.to_string(), recursive).await {
+            Ok(_) => Ok(()),
+            // Direct pins of content pinned recursively are refused, but it is pinned all the
+            // same.
+            Err(e) if e.to_string().contains("already pinned") => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Remove the pin on cid, whether direct or recursive.
+    pub async fn unpin(&self, cid: &Cid) -> Result<(), Error> {
+        self.client.pin_rm(&cid.to_string(), true).await?;
+        Ok(())
+    }
+
+    // List the CIDs pinned directly or recursively. Content only pinned through a recursive pin
+    // on one of its ancestors is left out.
+    pub fn pins(&self) -> impl Stream<Item = Result<Cid, Error>> + '_ {
+        futures::stream::once(async move {
+            let pins = self.client.pin_ls(None, Some("direct")).await?;
+            let mut keys: Vec<String> = pins.keys.into_keys().collect();
+            let pins = self.client.pin_ls(None, Some("recursive")).await?;
+            keys.extend(pins.keys.into_keys());
+            Ok::<_, Error>(futures::stream::iter(keys).map(|key| Ok(Cid::try_from(key)?)))
+        })
+        .try_flatten()
+    }
+
+    // Resolve an IPNS name to the '/ipfs/...' path its record currently points to.
+    pub async fn resolve_name(&self, name: &str) -> Result<String, Error> {
+        let resolved = self.client.name_resolve(Some(name), true, false).await?;
+        Ok(resolved.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::Daemon;
+    use crate::unixfs::NodeType;
+
+    // Block source answering every request with the same result, after an optional stall.
+    struct Stub {
+        block: Option<Bytes>,
+        stall: bool,
+    }
+
+    impl BlockSource for Stub {
+        fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>> {
+            Box::pin(async move {
+                if self.stall {
+                    future::pending::<()>().await;
+                }
+                self.block
+                    .clone()
+                    .ok_or_else(|| Error::NotFound(cid.to_string()))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        use std::convert::Infallible;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Instant;
+
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::Response;
+
+        let data = Bytes::from_static(b"only remote");
+        let cid = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(&data));
+
+        // Daemon without the block, which searches the network for it unless told not to.
+        let searched = Arc::new(AtomicBool::new(false));
+        let make_service = make_service_fn({
+            let searched = searched.clone();
+            move |_| {
+                let searched = searched.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let query = request.uri().query().unwrap_or_default();
+                        let offline = query.contains("offline=true");
+                        let searched = searched.clone();
+                        async move {
+                            if !offline {
+                                searched.store(true, Ordering::SeqCst);
+                                future::pending::<()>().await;
+                            }
+                            let message = "block was not found locally (offline)";
+                            let response = Response::builder()
+                                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                .body(Body::from(message))
+                                .unwrap();
+                            Ok::<_, Infallible>(response)
+                        }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+
+        let addr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let mut client = Client::new(addr).with_offline(true);
+        client.gateway_delay = Duration::from_millis(10);
+        client.gateways = vec![Arc::new(Stub {
+            block: Some(data),
+            stall: false,
+        })];
+
+        let start = Instant::now();
+        let result = client.get_block(&cid).await;
+        assert!(matches!(result, Err(Error::NotFoundLocally(c)) if c == cid));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!searched.load(Ordering::SeqCst));
+    }
+
+    // Block source serving the blocks it holds.
+    struct Blocks(std::collections::HashMap<Cid, Bytes>);
+
+    impl BlockSource for Blocks {
+        fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>> {
+            let block = self.0.get(cid).cloned();
+            Box::pin(async move { block.ok_or_else(|| Error::NotFound(cid.to_string())) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_blocks() {
+        let cid = |data: &[u8]| Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(data));
+        let (a, b, missing) = (cid(b"a"), cid(b"b"), cid(b"missing"));
+        let metrics = Metrics::new();
+        let mut client =
+            Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap()).with_metrics(metrics.clone());
+        client.local = Arc::new(Blocks(
+            [(a, Bytes::from_static(b"a")), (b, Bytes::from_static(b"b"))].into(),
+        ));
+
+        let results: Vec<_> = client.get_blocks(&[a, missing, b]).collect().await;
+        assert_eq!(results.len(), 3);
+        let mut found: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+        found.sort_by_key(|(_, data)| data.clone());
+        assert_eq!(
+            found,
+            vec![
+                &(a, Bytes::from_static(b"a")),
+                &(b, Bytes::from_static(b"b"))
+            ]
+        );
+        assert!(matches!(
+            results.iter().find(|r| r.is_err()),
+            Some(Err(Error::NotFound(_)))
+        ));
+        assert_eq!(metrics.ipfs_fetched_bytes.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_walk_dag_diamond() {
+        let mut blocks = std::collections::HashMap::new();
+        let mut put = |node: Node| {
+            let data = Bytes::from(node.encode());
+            let cid = Cid::new_v1(unixfs::DAG_PB, Code::Sha2_256.digest(&data));
+            blocks.insert(cid, data);
+            Link {
+                cid,
+                name: cid.to_string(),
+                tsize: 0,
+            }
+        };
+        // Two parents sharing a child.
+        let child = put(Node::file(Bytes::from_static(b"shared")));
+        let left = put(Node::directory(vec![child.clone()]));
+        let right = put(Node::file_root(vec![child.clone()]));
+        let root = put(Node::directory(vec![left.clone(), right.clone()]));
+
+        let mut client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        client.local = Arc::new(Blocks(blocks));
+        let walked: Vec<Cid> = client
+            .walk_dag(root.cid)
+            .map(|r| r.unwrap().0)
+            .collect()
+            .await;
+
+        assert_eq!(walked, vec![root.cid, left.cid, child.cid, right.cid]);
+    }
+
+    // Block source failing a number of times before serving its block.
+    struct Flaky {
+        block: Bytes,
+        failures: u32,
+        attempts: std::sync::atomic::AtomicU32,
+    }
+
+    impl BlockSource for Flaky {
+        fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>> {
+            let attempt = self
+                .attempts
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let result = match attempt < self.failures {
+                true => Err(Error::Timeout(format!("fetching {cid}"))),
+                false => Ok(self.block.clone()),
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let data = Bytes::from_static(b"hello world");
+        let cid = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(&data));
+        let flaky = Arc::new(Flaky {
+            block: data.clone(),
+            failures: 2,
+            attempts: Default::default(),
+        });
+
+        let mut client =
+            Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap()).with_retry(RetryPolicy {
+                initial_backoff: Duration::ZERO,
+                ..Default::default()
+            });
+        client.local = flaky.clone();
+        assert_eq!(client.get_block(&cid).await.unwrap(), data);
+        assert_eq!(flaky.attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        // Missing blocks aren't retried.
+        client.local = Arc::new(Blocks(Default::default()));
+        assert!(matches!(
+            client.get_block(&cid).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_add_file_roundtrip() {
+        let daemon = Daemon::start();
+        let client = daemon.client().with_chunk_size(1024);
+        let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+
+        let cid = client.add_file(&data[..]).await.unwrap();
+        // Identical contents always get the same CID.
+        assert_eq!(
+            client.add_bytes(Bytes::from(data.clone())).await.unwrap(),
+            cid
+        );
+
+        let fetched: Vec<u8> = client
+            .get_file(&format!("/ipfs/{cid}"))
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(fetched, data);
+    }
+
+    #[tokio::test]
+    async fn test_put_directory() {
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let (file, size) = client.add_file_sized(&b"hello"[..]).await.unwrap();
+        assert_eq!(size, 5);
+        let entries = |n: usize| {
+            (0..n)
+                .map(|i| Link {
+                    cid: file,
+                    name: format!("file-{i:05}.txt"),
+                    tsize: size,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (small, _) = client.put_directory(entries(10)).await.unwrap();
+        assert_eq!(
+            client.get_node(&small).await.unwrap().typ,
+            NodeType::Directory
+        );
+
+        // Directories whose links would outgrow a block are sharded.
+        let (large, _) = client.put_directory(entries(10_000)).await.unwrap();
+        let root = client.get_node(&large).await.unwrap();
+        assert_eq!(root.typ, NodeType::HamtShard);
+        let get_node = |cid| {
+            let client = client.clone();
+            async move { client.get_node(&cid).await }
+        };
+        let listed = unixfs::dir_entries(&root, get_node).await.unwrap();
+        assert_eq!(listed.len(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_add_file_chunker() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let original: Vec<u8> = (0..1 << 20).map(|_| rng.gen()).collect();
+        let mut edited = vec![0xff];
+        edited.extend_from_slice(&original);
+
+        // Leaves of the edited file also found in the original, out of all of them.
+        async fn shared(client: &Client, original: &[u8], edited: &[u8]) -> (usize, usize) {
+            let mut leaves = Vec::new();
+            for data in [original, edited] {
+                let cid = client.add_file(data).await.unwrap();
+                let node = client.get_node(&cid).await.unwrap();
+                let cids: HashSet<Cid> = node.links.iter().map(|link| link.cid).collect();
+                leaves.push(cids);
+            }
+            (leaves[1].intersection(&leaves[0]).count(), leaves[1].len())
+        }
+
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let buzhash = client.clone().with_chunker(Chunker::Buzhash {
+            min: 4096,
+            avg: 8192,
+            max: 65536,
+        });
+        let (found, total) = shared(&buzhash, &original, &edited).await;
+        assert!(found * 10 > total * 9, "{found} of {total} leaves shared");
+        let fixed = client.with_chunk_size(8192);
+        assert_eq!(shared(&fixed, &original, &edited).await.0, 0);
+    }
+
+    #[tokio::test]
+    async fn test_import_car() {
+        use crate::car::tests::build_car;
+
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let a = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(b"hello"));
+        let b = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(b"world"));
+        let car = build_car(&[(a, b"hello"), (b, b"world")]);
+        assert_eq!(client.import_car(&car[..]).await.unwrap(), vec![a]);
+        assert_eq!(&client.get_block(&b).await.unwrap()[..], b"world");
+
+        // Archives holding a corrupt block are refused before anything is stored.
+        let c = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(b"other"));
+        let car = build_car(&[(a, b"hello"), (c, b"0ther")]);
+        let result = client.import_car(&car[..]).await;
+        assert!(matches!(result, Err(Error::Corrupt(cid)) if cid == c));
+        assert_eq!(daemon.requests("block/put"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dag_roundtrip() {
+        use std::collections::BTreeMap;
+
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let leaf = client
+            .add_bytes(Bytes::from_static(b"test_dag_roundtrip"))
+            .await
+            .unwrap();
+        let inner = BTreeMap::from([("file".to_owned(), Ipld::Link(leaf))]);
+        let node = Ipld::Map(BTreeMap::from([
+            ("name".to_owned(), Ipld::String("root".to_owned())),
+            ("inner".to_owned(), Ipld::Map(inner)),
+        ]));
+
+        for codec in [dag::DAG_CBOR, dag::DAG_JSON] {
+            let cid = client.put_dag(&node, codec).await.unwrap();
+            assert_eq!(cid.codec(), codec);
+            assert_eq!(client.put_dag(&node, codec).await.unwrap(), cid);
+            let fetched = client.get_dag(&cid).await.unwrap();
+            assert_eq!(fetched, node);
+            // The link leads to the file it was made from.
+            let links = dag::links(&fetched);
+            assert_eq!(links, vec![leaf]);
+            let data = client.get_file_range(&format!("/ipfs/{}", links[0]), 0, 100);
+            assert_eq!(&data.await.unwrap()[..], b"test_dag_roundtrip");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_file_with_progress() {
+        let daemon = Daemon::start();
+        let client = daemon.client().with_chunk_size(1024);
+        let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+        let cid = client.add_file(&data[..]).await.unwrap();
+
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let progress: Progress = {
+            let reports = reports.clone();
+            Arc::new(move |fetched, total| reports.lock().unwrap().push((fetched, total)))
+        };
+        let fetched: Vec<u8> = client
+            .get_file_with_progress(&format!("/ipfs/{cid}"), progress)
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(fetched, data);
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports[0], (0, None));
+        assert_eq!(reports[1], (0, Some(10_000)));
+        assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert_eq!(reports.last(), Some(&(10_000, Some(10_000))));
+    }
+
+    #[tokio::test]
+    async fn test_pin_unpin() {
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let cid = client
+            .add_bytes(Bytes::from_static(b"test_pin_unpin"))
+            .await
+            .unwrap();
+
+        client.pin(&cid, true).await.unwrap();
+        // Pinning again, even directly, is not an error.
+        client.pin(&cid, true).await.unwrap();
+        client.pin(&cid, false).await.unwrap();
+        let pins: Vec<Cid> = client.pins().try_collect().await.unwrap();
+        assert!(pins.contains(&cid));
+
+        client.unpin(&cid).await.unwrap();
+        let pins: Vec<Cid> = client.pins().try_collect().await.unwrap();
+        assert!(!pins.contains(&cid));
+    }
+
+    #[tokio::test]
+    async fn test_gateway_fallback() {
+        let data = Bytes::from_static(b"hello world");
+        let cid = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(&data));
+
+        let mut client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        client.local = Arc::new(Stub {
+            block: None,
+            stall: true,
+        });
+        client.gateway_delay = Duration::from_millis(10);
+        client.gateways = vec![
+            // Missing the block, and serving bad data.
+            Arc::new(Stub {
+                block: None,
+                stall: false,
+            }),
+            Arc::new(Stub {
+                block: Some(Bytes::from_static(b"hello w0rld")),
+                stall: false,
+            }),
+            Arc::new(Stub {
+                block: Some(data.clone()),
+                stall: false,
+            }),
+        ];
+
+        assert_eq!(client.get_block(&cid).await.unwrap(), data);
+    }
+
+    // How a stub gateway answers requests for a byte range of a file.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Ranges {
+        Served,
+        Ignored,
+        Corrupted,
+    }
+
+    // Serve a file made of 1 KiB raw leaves through a stub gateway, returning the root of the
+    // file, its contents, the URL of the gateway and the requests it got.
+    async fn range_gateway(
+        ranges: Ranges,
+    ) -> (Cid, Vec<u8>, String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use std::collections::HashMap;
+        use std::convert::Infallible;
+        use std::sync::Mutex;
+
+        use hyper::header::{CONTENT_RANGE, RANGE};
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::Response;
+
+        let contents: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut blocks = HashMap::new();
+        let mut links = Vec::new();
+        for leaf in contents.chunks(1024) {
+            let cid = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(leaf));
+            blocks.insert(cid.to_string(), Bytes::copy_from_slice(leaf));
+            links.push(Link {
+                cid,
+                name: String::new(),
+                tsize: leaf.len() as u64,
+            });
+        }
+        let root_block = Bytes::from(Node::file_root(links).encode());
+        let root = Cid::new_v1(unixfs::DAG_PB, Code::Sha2_256.digest(&root_block));
+        blocks.insert(root.to_string(), root_block);
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let state = Arc::new((blocks, contents.clone(), requests.clone()));
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let (blocks, contents, requests) = &*state;
+                    let cid = request.uri().path().trim_start_matches("/ipfs/");
+                    let range = request.headers().get(RANGE).map(|r| r.to_str().unwrap());
+                    let mut response = Response::builder();
+                    let body = match (request.uri().query(), range) {
+                        (Some("format=raw"), _) => {
+                            requests.lock().unwrap().push(format!("block {cid}"));
+                            blocks[cid].clone()
+                        }
+                        (_, Some(range)) => {
+                            requests.lock().unwrap().push(range.to_owned());
+                            let span = &range["bytes=".len()..];
+                            let (from, to) = span.split_once('-').unwrap();
+                            let (from, to): (usize, usize) =
+                                (from.parse().unwrap(), to.parse().unwrap());
+                            let mut body = contents[from..=to].to_vec();
+                            match ranges {
+                                Ranges::Ignored => Bytes::from(contents.clone()),
+                                Ranges::Served | Ranges::Corrupted => {
+                                    if ranges == Ranges::Corrupted {
+                                        body[0] ^= 0xff;
+                                    }
+                                    let total = contents.len();
+                                    response = response
+                                        .status(StatusCode::PARTIAL_CONTENT)
+                                        .header(CONTENT_RANGE, format!("bytes {span}/{total}"));
+                                    Bytes::from(body)
+                                }
+                            }
+                        }
+                        _ => Bytes::from(contents.clone()),
+                    };
+                    let response = response.body(Body::from(body)).unwrap();
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (root, contents, url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_gateway_ranges() {
+        for ranges in [Ranges::Served, Ranges::Ignored, Ranges::Corrupted] {
+            let (root, contents, url, requests) = range_gateway(ranges).await;
+            let mut client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap())
+                .with_gateways(&[url], Duration::ZERO);
+            client.local = Arc::new(Stub {
+                block: None,
+                stall: false,
+            });
+
+            let result = client
+                .get_file_range(&format!("/ipfs/{root}"), 1500, 1000)
+                .await;
+            let requests = requests.lock().unwrap().clone();
+            // Bytes 1500 to 2499 are in the second and third leaves, so the range requested
+            // starts and ends with them.
+            assert_eq!(requests[0], format!("block {root}"));
+            assert_eq!(requests[1], "bytes=1024-3071");
+            match ranges {
+                Ranges::Served => {
+                    assert_eq!(result.unwrap(), contents[1500..2500]);
+                    assert_eq!(requests.len(), 2);
+                }
+                // The leaves are fetched as blocks instead.
+                Ranges::Ignored => {
+                    assert_eq!(result.unwrap(), contents[1500..2500]);
+                    let blocks = requests[2..].iter().filter(|r| r.starts_with("block "));
+                    assert_eq!(blocks.count(), 2);
+                }
+                Ranges::Corrupted => {
+                    let leaf =
+                        Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(&contents[1024..2048]));
+                    assert!(matches!(result, Err(Error::Corrupt(cid)) if cid == leaf));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offline_ranges() {
+        let (root, _, url, requests) = range_gateway(Ranges::Served).await;
+        let mut client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap())
+            .with_gateways(&[url], Duration::ZERO);
+        client.local = Arc::new(Stub {
+            block: None,
+            stall: false,
+        });
+        let root_block = client.get_block(&root).await.unwrap();
+        requests.lock().unwrap().clear();
+
+        // Only the root is held locally, and the leaves aren't asked of the gateway.
+        client.offline = true;
+        client.local = Arc::new(Blocks([(root, root_block)].into()));
+        let result = client
+            .get_file_range(&format!("/ipfs/{root}"), 1500, 1000)
+            .await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_unreachable() {
+        // Nothing listens on port 1.
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let client = Client::new(addr.clone());
+
+        let result = tokio::time::timeout(
+            HEALTH_CHECK_TIMEOUT + Duration::from_secs(1),
+            client.health_check(),
+        )
+        .await
+        .expect("health check hung");
+        assert!(matches!(result, Err(Error::Unreachable(a, _)) if a == addr));
+    }
+
+    #[test]
+    fn test_verify_block() {
+        let data = b"hello world";
+        let cid = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(data));
+        assert!(verify_block(&cid, data).is_ok());
+
+        let tampered = b"hello w0rld";
+        assert!(matches!(
+            verify_block(&cid, tampered),
+            Err(Error::Corrupt(c)) if c == cid
+        ));
+    }
+
+    #[test]
+    fn test_file_root_tsize() {
+        let leaf = Link {
+            cid: Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(b"leaf")),
+            name: String::new(),
+            tsize: 4,
+        };
+        let (inner, size) = file_root(&[(leaf.clone(), 4), (leaf, 4)]);
+        assert_eq!(size, 8);
+        let block = inner.encode();
+        let inner = Link {
+            cid: Cid::new_v1(unixfs::DAG_PB, Code::Sha2_256.digest(&block)),
+            name: String::new(),
+            tsize: inner.dag_size(),
+        };
+        assert_eq!(inner.tsize, block.len() as u64 + 8);
+
+        // Nodes record the size of the data under their links, and links that of the blocks.
+        let (root, size) = file_root(&[(inner.clone(), 8), (inner, 8)]);
+        assert_eq!(size, 16);
+        assert_eq!(root.blocksizes, [8, 8]);
+        assert_eq!(root.links[0].tsize, block.len() as u64 + 8);
+    }
 }
@@ -1,15 +1,151 @@
+pub mod access;
+pub mod bandwidth;
+pub mod car;
+pub mod chunker;
+pub mod dag;
+pub mod dht;
 pub mod dial;
+pub mod dns;
+pub mod gateway;
+pub mod hamt;
+pub mod handshake;
 pub mod ipfs;
+pub mod keep_alive;
+pub mod metrics;
+pub mod peerstore;
+pub mod pubsub;
+pub mod relay;
+pub mod swarm;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod unixfs;
 
 use core::ops::{Deref, DerefMut};
 
 use futures::stream::SelectNextSome;
 use futures::StreamExt;
-use libp2p::{swarm, Swarm};
+use libp2p::{
+    autonat, connection_limits, gossipsub, identify, identity, kad, mdns, ping, swarm as p2p_swarm,
+    Swarm,
+};
 
-pub struct DefaultSwarm(pub swarm::Swarm<DefaultBehaviour>);
+pub use bandwidth::Traffic;
+pub use peerstore::PeerInfo;
+pub use swarm::{PeerEvent, Reachability, SwarmConfig, SwarmService};
+
+// Kademlia configuration of the nodes, with provider records kept for four republish intervals.
+fn kad_config(config: &SwarmConfig) -> kad::Config {
+    let mut kad = kad::Config::new(KAD_PROTOCOL);
+    kad.set_provider_publication_interval(Some(config.provider_republish));
+    kad.set_provider_record_ttl(Some(config.provider_republish * 4));
+    kad
+}
+
+// Gossipsub of the node identified by id_keys, scoring peers if config says so.
+fn gossipsub(
+    id_keys: &identity::Keypair,
+    config: &SwarmConfig,
+) -> Result<pubsub::Gossipsub, swarm::Error> {
+    let message_id = config.message_id.clone();
+    let mut gossipsub = pubsub::Gossipsub::new_with_transform(
+        gossipsub::MessageAuthenticity::Signed(id_keys.clone()),
+        // Messages are forwarded once accepted by the validators of their topic. Unsigned
+        // messages get that far only on topics that take them, see pubsub::Signing.
+        gossipsub::ConfigBuilder::default()
+            .validate_messages()
+            .validation_mode(gossipsub::ValidationMode::Permissive)
+            .message_id_fn(move |message| message_id.id(message))
+            .build()
+            .map_err(|e| swarm::Error::Build(e.to_string()))?,
+        pubsub::SignedAuthors,
+    )
+    .map_err(|e| swarm::Error::Build(e.to_string()))?;
+    if let Some(scoring) = &config.peer_scoring {
+        gossipsub
+            .with_peer_score(scoring.params.clone(), scoring.thresholds.clone())
+            .map_err(swarm::Error::Build)?;
+    }
+    Ok(gossipsub)
+}
+
+// Protocol the nodes speak Kademlia over.
+pub const KAD_PROTOCOL: p2p_swarm::StreamProtocol = p2p_swarm::StreamProtocol::new("/ww");
+
+// Protocol of the streams the nodes serve Cap'n Proto RPC over.
+pub const RPC_PROTOCOL: p2p_swarm::StreamProtocol = p2p_swarm::StreamProtocol::new("/ww/rpc");
+
+// Agent version the nodes tell peers over identify.
+pub const AGENT_VERSION: &str = concat!("ww/", env!("CARGO_PKG_VERSION"));
+
+pub struct DefaultSwarm(pub p2p_swarm::Swarm<DefaultBehaviour>);
 
 impl DefaultSwarm {
+    // Swarm of the node identified by id_keys, running the default behaviours over the
+    // transports enabled in config, and counting the bytes of its connections in bandwidth.
+    pub fn new(
+        id_keys: identity::Keypair,
+        config: &SwarmConfig,
+        bandwidth: bandwidth::Bandwidth,
+    ) -> Result<Self, swarm::Error> {
+        let peer_id = id_keys.public().to_peer_id();
+        // Without mDNS, the behaviour is a no-op that doesn't even open a socket.
+        let mdns = match config.mdns {
+            true => Some(
+                mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)
+                    .map_err(|e| swarm::Error::Build(e.to_string()))?,
+            ),
+            false => None,
+        };
+        let (relayed, relay) = libp2p::relay::client::new(peer_id);
+        // Relays are pinned, so reservations don't go away with idle connections.
+        let relays = config
+            .relays
+            .iter()
+            .filter_map(|addr| match addr.iter().last() {
+                Some(libp2p::multiaddr::Protocol::P2p(peer)) => Some(peer),
+                _ => None,
+            });
+        let pinned = config.pinned.iter().copied().chain(relays).collect();
+        let behaviour = DefaultBehaviour {
+            access: access::Behaviour::new(config.access.clone()),
+            keep_alive: keep_alive::Behaviour::new(pinned),
+            mdns: mdns.into(),
+            ping: ping::Behaviour::default(),
+            // TODO custom protocol name, cfg
+            kad: kad::Behaviour::with_config(
+                peer_id,
+                kad::store::MemoryStore::new(peer_id),
+                kad_config(config),
+            ),
+            identify: identify::Behaviour::new(
+                identify::Config::new(config.identify_protocol.clone(), id_keys.public())
+                    .with_agent_version(AGENT_VERSION.to_owned())
+                    // Tells peers about circuit addresses as soon as reservations are made.
+                    .with_push_listen_addr_updates(true),
+            ),
+            autonat: autonat::Behaviour::new(peer_id, config.autonat),
+            relay,
+            gossipsub: gossipsub(&id_keys, config)?,
+            streams: libp2p::stream::Behaviour::new(),
+            limits: connection_limits::Behaviour::new(
+                connection_limits::ConnectionLimits::default()
+                    .with_max_pending_incoming(Some(config.max_pending_inbound))
+                    .with_max_pending_outgoing(Some(config.max_pending_outbound)),
+            ),
+        };
+
+        let transport = swarm::transport(&id_keys, config, relayed, bandwidth)?;
+        let swarm = libp2p::SwarmBuilder::with_existing_identity(id_keys)
+            .with_tokio()
+            .with_other_transport(|_| transport)
+            .map_err(|e| swarm::Error::Build(e.to_string()))?
+            .with_behaviour(|_| behaviour)
+            .map_err(|e| swarm::Error::Build(e.to_string()))?
+            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(config.idle_timeout))
+            .build();
+        Ok(Self(swarm))
+    }
+
     // Forward tge call to the inner Swarm.
     pub fn select_next_some(&mut self) -> SelectNextSome<'_, Swarm<DefaultBehaviour>> {
         self.0.select_next_some()
@@ -18,7 +154,7 @@ impl DefaultSwarm {
 
 // Required to use DefaultSwarm as Swarm in our modules.
 impl Deref for DefaultSwarm {
-    type Target = swarm::Swarm<DefaultBehaviour>;
+    type Target = p2p_swarm::Swarm<DefaultBehaviour>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -34,18 +170,26 @@ impl DerefMut for DefaultSwarm {
 
 impl dial::Dialer for DefaultSwarm {
     // Forward the call to the inner Swarm.
-    fn dial(&mut self, opts: swarm::dial_opts::DialOpts) -> Result<(), swarm::DialError> {
+    fn dial(&mut self, opts: p2p_swarm::dial_opts::DialOpts) -> Result<(), p2p_swarm::DialError> {
         self.0.dial(opts)
     }
 }
 
-#[derive(swarm::NetworkBehaviour)]
+#[derive(p2p_swarm::NetworkBehaviour)]
 #[behaviour(to_swarm = "DefaultBehaviourEvent")]
 pub struct DefaultBehaviour {
-    pub mdns: libp2p::mdns::tokio::Behaviour,
+    // First, so that refused connections are closed before the others set anything up.
+    pub access: access::Behaviour,
+    pub keep_alive: keep_alive::Behaviour,
+    pub mdns: p2p_swarm::behaviour::toggle::Toggle<libp2p::mdns::tokio::Behaviour>,
     pub ping: libp2p::ping::Behaviour,
     pub kad: libp2p::kad::Behaviour<libp2p::kad::store::MemoryStore>,
     pub identify: libp2p::identify::Behaviour,
+    pub autonat: libp2p::autonat::Behaviour,
+    pub relay: libp2p::relay::client::Behaviour,
+    pub gossipsub: pubsub::Gossipsub,
+    pub streams: libp2p::stream::Behaviour,
+    pub limits: libp2p::connection_limits::Behaviour,
 }
 
 // Events explicitly managed or intercepted by the DefaultBehaviour.
@@ -55,6 +199,9 @@ pub enum DefaultBehaviourEvent {
     Ping(libp2p::ping::Event),
     Kad(libp2p::kad::Event),
     Identify(libp2p::identify::Event),
+    Autonat(libp2p::autonat::Event),
+    Relay(libp2p::relay::client::Event),
+    Gossipsub(libp2p::gossipsub::Event),
 }
 
 impl From<libp2p::mdns::Event> for DefaultBehaviourEvent {
@@ -80,3 +227,35 @@ impl From<libp2p::identify::Event> for DefaultBehaviourEvent {
         DefaultBehaviourEvent::Identify(event)
     }
 }
+
+impl From<libp2p::autonat::Event> for DefaultBehaviourEvent {
+    fn from(event: libp2p::autonat::Event) -> Self {
+        DefaultBehaviourEvent::Autonat(event)
+    }
+}
+
+impl From<libp2p::relay::client::Event> for DefaultBehaviourEvent {
+    fn from(event: libp2p::relay::client::Event) -> Self {
+        DefaultBehaviourEvent::Relay(event)
+    }
+}
+
+impl From<libp2p::gossipsub::Event> for DefaultBehaviourEvent {
+    fn from(event: libp2p::gossipsub::Event) -> Self {
+        DefaultBehaviourEvent::Gossipsub(event)
+    }
+}
+
+// Streams are handed out through their control, never as events.
+impl From<()> for DefaultBehaviourEvent {
+    fn from(_: ()) -> Self {
+        unreachable!("streams emit no events")
+    }
+}
+
+// Access control, keep-alive and connection limits never emit events.
+impl From<std::convert::Infallible> for DefaultBehaviourEvent {
+    fn from(event: std::convert::Infallible) -> Self {
+        match event {}
+    }
+}
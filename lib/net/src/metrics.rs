@@ -0,0 +1,193 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use libp2p::PeerId;
+use prometheus_client::encoding::{text, EncodeLabelSet};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+const CONTENT_TYPE_OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Labels of the pubsub messages counted by topic, "in" for received and "out" for published.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TopicLabels {
+    pub topic: String,
+    pub direction: &'static str,
+}
+
+// Labels of the bytes counted by peer and the protocol negotiated on the streams they went
+// over, "in" for read from the streams and "out" for written to them.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct PeerBytesLabels {
+    pub peer: String,
+    pub protocol: String,
+    pub direction: &'static str,
+}
+
+// Metrics of the node, shared by the subsystems they are handed to. Recording one is an atomic
+// update, and subsystems without metrics record nothing, so they cost nothing unless served.
+// Names are prefixed with "ww_", and the cache hit ratio is hits / (hits + misses).
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    pub connected_peers: Gauge,
+    pub pubsub_messages: Family<TopicLabels, Counter>,
+    pub peer_bytes: Family<PeerBytesLabels, Counter>,
+    pub ipfs_fetched_bytes: Counter,
+    pub cache_hits: Counter,
+    pub cache_misses: Counter,
+    pub wasm_instances: Gauge,
+    pub rpc_call_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("ww");
+        let connected_peers = Gauge::default();
+        registry.register(
+            "connected_peers",
+            "Peers with an established connection",
+            connected_peers.clone(),
+        );
+        let pubsub_messages = Family::<TopicLabels, Counter>::default();
+        registry.register(
+            "pubsub_messages",
+            "Pubsub messages received and published, by topic",
+            pubsub_messages.clone(),
+        );
+        let peer_bytes = Family::<PeerBytesLabels, Counter>::default();
+        registry.register(
+            "peer_bytes",
+            "Bytes carried over the streams of connections, by peer and stream protocol",
+            peer_bytes.clone(),
+        );
+        let ipfs_fetched_bytes = Counter::default();
+        registry.register(
+            "ipfs_fetched_bytes",
+            "Bytes of the blocks fetched from IPFS",
+            ipfs_fetched_bytes.clone(),
+        );
+        let cache_hits = Counter::default();
+        registry.register(
+            "cache_hits",
+            "Blocks found in the block cache",
+            cache_hits.clone(),
+        );
+        let cache_misses = Counter::default();
+        registry.register(
+            "cache_misses",
+            "Blocks missing from the block cache",
+            cache_misses.clone(),
+        );
+        let wasm_instances = Gauge::default();
+        registry.register(
+            "wasm_instances",
+            "WASM instances running",
+            wasm_instances.clone(),
+        );
+        // From 1ms to about 30s.
+        let rpc_call_duration = Histogram::new(exponential_buckets(0.001, 2.0, 16));
+        registry.register(
+            "rpc_call_duration_seconds",
+            "Time RPC calls took to return",
+            rpc_call_duration.clone(),
+        );
+        Self {
+            registry: Arc::new(registry),
+            connected_peers,
+            pubsub_messages,
+            peer_bytes,
+            ipfs_fetched_bytes,
+            cache_hits,
+            cache_misses,
+            wasm_instances,
+            rpc_call_duration,
+        }
+    }
+
+    pub fn pubsub_message(&self, topic: &str, direction: &'static str) {
+        let labels = TopicLabels {
+            topic: topic.to_owned(),
+            direction,
+        };
+        self.pubsub_messages.get_or_create(&labels).inc();
+    }
+
+    // Counter of the bytes carried in direction over connections to peer through protocol, to
+    // be updated directly from then on.
+    pub fn peer_bytes(&self, peer: &PeerId, protocol: &str, direction: &'static str) -> Counter {
+        let labels = PeerBytesLabels {
+            peer: peer.to_string(),
+            protocol: protocol.to_owned(),
+            direction,
+        };
+        self.peer_bytes.get_or_create(&labels).clone()
+    }
+
+    // Stop serving the bytes counted for peer through protocol, once the peer is gone.
+    pub fn remove_peer_bytes(&self, peer: &PeerId, protocol: &str) {
+        for direction in ["in", "out"] {
+            let labels = PeerBytesLabels {
+                peer: peer.to_string(),
+                protocol: protocol.to_owned(),
+                direction,
+            };
+            self.peer_bytes.remove(&labels);
+        }
+    }
+
+    // The metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        // Writing to a String doesn't fail.
+        text::encode(&mut out, &self.registry).unwrap();
+        out
+    }
+}
+
+// Serve metrics over HTTP at /metrics on addr, in the background. Returns the address actually
+// bound, which differs from addr when its port is 0.
+pub fn serve(addr: SocketAddr, metrics: Metrics) -> Result<SocketAddr, hyper::Error> {
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&metrics, request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    }));
+    let addr = server.local_addr();
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::warn!("metrics server failed: {e}");
+        }
+    });
+    Ok(addr)
+}
+
+fn respond(metrics: &Metrics, request: Request<Body>) -> Response<Body> {
+    let mut response = Response::default();
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let content_type = CONTENT_TYPE_OPENMETRICS.parse().unwrap();
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+            *response.body_mut() = Body::from(metrics.encode());
+        }
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+    response
+}
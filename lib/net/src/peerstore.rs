@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libp2p::{identify, Multiaddr, PeerId};
+
+// Addresses of the peers the node has seen, kept across restarts to seed dialing. Peers not seen
+// for longer than the staleness window are forgotten.
+#[derive(Debug)]
+pub struct Peerstore {
+    staleness: Duration,
+    peers: HashMap<PeerId, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    addrs: Vec<Multiaddr>,
+    // Last time the peer was seen, in seconds since the Unix epoch.
+    seen: u64,
+    // What the peer last told about itself over identify, only known while the node runs.
+    info: Option<PeerInfo>,
+}
+
+// What a peer tells about itself over the identify protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    // Protocols the peer supports, e.g. "/ww" for Kademlia.
+    pub protocols: Vec<String>,
+    pub agent_version: String,
+    pub protocol_version: String,
+    pub listen_addrs: Vec<Multiaddr>,
+    // Address the peer saw the node connect from, which may be how other nodes can reach it.
+    pub observed_addr: Multiaddr,
+}
+
+impl From<identify::Info> for PeerInfo {
+    fn from(info: identify::Info) -> Self {
+        Self {
+            protocols: info.protocols.iter().map(ToString::to_string).collect(),
+            agent_version: info.agent_version,
+            protocol_version: info.protocol_version,
+            listen_addrs: info.listen_addrs,
+            observed_addr: info.observed_addr,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Peerstore {
+    pub fn new(staleness: Duration) -> Self {
+        Self {
+            staleness,
+            peers: HashMap::new(),
+        }
+    }
+
+    // Load the store saved at path, dropping the peers that went stale since. A missing file
+    // holds no peers.
+    pub fn load(path: &Path, staleness: Duration) -> io::Result<Self> {
+        let mut store = Self::new(staleness);
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            let (peer, entry) = parse_line(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad peerstore line {line}"),
+                )
+            })?;
+            store.peers.insert(peer, entry);
+        }
+        store.prune();
+        Ok(store)
+    }
+
+    // Save the fresh peers to path, replacing what was there.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut data = String::new();
+        for (peer, entry) in self.fresh() {
+            data.push_str(&format!("{peer} {}", entry.seen));
+            for addr in &entry.addrs {
+                data.push_str(&format!(" {addr}"));
+            }
+            data.push('\n');
+        }
+        // Write next to the file and rename, so a crash doesn't leave half a store behind.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+
+    // Record that peer was seen at addr just now.
+    pub fn record(&mut self, peer: PeerId, addr: Multiaddr) {
+        self.record_at(peer, addr, now());
+    }
+
+    fn record_at(&mut self, peer: PeerId, addr: Multiaddr, seen: u64) {
+        let entry = self.peers.entry(peer).or_insert(Entry {
+            addrs: Vec::new(),
+            seen,
+            info: None,
+        });
+        entry.seen = entry.seen.max(seen);
+        if !entry.addrs.contains(&addr) {
+            entry.addrs.push(addr);
+        }
+    }
+
+    // Record what peer told about itself just now, along with the addresses it listens on.
+    pub fn identify(&mut self, peer: PeerId, info: PeerInfo) {
+        for addr in &info.listen_addrs {
+            self.record(peer, addr.clone());
+        }
+        let entry = self.peers.entry(peer).or_insert(Entry {
+            addrs: Vec::new(),
+            seen: now(),
+            info: None,
+        });
+        entry.info = Some(info);
+    }
+
+    // What peer last told about itself, if it did since the node started and isn't stale.
+    pub fn info(&self, peer: &PeerId) -> Option<PeerInfo> {
+        self.peers
+            .get(peer)
+            .filter(|entry| self.is_fresh(entry))
+            .and_then(|entry| entry.info.clone())
+    }
+
+    // Addresses peer was seen at, if it isn't stale.
+    pub fn addrs(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        match self.peers.get(peer) {
+            Some(entry) if self.is_fresh(entry) => entry.addrs.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Peers that aren't stale, with their addresses, to dial.
+    pub fn peers(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.fresh()
+            .map(|(peer, entry)| (*peer, entry.addrs.clone()))
+            .collect()
+    }
+
+    // Forget the stale peers.
+    pub fn prune(&mut self) {
+        let cutoff = now().saturating_sub(self.staleness.as_secs());
+        self.peers.retain(|_, entry| entry.seen >= cutoff);
+    }
+
+    fn is_fresh(&self, entry: &Entry) -> bool {
+        entry.seen >= now().saturating_sub(self.staleness.as_secs())
+    }
+
+    fn fresh(&self) -> impl Iterator<Item = (&PeerId, &Entry)> {
+        self.peers.iter().filter(|(_, entry)| self.is_fresh(entry))
+    }
+}
+
+// Parse a "<peer> <seen> <addr>..." line of a saved store.
+fn parse_line(line: &str) -> Option<(PeerId, Entry)> {
+    let mut fields = line.split(' ');
+    let peer = fields.next()?.parse().ok()?;
+    let seen = fields.next()?.parse().ok()?;
+    let addrs = fields
+        .map(|addr| addr.parse().ok())
+        .collect::<Option<_>>()?;
+    let info = None;
+    Some((peer, Entry { addrs, seen, info }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ww-peerstore-{:x}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = temp_path();
+        let (fresh, stale) = (PeerId::random(), PeerId::random());
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+
+        let mut store = Peerstore::new(DAY);
+        store.record(fresh, addr.clone());
+        store.record_at(stale, addr.clone(), now() - 2 * DAY.as_secs());
+        store.save(&path).unwrap();
+
+        let loaded = Peerstore::load(&path, DAY).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.addrs(&fresh), vec![addr.clone()]);
+        assert!(loaded.addrs(&stale).is_empty());
+        assert_eq!(loaded.peers(), vec![(fresh, vec![addr])]);
+    }
+
+    #[test]
+    fn test_identify() {
+        let peer = PeerId::random();
+        let listen: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let info = PeerInfo {
+            protocols: vec!["/ww".to_owned()],
+            agent_version: "ww/0.1.0".to_owned(),
+            protocol_version: "/ww/identify/0.0.1".to_owned(),
+            listen_addrs: vec![listen.clone()],
+            observed_addr: "/ip4/10.0.0.2/tcp/50000".parse().unwrap(),
+        };
+
+        let mut store = Peerstore::new(DAY);
+        assert_eq!(store.info(&peer), None);
+        store.identify(peer, info.clone());
+        assert_eq!(store.info(&peer), Some(info));
+        assert_eq!(store.addrs(&peer), vec![listen]);
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let path = temp_path();
+        assert!(Peerstore::load(&path, DAY).unwrap().peers().is_empty());
+    }
+}
@@ -0,0 +1,882 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use libp2p::gossipsub::{self, IdentTopic, MessageAcceptance, MessageId, TopicHash};
+use libp2p::PeerId;
+use multihash_codetable::{Code, MultihashDigest};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::metrics::Metrics;
+use crate::swarm::{Command, DEFAULT_DEDUP_CAPACITY, DEFAULT_DEDUP_WINDOW};
+
+// How many messages a subscription holds before the subscriber starts missing some.
+const SUBSCRIPTION_CAPACITY: usize = 64;
+
+// Identifies subscriptions, so that dropping one leaves the others to the same topic alone.
+static NEXT_SUBSCRIPTION: AtomicU64 = AtomicU64::new(0);
+
+// Prefix of the topics requests are answered on, followed by an ID of their own.
+const REPLY_TOPIC_PREFIX: &str = "/ww/reply/";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    // Subscribing to the topic failed.
+    Subscribe(String),
+    // No peer is subscribed to the topic, so a message would go nowhere.
+    NoPeers,
+    // Publishing failed.
+    Publish(String),
+    // The subscriber fell behind and missed that many messages.
+    Lagged(u64),
+    // No reply came in time.
+    Timeout,
+    // The swarm is shut down.
+    Stopped,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Subscribe(msg) => write!(f, "subscribing: {msg}"),
+            Error::NoPeers => write!(f, "no peer subscribed to the topic"),
+            Error::Publish(msg) => write!(f, "publishing: {msg}"),
+            Error::Lagged(n) => write!(f, "subscriber missed {n} messages"),
+            Error::Timeout => write!(f, "request timed out"),
+            Error::Stopped => write!(f, "swarm stopped"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Message received on a topic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    // Peer that published the message.
+    pub source: PeerId,
+    pub topic: String,
+    pub data: Vec<u8>,
+}
+
+// Request published by Pubsub::request, to be answered with Pubsub::reply. On the wire, the ID
+// and the length of the reply topic as big-endian u64 and u16, then the reply topic and the
+// payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    // Correlates the request with its replies.
+    pub id: u64,
+    // Topic the requester listens for replies on.
+    pub reply_topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let topic = self.reply_topic.as_bytes();
+        let mut data = Vec::with_capacity(10 + topic.len() + self.payload.len());
+        data.extend(self.id.to_be_bytes());
+        data.extend((topic.len() as u16).to_be_bytes());
+        data.extend(topic);
+        data.extend(&self.payload);
+        data
+    }
+
+    // Request data holds, if it holds one.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let (id, rest) = data.split_first_chunk::<8>()?;
+        let (len, rest) = rest.split_first_chunk::<2>()?;
+        let len = u16::from_be_bytes(*len) as usize;
+        if rest.len() < len {
+            return None;
+        }
+        let (topic, payload) = rest.split_at(len);
+        Some(Self {
+            id: u64::from_be_bytes(*id),
+            reply_topic: String::from_utf8(topic.to_vec()).ok()?,
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+// Payload of the reply data holds to the request id, if it holds one. On the wire, the ID as a
+// big-endian u64, then the payload.
+fn reply_payload(id: u64, data: &[u8]) -> Option<&[u8]> {
+    let (reply_id, payload) = data.split_first_chunk::<8>()?;
+    (u64::from_be_bytes(*reply_id) == id).then_some(payload)
+}
+
+// What becomes of a message once validated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationResult {
+    // Delivered to local subscribers and forwarded to other peers.
+    Accept,
+    // Dropped, and held against the peer it came from.
+    Reject,
+    // Dropped.
+    Ignore,
+}
+
+impl From<ValidationResult> for MessageAcceptance {
+    fn from(result: ValidationResult) -> Self {
+        match result {
+            ValidationResult::Accept => MessageAcceptance::Accept,
+            ValidationResult::Reject => MessageAcceptance::Reject,
+            ValidationResult::Ignore => MessageAcceptance::Ignore,
+        }
+    }
+}
+
+// State of a topic, as seen by the node.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopicInfo {
+    // Peers the node exchanges all the messages of the topic with.
+    pub mesh_peers: Vec<PeerId>,
+    // Peers subscribed to the topic, in the mesh or not.
+    pub all_peers: Vec<PeerId>,
+    // Messages received on the topic, valid or not.
+    pub received: u64,
+    // Messages the node published on the topic.
+    pub published: u64,
+}
+
+// Whether the messages of a topic must be signed by their author.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Signing {
+    // Messages without a signature are rejected, while messages with one are delivered only if
+    // it matches their author. Every topic is strict unless set otherwise.
+    #[default]
+    Strict,
+    // Messages without a signature are delivered too, for topics legacy peers publish on
+    // unsigned. They are taken to come from the peer that sent them, whoever they claim.
+    Permissive,
+}
+
+// How gossipsub scores peers, with penalties for the messages the validators reject and rewards
+// for the messages peers are first to deliver. Peers scoring below zero are pruned from the mesh
+// of a topic, and below the thresholds they are no longer gossiped with, published to, and at
+// last listened to.
+#[derive(Clone, Debug)]
+pub struct Scoring {
+    pub params: gossipsub::PeerScoreParams,
+    pub thresholds: gossipsub::PeerScoreThresholds,
+    // Scores the peers of each topic the node subscribes to that params has no scores for.
+    pub topic: gossipsub::TopicScoreParams,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Self {
+            params: gossipsub::PeerScoreParams::default(),
+            thresholds: gossipsub::PeerScoreThresholds::default(),
+            topic: gossipsub::TopicScoreParams {
+                // Time in the mesh earns a little, a second at a time, so a few invalid messages
+                // outweigh an hour of it.
+                time_in_mesh_weight: 0.01,
+                time_in_mesh_quantum: Duration::from_secs(1),
+                invalid_message_deliveries_weight: -10.0,
+                // Quiet topics aren't held against the peers of their mesh.
+                mesh_message_deliveries_weight: 0.0,
+                mesh_failure_penalty_weight: 0.0,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+// Gossipsub, telling who authored a message only when it is signed.
+pub type Gossipsub = gossipsub::Behaviour<SignedAuthors>;
+
+// Leaves the data of messages untouched, but drops the author of a received message unless it
+// is signed. Gossipsub has checked the signature by then, while it lets unsigned messages claim
+// any author.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SignedAuthors;
+
+impl gossipsub::DataTransform for SignedAuthors {
+    fn inbound_transform(
+        &self,
+        raw: gossipsub::RawMessage,
+    ) -> Result<gossipsub::Message, io::Error> {
+        Ok(gossipsub::Message {
+            source: raw.source.filter(|_| raw.signature.is_some()),
+            data: raw.data,
+            sequence_number: raw.sequence_number,
+            topic: raw.topic,
+        })
+    }
+
+    fn outbound_transform(&self, _: &TopicHash, data: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        Ok(data)
+    }
+}
+
+// Identifies the messages of every topic, so that the copies of a message are known for what
+// they are. Every node of a topic must identify its messages the same way.
+#[derive(Clone)]
+pub struct MessageIdFn(Arc<dyn Fn(&gossipsub::Message) -> MessageId + Send + Sync>);
+
+impl MessageIdFn {
+    pub fn new(id: impl Fn(&gossipsub::Message) -> MessageId + Send + Sync + 'static) -> Self {
+        Self(Arc::new(id))
+    }
+
+    pub fn id(&self, message: &gossipsub::Message) -> MessageId {
+        (self.0)(message)
+    }
+}
+
+// SHA-256 of the author and sequence number of the message, or of its topic and data if it
+// lacks either.
+impl Default for MessageIdFn {
+    fn default() -> Self {
+        Self::new(|message| {
+            let mut bytes = Vec::new();
+            match (message.source, message.sequence_number) {
+                (Some(source), Some(seqno)) => {
+                    bytes.extend(source.to_bytes());
+                    bytes.extend(seqno.to_be_bytes());
+                }
+                _ => {
+                    bytes.extend(message.topic.as_str().as_bytes());
+                    bytes.extend(&message.data);
+                }
+            }
+            MessageId::new(Code::Sha2_256.digest(&bytes).digest())
+        })
+    }
+}
+
+impl fmt::Debug for MessageIdFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("MessageIdFn").finish_non_exhaustive()
+    }
+}
+
+pub(crate) type Validator = Arc<dyn Fn(&Message) -> ValidationResult + Send + Sync>;
+
+// Publishes and subscribes to topics through the swarm of a SwarmService.
+#[derive(Clone)]
+pub struct Pubsub {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Pubsub {
+    pub(crate) fn new(commands: mpsc::UnboundedSender<Command>) -> Self {
+        Self { commands }
+    }
+
+    // Publish data on topic.
+    pub async fn publish(&self, topic: &str, data: impl Into<Vec<u8>>) -> Result<(), Error> {
+        let (reply, result) = oneshot::channel();
+        let publish = Command::Publish {
+            topic: topic.to_owned(),
+            data: data.into(),
+            reply,
+        };
+        if self.commands.send(publish).is_err() {
+            return Err(Error::Stopped);
+        }
+        result.await.unwrap_or(Err(Error::Stopped))
+    }
+
+    // Publish a request with payload on topic, and wait for the first reply to it for up to
+    // timeout. The reply comes on a topic of its own, subscribed to until then. The subscription
+    // reaches the peers of the node before the request does, so peers answering right away
+    // know where to.
+    pub async fn request(
+        &self,
+        topic: &str,
+        payload: impl Into<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<Message, Error> {
+        let id = rand::random::<u64>();
+        let request = Request {
+            id,
+            reply_topic: format!("{REPLY_TOPIC_PREFIX}{:016x}", rand::random::<u64>()),
+            payload: payload.into(),
+        };
+        let mut replies = self.subscribe(&request.reply_topic);
+        let reply = async {
+            self.publish(topic, request.encode()).await?;
+            while let Some(message) = replies.next().await {
+                // Having missed some messages, the reply may still be to come.
+                let Ok(mut message) = message else {
+                    continue;
+                };
+                if let Some(payload) = reply_payload(id, &message.data) {
+                    message.data = payload.to_vec();
+                    return Ok(message);
+                }
+            }
+            Err(Error::Stopped)
+        };
+        match tokio::time::timeout(timeout, reply).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    // Answer request with payload.
+    pub async fn reply(&self, request: &Request, payload: impl Into<Vec<u8>>) -> Result<(), Error> {
+        let mut data = request.id.to_be_bytes().to_vec();
+        data.extend(payload.into());
+        self.publish(&request.reply_topic, data).await
+    }
+
+    // Score gossipsub gives peer, None if peers aren't scored or peer is unknown.
+    pub async fn peer_score(&self, peer: PeerId) -> Option<f64> {
+        let (reply, score) = oneshot::channel();
+        let command = Command::PeerScore { peer, reply };
+        if self.commands.send(command).is_err() {
+            return None;
+        }
+        score.await.ok().flatten()
+    }
+
+    // State of topic. Empty once the swarm is shut down.
+    pub async fn info(&self, topic: &str) -> TopicInfo {
+        let (reply, info) = oneshot::channel();
+        let command = Command::TopicInfo {
+            topic: topic.to_owned(),
+            reply,
+        };
+        if self.commands.send(command).is_err() {
+            return TopicInfo::default();
+        }
+        info.await.unwrap_or_default()
+    }
+
+    // Peers the node exchanges all the messages of topic with.
+    pub async fn mesh_peers(&self, topic: &str) -> Vec<PeerId> {
+        self.info(topic).await.mesh_peers
+    }
+
+    // Peers subscribed to topic.
+    pub async fn all_peers(&self, topic: &str) -> Vec<PeerId> {
+        self.info(topic).await.all_peers
+    }
+
+    // Check the messages received on topic with validator before they are delivered or
+    // forwarded, in place of any validator set before. Validators run on a blocking thread, one
+    // message at a time per topic so that messages keep their order.
+    pub fn set_validator(
+        &self,
+        topic: &str,
+        validator: impl Fn(&Message) -> ValidationResult + Send + Sync + 'static,
+    ) {
+        let set = Command::SetValidator {
+            topic: topic.to_owned(),
+            validator: Arc::new(validator),
+        };
+        let _ = self.commands.send(set);
+    }
+
+    // Require the messages received on topic to be signed, or not. Messages the node publishes
+    // are signed either way. Unsigned messages on a strict topic are rejected before they reach
+    // the validator of the topic.
+    pub fn set_signing(&self, topic: &str, signing: Signing) {
+        let set = Command::SetSigning {
+            topic: topic.to_owned(),
+            signing,
+        };
+        let _ = self.commands.send(set);
+    }
+
+    // Stream of the messages other peers publish on topic, in the order they arrive. A subscriber
+    // that falls too far behind gets Lagged in place of the messages it missed, rather than
+    // holding back the swarm. The stream ends when the swarm is shut down, and dropping it
+    // unsubscribes.
+    pub fn subscribe(&self, topic: &str) -> Subscription {
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        let id = NEXT_SUBSCRIPTION.fetch_add(1, Ordering::Relaxed);
+        let subscribe = Command::Subscribe {
+            topic: topic.to_owned(),
+            id,
+            sender,
+        };
+        // If the swarm is shut down, the sender is dropped and the stream ends right away.
+        let _ = self.commands.send(subscribe);
+        Subscription {
+            topic: topic.to_owned(),
+            id,
+            commands: self.commands.clone(),
+            receiver,
+        }
+    }
+}
+
+// Messages of a topic, until dropped.
+pub struct Subscription {
+    topic: String,
+    id: u64,
+    commands: mpsc::UnboundedSender<Command>,
+    receiver: mpsc::Receiver<Result<Message, Error>>,
+}
+
+impl Stream for Subscription {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let unsubscribe = Command::Unsubscribe {
+            topic: std::mem::take(&mut self.topic),
+            id: self.id,
+        };
+        let _ = self.commands.send(unsubscribe);
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    sender: mpsc::Sender<Result<Message, Error>>,
+    // Messages missed since the subscriber was last told.
+    missed: u64,
+}
+
+impl Subscriber {
+    fn deliver(&mut self, message: Message) {
+        if self.missed > 0 {
+            if self
+                .sender
+                .try_send(Err(Error::Lagged(self.missed)))
+                .is_err()
+            {
+                self.missed += 1;
+                return;
+            }
+            self.missed = 0;
+        }
+        if self.sender.try_send(Ok(message)).is_err() {
+            self.missed += 1;
+        }
+    }
+}
+
+// Message waiting on its validation result.
+pub(crate) struct Validation {
+    id: MessageId,
+    source: PeerId,
+    message: Message,
+    result: ValidationResult,
+}
+
+// IDs of the messages delivered lately, so that a copy arriving within window of the first is
+// dropped. The oldest are forgotten early once there are capacity of them, so bursts don't make
+// it grow without bound.
+struct Seen {
+    window: Duration,
+    capacity: usize,
+    ids: HashSet<MessageId>,
+    // When each ID was first seen, oldest first.
+    order: VecDeque<(Instant, MessageId)>,
+}
+
+impl Seen {
+    fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Remember id as seen at now, false if it was seen within the window already.
+    fn insert(&mut self, id: &MessageId, now: Instant) -> bool {
+        while let Some((at, old)) = self.order.front() {
+            if now.duration_since(*at) < self.window && self.order.len() < self.capacity {
+                break;
+            }
+            self.ids.remove(old);
+            self.order.pop_front();
+        }
+        // With no room at all, nothing is remembered.
+        if self.capacity == 0 {
+            return true;
+        }
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back((now, id.clone()));
+        true
+    }
+}
+
+// Validate the messages of a topic in turn, until the topic gets another validator.
+async fn validate(
+    validator: Validator,
+    mut pending: mpsc::UnboundedReceiver<Validation>,
+    validated: mpsc::UnboundedSender<Validation>,
+) {
+    while let Some(mut validation) = pending.recv().await {
+        let validator = validator.clone();
+        let check = tokio::task::spawn_blocking(move || {
+            validation.result = validator(&validation.message);
+            validation
+        });
+        // A validator that panics takes the message it was checking with it.
+        if let Ok(validation) = check.await {
+            let _ = validated.send(validation);
+        }
+    }
+}
+
+// Local subscribers of the topics the swarm is subscribed to, and the validators of the topics.
+pub(crate) struct Topics {
+    subscribers: HashMap<TopicHash, Vec<Subscriber>>,
+    // Messages received and published, by topic.
+    counts: HashMap<TopicHash, (u64, u64)>,
+    validators: HashMap<TopicHash, mpsc::UnboundedSender<Validation>>,
+    // Topics taking unsigned messages.
+    permissive: HashSet<TopicHash>,
+    // Messages delivered lately.
+    seen: Seen,
+    // How peers are scored on the topics subscribed to, if they are.
+    scoring: Option<Scoring>,
+    validated: (
+        mpsc::UnboundedSender<Validation>,
+        mpsc::UnboundedReceiver<Validation>,
+    ),
+    metrics: Option<Metrics>,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Self {
+            subscribers: HashMap::new(),
+            counts: HashMap::new(),
+            validators: HashMap::new(),
+            permissive: HashSet::new(),
+            seen: Seen::new(DEFAULT_DEDUP_WINDOW, DEFAULT_DEDUP_CAPACITY),
+            scoring: None,
+            validated: mpsc::unbounded_channel(),
+            metrics: None,
+        }
+    }
+}
+
+impl Topics {
+    // Count the messages of each topic in metrics too, if set.
+    pub fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // Drop the copies of a delivered message that arrive within window of it, remembering no
+    // more than capacity messages at once.
+    pub fn with_dedup(mut self, window: Duration, capacity: usize) -> Self {
+        self.seen = Seen::new(window, capacity);
+        self
+    }
+
+    // Score the peers of the topics subscribed to with scoring, which gossipsub scores with.
+    pub fn with_scoring(mut self, scoring: Option<Scoring>) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    pub fn set_validator(&mut self, topic: String, validator: Validator) {
+        let (pending, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(validate(validator, receiver, self.validated.0.clone()));
+        self.validators
+            .insert(IdentTopic::new(topic).hash(), pending);
+    }
+
+    pub fn set_signing(&mut self, topic: String, signing: Signing) {
+        let topic = IdentTopic::new(topic).hash();
+        match signing {
+            Signing::Strict => self.permissive.remove(&topic),
+            Signing::Permissive => self.permissive.insert(topic),
+        };
+    }
+
+    // Next message done validating.
+    pub async fn validated(&mut self) -> Validation {
+        // Never None, as the sender is kept in self.
+        self.validated.1.recv().await.unwrap()
+    }
+
+    // Report the result of the validation of the message to gossipsub, and deliver it if it is
+    // accepted and no copy of it was delivered lately.
+    pub fn on_validated(&mut self, gossipsub: &mut Gossipsub, validation: Validation) {
+        let Validation {
+            id,
+            source,
+            message,
+            result,
+        } = validation;
+        gossipsub.report_message_validation_result(&id, &source, result.into());
+        if result == ValidationResult::Accept && self.seen.insert(&id, Instant::now()) {
+            self.deliver(message);
+        }
+    }
+
+    fn deliver(&mut self, message: Message) {
+        let topic = IdentTopic::new(message.topic.clone()).hash();
+        for subscriber in self.subscribers.get_mut(&topic).into_iter().flatten() {
+            subscriber.deliver(message.clone());
+        }
+    }
+
+    pub fn publish(
+        &mut self,
+        gossipsub: &mut Gossipsub,
+        topic: String,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        let topic = IdentTopic::new(topic);
+        match gossipsub.publish(topic.clone(), data) {
+            Ok(_) => {
+                self.counts.entry(topic.hash()).or_default().1 += 1;
+                if let Some(metrics) = &self.metrics {
+                    metrics.pubsub_message(topic.hash().as_str(), "out");
+                }
+                Ok(())
+            }
+            Err(gossipsub::PublishError::InsufficientPeers) => Err(Error::NoPeers),
+            Err(e) => Err(Error::Publish(e.to_string())),
+        }
+    }
+
+    pub fn subscribe(
+        &mut self,
+        gossipsub: &mut Gossipsub,
+        topic: String,
+        id: u64,
+        sender: mpsc::Sender<Result<Message, Error>>,
+    ) {
+        let topic = IdentTopic::new(topic);
+        match gossipsub.subscribe(&topic) {
+            Ok(true) => self.score(gossipsub, &topic),
+            Ok(false) => {}
+            Err(e) => {
+                let _ = sender.try_send(Err(Error::Subscribe(e.to_string())));
+                return;
+            }
+        }
+        let subscriber = Subscriber {
+            id,
+            sender,
+            missed: 0,
+        };
+        self.subscribers
+            .entry(topic.hash())
+            .or_default()
+            .push(subscriber);
+    }
+
+    // Score the peers of topic, just subscribed to, unless the scoring params have scores of
+    // their own for it.
+    fn score(&self, gossipsub: &mut Gossipsub, topic: &IdentTopic) {
+        let Some(scoring) = &self.scoring else {
+            return;
+        };
+        if scoring.params.topics.contains_key(&topic.hash()) {
+            return;
+        }
+        if let Err(e) = gossipsub.set_topic_params(topic.clone(), scoring.topic.clone()) {
+            tracing::warn!("scoring the peers of {topic}: {e}");
+        }
+    }
+
+    // Remove the subscriber, and leave the topic if it was the last one.
+    pub fn unsubscribe(&mut self, gossipsub: &mut Gossipsub, topic: String, id: u64) {
+        let topic = IdentTopic::new(topic);
+        let Some(subscribers) = self.subscribers.get_mut(&topic.hash()) else {
+            return;
+        };
+        subscribers.retain(|subscriber| subscriber.id != id);
+        if subscribers.is_empty() {
+            self.subscribers.remove(&topic.hash());
+            let _ = gossipsub.unsubscribe(&topic);
+        }
+    }
+
+    pub fn info(&self, gossipsub: &Gossipsub, topic: String) -> TopicInfo {
+        let topic = IdentTopic::new(topic).hash();
+        let (received, published) = self.counts.get(&topic).copied().unwrap_or_default();
+        TopicInfo {
+            mesh_peers: gossipsub.mesh_peers(&topic).copied().collect(),
+            all_peers: gossipsub
+                .all_peers()
+                .filter(|(_, topics)| topics.contains(&&topic))
+                .map(|(peer, _)| *peer)
+                .collect(),
+            received,
+            published,
+        }
+    }
+
+    // Validate the message the event is about, if its topic has a validator, or else accept it
+    // and hand it to the subscribers of its topic. Gossipsub has already checked the signature of
+    // a signed message, so an unsigned one on a strict topic is all there is left to reject. An
+    // unsigned message has no author, so it is taken to come from the peer that sent it.
+    pub fn on_event(&mut self, gossipsub: &mut Gossipsub, event: &gossipsub::Event) {
+        let gossipsub::Event::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = event
+        else {
+            return;
+        };
+        self.counts.entry(message.topic.clone()).or_default().0 += 1;
+        if let Some(metrics) = &self.metrics {
+            metrics.pubsub_message(message.topic.as_str(), "in");
+        }
+        let validation = Validation {
+            id: message_id.clone(),
+            source: *propagation_source,
+            message: Message {
+                source: message.source.unwrap_or(*propagation_source),
+                topic: message.topic.as_str().to_owned(),
+                data: message.data.clone(),
+            },
+            result: ValidationResult::Accept,
+        };
+        if message.source.is_none() && !self.permissive.contains(&message.topic) {
+            tracing::debug!("rejecting unsigned message on {}", message.topic);
+            let validation = Validation {
+                result: ValidationResult::Reject,
+                ..validation
+            };
+            self.on_validated(gossipsub, validation);
+            return;
+        }
+        match self.validators.get(&message.topic) {
+            Some(pending) => {
+                let _ = pending.send(validation);
+            }
+            None => self.on_validated(gossipsub, validation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data: &str) -> Message {
+        Message {
+            source: PeerId::random(),
+            topic: "topic".to_owned(),
+            data: data.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lagged() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let mut subscriber = Subscriber {
+            id: 0,
+            sender,
+            missed: 0,
+        };
+        for data in ["a", "b", "c"] {
+            subscriber.deliver(message(data));
+        }
+        assert_eq!(receiver.recv().await.unwrap().unwrap().data, b"a");
+
+        // The notice takes the only room left, so d is missed in turn.
+        subscriber.deliver(message("d"));
+        assert_eq!(receiver.recv().await.unwrap(), Err(Error::Lagged(2)));
+        subscriber.deliver(message("e"));
+        assert_eq!(receiver.recv().await.unwrap(), Err(Error::Lagged(1)));
+    }
+
+    #[test]
+    fn test_request() {
+        let request = Request {
+            id: 7,
+            reply_topic: "/ww/reply/1".to_owned(),
+            payload: b"ping".to_vec(),
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode(&[0; 9]), None);
+        // The reply topic runs past the end.
+        assert_eq!(Request::decode(&[0, 0, 0, 0, 0, 0, 0, 7, 0, 2, b'a']), None);
+
+        let mut reply = 7u64.to_be_bytes().to_vec();
+        reply.extend(b"pong");
+        assert_eq!(reply_payload(7, &reply), Some(&b"pong"[..]));
+        assert_eq!(reply_payload(8, &reply), None);
+    }
+
+    #[test]
+    fn test_seen() {
+        let id = |n: u8| MessageId::new(&[n]);
+        let mut seen = Seen::new(Duration::from_secs(10), 2);
+        let start = Instant::now();
+        assert!(seen.insert(&id(0), start));
+        assert!(!seen.insert(&id(0), start + Duration::from_secs(9)));
+        assert!(seen.insert(&id(0), start + Duration::from_secs(10)));
+
+        // A third message pushes the oldest out, window or not.
+        let now = start + Duration::from_secs(11);
+        assert!(seen.insert(&id(1), now));
+        assert!(seen.insert(&id(2), now));
+        assert_eq!(seen.ids.len(), 2);
+        assert!(seen.insert(&id(0), now));
+    }
+
+    #[tokio::test]
+    async fn test_dedup() {
+        let config = gossipsub::ConfigBuilder::default()
+            .validation_mode(gossipsub::ValidationMode::Anonymous)
+            .build()
+            .unwrap();
+        let anonymous = gossipsub::MessageAuthenticity::Anonymous;
+        let mut gossipsub =
+            Gossipsub::new_with_transform(anonymous, config, SignedAuthors).unwrap();
+        let mut topics = Topics::default().with_dedup(Duration::from_secs(60), 16);
+        let (sender, mut receiver) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        topics.subscribe(&mut gossipsub, "topic".to_owned(), 0, sender);
+
+        let source = PeerId::random();
+        for (id, data) in [(0u8, "a"), (0, "a"), (1, "b")] {
+            let validation = Validation {
+                id: MessageId::new(&[id]),
+                source,
+                message: message(data),
+                result: ValidationResult::Accept,
+            };
+            topics.on_validated(&mut gossipsub, validation);
+        }
+        assert_eq!(receiver.recv().await.unwrap().unwrap().data, b"a");
+        assert_eq!(receiver.recv().await.unwrap().unwrap().data, b"b");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_message_id() {
+        let message = |source, sequence_number, data: &str| gossipsub::Message {
+            source,
+            data: data.into(),
+            sequence_number,
+            topic: IdentTopic::new("topic").hash(),
+        };
+        let id = |source, sequence_number, data| {
+            MessageIdFn::default().id(&message(source, sequence_number, data))
+        };
+        let author = Some(PeerId::random());
+        // Copies are known by author and sequence number, whatever they carry.
+        assert_eq!(id(author, Some(1), "a"), id(author, Some(1), "b"));
+        assert_ne!(id(author, Some(1), "a"), id(author, Some(2), "a"));
+        // Anonymous messages by their data.
+        assert_eq!(id(None, None, "a"), id(None, None, "a"));
+        assert_ne!(id(None, None, "a"), id(None, None, "b"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use libp2p::core::transport::ListenerId;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{relay, Multiaddr, PeerId};
+use tokio::time::Instant;
+
+use crate::swarm::Reachability;
+use crate::{DefaultBehaviourEvent, DefaultSwarm};
+
+// Delay before reserving on a relay again after a failed reservation, doubled with every
+// failure since the last reservation accepted.
+const BACKOFF: Duration = Duration::from_secs(1);
+// Longest delay between reservations on a relay.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// Delay before the next reservation after failures in a row.
+fn backoff(failures: u32) -> Duration {
+    BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+// Relay the node may reserve a slot on, and where the reservation stands.
+struct Relay {
+    // Address of the relay, ending with its peer ID.
+    addr: Multiaddr,
+    // Listener on the circuits of the relay, holding a reservation or waiting for one.
+    listener: Option<ListenerId>,
+    // Reservations failed in a row.
+    failures: u32,
+    // When to reserve again after the last failure.
+    retry_at: Option<Instant>,
+}
+
+impl Relay {
+    fn peer(&self) -> Option<PeerId> {
+        match self.addr.iter().last() {
+            Some(Protocol::P2p(peer)) => Some(peer),
+            _ => None,
+        }
+    }
+
+    // Listen on the circuits of the relay, which reserves a slot on it.
+    fn reserve(&mut self, swarm: &mut DefaultSwarm) {
+        self.retry_at = None;
+        match swarm.listen_on(self.addr.clone().with(Protocol::P2pCircuit)) {
+            Ok(listener) => self.listener = Some(listener),
+            Err(e) => self.failed(&e.to_string()),
+        }
+    }
+
+    fn failed(&mut self, error: &str) {
+        self.listener = None;
+        self.failures += 1;
+        let delay = backoff(self.failures);
+        let addr = &self.addr;
+        tracing::warn!("reservation on {addr} failed, retrying in {delay:?}: {error}");
+        self.retry_at = Some(Instant::now() + delay);
+    }
+}
+
+// Reservations on relays, held while the node is private so peers can still dial it through
+// the /p2p-circuit address of each relay. Those addresses are added to the external addresses
+// of the node, which identify tells peers about.
+pub(crate) struct Relays {
+    relays: Vec<Relay>,
+    private: bool,
+}
+
+impl Relays {
+    pub fn new(addrs: Vec<Multiaddr>) -> Self {
+        let relays = addrs
+            .into_iter()
+            .map(|addr| Relay {
+                addr,
+                listener: None,
+                failures: 0,
+                retry_at: None,
+            })
+            .collect();
+        Self {
+            relays,
+            private: false,
+        }
+    }
+
+    // Reserve a slot on every relay once the node is found to be private, and give the
+    // reservations up once it is found to be public again.
+    pub fn on_reachability(&mut self, swarm: &mut DefaultSwarm, reachability: Reachability) {
+        self.private = reachability == Reachability::Private;
+        for relay in &mut self.relays {
+            if self.private {
+                if relay.listener.is_none() && relay.retry_at.is_none() {
+                    relay.reserve(swarm);
+                }
+                continue;
+            }
+            // Forgotten before it closes, so closing isn't taken for a failure.
+            if let Some(listener) = relay.listener.take() {
+                swarm.remove_listener(listener);
+            }
+            relay.failures = 0;
+            relay.retry_at = None;
+        }
+    }
+
+    pub fn on_event(
+        &mut self,
+        swarm: &mut DefaultSwarm,
+        event: &SwarmEvent<DefaultBehaviourEvent>,
+    ) {
+        let ours = |relays: &mut Vec<Relay>, id: &ListenerId| {
+            relays
+                .iter_mut()
+                .position(|relay| relay.listener.as_ref() == Some(id))
+        };
+        match event {
+            SwarmEvent::Behaviour(DefaultBehaviourEvent::Relay(
+                relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
+            )) => {
+                let accepted = self
+                    .relays
+                    .iter_mut()
+                    .filter(|relay| relay.peer() == Some(*relay_peer_id));
+                for relay in accepted {
+                    relay.failures = 0;
+                }
+            }
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } if ours(&mut self.relays, listener_id).is_some() => {
+                tracing::info!("reachable through relay at {address}");
+                swarm.add_external_address(address.clone());
+            }
+            SwarmEvent::ExpiredListenAddr {
+                listener_id,
+                address,
+            } if ours(&mut self.relays, listener_id).is_some() => {
+                swarm.remove_external_address(address);
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                addresses,
+                reason,
+            } => {
+                let Some(i) = ours(&mut self.relays, listener_id) else {
+                    return;
+                };
+                for address in addresses {
+                    swarm.remove_external_address(address);
+                }
+                let error = match reason {
+                    Ok(()) => "reservation closed".to_owned(),
+                    Err(e) => e.to_string(),
+                };
+                self.relays[i].failed(&error);
+            }
+            _ => {}
+        }
+    }
+
+    // Wait for a reservation to be due again, forever if none is.
+    pub async fn retry_due(&self) {
+        match self.relays.iter().filter_map(|relay| relay.retry_at).min() {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    // Reserve again on the relays due for it, if the node is still private.
+    pub fn retry(&mut self, swarm: &mut DefaultSwarm) {
+        let now = Instant::now();
+        for relay in &mut self.relays {
+            if relay.retry_at.is_some_and(|at| at <= now) {
+                match self.private {
+                    true => relay.reserve(swarm),
+                    false => relay.retry_at = None,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let delays: Vec<u64> = (1..=4).map(|n| backoff(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8]);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,2060 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::FuturesUnordered;
+use futures::{Stream, StreamExt};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::Boxed;
+use libp2p::core::transport::ListenerId;
+use libp2p::core::upgrade;
+use libp2p::kad::Quorum;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{
+    autonat, identify, identity, kad, mdns, noise, quic, relay, tcp, yamux, Multiaddr, PeerId,
+    Transport as _,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::access::AccessPolicy;
+use crate::bandwidth::{Bandwidth, Traffic};
+use crate::dht::{self, Queries};
+use crate::dns::Dns;
+use crate::handshake::{HandshakePool, Pooled};
+use crate::metrics::Metrics;
+use crate::peerstore::{PeerInfo, Peerstore};
+use crate::pubsub::{self, MessageIdFn, Pubsub, Topics};
+use crate::relay::Relays;
+use crate::{DefaultBehaviourEvent, DefaultSwarm, KAD_PROTOCOL};
+
+// How many events a subscriber can fall behind by before it starts missing some.
+const EVENT_CAPACITY: usize = 256;
+
+// How long known peers are kept for after they were last seen.
+pub const DEFAULT_PEER_STALENESS: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// How often the node announces again the keys it provides, so the records don't expire.
+pub const DEFAULT_PROVIDER_REPUBLISH: Duration = Duration::from_secs(12 * 60 * 60);
+
+// How many connections may be handshaking at once, in each direction.
+pub const DEFAULT_MAX_PENDING_INBOUND: u32 = 32;
+pub const DEFAULT_MAX_PENDING_OUTBOUND: u32 = 32;
+
+// How many threads drive the QUIC connections of the swarm, handshakes included.
+pub const DEFAULT_HANDSHAKE_THREADS: usize = 2;
+
+// How long a connection no protocol uses is kept open for.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// How long a delivered pubsub message is remembered for, and how many are remembered at most.
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(2 * 60);
+pub const DEFAULT_DEDUP_CAPACITY: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    // No transport is enabled.
+    NoTransport,
+    // The address uses protocols no transport speaks.
+    Unsupported(Multiaddr),
+    // The address needs a transport that is disabled.
+    Disabled(Multiaddr, Transport),
+    // The configuration is inconsistent.
+    Config(String),
+    // The swarm could not be built.
+    Build(String),
+    // Listening on the address failed.
+    Listen(Multiaddr, String),
+    // The swarm didn't wind down within the timeout.
+    ShutdownTimeout(Duration),
+    // The event loop of the swarm crashed.
+    Crashed(String),
+    // The peerstore at the path could not be read.
+    Peerstore(PathBuf, io::Error),
+    // The service was started outside of a tokio runtime.
+    NoRuntime,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoTransport => write!(f, "no transport enabled"),
+            Error::Unsupported(addr) => write!(f, "no transport can listen on {addr}"),
+            Error::Disabled(addr, t) => write!(f, "{addr} needs the {t} transport, which is off"),
+            Error::Config(msg) => write!(f, "invalid swarm config: {msg}"),
+            Error::Build(msg) => write!(f, "building swarm: {msg}"),
+            Error::Listen(addr, msg) => write!(f, "listening on {addr}: {msg}"),
+            Error::ShutdownTimeout(t) => write!(f, "swarm still running after {t:?}"),
+            Error::Crashed(msg) => write!(f, "swarm crashed: {msg}"),
+            Error::Peerstore(path, e) => write!(f, "peerstore {}: {e}", path.display()),
+            Error::NoRuntime => write!(f, "not running on a tokio runtime"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Transports the swarm can run over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    // TCP, secured with noise and multiplexed with yamux.
+    Tcp,
+    // QUIC v1.
+    Quic,
+}
+
+impl Transport {
+    // Transport listening on addr goes through, None if there is none.
+    pub fn of(addr: &Multiaddr) -> Option<Self> {
+        let protocols: Vec<Protocol> = addr.iter().collect();
+        match protocols.as_slice() {
+            [Protocol::Ip4(_) | Protocol::Ip6(_), Protocol::Tcp(_)] => Some(Transport::Tcp),
+            [Protocol::Ip4(_) | Protocol::Ip6(_), Protocol::Udp(_), Protocol::QuicV1] => {
+                Some(Transport::Quic)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Quic => write!(f, "quic"),
+        }
+    }
+}
+
+// How the swarm is set up: what it listens on and over which transports.
+#[derive(Clone, Debug)]
+pub struct SwarmConfig {
+    // Addresses to listen on. A port of 0 is picked by the OS, or from port_range if set.
+    pub listen_addrs: Vec<Multiaddr>,
+    // Ports the addresses with a port of 0 listen on the first free one of.
+    pub port_range: Option<RangeInclusive<u16>>,
+    pub tcp: bool,
+    pub quic: bool,
+    // Name of the protocol used to identify the node through libp2p Identify.
+    pub identify_protocol: String,
+    // Kademlia mode, chosen from the confirmed external addresses if None.
+    pub kad_mode: Option<kad::Mode>,
+    // File the addresses of known peers are loaded from at startup and saved to on shutdown.
+    pub peerstore: Option<PathBuf>,
+    // How long known peers are kept for after they were last seen.
+    pub peer_staleness: Duration,
+    // Most inbound connections handshaking at once. Any more are refused, and left for the
+    // remote to retry.
+    pub max_pending_inbound: u32,
+    // Most dials in flight at once. Any more wait for one to complete.
+    pub max_pending_outbound: u32,
+    // Threads driving the QUIC connections, and checking the certificates of their handshakes,
+    // apart from the event loop.
+    pub handshake_threads: usize,
+    // How long a connection no protocol uses is kept open for, unless it is to a pinned peer.
+    pub idle_timeout: Duration,
+    // Peers whose connections are kept open however long they stay idle, along with the
+    // relays.
+    pub pinned: HashSet<PeerId>,
+    // Peers that may connect to the node.
+    pub access: AccessPolicy,
+    // Whether peers on the local network are discovered, dialed and recorded through mDNS.
+    pub mdns: bool,
+    // How often the node announces again the keys it provides. Provider records live for four
+    // times as long, so a few missed announcements don't lose them.
+    pub provider_republish: Duration,
+    // Where connected peers and pubsub messages are counted, if anywhere.
+    pub metrics: Option<Metrics>,
+    // How the node asks peers to dial it back to find out whether it is reachable, and how it
+    // dials back those asking it.
+    pub autonat: autonat::Config,
+    // Relays the node reserves a slot on while it isn't reachable, each ending with the peer ID
+    // of the relay. Peers then reach the node through its /p2p-circuit address on them.
+    pub relays: Vec<Multiaddr>,
+    // Resolves the host names of the addresses dialed.
+    pub dns: Dns,
+    // Identifies pubsub messages, so that copies are dropped rather than delivered again.
+    pub message_id: MessageIdFn,
+    // How long after a pubsub message is delivered its copies are dropped for.
+    pub dedup_window: Duration,
+    // Most pubsub messages remembered at once. The oldest are forgotten first, before the
+    // window is up if need be.
+    pub dedup_capacity: usize,
+    // How gossipsub scores peers to keep misbehaving ones out of the mesh, if it does.
+    pub peer_scoring: Option<pubsub::Scoring>,
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        Self {
+            listen_addrs: vec![
+                "/ip4/0.0.0.0/tcp/0".parse().unwrap(),
+                "/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap(),
+            ],
+            port_range: None,
+            tcp: true,
+            quic: true,
+            identify_protocol: "/ww/identify/0.0.1".to_owned(),
+            kad_mode: None,
+            peerstore: None,
+            peer_staleness: DEFAULT_PEER_STALENESS,
+            max_pending_inbound: DEFAULT_MAX_PENDING_INBOUND,
+            max_pending_outbound: DEFAULT_MAX_PENDING_OUTBOUND,
+            handshake_threads: DEFAULT_HANDSHAKE_THREADS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            pinned: HashSet::new(),
+            access: AccessPolicy::Open,
+            provider_republish: DEFAULT_PROVIDER_REPUBLISH,
+            mdns: false,
+            metrics: None,
+            autonat: autonat::Config::default(),
+            relays: Vec::new(),
+            dns: Dns::default(),
+            message_id: MessageIdFn::default(),
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            dedup_capacity: DEFAULT_DEDUP_CAPACITY,
+            peer_scoring: None,
+        }
+    }
+}
+
+impl SwarmConfig {
+    // Check that some transport is on and that each listen address goes through one that is.
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.tcp && !self.quic {
+            return Err(Error::NoTransport);
+        }
+        if self.max_pending_inbound == 0 || self.max_pending_outbound == 0 {
+            return Err(Error::Config(
+                "connection limits must be positive".to_owned(),
+            ));
+        }
+        if self.handshake_threads == 0 {
+            return Err(Error::Config(
+                "handshake threads must be positive".to_owned(),
+            ));
+        }
+        if self.provider_republish.is_zero() {
+            return Err(Error::Config(
+                "provider republish interval must be positive".to_owned(),
+            ));
+        }
+        if let Some(ports) = &self.port_range {
+            if ports.is_empty() || *ports.start() == 0 {
+                return Err(Error::Config(format!("bad port range {ports:?}")));
+            }
+        }
+        for addr in &self.listen_addrs {
+            match Transport::of(addr) {
+                None => return Err(Error::Unsupported(addr.clone())),
+                Some(Transport::Tcp) if !self.tcp => {
+                    return Err(Error::Disabled(addr.clone(), Transport::Tcp))
+                }
+                Some(Transport::Quic) if !self.quic => {
+                    return Err(Error::Disabled(addr.clone(), Transport::Quic))
+                }
+                Some(_) => {}
+            }
+        }
+        for addr in &self.relays {
+            if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                let msg = format!("relay {addr} doesn't end with a peer ID");
+                return Err(Error::Config(msg));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Transport of a swarm, over the transports enabled in config and over circuits of relays,
+// counting the bytes of each connection in bandwidth.
+pub(crate) fn transport(
+    id_keys: &identity::Keypair,
+    config: &SwarmConfig,
+    relayed: relay::client::Transport,
+    bandwidth: Bandwidth,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Error> {
+    if !config.tcp && !config.quic {
+        return Err(Error::NoTransport);
+    }
+    // First, so circuit addresses aren't taken for the address of the relay by the others.
+    let noise = noise::Config::new(id_keys).map_err(|e| Error::Build(e.to_string()))?;
+    let relayed = relayed
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise)
+        .multiplex(yamux::Config::default())
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+    let mut transports = vec![relayed.boxed()];
+    if config.tcp {
+        let noise = noise::Config::new(id_keys).map_err(|e| Error::Build(e.to_string()))?;
+        let tcp = tcp::tokio::Transport::new(tcp::Config::default())
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise)
+            .multiplex(yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+        transports.push(tcp.boxed());
+    }
+    if config.quic {
+        let quic = quic::tokio::Transport::new(quic::Config::new(id_keys))
+            .map(|(peer, connection), _| (peer, StreamMuxerBox::new(connection)));
+        let pool = HandshakePool::new(config.handshake_threads)
+            .map_err(|e| Error::Build(format!("handshake pool: {e}")))?;
+        transports.push(Pooled::new(quic.boxed(), Arc::new(pool)).boxed());
+    }
+    let transport = transports
+        .into_iter()
+        .reduce(|a, b| {
+            a.or_transport(b)
+                .map(|either, _| either.into_inner())
+                .boxed()
+        })
+        .ok_or(Error::NoTransport)?;
+    let metered = transport.map(move |(peer, muxer), _| (peer, bandwidth.meter(peer, muxer)));
+    Ok(metered.boxed())
+}
+
+// Listen on addr, trying each port of ports in turn if the address leaves the port to pick.
+fn listen(
+    swarm: &mut DefaultSwarm,
+    addr: &Multiaddr,
+    ports: Option<&RangeInclusive<u16>>,
+) -> Result<ListenerId, Error> {
+    let any_port = addr
+        .iter()
+        .any(|p| matches!(p, Protocol::Tcp(0) | Protocol::Udp(0)));
+    let Some(ports) = ports.filter(|_| any_port) else {
+        return swarm
+            .listen_on(addr.clone())
+            .map_err(|e| Error::Listen(addr.clone(), e.to_string()));
+    };
+    let mut error = String::new();
+    for port in ports.clone() {
+        let addr = addr
+            .iter()
+            .map(|p| match p {
+                Protocol::Tcp(0) => Protocol::Tcp(port),
+                Protocol::Udp(0) => Protocol::Udp(port),
+                p => p,
+            })
+            .collect();
+        match swarm.listen_on(addr) {
+            Ok(id) => return Ok(id),
+            Err(e) => error = e.to_string(),
+        }
+    }
+    Err(Error::Listen(
+        addr.clone(),
+        format!("no free port in {ports:?}: {error}"),
+    ))
+}
+
+// Changes in the connectivity of the node, as seen by subscribers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    // First connection to the peer established.
+    PeerConnected { peer: PeerId, addr: Multiaddr },
+    // Last connection to the peer closed.
+    PeerDisconnected { peer: PeerId },
+    // The node started listening on a new address.
+    NewListenAddr { addr: Multiaddr },
+    // An address other nodes can reach the node at was confirmed.
+    NewExternalAddr { addr: Multiaddr },
+    // Whether other nodes can dial the node changed.
+    ReachabilityChanged { reachability: Reachability },
+    // Dialing a peer, or an address if it isn't known, failed.
+    DialFailure { peer: Option<PeerId>, error: String },
+}
+
+// Whether other nodes can dial the node, as found out through AutoNAT by asking connected peers
+// to dial it back at its addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Reachability {
+    // No peer answered yet.
+    #[default]
+    Unknown,
+    // Peers dialed the node back at one of its addresses.
+    Public,
+    // Peers failed to dial the node back at any of its addresses.
+    Private,
+}
+
+impl From<&autonat::NatStatus> for Reachability {
+    fn from(status: &autonat::NatStatus) -> Self {
+        match status {
+            autonat::NatStatus::Public(_) => Reachability::Public,
+            autonat::NatStatus::Private => Reachability::Private,
+            autonat::NatStatus::Unknown => Reachability::Unknown,
+        }
+    }
+}
+
+// Requests handled by the event loop of the swarm.
+pub(crate) enum Command {
+    Dial(Multiaddr),
+    ListenAddrs(oneshot::Sender<Vec<Multiaddr>>),
+    ExternalAddrs(oneshot::Sender<Vec<Multiaddr>>),
+    Reachability(oneshot::Sender<Reachability>),
+    PeerInfo {
+        peer: PeerId,
+        reply: oneshot::Sender<Option<PeerInfo>>,
+    },
+    Connections(oneshot::Sender<Connections>),
+    SetAccess(AccessPolicy),
+    PutRecord {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        quorum: Quorum,
+        reply: oneshot::Sender<Result<(), dht::Error>>,
+    },
+    GetRecord {
+        key: Vec<u8>,
+        quorum: Quorum,
+        reply: oneshot::Sender<Result<Vec<u8>, dht::Error>>,
+    },
+    StartProviding {
+        key: Vec<u8>,
+        reply: oneshot::Sender<Result<(), dht::Error>>,
+    },
+    GetProviders {
+        key: Vec<u8>,
+        providers: mpsc::UnboundedSender<PeerId>,
+    },
+    Publish {
+        topic: String,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<(), pubsub::Error>>,
+    },
+    Subscribe {
+        topic: String,
+        id: u64,
+        sender: mpsc::Sender<Result<pubsub::Message, pubsub::Error>>,
+    },
+    Unsubscribe {
+        topic: String,
+        id: u64,
+    },
+    SetValidator {
+        topic: String,
+        validator: pubsub::Validator,
+    },
+    SetSigning {
+        topic: String,
+        signing: pubsub::Signing,
+    },
+    TopicInfo {
+        topic: String,
+        reply: oneshot::Sender<pubsub::TopicInfo>,
+    },
+    PeerScore {
+        peer: PeerId,
+        reply: oneshot::Sender<Option<f64>>,
+    },
+    Shutdown,
+}
+
+// Connections of the swarm, by state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Connections {
+    pub pending_inbound: u32,
+    pub pending_outbound: u32,
+    pub established: u32,
+    // Dials waiting for others to complete before they start.
+    pub queued_dials: usize,
+}
+
+// Runs the event loop of a swarm in the background, publishing connectivity events to any
+// number of subscribers.
+pub struct SwarmService {
+    peer_id: PeerId,
+    bandwidth: Bandwidth,
+    streams: libp2p::stream::Control,
+    commands: mpsc::UnboundedSender<Command>,
+    // Both None once the service is shut down.
+    events: Option<broadcast::Sender<PeerEvent>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl SwarmService {
+    // Build a swarm of the node identified by id_keys as set up in config, and spawn its event
+    // loop on the current tokio runtime. If any step fails, what was set up until then is
+    // dropped with the swarm, and there is no service to shut down.
+    pub fn new(id_keys: identity::Keypair, config: SwarmConfig) -> Result<Self, Error> {
+        config.validate()?;
+        // Listeners register their sockets with the runtime, which panics outside of one.
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| Error::NoRuntime)?;
+        let peer_id = id_keys.public().to_peer_id();
+        let bandwidth = Bandwidth::new(config.metrics.clone());
+        let mut swarm = DefaultSwarm::new(id_keys, &config, bandwidth.clone())?;
+        let streams = swarm.behaviour().streams.new_control();
+        if let Some(mode) = config.kad_mode {
+            swarm.behaviour_mut().kad.set_mode(Some(mode));
+        }
+        let listeners = config
+            .listen_addrs
+            .iter()
+            .map(|addr| listen(&mut swarm, addr, config.port_range.as_ref()))
+            .collect::<Result<_, _>>()?;
+
+        let peerstore = match &config.peerstore {
+            Some(path) => Peerstore::load(path, config.peer_staleness)
+                .map_err(|e| Error::Peerstore(path.clone(), e))?,
+            None => Peerstore::new(config.peer_staleness),
+        };
+        for (peer, addrs) in peerstore.peers() {
+            let opts = DialOpts::peer_id(peer).addresses(addrs).build();
+            if let Err(e) = swarm.0.dial(opts) {
+                tracing::debug!("failed to dial known peer {peer}: {e}");
+            }
+        }
+        // Connected from the start, so they can tell whether the node is reachable before it
+        // reserves a slot on them.
+        for addr in &config.relays {
+            if let Err(e) = swarm.0.dial(addr.clone()) {
+                tracing::warn!("failed to dial relay {addr}: {e}");
+            }
+        }
+
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let task = runtime.spawn(run(
+            swarm,
+            listeners,
+            peerstore,
+            config,
+            receiver,
+            events.clone(),
+        ));
+        Ok(Self {
+            peer_id,
+            bandwidth,
+            streams,
+            commands,
+            events: Some(events),
+            task: Some(task),
+        })
+    }
+
+    // Peer ID of the node, derived from the keys it was built with.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    // Bytes exchanged with each connected peer since it connected, by the protocols negotiated
+    // on the streams they went over, like /meshsub/1.1.0.
+    pub fn bandwidth_report(&self) -> HashMap<(PeerId, String), Traffic> {
+        self.bandwidth.report()
+    }
+
+    // Opens streams to peers and accepts theirs, by protocol, like RPC_PROTOCOL. Dials a peer
+    // the node isn't connected to at an address it knows of.
+    pub fn streams(&self) -> libp2p::stream::Control {
+        self.streams.clone()
+    }
+
+    // Dial addr in the background, once fewer than the most dials allowed are in flight. Host
+    // names in addr are resolved first, and each address they resolve to is dialed. Failures
+    // are reported to subscribers as DialFailure.
+    pub fn dial(&self, addr: Multiaddr) {
+        let _ = self.commands.send(Command::Dial(addr));
+    }
+
+    // Addresses the swarm is listening on, with the ports actually bound. Addresses show up
+    // once the transports report them, as NewListenAddr events.
+    pub async fn listen_addrs(&self) -> Vec<Multiaddr> {
+        let (reply, addrs) = oneshot::channel();
+        if self.commands.send(Command::ListenAddrs(reply)).is_err() {
+            return Vec::new();
+        }
+        addrs.await.unwrap_or_default()
+    }
+
+    // Addresses other nodes were confirmed to reach the node at, as opposed to those it listens
+    // on, which may be private. Addresses show up as they are confirmed, as NewExternalAddr
+    // events, and go away once they expire.
+    pub async fn external_addrs(&self) -> Vec<Multiaddr> {
+        let (reply, addrs) = oneshot::channel();
+        if self.commands.send(Command::ExternalAddrs(reply)).is_err() {
+            return Vec::new();
+        }
+        addrs.await.unwrap_or_default()
+    }
+
+    // Whether other nodes can dial the node, as last found out. Changes are published as
+    // ReachabilityChanged events. Unknown once the service is shut down.
+    pub async fn reachability(&self) -> Reachability {
+        let (reply, reachability) = oneshot::channel();
+        if self.commands.send(Command::Reachability(reply)).is_err() {
+            return Reachability::Unknown;
+        }
+        reachability.await.unwrap_or_default()
+    }
+
+    // What peer last told about itself over identify, which it does on every connection. None
+    // if it hasn't yet, or if the service is shut down.
+    pub async fn peer_info(&self, peer: PeerId) -> Option<PeerInfo> {
+        let (reply, info) = oneshot::channel();
+        let command = Command::PeerInfo { peer, reply };
+        if self.commands.send(command).is_err() {
+            return None;
+        }
+        info.await.ok().flatten()
+    }
+
+    // Replace the access policy, closing the connections to the peers it no longer permits.
+    pub fn set_access(&self, policy: AccessPolicy) {
+        let _ = self.commands.send(Command::SetAccess(policy));
+    }
+
+    // Store value under key in the DHT, succeeding once quorum nodes hold it.
+    pub async fn put_record(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        quorum: Quorum,
+    ) -> Result<(), dht::Error> {
+        let (reply, result) = oneshot::channel();
+        let put = Command::PutRecord {
+            key: key.into(),
+            value: value.into(),
+            quorum,
+            reply,
+        };
+        if self.commands.send(put).is_err() {
+            return Err(dht::Error::Stopped);
+        }
+        result.await.unwrap_or(Err(dht::Error::Stopped))
+    }
+
+    // Value stored under key in the DHT, once quorum nodes returned it.
+    pub async fn get_record(
+        &self,
+        key: impl Into<Vec<u8>>,
+        quorum: Quorum,
+    ) -> Result<Vec<u8>, dht::Error> {
+        let (reply, result) = oneshot::channel();
+        let get = Command::GetRecord {
+            key: key.into(),
+            quorum,
+            reply,
+        };
+        if self.commands.send(get).is_err() {
+            return Err(dht::Error::Stopped);
+        }
+        result.await.unwrap_or(Err(dht::Error::Stopped))
+    }
+
+    // Announce that the node provides key, succeeding once the announcement reached the nodes
+    // closest to it. The node announces it again periodically for as long as it runs.
+    pub async fn start_providing(&self, key: impl Into<Vec<u8>>) -> Result<(), dht::Error> {
+        let (reply, result) = oneshot::channel();
+        let start = Command::StartProviding {
+            key: key.into(),
+            reply,
+        };
+        if self.commands.send(start).is_err() {
+            return Err(dht::Error::Stopped);
+        }
+        result.await.unwrap_or(Err(dht::Error::Stopped))
+    }
+
+    // Stream of the peers providing key, as they are found. Each shows up once, and the stream
+    // ends when the query does. Dropping it stops the query.
+    pub fn get_providers(&self, key: impl Into<Vec<u8>>) -> impl Stream<Item = PeerId> {
+        let (providers, receiver) = mpsc::unbounded_channel();
+        let get = Command::GetProviders {
+            key: key.into(),
+            providers,
+        };
+        // The stream is empty if the service is shut down.
+        let _ = self.commands.send(get);
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            let provider = receiver.recv().await?;
+            Some((provider, receiver))
+        })
+    }
+
+    // Handle to publish and subscribe to topics through the swarm.
+    pub fn pubsub(&self) -> Pubsub {
+        Pubsub::new(self.commands.clone())
+    }
+
+    // Connections of the swarm, none once it is shut down.
+    pub async fn connections(&self) -> Connections {
+        let (reply, connections) = oneshot::channel();
+        if self.commands.send(Command::Connections(reply)).is_err() {
+            return Connections::default();
+        }
+        connections.await.unwrap_or_default()
+    }
+
+    // Stream of the events from now on. Each subscriber gets every event, and one that falls
+    // too far behind skips the oldest ones rather than holding back the swarm.
+    // The stream ends once the service is shut down, and is empty if it already is.
+    pub fn subscribe(&self) -> impl Stream<Item = PeerEvent> {
+        let receiver = self.events.as_ref().map(broadcast::Sender::subscribe);
+        futures::stream::unfold(receiver, |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, Some(receiver))),
+                    Err(RecvError::Lagged(n)) => tracing::warn!("subscriber missed {n} events"),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    // Stop the swarm: close its listeners and connections, pending ones included, publish the
+    // events that follow to subscribers, save the peerstore and wait for the event loop to end.
+    // Stops it outright if that takes longer than timeout. Does nothing if the service is
+    // already shut down.
+    pub async fn shutdown(&mut self, timeout: Duration) -> Result<(), Error> {
+        let Some(mut task) = self.task.take() else {
+            return Ok(());
+        };
+        // Subscribers hear of the shutdown once the event loop drops its sender.
+        self.events = None;
+        let _ = self.commands.send(Command::Shutdown);
+        match tokio::time::timeout(timeout, &mut task).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(Error::Crashed(e.to_string())),
+            Err(_) => {
+                task.abort();
+                Err(Error::ShutdownTimeout(timeout))
+            }
+        }
+    }
+}
+
+impl Drop for SwarmService {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+async fn run(
+    mut swarm: DefaultSwarm,
+    listeners: Vec<ListenerId>,
+    mut peerstore: Peerstore,
+    config: SwarmConfig,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    events: broadcast::Sender<PeerEvent>,
+) {
+    let (dns, max_dials, metrics) = (config.dns, config.max_pending_outbound, config.metrics);
+    let mut relays = Relays::new(config.relays);
+    let mut dials = VecDeque::new();
+    let mut resolving = FuturesUnordered::new();
+    let mut queries = Queries::default();
+    let mut topics = Topics::default()
+        .with_metrics(metrics.clone())
+        .with_dedup(config.dedup_window, config.dedup_capacity)
+        .with_scoring(config.peer_scoring);
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Dial(addr)) if Dns::names_host(&addr) => {
+                    let dns = dns.clone();
+                    resolving.push(async move {
+                        let resolved = dns.resolve(&addr).await;
+                        (addr, resolved)
+                    });
+                }
+                Some(Command::Dial(addr)) => dials.push_back(addr),
+                Some(Command::ListenAddrs(reply)) => {
+                    let _ = reply.send(swarm.listeners().cloned().collect());
+                }
+                Some(Command::ExternalAddrs(reply)) => {
+                    let _ = reply.send(swarm.external_addresses().cloned().collect());
+                }
+                Some(Command::Reachability(reply)) => {
+                    let status = swarm.behaviour().autonat.nat_status();
+                    let _ = reply.send(Reachability::from(&status));
+                }
+                Some(Command::PeerInfo { peer, reply }) => {
+                    let _ = reply.send(peerstore.info(&peer));
+                }
+                Some(Command::Connections(reply)) => {
+                    let info = swarm.network_info();
+                    let counters = info.connection_counters();
+                    let _ = reply.send(Connections {
+                        pending_inbound: counters.num_pending_incoming(),
+                        pending_outbound: counters.num_pending_outgoing(),
+                        established: counters.num_established(),
+                        queued_dials: dials.len(),
+                    });
+                }
+                Some(Command::SetAccess(policy)) => {
+                    let refused: Vec<PeerId> = swarm
+                        .connected_peers()
+                        .filter(|peer| !policy.permits(peer))
+                        .copied()
+                        .collect();
+                    swarm.behaviour_mut().access.set_policy(policy);
+                    for peer in refused {
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                }
+                Some(Command::PutRecord { key, value, quorum, reply }) => {
+                    let kad = &mut swarm.behaviour_mut().kad;
+                    queries.put(kad, key, value, quorum, reply);
+                }
+                Some(Command::GetRecord { key, quorum, reply }) => {
+                    queries.get(&mut swarm.behaviour_mut().kad, key, quorum, reply);
+                }
+                Some(Command::StartProviding { key, reply }) => {
+                    queries.start_providing(&mut swarm.behaviour_mut().kad, key, reply);
+                }
+                Some(Command::GetProviders { key, providers }) => {
+                    queries.get_providers(&mut swarm.behaviour_mut().kad, key, providers);
+                }
+                Some(Command::Publish { topic, data, reply }) => {
+                    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+                    let _ = reply.send(topics.publish(gossipsub, topic, data));
+                }
+                Some(Command::Subscribe { topic, id, sender }) => {
+                    topics.subscribe(&mut swarm.behaviour_mut().gossipsub, topic, id, sender);
+                }
+                Some(Command::Unsubscribe { topic, id }) => {
+                    topics.unsubscribe(&mut swarm.behaviour_mut().gossipsub, topic, id);
+                }
+                Some(Command::SetValidator { topic, validator }) => {
+                    topics.set_validator(topic, validator);
+                }
+                Some(Command::SetSigning { topic, signing }) => {
+                    topics.set_signing(topic, signing);
+                }
+                Some(Command::TopicInfo { topic, reply }) => {
+                    let _ = reply.send(topics.info(&swarm.behaviour().gossipsub, topic));
+                }
+                Some(Command::PeerScore { peer, reply }) => {
+                    let _ = reply.send(swarm.behaviour().gossipsub.peer_score(&peer));
+                }
+                Some(Command::Shutdown) => break,
+                None => return,
+            },
+            validation = topics.validated() => {
+                topics.on_validated(&mut swarm.behaviour_mut().gossipsub, validation);
+            }
+            () = relays.retry_due() => relays.retry(&mut swarm),
+            Some((addr, resolved)) = resolving.next() => match resolved {
+                Ok(addrs) => dials.extend(addrs),
+                Err(e) => {
+                    tracing::debug!("failed to dial {addr}: {e}");
+                    let peer = match addr.iter().last() {
+                        Some(Protocol::P2p(peer)) => Some(peer),
+                        _ => None,
+                    };
+                    let _ = events.send(PeerEvent::DialFailure { peer, error: e.to_string() });
+                }
+            },
+            event = swarm.select_next_some() => {
+                record(&mut peerstore, &event);
+                relays.on_event(&mut swarm, &event);
+                match &event {
+                    SwarmEvent::Behaviour(DefaultBehaviourEvent::Kad(event)) => {
+                        queries.on_event(&mut swarm.behaviour_mut().kad, event);
+                    }
+                    SwarmEvent::Behaviour(DefaultBehaviourEvent::Gossipsub(event)) => {
+                        topics.on_event(&mut swarm.behaviour_mut().gossipsub, event);
+                    }
+                    _ => {}
+                }
+                if let Some(event) = handle_event(&mut swarm, event) {
+                    if let PeerEvent::ReachabilityChanged { reachability } = &event {
+                        relays.on_reachability(&mut swarm, *reachability);
+                    }
+                    // Nobody may be listening, which is fine.
+                    let _ = events.send(event);
+                }
+                if let Some(metrics) = &metrics {
+                    metrics.connected_peers.set(swarm.network_info().num_peers() as i64);
+                }
+            }
+        }
+        dial_queued(&mut swarm, &mut dials, max_dials, &events);
+    }
+
+    for id in listeners {
+        swarm.remove_listener(id);
+    }
+    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    for peer in peers {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    // Closing connections lets the muxers say goodbye to the peers. Dials still in flight are
+    // dropped with the swarm, or closed if they complete in the meantime.
+    while swarm.network_info().connection_counters().num_established() > 0 {
+        let event = swarm.select_next_some().await;
+        if let SwarmEvent::ConnectionEstablished { peer_id, .. } = &event {
+            let _ = swarm.disconnect_peer_id(*peer_id);
+        }
+        if let Some(event) = handle_event(&mut swarm, event) {
+            let _ = events.send(event);
+        }
+    }
+
+    if let Some(path) = config.peerstore {
+        if let Err(e) = peerstore.save(&path) {
+            tracing::warn!("failed to save peerstore to {}: {e}", path.display());
+        }
+    }
+}
+
+// Dial queued addresses while fewer than max dials are in flight.
+fn dial_queued(
+    swarm: &mut DefaultSwarm,
+    dials: &mut VecDeque<Multiaddr>,
+    max: u32,
+    events: &broadcast::Sender<PeerEvent>,
+) {
+    while swarm
+        .network_info()
+        .connection_counters()
+        .num_pending_outgoing()
+        < max
+    {
+        let Some(addr) = dials.pop_front() else {
+            return;
+        };
+        if let Err(e) = swarm.0.dial(addr) {
+            let error = e.to_string();
+            let _ = events.send(PeerEvent::DialFailure { peer: None, error });
+        }
+    }
+}
+
+// Record the addresses peers can be dialed at: those we dialed them at, those they say they
+// listen on and those they announce on the local network. What peers say about themselves is
+// recorded as well.
+fn record(peerstore: &mut Peerstore, event: &SwarmEvent<DefaultBehaviourEvent>) {
+    match event {
+        SwarmEvent::ConnectionEstablished {
+            peer_id, endpoint, ..
+        } if endpoint.is_dialer() => {
+            peerstore.record(*peer_id, endpoint.get_remote_address().clone());
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Identify(identify::Event::Received {
+            peer_id,
+            info,
+            ..
+        })) => {
+            peerstore.identify(*peer_id, PeerInfo::from(info.clone()));
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
+            for (peer, addr) in peers {
+                peerstore.record(*peer, addr.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+// Handle an event of the swarm, returning what subscribers should hear of it.
+fn handle_event(
+    swarm: &mut DefaultSwarm,
+    event: SwarmEvent<DefaultBehaviourEvent>,
+) -> Option<PeerEvent> {
+    match event {
+        SwarmEvent::NewListenAddr { address, .. } => {
+            // TODO:  seal & sign a PeerRecord and announce it to the DHT,
+            // using our PeerID as the key.
+            tracing::info!("listening on {address:?}");
+            Some(PeerEvent::NewListenAddr { addr: address })
+        }
+        SwarmEvent::ExternalAddrConfirmed { address } => {
+            tracing::info!("reachable at {address:?}");
+            Some(PeerEvent::NewExternalAddr { addr: address })
+        }
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            endpoint,
+            num_established,
+            ..
+        } if num_established.get() == 1 => Some(PeerEvent::PeerConnected {
+            peer: peer_id,
+            addr: endpoint.get_remote_address().clone(),
+        }),
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            ..
+        } => Some(PeerEvent::PeerDisconnected { peer: peer_id }),
+        SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+            tracing::debug!("failed to dial {peer_id:?}: {error}");
+            Some(PeerEvent::DialFailure {
+                peer: peer_id,
+                error: error.to_string(),
+            })
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Mdns(event)) => {
+            crate::dial::default_mdns_handler(swarm, event);
+            None
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Ping(event)) => {
+            tracing::debug!("got PING event: {event:?}");
+            None
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Kad(event)) => {
+            tracing::debug!("got KAD event: {event:?}");
+            None
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Gossipsub(event)) => {
+            tracing::debug!("got GOSSIPSUB event: {event:?}");
+            None
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Autonat(autonat::Event::StatusChanged {
+            old,
+            new,
+        })) => {
+            let (old, new) = (Reachability::from(&old), Reachability::from(&new));
+            // Being public at another address doesn't change a thing.
+            if old == new {
+                return None;
+            }
+            tracing::info!("reachability changed from {old:?} to {new:?}");
+            Some(PeerEvent::ReachabilityChanged { reachability: new })
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Autonat(event)) => {
+            tracing::debug!("got AUTONAT event: {event:?}");
+            None
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Relay(event)) => {
+            tracing::debug!("got RELAY event: {event:?}");
+            None
+        }
+        SwarmEvent::Behaviour(DefaultBehaviourEvent::Identify(event)) => {
+            tracing::debug!("got IDENTIFY event: {event:?}");
+            // Peers speaking Kademlia join the routing table at the addresses they listen on.
+            if let identify::Event::Received { peer_id, info, .. } = event {
+                if info.protocols.contains(&KAD_PROTOCOL) {
+                    for addr in info.listen_addrs {
+                        swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                    }
+                }
+            }
+            None
+        }
+        event => {
+            tracing::debug!("got event: {event:?}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::{Scoring, Signing, ValidationResult};
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+    use libp2p::gossipsub;
+
+    fn config(listen_addrs: &[&str]) -> SwarmConfig {
+        SwarmConfig {
+            listen_addrs: listen_addrs
+                .iter()
+                .map(|addr| addr.parse().unwrap())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn node(config: SwarmConfig) -> (PeerId, SwarmService) {
+        let id_keys = identity::Keypair::generate_ed25519();
+        let peer = id_keys.public().to_peer_id();
+        (peer, SwarmService::new(id_keys, config).unwrap())
+    }
+
+    fn connected(peer: PeerId) -> impl Fn(&PeerEvent) -> bool {
+        move |event| matches!(event, PeerEvent::PeerConnected { peer: p, .. } if *p == peer)
+    }
+
+    fn listening(event: &PeerEvent) -> bool {
+        matches!(event, PeerEvent::NewListenAddr { .. })
+    }
+
+    // Wait for the next event matching f.
+    async fn next_matching(
+        events: &mut (impl Stream<Item = PeerEvent> + Unpin),
+        f: impl Fn(&PeerEvent) -> bool,
+    ) -> PeerEvent {
+        let next = async {
+            while let Some(event) = events.next().await {
+                if f(&event) {
+                    return event;
+                }
+            }
+            panic!("event stream ended");
+        };
+        tokio::time::timeout(Duration::from_secs(10), next)
+            .await
+            .expect("timed out waiting for event")
+    }
+
+    #[tokio::test]
+    async fn test_peer_connected() {
+        let (a_id, a) = node(config(&["/ip4/127.0.0.1/tcp/0"]));
+        let (b_id, b) = node(config(&[]));
+        let mut a_events = Box::pin(a.subscribe());
+        let mut b_events = Box::pin(b.subscribe());
+
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut a_events, listening).await
+        else {
+            unreachable!();
+        };
+        b.dial(addr);
+
+        next_matching(&mut a_events, connected(b_id)).await;
+        next_matching(&mut b_events, connected(a_id)).await;
+    }
+
+    #[tokio::test]
+    async fn test_identity() {
+        let (id, a) = node(config(&["/ip4/127.0.0.1/tcp/0"]));
+        let mut events = Box::pin(a.subscribe());
+        assert_eq!(a.peer_id(), id);
+
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut events, listening).await else {
+            unreachable!();
+        };
+        assert_eq!(a.listen_addrs().await, vec![addr]);
+        assert_eq!(a.peer_id(), id);
+        // Nothing confirmed the node is reachable from outside.
+        assert!(a.external_addrs().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (a_id, a) = node(config(&["/ip4/127.0.0.1/tcp/0"]));
+        let (_, mut b) = node(config(&[]));
+        let mut a_events = Box::pin(a.subscribe());
+        let mut b_events = Box::pin(b.subscribe());
+
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut a_events, listening).await
+        else {
+            unreachable!();
+        };
+        b.dial(addr);
+        next_matching(&mut b_events, connected(a_id)).await;
+
+        // Dials still in flight don't hold up the shutdown.
+        b.dial("/ip4/192.0.2.1/tcp/4001".parse().unwrap());
+        b.shutdown(Duration::from_secs(10)).await.unwrap();
+        assert!(b.commands.is_closed());
+        assert!(b.listen_addrs().await.is_empty());
+        let disconnected = |e: &PeerEvent| matches!(e, PeerEvent::PeerDisconnected { .. });
+        next_matching(&mut b_events, disconnected).await;
+        assert_eq!(b_events.next().await, None);
+
+        b.shutdown(Duration::from_secs(10)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_access() {
+        let (b_id, b) = node(config(&[]));
+        let (c_id, c) = node(config(&[]));
+        let (a_id, a) = node(SwarmConfig {
+            access: AccessPolicy::Deny([c_id].into()),
+            ..config(&["/ip4/127.0.0.1/tcp/0"])
+        });
+        let mut a_events = Box::pin(a.subscribe());
+        let mut b_events = Box::pin(b.subscribe());
+        let mut c_events = Box::pin(c.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut a_events, listening).await
+        else {
+            unreachable!();
+        };
+
+        // c gets through the handshake, only to be disconnected before anything else happens.
+        c.dial(addr.clone());
+        let dropped = |e: &PeerEvent| {
+            matches!(
+                e,
+                PeerEvent::PeerDisconnected { .. } | PeerEvent::DialFailure { .. }
+            )
+        };
+        next_matching(&mut c_events, dropped).await;
+
+        // The first peer a lets in is b.
+        b.dial(addr);
+        let any_connected = |e: &PeerEvent| matches!(e, PeerEvent::PeerConnected { .. });
+        let event = next_matching(&mut a_events, any_connected).await;
+        assert!(connected(b_id)(&event));
+        next_matching(&mut b_events, connected(a_id)).await;
+
+        // Changing the policy closes the connections it no longer permits.
+        a.set_access(AccessPolicy::Allow([c_id].into()));
+        let disconnected =
+            |e: &PeerEvent| matches!(e, PeerEvent::PeerDisconnected { peer } if *peer == b_id);
+        next_matching(&mut a_events, disconnected).await;
+    }
+
+    #[tokio::test]
+    async fn test_records() {
+        let server = |listen_addrs: &[&str]| SwarmConfig {
+            kad_mode: Some(kad::Mode::Server),
+            ..config(listen_addrs)
+        };
+        let (a_id, a) = node(server(&["/ip4/127.0.0.1/tcp/0"]));
+        let (_, b) = node(server(&["/ip4/127.0.0.1/tcp/0"]));
+        let mut a_events = Box::pin(a.subscribe());
+        let mut b_events = Box::pin(b.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut a_events, listening).await
+        else {
+            unreachable!();
+        };
+        b.dial(addr);
+        next_matching(&mut b_events, connected(a_id)).await;
+        // Give identify the time to add the peers to the routing tables.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        a.put_record("key", "value", Quorum::One).await.unwrap();
+        assert_eq!(b.get_record("key", Quorum::One).await.unwrap(), b"value");
+        assert!(matches!(
+            b.get_record("missing", Quorum::One).await,
+            Err(dht::Error::NotFound)
+        ));
+
+        let large = vec![0; dht::MAX_VALUE_SIZE + 1];
+        let result = a.put_record("large", large, Quorum::One).await;
+        assert!(matches!(result, Err(dht::Error::ValueTooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn test_providers() {
+        let server = |listen_addrs: &[&str]| SwarmConfig {
+            kad_mode: Some(kad::Mode::Server),
+            ..config(listen_addrs)
+        };
+        let (a_id, a) = node(server(&["/ip4/127.0.0.1/tcp/0"]));
+        let (_, b) = node(server(&["/ip4/127.0.0.1/tcp/0"]));
+        let mut a_events = Box::pin(a.subscribe());
+        let mut b_events = Box::pin(b.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut a_events, listening).await
+        else {
+            unreachable!();
+        };
+        b.dial(addr);
+        next_matching(&mut b_events, connected(a_id)).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        a.start_providing("dataset").await.unwrap();
+        let providers: Vec<PeerId> = b.get_providers("dataset").collect().await;
+        assert_eq!(providers, vec![a_id]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs multicast on a local network interface"]
+    async fn test_mdns() {
+        let lan = || SwarmConfig {
+            mdns: true,
+            ..config(&["/ip4/0.0.0.0/tcp/0"])
+        };
+        let (a_id, a) = node(lan());
+        let (b_id, b) = node(lan());
+        let mut a_events = Box::pin(a.subscribe());
+        let mut b_events = Box::pin(b.subscribe());
+
+        next_matching(&mut a_events, connected(b_id)).await;
+        next_matching(&mut b_events, connected(a_id)).await;
+    }
+
+    // Connect a client node to a node listening on the loopback interface.
+    async fn pair(listener: SwarmConfig) -> ((PeerId, SwarmService), (PeerId, SwarmService)) {
+        let (a_id, a) = node(SwarmConfig {
+            listen_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            ..listener
+        });
+        let (b_id, b) = node(config(&[]));
+        let mut a_events = Box::pin(a.subscribe());
+        let mut b_events = Box::pin(b.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut a_events, listening).await
+        else {
+            unreachable!();
+        };
+        b.dial(addr);
+        next_matching(&mut b_events, connected(a_id)).await;
+        ((a_id, a), (b_id, b))
+    }
+
+    // What node learned of peer over identify, once it did.
+    async fn identified(node: &SwarmService, peer: PeerId) -> PeerInfo {
+        let info = async {
+            loop {
+                if let Some(info) = node.peer_info(peer).await {
+                    return info;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), info)
+            .await
+            .expect("timed out waiting for identify")
+    }
+
+    #[tokio::test]
+    async fn test_peer_info() {
+        let server = SwarmConfig {
+            kad_mode: Some(kad::Mode::Server),
+            ..Default::default()
+        };
+        let ((a_id, a), (b_id, b)) = pair(server).await;
+        for (node, peer) in [(&a, b_id), (&b, a_id)] {
+            let info = identified(node, peer).await;
+            assert_eq!(info.agent_version, crate::AGENT_VERSION);
+            assert_eq!(info.protocol_version, "/ww/identify/0.0.1");
+            for protocol in ["/ipfs/id/1.0.0", "/ipfs/ping/1.0.0"] {
+                assert!(info.protocols.iter().any(|p| p == protocol), "{info:?}");
+            }
+        }
+        // Only a answers Kademlia queries and listens, so b learns it can query it and where.
+        let info = identified(&b, a_id).await;
+        assert!(info.protocols.iter().any(|p| p == KAD_PROTOCOL.as_ref()));
+        assert_eq!(info.listen_addrs, a.listen_addrs().await);
+        assert_eq!(a.peer_info(PeerId::random()).await, None);
+    }
+
+    // Reachability client finds out once connected to server, each probing the other right away
+    // and dialing back loopback addresses.
+    async fn probed(client: SwarmConfig, server: SwarmConfig) -> Reachability {
+        let probing = |config: SwarmConfig| SwarmConfig {
+            autonat: autonat::Config {
+                boot_delay: Duration::from_millis(100),
+                retry_interval: Duration::from_millis(500),
+                only_global_ips: false,
+                ..Default::default()
+            },
+            ..config
+        };
+        let (_, server) = node(probing(server));
+        let (_, client) = node(probing(client));
+        let mut server_events = Box::pin(server.subscribe());
+        let mut client_events = Box::pin(client.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut server_events, listening).await
+        else {
+            unreachable!();
+        };
+        assert_eq!(client.reachability().await, Reachability::Unknown);
+
+        client.dial(addr);
+        let changed = |e: &PeerEvent| matches!(e, PeerEvent::ReachabilityChanged { .. });
+        let PeerEvent::ReachabilityChanged { reachability } =
+            next_matching(&mut client_events, changed).await
+        else {
+            unreachable!();
+        };
+        assert_eq!(client.reachability().await, reachability);
+        reachability
+    }
+
+    #[tokio::test]
+    async fn test_reachability() {
+        // The server dials the client back at the address it listens on.
+        let tcp = config(&["/ip4/127.0.0.1/tcp/0"]);
+        assert_eq!(probed(tcp.clone(), tcp).await, Reachability::Public);
+
+        // The client only listens over QUIC, which the server can't dial, and dials out over
+        // TCP from a port nothing listens on.
+        let quic = config(&["/ip4/127.0.0.1/udp/0/quic-v1"]);
+        let tcp_only = SwarmConfig {
+            quic: false,
+            ..config(&["/ip4/127.0.0.1/tcp/0"])
+        };
+        assert_eq!(probed(quic, tcp_only).await, Reachability::Private);
+    }
+
+    #[derive(libp2p::swarm::NetworkBehaviour)]
+    struct StubRelay {
+        relay: relay::Behaviour,
+        autonat: autonat::Behaviour,
+        identify: identify::Behaviour,
+    }
+
+    // Relay listening over TCP only, which sends the addresses peers tell it they listen on.
+    async fn stub_relay() -> (Multiaddr, mpsc::UnboundedReceiver<Vec<Multiaddr>>) {
+        let mut stub = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|keys| {
+                let peer = keys.public().to_peer_id();
+                let autonat = autonat::Config {
+                    only_global_ips: false,
+                    ..Default::default()
+                };
+                StubRelay {
+                    relay: relay::Behaviour::new(peer, relay::Config::default()),
+                    autonat: autonat::Behaviour::new(peer, autonat),
+                    identify: identify::Behaviour::new(identify::Config::new(
+                        SwarmConfig::default().identify_protocol,
+                        keys.public(),
+                    )),
+                }
+            })
+            .unwrap()
+            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        let listen_addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+        stub.listen_on(listen_addr).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = stub.select_next_some().await {
+                break address;
+            }
+        };
+        // Reservations hand out the external addresses of the relay.
+        stub.add_external_address(addr.clone());
+        let relay = addr.with(Protocol::P2p(*stub.local_peer_id()));
+
+        let (identified, listen_addrs) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                if let SwarmEvent::Behaviour(StubRelayEvent::Identify(
+                    identify::Event::Received { info, .. },
+                )) = stub.select_next_some().await
+                {
+                    let _ = identified.send(info.listen_addrs);
+                }
+            }
+        });
+        (relay, listen_addrs)
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let (relay, mut listen_addrs) = stub_relay().await;
+        // The relay can't dial the node back over QUIC, so it finds the node private.
+        let (_, node) = node(SwarmConfig {
+            autonat: autonat::Config {
+                boot_delay: Duration::from_millis(100),
+                retry_interval: Duration::from_millis(500),
+                only_global_ips: false,
+                ..Default::default()
+            },
+            relays: vec![relay.clone()],
+            ..config(&["/ip4/127.0.0.1/udp/0/quic-v1"])
+        });
+        let mut events = Box::pin(node.subscribe());
+
+        let circuit = relay.with(Protocol::P2pCircuit).to_string();
+        let reserved = |e: &PeerEvent| match e {
+            PeerEvent::NewListenAddr { addr } => addr.to_string().starts_with(&circuit),
+            _ => false,
+        };
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut events, reserved).await else {
+            unreachable!();
+        };
+        assert_eq!(node.reachability().await, Reachability::Private);
+        assert!(node.external_addrs().await.contains(&addr));
+
+        // The circuit address reaches peers over identify.
+        let told = async {
+            while let Some(addrs) = listen_addrs.recv().await {
+                if addrs.contains(&addr) {
+                    return;
+                }
+            }
+        };
+        let told = tokio::time::timeout(Duration::from_secs(10), told);
+        assert!(told.await.is_ok(), "{addr} not told to the relay");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (b_id, b) = node(config(&[]));
+        let (c_id, c) = node(config(&[]));
+        let (_, a) = node(SwarmConfig {
+            idle_timeout: Duration::from_millis(500),
+            pinned: [c_id].into(),
+            ..config(&["/ip4/127.0.0.1/tcp/0"])
+        });
+        let mut events = Box::pin(a.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut events, listening).await else {
+            unreachable!();
+        };
+        b.dial(addr.clone());
+        next_matching(&mut events, connected(b_id)).await;
+        c.dial(addr);
+        next_matching(&mut events, connected(c_id)).await;
+
+        // Left idle, the connection to b is closed while the one to c is kept.
+        let disconnected = |e: &PeerEvent| matches!(e, PeerEvent::PeerDisconnected { .. });
+        let PeerEvent::PeerDisconnected { peer } = next_matching(&mut events, disconnected).await
+        else {
+            unreachable!();
+        };
+        assert_eq!(peer, b_id);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(a.connections().await.established, 1);
+    }
+
+    // Resolver knowing only the dnsaddr records of bootstrap.example.
+    struct Bootstrap(Vec<String>);
+
+    impl crate::dns::Resolver for Bootstrap {
+        fn lookup_ip<'a>(
+            &'a self,
+            host: &'a str,
+        ) -> BoxFuture<'a, Result<crate::dns::Answer<std::net::IpAddr>, String>> {
+            Box::pin(async move { Err(format!("{host} not found")) })
+        }
+
+        fn lookup_txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> BoxFuture<'a, Result<crate::dns::Answer<String>, String>> {
+            let records = match name {
+                "_dnsaddr.bootstrap.example" => Ok(self.0.clone()),
+                _ => Err(format!("{name} not found")),
+            };
+            Box::pin(async move {
+                Ok(crate::dns::Answer {
+                    records: records?,
+                    ttl: Duration::from_secs(60),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dial_dns() {
+        let (b_id, b) = node(config(&["/ip4/127.0.0.1/tcp/0"]));
+        let (c_id, c) = node(config(&["/ip4/127.0.0.1/tcp/0"]));
+        let mut records = Vec::new();
+        for (id, node) in [(b_id, &b), (c_id, &c)] {
+            let mut events = Box::pin(node.subscribe());
+            let PeerEvent::NewListenAddr { addr } = next_matching(&mut events, listening).await
+            else {
+                unreachable!();
+            };
+            records.push(format!("dnsaddr={}", addr.with(Protocol::P2p(id))));
+        }
+        let (_, a) = node(SwarmConfig {
+            dns: Dns::new(Bootstrap(records)),
+            ..config(&[])
+        });
+        let mut events = Box::pin(a.subscribe());
+
+        // Each address the name resolves to is dialed.
+        a.dial("/dnsaddr/bootstrap.example".parse().unwrap());
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let connected = |e: &PeerEvent| matches!(e, PeerEvent::PeerConnected { .. });
+            let PeerEvent::PeerConnected { peer, .. } = next_matching(&mut events, connected).await
+            else {
+                unreachable!();
+            };
+            peers.push(peer);
+        }
+        peers.sort();
+        let mut expected = vec![b_id, c_id];
+        expected.sort();
+        assert_eq!(peers, expected);
+
+        a.dial("/dns4/missing.example/tcp/4001".parse().unwrap());
+        let failed = |e: &PeerEvent| matches!(e, PeerEvent::DialFailure { .. });
+        let PeerEvent::DialFailure { peer, error } = next_matching(&mut events, failed).await
+        else {
+            unreachable!();
+        };
+        assert_eq!(peer, None);
+        assert!(error.contains("missing.example"), "{error}");
+    }
+
+    // Publish data on topic once some peer subscribed to it.
+    async fn publish_when_heard(pubsub: &Pubsub, topic: &str, data: &str) {
+        for _ in 0..100 {
+            match pubsub.publish(topic, data).await {
+                Err(pubsub::Error::NoPeers) => tokio::time::sleep(Duration::from_millis(100)).await,
+                result => return result.unwrap(),
+            }
+        }
+        panic!("nobody subscribed to {topic}");
+    }
+
+    #[tokio::test]
+    async fn test_pubsub() {
+        let ((a_id, a), (_, b)) = pair(SwarmConfig::default()).await;
+        let mut messages = b.pubsub().subscribe("topic");
+        let pubsub = a.pubsub();
+
+        publish_when_heard(&pubsub, "topic", "0").await;
+        for i in 1..5 {
+            pubsub.publish("topic", i.to_string()).await.unwrap();
+        }
+        for i in 0..5 {
+            let next = tokio::time::timeout(Duration::from_secs(10), messages.next());
+            let message = next.await.unwrap().unwrap().unwrap();
+            assert_eq!(message.source, a_id);
+            assert_eq!(message.topic, "topic");
+            assert_eq!(message.data, i.to_string().as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth() {
+        let ((a_id, a), (b_id, b)) = pair(SwarmConfig::default()).await;
+        let mut messages = b.pubsub().subscribe("topic");
+        let pubsub = a.pubsub();
+        publish_when_heard(&pubsub, "topic", "0").await;
+        messages.next().await.unwrap().unwrap();
+        let traffic = |node: &SwarmService, peer: PeerId| {
+            // Messages go over the streams negotiated for gossipsub.
+            let gossip =
+                |(p, protocol): &(PeerId, String)| *p == peer && protocol.starts_with("/meshsub/");
+            let traffic = node
+                .bandwidth_report()
+                .into_iter()
+                .filter(|(key, _)| gossip(key));
+            traffic.fold(Traffic::default(), |sum, (_, traffic)| Traffic {
+                inbound: sum.inbound + traffic.inbound,
+                outbound: sum.outbound + traffic.outbound,
+            })
+        };
+        let (sent, received) = (traffic(&a, b_id), traffic(&b, a_id));
+
+        // Ten messages of 50kB, each sent once and counted on both ends.
+        const SIZE: u64 = 10 * 50_000;
+        for i in 0..10u8 {
+            pubsub.publish("topic", vec![i; 50_000]).await.unwrap();
+        }
+        for _ in 0..10 {
+            let next = tokio::time::timeout(Duration::from_secs(10), messages.next());
+            next.await.unwrap().unwrap().unwrap();
+        }
+        // Framing and signatures add a little.
+        let within = |bytes: u64| (SIZE..SIZE + SIZE / 20).contains(&bytes);
+        let (sent, received) = (
+            traffic(&a, b_id).outbound - sent.outbound,
+            traffic(&b, a_id).inbound - received.inbound,
+        );
+        assert!(within(sent), "{sent} bytes sent");
+        assert!(within(received), "{received} bytes received");
+    }
+
+    #[tokio::test]
+    async fn test_validator() {
+        let ((_, a), (_, b)) = pair(SwarmConfig::default()).await;
+        b.pubsub()
+            .set_validator("topic", |message| match message.data.starts_with(b"bad") {
+                true => ValidationResult::Reject,
+                false => ValidationResult::Accept,
+            });
+        let mut messages = b.pubsub().subscribe("topic");
+        let pubsub = a.pubsub();
+
+        publish_when_heard(&pubsub, "topic", "0").await;
+        for data in ["bad", "1", "bad", "2"] {
+            pubsub.publish("topic", data).await.unwrap();
+        }
+        for i in 0..3 {
+            let next = tokio::time::timeout(Duration::from_secs(10), messages.next());
+            let message = next.await.unwrap().unwrap().unwrap();
+            assert_eq!(message.data, i.to_string().as_bytes());
+        }
+    }
+
+    // Peer dialing addr, and publishing unsigned messages in the name of author once some peer
+    // subscribed to their topic.
+    fn forger(
+        addr: Multiaddr,
+        author: PeerId,
+    ) -> (PeerId, mpsc::UnboundedSender<(String, String)>) {
+        let mut forger = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|_| {
+                let config = gossipsub::ConfigBuilder::default()
+                    .validation_mode(gossipsub::ValidationMode::Permissive)
+                    .build()
+                    .unwrap();
+                gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Author(author), config)
+                    .unwrap()
+            })
+            .unwrap()
+            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        forger.dial(addr).unwrap();
+        let forger_id = *forger.local_peer_id();
+
+        let (publish, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut pending = VecDeque::new();
+            let mut retry = tokio::time::interval(Duration::from_millis(100));
+            loop {
+                tokio::select! {
+                    _ = forger.select_next_some() => {}
+                    Some(request) = requests.recv() => pending.push_back(request),
+                    _ = retry.tick() => {}
+                }
+                while let Some((topic, data)) = pending.front().cloned() {
+                    let topic = gossipsub::IdentTopic::new(topic);
+                    match forger.behaviour_mut().publish(topic, data) {
+                        Err(gossipsub::PublishError::InsufficientPeers) => break,
+                        _ => pending.pop_front(),
+                    };
+                }
+            }
+        });
+        (forger_id, publish)
+    }
+
+    #[tokio::test]
+    async fn test_signing() {
+        let ((_, a), (b_id, b)) = pair(SwarmConfig::default()).await;
+        let mut strict = a.pubsub().subscribe("strict");
+        let mut legacy = a.pubsub().subscribe("legacy");
+        a.pubsub().set_signing("legacy", Signing::Permissive);
+
+        // Signed by b, checked by a.
+        publish_when_heard(&b.pubsub(), "strict", "signed").await;
+        let next = tokio::time::timeout(Duration::from_secs(10), strict.next());
+        let message = next.await.unwrap().unwrap().unwrap();
+        assert_eq!(message.source, b_id);
+        assert_eq!(message.data, b"signed");
+
+        // Claims to come from b, without a signature to show for it.
+        let (forger_id, publish) = forger(a.listen_addrs().await.remove(0), b_id);
+        publish.send(("strict".into(), "forged".into())).unwrap();
+        publish.send(("legacy".into(), "forged".into())).unwrap();
+        let next = tokio::time::timeout(Duration::from_secs(10), legacy.next());
+        let message = next.await.unwrap().unwrap().unwrap();
+        // Taken for what it is, a message from the forger.
+        assert_eq!(message.source, forger_id);
+        assert_eq!(message.data, b"forged");
+
+        // The forgery on the strict topic came first, and was received but not delivered.
+        assert_eq!(a.pubsub().info("strict").await.received, 2);
+        assert!(strict.next().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let ((a_id, a), (b_id, b)) = pair(SwarmConfig::default()).await;
+        let mut requests = b.pubsub().subscribe("echo");
+        let pubsub = b.pubsub();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = requests.next().await {
+                let request = pubsub::Request::decode(&message.data).unwrap();
+                let reply = request.payload.to_ascii_uppercase();
+                pubsub.reply(&request, reply).await.unwrap();
+            }
+        });
+
+        let pubsub = a.pubsub();
+        let timeout = Duration::from_secs(10);
+        for _ in 0..100 {
+            match pubsub.request("echo", "hello", timeout).await {
+                Err(pubsub::Error::NoPeers) => tokio::time::sleep(Duration::from_millis(100)).await,
+                reply => {
+                    let reply = reply.unwrap();
+                    assert_eq!(reply.source, b_id);
+                    assert_eq!(reply.data, b"HELLO");
+                    // Done with the reply topic, a leaves it.
+                    return left(&b, a_id, &reply.topic).await;
+                }
+            }
+        }
+        panic!("nobody subscribed to echo");
+    }
+
+    // Wait for node to hear that peer left topic.
+    async fn left(node: &SwarmService, peer: PeerId, topic: &str) {
+        for _ in 0..100 {
+            if !node.pubsub().all_peers(topic).await.contains(&peer) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("{peer} never left {topic}");
+    }
+
+    #[tokio::test]
+    async fn test_peer_scoring() {
+        let scoring = SwarmConfig {
+            peer_scoring: Some(Scoring::default()),
+            ..Default::default()
+        };
+        let ((_, a), (b_id, b)) = pair(scoring).await;
+        let pubsub = a.pubsub();
+        pubsub.set_validator("topic", |_| ValidationResult::Reject);
+        let _subscriptions = [&a, &b].map(|node| node.pubsub().subscribe("topic"));
+        for _ in 0..100 {
+            if pubsub.mesh_peers("topic").await.contains(&b_id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(pubsub.peer_score(b_id).await.unwrap() >= 0.0);
+
+        // Every message of b is invalid, until a prunes it at a heartbeat.
+        publish_when_heard(&b.pubsub(), "topic", "bad").await;
+        for _ in 0..20 {
+            b.pubsub().publish("topic", "bad").await.unwrap();
+        }
+        for _ in 0..100 {
+            let score = pubsub.peer_score(b_id).await.unwrap();
+            if score < 0.0 && !pubsub.mesh_peers("topic").await.contains(&b_id) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("b was never pruned");
+    }
+
+    #[tokio::test]
+    async fn test_streams() {
+        use futures::{AsyncReadExt, AsyncWriteExt};
+
+        let ((a_id, a), (_, b)) = pair(SwarmConfig::default()).await;
+        let mut incoming = a.streams().accept(crate::RPC_PROTOCOL).unwrap();
+        let opened = b.streams().open_stream(a_id, crate::RPC_PROTOCOL).await;
+        let mut stream = opened.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.close().await.unwrap();
+
+        let (peer, mut stream) = incoming.next().await.unwrap();
+        assert_eq!(peer, b.peer_id());
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_topic_info() {
+        let ((a_id, a), (b_id, b)) = pair(SwarmConfig::default()).await;
+        let (c_id, c) = node(config(&[]));
+        let mut c_events = Box::pin(c.subscribe());
+        c.dial(a.listen_addrs().await.remove(0));
+        next_matching(&mut c_events, connected(a_id)).await;
+        let _subscriptions = [&a, &b, &c].map(|node| node.pubsub().subscribe("topic"));
+
+        // Subscribers join the mesh of a at its next heartbeats.
+        let pubsub = a.pubsub();
+        let mut mesh = Vec::new();
+        for _ in 0..100 {
+            mesh = pubsub.mesh_peers("topic").await;
+            if mesh.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        mesh.sort();
+        let mut subscribers = vec![b_id, c_id];
+        subscribers.sort();
+        assert_eq!(mesh, subscribers);
+        let mut all = pubsub.all_peers("topic").await;
+        all.sort();
+        assert_eq!(all, subscribers);
+
+        b.pubsub().publish("topic", "hello").await.unwrap();
+        assert_eq!(b.pubsub().info("topic").await.published, 1);
+        for _ in 0..100 {
+            if pubsub.info("topic").await.received == 1 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("a never received the message");
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let metrics = Metrics::new();
+        let addr = crate::metrics::serve("127.0.0.1:0".parse().unwrap(), metrics.clone()).unwrap();
+        let ((_, a), (_, b)) = pair(SwarmConfig {
+            metrics: Some(metrics),
+            ..Default::default()
+        })
+        .await;
+        let _messages = b.pubsub().subscribe("topic");
+        publish_when_heard(&a.pubsub(), "topic", "hello").await;
+
+        let uri = format!("http://{addr}/metrics").parse().unwrap();
+        let response = hyper::Client::new().get(uri).await.unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("ww_connected_peers 1\n"), "{body}");
+        let published = r#"ww_pubsub_messages_total{topic="topic",direction="out"} 1"#;
+        assert!(body.contains(published), "{body}");
+        assert!(
+            body.contains("ww_rpc_call_duration_seconds_count 0"),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_outbound() {
+        // Accepts connections but never completes a handshake, so dials to it stay in flight.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let (_, service) = node(SwarmConfig {
+            max_pending_outbound: 1,
+            ..config(&[])
+        });
+
+        for _ in 0..3 {
+            service.dial(addr.clone());
+        }
+        let connections = service.connections().await;
+        assert_eq!(connections.pending_outbound, 1);
+        assert_eq!(connections.queued_dials, 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_inbound() {
+        let (_, service) = node(SwarmConfig {
+            max_pending_inbound: 1,
+            ..config(&["/ip4/127.0.0.1/tcp/0"])
+        });
+        let mut events = Box::pin(service.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut events, listening).await else {
+            unreachable!();
+        };
+        let Some(Protocol::Tcp(port)) = addr.iter().nth(1) else {
+            unreachable!();
+        };
+
+        // Connect without ever handshaking, so the connections stay pending.
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            streams.push(
+                tokio::net::TcpStream::connect(("127.0.0.1", port))
+                    .await
+                    .unwrap(),
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(service.connections().await.pending_inbound, 1);
+    }
+
+    #[test]
+    fn test_new_outside_runtime() {
+        let id_keys = identity::Keypair::generate_ed25519();
+        let result = SwarmService::new(id_keys, config(&["/ip4/127.0.0.1/tcp/0"]));
+        assert!(matches!(result, Err(Error::NoRuntime)));
+    }
+
+    #[tokio::test]
+    async fn test_failed_listen() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = format!("/ip4/127.0.0.1/tcp/{}", taken.local_addr().unwrap().port());
+        // The first listener is up by the time the second fails, and is closed with the swarm.
+        let conflicting = config(&["/ip4/127.0.0.1/tcp/0", &taken]);
+        let result = SwarmService::new(identity::Keypair::generate_ed25519(), conflicting);
+        assert!(matches!(result, Err(Error::Listen(..))));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stopped() {
+        let (_, mut service) = node(config(&[]));
+        // The event loop is gone, as if it had crashed.
+        service.task.as_ref().unwrap().abort();
+        tokio::task::yield_now().await;
+        assert!(service.shutdown(Duration::from_secs(1)).await.is_err());
+        service.shutdown(Duration::from_secs(1)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_listen_addr() {
+        let quic_only = SwarmConfig {
+            tcp: false,
+            ..config(&["/ip4/127.0.0.1/udp/0/quic-v1"])
+        };
+        let (_, service) = node(quic_only);
+        let mut events = Box::pin(service.subscribe());
+
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut events, listening).await else {
+            unreachable!();
+        };
+        assert_eq!(Transport::of(&addr), Some(Transport::Quic));
+        assert!(addr
+            .iter()
+            .any(|p| matches!(p, Protocol::Udp(port) if port != 0)));
+        assert_eq!(service.listen_addrs().await, vec![addr]);
+    }
+
+    // QUIC handshakes, with the signature checks of the TLS certificates in them, run on the
+    // handshake pool. So a burst of handshakes doesn't stall the event loop, even with every
+    // task of the test on a single thread.
+    #[tokio::test]
+    async fn test_concurrent_handshakes() {
+        const DIALERS: usize = 24;
+        let quic_only = |listen_addrs: &[&str]| SwarmConfig {
+            tcp: false,
+            ..config(listen_addrs)
+        };
+        let (_, server) = node(quic_only(&["/ip4/127.0.0.1/udp/0/quic-v1"]));
+        let mut events = Box::pin(server.subscribe());
+        let PeerEvent::NewListenAddr { addr } = next_matching(&mut events, listening).await else {
+            unreachable!();
+        };
+
+        let dialers: Vec<_> = (0..DIALERS).map(|_| node(quic_only(&[])).1).collect();
+        for dialer in &dialers {
+            dialer.dial(addr.clone());
+        }
+        // The event loop keeps answering while the handshakes are in flight.
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        let mut slowest = Duration::ZERO;
+        loop {
+            let start = tokio::time::Instant::now();
+            let connections = tokio::time::timeout(Duration::from_secs(1), server.connections())
+                .await
+                .expect("event loop stalled");
+            slowest = slowest.max(start.elapsed());
+            let established = connections.established as usize;
+            if established == DIALERS {
+                break;
+            }
+            assert!(start < deadline, "{established} of {DIALERS} connected");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let answered = slowest < Duration::from_millis(500);
+        assert!(answered, "answered in {slowest:?}");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config(&["/ip4/127.0.0.1/udp/0"]).validate().is_err());
+        assert!(config(&["/dns4/localhost/tcp/0"]).validate().is_err());
+        let tcp_only = SwarmConfig {
+            quic: false,
+            ..Default::default()
+        };
+        assert!(matches!(
+            tcp_only.validate(),
+            Err(Error::Disabled(_, Transport::Quic))
+        ));
+        let none = SwarmConfig {
+            tcp: false,
+            quic: false,
+            ..config(&[])
+        };
+        assert!(matches!(none.validate(), Err(Error::NoTransport)));
+        let anonymous_relay = SwarmConfig {
+            relays: vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(matches!(anonymous_relay.validate(), Err(Error::Config(_))));
+    }
+}
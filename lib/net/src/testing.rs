@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use libp2p::Multiaddr;
+use multihash_codetable::{Code, MultihashDigest};
+use tokio::sync::oneshot;
+
+use crate::ipfs::{BlockSource, Cid, Client, Error};
+use crate::unixfs;
+
+// In-process stand-in for the HTTP API of an IPFS daemon, for tests that would otherwise need a
+// daemon listening on 127.0.0.1:5001. Only the endpoints the client uses are served. Blocks are
+// kept in memory by multihash, as kubo keys its blockstore, so they can be fetched by any CID
+// of their data. The server runs on a thread of its own until the daemon is dropped, so it
+// serves clients whichever runtime they block or run on.
+pub struct Daemon {
+    addr: Multiaddr,
+    state: Arc<State>,
+    // Stops the server once dropped.
+    _shutdown: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    blocks: Mutex<HashMap<Vec<u8>, Bytes>>,
+    // Pinned CIDs, with whether they are pinned recursively.
+    pins: Mutex<HashMap<String, bool>>,
+    // IPNS names with the '/ipfs/...' paths they resolve to.
+    names: Mutex<HashMap<String, String>>,
+    // Endpoints requested so far, e.g. "block/get", in order.
+    requests: Mutex<Vec<String>>,
+    // How long every request is held before it is answered.
+    delay: Mutex<Duration>,
+}
+
+impl Daemon {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("binding the stub daemon");
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let state = Arc::new(State::default());
+
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let serving = (state.clone(), addr.clone());
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let (state, addr) = serving;
+                // Files are read through a client of the blocks of the daemon itself.
+                let mut reader = Client::new(addr);
+                reader.local = Arc::new(Blocks(state.clone()));
+                let make_service = make_service_fn(move |_| {
+                    let (state, reader) = (state.clone(), reader.clone());
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |request| {
+                            let (state, reader) = (state.clone(), reader.clone());
+                            async move {
+                                let response = serve(&state, &reader, request).await;
+                                Ok::<_, Infallible>(response)
+                            }
+                        }))
+                    }
+                });
+                let server = hyper::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(make_service);
+                let _ = server
+                    .with_graceful_shutdown(async {
+                        let _ = stopped.await;
+                    })
+                    .await;
+            });
+        });
+        Self {
+            addr,
+            state,
+            _shutdown: shutdown,
+        }
+    }
+
+    pub fn addr(&self) -> Multiaddr {
+        self.addr.clone()
+    }
+
+    // Client of the daemon.
+    pub fn client(&self) -> Client {
+        Client::new(self.addr())
+    }
+
+    // Hold every request for delay before answering it, e.g. to make clients time out.
+    pub fn set_delay(&self, delay: Duration) {
+        *self.state.delay.lock().unwrap() = delay;
+    }
+
+    // Resolve the IPNS name to path, an '/ipfs/...' path, or to nothing.
+    pub fn set_name(&self, name: &str, path: Option<&str>) {
+        let mut names = self.state.names.lock().unwrap();
+        match path {
+            Some(path) => names.insert(name.to_owned(), path.to_owned()),
+            None => names.remove(name),
+        };
+    }
+
+    // How many requests were made to endpoint, e.g. "block/get".
+    pub fn requests(&self, endpoint: &str) -> usize {
+        let requests = self.state.requests.lock().unwrap();
+        requests.iter().filter(|e| *e == endpoint).count()
+    }
+}
+
+// Blocks of the daemon, as a source for its own client.
+struct Blocks(Arc<State>);
+
+impl Blocks {
+    fn get(&self, cid: &Cid) -> Option<Bytes> {
+        self.0
+            .blocks
+            .lock()
+            .unwrap()
+            .get(&cid.hash().to_bytes())
+            .cloned()
+    }
+}
+
+impl BlockSource for Blocks {
+    fn get_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Bytes, Error>> {
+        let block = self.get(cid);
+        Box::pin(async move { block.ok_or_else(|| Error::NotFound(format!("block {cid}"))) })
+    }
+}
+
+async fn serve(state: &Arc<State>, reader: &Client, request: Request<Body>) -> Response<Body> {
+    let endpoint = request
+        .uri()
+        .path()
+        .trim_start_matches("/api/v0/")
+        .to_owned();
+    let arg = query(&request, "arg");
+    state.requests.lock().unwrap().push(endpoint.clone());
+    let delay = *state.delay.lock().unwrap();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    let result = match endpoint.as_str() {
+        "block/get" => block_get(state, arg),
+        "block/put" => block_put(state, request).await,
+        "cat" => match reader
+            .get_file_range(&arg.unwrap_or_default(), 0, u64::MAX)
+            .await
+        {
+            Ok(data) => Ok(Body::from(data)),
+            Err(e) => Err(e.to_string()),
+        },
+        "version" => Ok(json(&format!(
+            r#"{{"Version":"0.0.0","Commit":"","Repo":"16","System":"{}","Golang":"none"}}"#,
+            std::env::consts::ARCH
+        ))),
+        "pin/add" => {
+            let recursive = query(&request, "recursive").as_deref() != Some("false");
+            let cid = arg.unwrap_or_default();
+            let mut pins = state.pins.lock().unwrap();
+            if !recursive && pins.get(&cid) == Some(&true) {
+                Err(format!("{cid} already pinned recursively"))
+            } else {
+                pins.insert(cid.clone(), recursive);
+                Ok(json(&format!(r#"{{"Pins":["{cid}"]}}"#)))
+            }
+        }
+        "pin/rm" => {
+            let cid = arg.unwrap_or_default();
+            match state.pins.lock().unwrap().remove(&cid) {
+                Some(_) => Ok(json(&format!(r#"{{"Pins":["{cid}"]}}"#))),
+                None => Err("not pinned or pinned indirectly".to_owned()),
+            }
+        }
+        "pin/ls" => {
+            let typ = query(&request, "type").unwrap_or_else(|| "all".to_owned());
+            let pins = state.pins.lock().unwrap();
+            let keys: Vec<String> = pins
+                .iter()
+                .map(|(cid, recursive)| (cid, if *recursive { "recursive" } else { "direct" }))
+                .filter(|(_, pin)| typ == "all" || *pin == typ)
+                .map(|(cid, pin)| format!(r#""{cid}":{{"Type":"{pin}"}}"#))
+                .collect();
+            Ok(json(&format!(r#"{{"Keys":{{{}}}}}"#, keys.join(","))))
+        }
+        "name/resolve" => {
+            let name = arg.unwrap_or_default();
+            let name = name.trim_start_matches("/ipns/");
+            match state.names.lock().unwrap().get(name) {
+                Some(path) => Ok(json(&format!(r#"{{"Path":"{path}"}}"#))),
+                None => Err(format!("could not resolve name {name}")),
+            }
+        }
+        _ => Err(format!("unknown endpoint {endpoint}")),
+    };
+    match result {
+        Ok(body) => Response::new(body),
+        // Errors are reported as kubo reports them.
+        Err(message) => {
+            let message = message.replace('"', "'");
+            let body = format!(r#"{{"Message":"{message}","Code":0,"Type":"error"}}"#);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(json(&body))
+                .unwrap()
+        }
+    }
+}
+
+fn block_get(state: &State, arg: Option<String>) -> Result<Body, String> {
+    let arg = arg.unwrap_or_default();
+    let cid = Cid::try_from(arg.as_str()).map_err(|e| format!("invalid cid {arg}: {e}"))?;
+    let blocks = state.blocks.lock().unwrap();
+    match blocks.get(&cid.hash().to_bytes()) {
+        Some(block) => Ok(Body::from(block.clone())),
+        None => Err("block was not found locally (offline)".to_owned()),
+    }
+}
+
+// Store the block in the multipart form the client sends it in. Blocks are hashed with
+// sha2-256, which is all the client hashes with.
+async fn block_put(state: &State, request: Request<Body>) -> Result<Body, String> {
+    let content_type = request
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let boundary = match content_type.split_once("boundary=") {
+        Some((_, boundary)) => format!("--{}", boundary.trim_matches('"')),
+        None => return Err(format!("not a multipart form: {content_type}")),
+    };
+    let form = hyper::body::to_bytes(request.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    let block =
+        part(&form, boundary.as_bytes()).ok_or_else(|| "malformed multipart form".to_owned())?;
+    let cid = Cid::new_v1(unixfs::RAW, Code::Sha2_256.digest(block));
+    let size = block.len();
+    let mut blocks = state.blocks.lock().unwrap();
+    blocks.insert(cid.hash().to_bytes(), form.slice_ref(block));
+    Ok(json(&format!(r#"{{"Key":"{cid}","Size":{size}}}"#)))
+}
+
+// Contents of the only part of a multipart form.
+fn part<'a>(form: &'a [u8], boundary: &[u8]) -> Option<&'a [u8]> {
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    };
+    let start = find(form, boundary)?;
+    let headers = &form[start..];
+    let start = start + find(headers, b"\r\n\r\n")? + 4;
+    let end = [b"\r\n".as_slice(), boundary].concat();
+    let len = find(&form[start..], &end)?;
+    Some(&form[start..start + len])
+}
+
+fn json(body: &str) -> Body {
+    Body::from(body.to_owned())
+}
+
+// Value of the query parameter key of request, percent-decoded.
+fn query(request: &Request<Body>, key: &str) -> Option<String> {
+    let query = request.uri().query()?;
+    let value = query.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        (k == key).then_some(v)
+    })?;
+    let mut decoded = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                decoded.push(u8::from_str_radix(&hex, 16).ok()?);
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
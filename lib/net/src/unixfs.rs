@@ -0,0 +1,524 @@
+use std::future::Future;
+use std::ops::Range;
+
+use bytes::Bytes;
+use cid::Cid;
+
+use crate::hamt;
+use crate::ipfs::Error;
+
+// Multicodec of dag-pb encoded blocks.
+pub const DAG_PB: u64 = 0x70;
+// Multicodec of raw leaf blocks.
+pub const RAW: u64 = 0x55;
+
+// UnixFS node types, as defined in the unixfs.proto Data.DataType enum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeType {
+    Raw,
+    Directory,
+    File,
+    Metadata,
+    Symlink,
+    HamtShard,
+}
+
+impl NodeType {
+    fn to_u64(self) -> u64 {
+        match self {
+            NodeType::Raw => 0,
+            NodeType::Directory => 1,
+            NodeType::File => 2,
+            NodeType::Metadata => 3,
+            NodeType::Symlink => 4,
+            NodeType::HamtShard => 5,
+        }
+    }
+
+    fn from_u64(value: u64) -> Result<Self, Error> {
+        match value {
+            0 => Ok(NodeType::Raw),
+            1 => Ok(NodeType::Directory),
+            2 => Ok(NodeType::File),
+            3 => Ok(NodeType::Metadata),
+            4 => Ok(NodeType::Symlink),
+            5 => Ok(NodeType::HamtShard),
+            other => Err(Error::Decode(format!("unknown UnixFS type {other}"))),
+        }
+    }
+}
+
+// Named link from a dag-pb node to one of its children.
+#[derive(Clone, Debug)]
+pub struct Link {
+    pub cid: Cid,
+    pub name: String,
+    pub tsize: u64,
+}
+
+// Decoded UnixFS node.
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub typ: NodeType,
+    pub links: Vec<Link>,
+    // Inline data. File contents for leaves, the target for symlinks.
+    pub data: Bytes,
+    pub filesize: Option<u64>,
+    // Size of the file data held under each link, in link order.
+    pub blocksizes: Vec<u64>,
+    pub hash_type: Option<u64>,
+    pub fanout: Option<u64>,
+}
+
+impl Node {
+    // Directory node linking to the given named entries.
+    pub fn directory(links: Vec<Link>) -> Self {
+        Self {
+            typ: NodeType::Directory,
+            links,
+            data: Bytes::new(),
+            filesize: None,
+            blocksizes: Vec::new(),
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    // File node holding its data inline.
+    pub fn file(data: Bytes) -> Self {
+        Self {
+            typ: NodeType::File,
+            links: Vec::new(),
+            filesize: Some(data.len() as u64),
+            data,
+            blocksizes: Vec::new(),
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    // Root of a file whose data is split across the linked blocks, in order.
+    pub fn file_root(links: Vec<Link>) -> Self {
+        let blocksizes: Vec<u64> = links.iter().map(|link| link.tsize).collect();
+        Self {
+            typ: NodeType::File,
+            links,
+            data: Bytes::new(),
+            filesize: Some(blocksizes.iter().sum()),
+            blocksizes,
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    // Encode the node as a dag-pb block.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut unixfs = Vec::new();
+        put_varint_field(&mut unixfs, 1, self.typ.to_u64());
+        if !self.data.is_empty() {
+            put_bytes_field(&mut unixfs, 2, &self.data);
+        }
+        if let Some(filesize) = self.filesize {
+            put_varint_field(&mut unixfs, 3, filesize);
+        }
+        for size in &self.blocksizes {
+            put_varint_field(&mut unixfs, 4, *size);
+        }
+        if let Some(hash_type) = self.hash_type {
+            put_varint_field(&mut unixfs, 5, hash_type);
+        }
+        if let Some(fanout) = self.fanout {
+            put_varint_field(&mut unixfs, 6, fanout);
+        }
+
+        // dag-pb requires the links to be serialized before the data.
+        let mut block = Vec::new();
+        for link in &self.links {
+            let mut pb_link = Vec::new();
+            put_bytes_field(&mut pb_link, 1, &link.cid.to_bytes());
+            put_bytes_field(&mut pb_link, 2, link.name.as_bytes());
+            put_varint_field(&mut pb_link, 3, link.tsize);
+            put_bytes_field(&mut block, 2, &pb_link);
+        }
+        put_bytes_field(&mut block, 1, &unixfs);
+        block
+    }
+
+    // Decode a block according to the codec of its CID.
+    pub fn decode(cid: &Cid, block: Bytes) -> Result<Self, Error> {
+        match cid.codec() {
+            RAW => Ok(Self::raw(block)),
+            DAG_PB => Self::decode_pb(&block),
+            codec => Err(Error::Decode(format!("unsupported codec 0x{codec:x}"))),
+        }
+    }
+
+    fn raw(block: Bytes) -> Self {
+        Self {
+            typ: NodeType::Raw,
+            links: Vec::new(),
+            filesize: Some(block.len() as u64),
+            data: block,
+            blocksizes: Vec::new(),
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    // Decode a dag-pb PBNode and the UnixFS Data message it carries.
+    fn decode_pb(block: &[u8]) -> Result<Self, Error> {
+        let mut links = Vec::new();
+        let mut unixfs: &[u8] = &[];
+        let mut reader = Reader::new(block);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, Value::Bytes(bytes)) => unixfs = bytes,
+                (2, Value::Bytes(bytes)) => links.push(decode_link(bytes)?),
+                _ => {}
+            }
+        }
+
+        let mut node = Self {
+            typ: NodeType::Raw,
+            links,
+            data: Bytes::new(),
+            filesize: None,
+            blocksizes: Vec::new(),
+            hash_type: None,
+            fanout: None,
+        };
+        let mut reader = Reader::new(unixfs);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, Value::Varint(typ)) => node.typ = NodeType::from_u64(typ)?,
+                (2, Value::Bytes(data)) => node.data = Bytes::copy_from_slice(data),
+                (3, Value::Varint(size)) => node.filesize = Some(size),
+                (4, Value::Varint(size)) => node.blocksizes.push(size),
+                // Packed encoding of the repeated blocksizes field.
+                (4, Value::Bytes(packed)) => {
+                    let mut packed = Reader::new(packed);
+                    while !packed.is_empty() {
+                        node.blocksizes.push(packed.varint()?);
+                    }
+                }
+                (5, Value::Varint(hash_type)) => node.hash_type = Some(hash_type),
+                (6, Value::Varint(fanout)) => node.fanout = Some(fanout),
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.typ, NodeType::Directory | NodeType::HamtShard)
+    }
+
+    // Size of the file contents represented by the node.
+    pub fn size(&self) -> u64 {
+        self.filesize
+            .unwrap_or_else(|| self.data.len() as u64 + self.blocksizes.iter().sum::<u64>())
+    }
+
+    // Cumulative size of the block of the node and of the blocks under it, which links to the
+    // node record as their tsize.
+    pub fn dag_size(&self) -> u64 {
+        let links: u64 = self.links.iter().map(|link| link.tsize).sum();
+        self.encode().len() as u64 + links
+    }
+}
+
+// Link to the entry called name in the directory dir, navigating the shards of sharded
+// directories down to its bucket rather than going through all their entries.
+pub async fn dir_entry<F, Fut>(dir: &Node, name: &str, get_node: F) -> Result<Option<Link>, Error>
+where
+    F: Fn(Cid) -> Fut,
+    Fut: Future<Output = Result<Node, Error>>,
+{
+    match dir.typ {
+        NodeType::HamtShard => hamt::lookup(dir, name, get_node).await,
+        _ => Ok(dir.links.iter().find(|link| link.name == name).cloned()),
+    }
+}
+
+// Links to the entries of the directory dir, which are spread across the shards under it when
+// it is sharded.
+pub async fn dir_entries<F, Fut>(dir: &Node, get_node: F) -> Result<Vec<Link>, Error>
+where
+    F: Fn(Cid) -> Fut,
+    Fut: Future<Output = Result<Node, Error>>,
+{
+    match dir.typ {
+        NodeType::HamtShard => hamt::entries(dir, get_node).await,
+        _ => Ok(dir.links.clone()),
+    }
+}
+
+// Read len bytes of the file under root starting at offset, fetching only the nodes whose data
+// overlaps the range. The range is clamped to the file size, so fewer bytes are returned past
+// the end of the file.
+pub async fn read_range<F, Fut>(
+    root: &Node,
+    offset: u64,
+    len: u64,
+    mut get_node: F,
+) -> Result<Bytes, Error>
+where
+    F: FnMut(Cid) -> Fut,
+    Fut: Future<Output = Result<Node, Error>>,
+{
+    let range = offset..offset.saturating_add(len).min(root.size());
+    if range.is_empty() {
+        return Ok(Bytes::new());
+    }
+
+    let mut data = Vec::with_capacity((range.end - range.start) as usize);
+    let mut pending = Vec::new();
+    read_node(root, 0, &range, &mut data, &mut pending);
+    while let Some((cid, base)) = pending.pop() {
+        let node = get_node(cid).await?;
+        read_node(&node, base, &range, &mut data, &mut pending);
+    }
+    Ok(Bytes::from(data))
+}
+
+// Append the inline data of a node that falls in range, and queue its children overlapping the
+// range so that the next one in file order is popped first.
+fn read_node(
+    node: &Node,
+    base: u64,
+    range: &Range<u64>,
+    data: &mut Vec<u8>,
+    pending: &mut Vec<(Cid, u64)>,
+) {
+    // File nodes may carry data inline before the data of their children.
+    let inline_end = base + node.data.len() as u64;
+    let (from, to) = (range.start.max(base), range.end.min(inline_end));
+    if from < to {
+        data.extend_from_slice(&node.data[(from - base) as usize..(to - base) as usize]);
+    }
+
+    let mut start = inline_end;
+    let mut children = Vec::new();
+    for (i, link) in node.links.iter().enumerate() {
+        let size = node.blocksizes.get(i).copied().unwrap_or(link.tsize);
+        if start < range.end && start + size > range.start {
+            children.push((link.cid, start));
+        }
+        start += size;
+    }
+    pending.extend(children.into_iter().rev());
+}
+
+fn decode_link(bytes: &[u8]) -> Result<Link, Error> {
+    let mut cid = None;
+    let mut name = String::new();
+    let mut tsize = 0;
+    let mut reader = Reader::new(bytes);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (1, Value::Bytes(hash)) => cid = Some(Cid::try_from(hash)?),
+            (2, Value::Bytes(n)) => {
+                name = String::from_utf8(n.to_vec())
+                    .map_err(|_| Error::Decode("link name is not UTF-8".to_owned()))?
+            }
+            (3, Value::Varint(size)) => tsize = size,
+            _ => {}
+        }
+    }
+    let cid = cid.ok_or_else(|| Error::Decode("link without hash".to_owned()))?;
+    Ok(Link { cid, name, tsize })
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, (field << 3) | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+// Minimal protobuf wire format reader, enough for dag-pb and UnixFS messages.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value: u64 = 0;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(Error::Decode("truncated varint".to_owned()))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > self.buf.len() {
+            return Err(Error::Decode("truncated field".to_owned()));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, Value<'a>)>, Error> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            wire => return Err(Error::Decode(format!("unsupported wire type {wire}"))),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use multihash_codetable::{Code, MultihashDigest};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let child = Cid::new_v1(RAW, Code::Sha2_256.digest(b"hello"));
+        let dir = Node::directory(vec![Link {
+            cid: child,
+            name: "hello.txt".to_owned(),
+            tsize: 5,
+        }]);
+        let cid = Cid::new_v1(DAG_PB, Code::Sha2_256.digest(&dir.encode()));
+
+        let decoded = Node::decode(&cid, Bytes::from(dir.encode())).unwrap();
+        assert_eq!(decoded.typ, NodeType::Directory);
+        assert_eq!(decoded.links.len(), 1);
+        assert_eq!(decoded.links[0].cid, child);
+        assert_eq!(decoded.links[0].name, "hello.txt");
+        assert_eq!(decoded.links[0].tsize, 5);
+    }
+
+    #[test]
+    fn test_file_root_sizes() {
+        let leaf = Cid::new_v1(RAW, Code::Sha2_256.digest(b"leaf"));
+        let links = vec![
+            Link {
+                cid: leaf,
+                name: String::new(),
+                tsize: 4,
+            },
+            Link {
+                cid: leaf,
+                name: String::new(),
+                tsize: 4,
+            },
+        ];
+        let root = Node::file_root(links);
+        let cid = Cid::new_v1(DAG_PB, Code::Sha2_256.digest(&root.encode()));
+
+        let decoded = Node::decode(&cid, Bytes::from(root.encode())).unwrap();
+        assert_eq!(decoded.typ, NodeType::File);
+        assert_eq!(decoded.blocksizes, vec![4, 4]);
+        assert_eq!(decoded.size(), 8);
+    }
+
+    // File split as ["hello ", ["world", "!!"]], with the blocks it is made of.
+    fn nested_file() -> (Node, HashMap<Cid, Node>) {
+        let mut blocks = HashMap::new();
+        let mut leaf = |data: &'static [u8]| {
+            let cid = Cid::new_v1(RAW, Code::Sha2_256.digest(data));
+            blocks.insert(cid, Node::raw(Bytes::from_static(data)));
+            Link {
+                cid,
+                name: String::new(),
+                tsize: data.len() as u64,
+            }
+        };
+        let (hello, world, bang) = (leaf(b"hello "), leaf(b"world"), leaf(b"!!"));
+
+        let inner = Node::file_root(vec![world, bang]);
+        let inner_cid = Cid::new_v1(DAG_PB, Code::Sha2_256.digest(&inner.encode()));
+        let inner_link = Link {
+            cid: inner_cid,
+            name: String::new(),
+            tsize: inner.size(),
+        };
+        blocks.insert(inner_cid, inner);
+        (Node::file_root(vec![hello, inner_link]), blocks)
+    }
+
+    fn read(root: &Node, blocks: &HashMap<Cid, Node>, offset: u64, len: u64) -> (Bytes, usize) {
+        let mut fetched = 0;
+        let data = block_on(read_range(root, offset, len, |cid| {
+            fetched += 1;
+            let node = blocks.get(&cid).cloned();
+            async move { node.ok_or_else(|| Error::NotFound(cid.to_string())) }
+        }))
+        .unwrap();
+        (data, fetched)
+    }
+
+    #[test]
+    fn test_read_range_mid_file() {
+        let (root, blocks) = nested_file();
+        let (data, fetched) = read(&root, &blocks, 7, 3);
+        assert_eq!(&data[..], b"orl");
+        // Only the inner node and the "world" leaf overlap the range.
+        assert_eq!(fetched, 2);
+
+        let (data, _) = read(&root, &blocks, 4, 6);
+        assert_eq!(&data[..], b"o worl");
+    }
+
+    #[test]
+    fn test_read_range_past_eof() {
+        let (root, blocks) = nested_file();
+        let (data, _) = read(&root, &blocks, 9, 100);
+        assert_eq!(&data[..], b"d!!");
+
+        let (data, fetched) = read(&root, &blocks, 13, 10);
+        assert!(data.is_empty());
+        assert_eq!(fetched, 0);
+    }
+}
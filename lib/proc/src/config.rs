@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use net::metrics::Metrics;
+
+use crate::{DeterministicConfig, Error, Stdio};
+
+// Linear memory an instance may grow to by default, 1 GiB in 64 KiB WASM pages.
+pub const DEFAULT_MAX_MEMORY_PAGES: u32 = 16 * 1024;
+
+// Options and limits guests are run with.
+#[derive(Clone, Debug)]
+pub struct RunConfig {
+    // Fuel each instance starts with, every WASM operator it executes burning one unit. Guests
+    // that run out are stopped with a resource limit error. None leaves trusted guests
+    // unmetered.
+    pub fuel: Option<u64>,
+    // Most linear memory an instance may have, in pages. Growing past it fails in the guest,
+    // and modules requiring more fail to instantiate. Defaults to DEFAULT_MAX_MEMORY_PAGES.
+    pub max_memory_pages: u32,
+    // Wall-clock time a run may take before the guest is killed. Guests are interrupted in the
+    // syscall they are blocked in, so one spinning without making syscalls is only stopped by
+    // its fuel budget.
+    pub deadline: Option<Duration>,
+    // Where the stdout and stderr of the guest go.
+    pub stdio: Stdio,
+    // Arguments passed to the guest after its program name.
+    pub args: Vec<String>,
+    // Environment variables of the guest, as key and value pairs.
+    pub env: Vec<(String, String)>,
+    // When set, randomness and clocks are served from the seed and timestamp it holds instead
+    // of the host, so runs with the same configuration see the same values.
+    pub deterministic: Option<DeterministicConfig>,
+    // Where running instances are counted, if anywhere.
+    pub metrics: Option<Metrics>,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            max_memory_pages: DEFAULT_MAX_MEMORY_PAGES,
+            deadline: None,
+            stdio: Stdio::Inherit,
+            args: Vec::new(),
+            env: Vec::new(),
+            deterministic: None,
+            metrics: None,
+        }
+    }
+}
+
+impl RunConfig {
+    // Check that the arguments and environment can be passed to the guest as C strings, and
+    // that keys can be told apart from values.
+    pub fn validate(&self) -> Result<(), Error> {
+        for arg in &self.args {
+            if arg.contains('\0') {
+                return Err(Error::Config(format!("argument {arg:?} contains NUL")));
+            }
+        }
+        for (key, value) in &self.env {
+            if key.is_empty() || key.contains(['=', '\0']) {
+                return Err(Error::Config(format!(
+                    "invalid environment variable {key:?}"
+                )));
+            }
+            if value.contains('\0') {
+                return Err(Error::Config(format!("value of {key} contains NUL")));
+            }
+        }
+        Ok(())
+    }
+}
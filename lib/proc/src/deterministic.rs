@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use wasmer::{Function, FunctionEnv, FunctionEnvMut, Imports, Instance, Memory, Store};
+
+// WASI namespaces the overridden calls are defined in, which share their signatures.
+const NAMESPACES: [&str; 2] = ["wasi_snapshot_preview1", "wasix_32v1"];
+
+// Errnos returned to the guest.
+const ESUCCESS: i32 = 0;
+const EFAULT: i32 = 21;
+
+// Sources the nondeterministic WASI calls are served from in deterministic runs.
+#[derive(Clone, Debug)]
+pub struct DeterministicConfig {
+    // Seed of the generator random bytes are drawn from.
+    pub seed: u64,
+    // Time every clock reads, as the duration since the Unix epoch.
+    pub timestamp: Duration,
+}
+
+struct State {
+    rng: StdRng,
+    timestamp: u64,
+    // Memory of the instance, set once it is created.
+    memory: Option<Memory>,
+}
+
+impl State {
+    fn write(&self, store: &impl wasmer::AsStoreRef, ptr: i32, data: &[u8]) -> i32 {
+        let Some(memory) = &self.memory else {
+            return EFAULT;
+        };
+        match memory.view(store).write(ptr as u32 as u64, data) {
+            Ok(()) => ESUCCESS,
+            Err(_) => EFAULT,
+        }
+    }
+}
+
+// Replacements for the WASI calls reading randomness and clocks, so that runs with the same
+// seed and timestamp see the same values.
+pub struct Deterministic {
+    env: FunctionEnv<State>,
+}
+
+impl Deterministic {
+    pub fn new(store: &mut Store, config: &DeterministicConfig) -> Self {
+        let state = State {
+            rng: StdRng::seed_from_u64(config.seed),
+            timestamp: config.timestamp.as_nanos() as u64,
+            memory: None,
+        };
+        Self {
+            env: FunctionEnv::new(store, state),
+        }
+    }
+
+    // Define the replacements in imports, over the calls of the WASI environment.
+    pub fn define(&self, store: &mut Store, imports: &mut Imports) {
+        for namespace in NAMESPACES {
+            let random_get = Function::new_typed_with_env(store, &self.env, random_get);
+            imports.define(namespace, "random_get", random_get);
+            let clock_time_get = Function::new_typed_with_env(store, &self.env, clock_time_get);
+            imports.define(namespace, "clock_time_get", clock_time_get);
+            let clock_res_get = Function::new_typed_with_env(store, &self.env, clock_res_get);
+            imports.define(namespace, "clock_res_get", clock_res_get);
+        }
+    }
+
+    // Give the replacements access to the memory of instance, which they write their results
+    // to.
+    pub fn attach(
+        &self,
+        store: &mut Store,
+        instance: &Instance,
+    ) -> Result<(), wasmer::ExportError> {
+        let memory = instance.exports.get_memory("memory")?.clone();
+        self.env.as_mut(store).memory = Some(memory);
+        Ok(())
+    }
+}
+
+fn random_get(mut env: FunctionEnvMut<State>, buf: i32, len: i32) -> i32 {
+    let (state, store) = env.data_and_store_mut();
+    let mut bytes = vec![0; len as u32 as usize];
+    state.rng.fill_bytes(&mut bytes);
+    state.write(&store, buf, &bytes)
+}
+
+fn clock_time_get(env: FunctionEnvMut<State>, _clock: i32, _precision: i64, time: i32) -> i32 {
+    let state = env.data();
+    state.write(&env, time, &state.timestamp.to_le_bytes())
+}
+
+fn clock_res_get(env: FunctionEnvMut<State>, _clock: i32, resolution: i32) -> i32 {
+    env.data().write(&env, resolution, &1u64.to_le_bytes())
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use net::ipfs;
+
+#[derive(Debug)]
+pub enum Error {
+    // The module could not be fetched from IPFS.
+    Fetch(ipfs::Error),
+    // The module could not be read from a local file.
+    Read(PathBuf, io::Error),
+    // The fetched bytes are not a WASM module.
+    Invalid(String),
+    // The options a guest is run with are unusable.
+    Config(String),
+    // The module failed to compile.
+    Compile(wasmer::CompileError),
+    // The guest trapped.
+    Trap(wasmer::RuntimeError),
+    // The guest was stopped for going over one of the limits it was run with.
+    ResourceLimit(String),
+    // The guest was killed for running past its deadline.
+    DeadlineExceeded(Duration),
+    // The guest was stopped by the host, e.g. on Ctrl-C.
+    Interrupted,
+    // A snapshot is malformed or doesn't fit the instance it is restored into.
+    Snapshot(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Fetch(e) => write!(f, "fetching module: {e}"),
+            Error::Read(path, e) => write!(f, "reading module {}: {e}", path.display()),
+            Error::Invalid(msg) => write!(f, "invalid module: {msg}"),
+            Error::Config(msg) => write!(f, "invalid run configuration: {msg}"),
+            Error::Compile(e) => write!(f, "compiling module: {e}"),
+            Error::Trap(e) => write!(f, "guest trapped: {e}"),
+            Error::ResourceLimit(msg) => write!(f, "resource limit exceeded: {msg}"),
+            Error::DeadlineExceeded(deadline) => write!(f, "deadline of {deadline:?} exceeded"),
+            Error::Interrupted => write!(f, "guest interrupted"),
+            Error::Snapshot(msg) => write!(f, "snapshot: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ipfs::Error> for Error {
+    fn from(e: ipfs::Error) -> Self {
+        Error::Fetch(e)
+    }
+}
+
+impl From<wasmer::CompileError> for Error {
+    fn from(e: wasmer::CompileError) -> Self {
+        Error::Compile(e)
+    }
+}
+
+impl From<wasmer::RuntimeError> for Error {
+    fn from(e: wasmer::RuntimeError) -> Self {
+        Error::Trap(e)
+    }
+}
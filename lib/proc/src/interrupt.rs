@@ -0,0 +1,62 @@
+use std::sync::{Arc, Mutex};
+
+use wasmer_wasix::types::wasi::Signal;
+use wasmer_wasix::WasiProcess;
+
+use crate::preempt::Flag;
+
+#[derive(Default)]
+struct State {
+    interrupted: bool,
+    // Process of the guest while it runs.
+    process: Option<WasiProcess>,
+    flag: Option<Arc<Flag>>,
+}
+
+// Stops a guest from anywhere, e.g. on Ctrl-C, whether it is running yet or not. The guest is
+// sent SIGKILL and preempted, like by a watchdog, so it is stopped in the syscall it is blocked
+// in or at the next call or loop iteration if it is busy in WASM.
+#[derive(Clone, Default)]
+pub struct Interrupt {
+    state: Arc<Mutex<State>>,
+}
+
+impl Interrupt {
+    pub fn interrupt(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.interrupted {
+            return;
+        }
+        state.interrupted = true;
+        if let Some(flag) = &state.flag {
+            flag.raise();
+        }
+        if let Some(process) = &state.process {
+            tracing::debug!("interrupting process {}", process.pid());
+            process.signal_process(Signal::Sigkill);
+        }
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.state.lock().unwrap().interrupted
+    }
+
+    // Hand the process of the guest over as it starts running, returning false if the guest
+    // was interrupted before, in which case it shouldn't run at all.
+    pub(crate) fn attach(&self, process: WasiProcess, flag: Option<Arc<Flag>>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.interrupted {
+            return false;
+        }
+        state.process = Some(process);
+        state.flag = flag;
+        true
+    }
+
+    // Take the process back once the guest stopped running.
+    pub(crate) fn detach(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.process = None;
+        state.flag = None;
+    }
+}
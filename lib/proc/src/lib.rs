@@ -1,41 +1,213 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
 use uuid::Uuid;
-use wasmer::{self};
-use wasmer_wasix::{virtual_fs, WasiEnv, WasiFunctionEnv};
+use wasmer::wasmparser::Operator;
+use wasmer::{self, BaseTunables, CompilerConfig, NativeEngineExt, Target};
+use wasmer_middlewares::metering::{self, Metering, MeteringPoints};
+use wasmer_wasix::{virtual_fs, WasiEnv, WasiError, WasiFunctionEnv};
+
+mod config;
+mod deterministic;
+mod error;
+mod interrupt;
+mod loader;
+mod mount;
+mod piped;
+mod preempt;
+mod snapshot;
+mod stdio;
+mod tunables;
+mod watchdog;
+
+pub use config::{RunConfig, DEFAULT_MAX_MEMORY_PAGES};
+pub use deterministic::DeterministicConfig;
+pub use error::Error;
+pub use interrupt::Interrupt;
+pub use loader::Loader;
+pub use mount::Mount;
+pub use piped::Pipes;
+pub use stdio::{Output, Stdio};
+
+use deterministic::Deterministic;
+use preempt::{Flag, Preempt};
+use stdio::Pipe;
+use tunables::LimitingTunables;
+use watchdog::Watchdog;
 
 pub struct WasmProcess {
     function: wasmer::Function,
     env: WasiFunctionEnv,
+    instance: wasmer::Instance,
+    // Options the instance was created with, so hitting a limit can be told apart from a trap.
+    config: RunConfig,
+    // What the guest wrote to its stdout and stderr, when captured.
+    stdout: Arc<Mutex<Vec<u8>>>,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    interrupt: Interrupt,
+    // Flag trapping the guest at its next call or loop iteration once raised.
+    flag: Option<Arc<Flag>>,
 }
 
 impl WasmProcess {
-    pub fn new(wasi_env: WasiFunctionEnv, function: wasmer::Function) -> Self {
+    pub fn new(
+        wasi_env: WasiFunctionEnv,
+        instance: wasmer::Instance,
+        function: wasmer::Function,
+    ) -> Self {
         Self {
             function: function,
             env: wasi_env,
+            instance,
+            config: RunConfig::default(),
+            stdout: Arc::default(),
+            stderr: Arc::default(),
+            interrupt: Interrupt::default(),
+            flag: None,
         }
     }
 
-    pub fn run(
-        &mut self,
-        store: &mut wasmer::Store,
-    ) -> Result<Box<[wasmer::Value]>, wasmer::RuntimeError> {
-        let exit_code = self.function.call(store, &[])?;
+    // Handle stopping the guest from another thread, before or while it runs.
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    // What the guest wrote to its stdout and stderr so far. Empty unless they are captured.
+    pub fn output(&self) -> Output {
+        Output {
+            stdout: self.stdout.lock().unwrap().clone(),
+            stderr: self.stderr.lock().unwrap().clone(),
+        }
+    }
+
+    // Run the guest to completion and return its exit code, which is the one it passed to
+    // proc_exit or 0 if it returned. Guests that trap, are stopped by the host for going over
+    // a limit or are interrupted fail with the matching error instead.
+    pub fn run(&mut self, store: &mut wasmer::Store) -> Result<i32, Error> {
+        let process = self.env.data(store).process.clone();
+        if let Some(flag) = &self.flag {
+            flag.lower();
+        }
+        if !self.interrupt.attach(process.clone(), self.flag.clone()) {
+            return Err(Error::Interrupted);
+        }
+        let watchdog = self
+            .config
+            .deadline
+            .map(|deadline| Watchdog::spawn(deadline, process, self.flag.clone()));
+        if let Some(metrics) = &self.config.metrics {
+            metrics.wasm_instances.inc();
+        }
+        let result = self.function.call(store, &[]);
+        if let Some(metrics) = &self.config.metrics {
+            metrics.wasm_instances.dec();
+        }
+        let expired = watchdog.is_some_and(Watchdog::cancel);
+        self.interrupt.detach();
         self.env.on_exit(store, None);
-        Ok(exit_code)
+        match result {
+            Err(_) if self.interrupt.is_interrupted() => Err(Error::Interrupted),
+            Err(_) if expired => Err(Error::DeadlineExceeded(self.config.deadline.unwrap())),
+            Err(_) if self.config.fuel.is_some() && self.fuel_exhausted(store) => {
+                Err(Error::ResourceLimit("fuel exhausted".to_owned()))
+            }
+            Err(e) => match e.downcast::<WasiError>() {
+                Ok(WasiError::Exit(code)) => Ok(code.raw()),
+                Ok(e) => Err(Error::Trap(wasmer::RuntimeError::user(Box::new(e)))),
+                Err(e) => Err(Error::Trap(e)),
+            },
+            Ok(_) => Ok(0),
+        }
+    }
+
+    // Save the exported memories and mutable globals of the instance, e.g. to store them in
+    // IPFS and later restore them into a fresh instance of the same module.
+    pub fn snapshot(&self, store: &mut wasmer::Store) -> Result<Vec<u8>, Error> {
+        snapshot::snapshot(store, &self.instance)
+    }
+
+    // Load a snapshot taken from an instance of the same module. Snapshots that don't match the
+    // memories and globals of the instance are refused without changing it.
+    pub fn restore(&mut self, store: &mut wasmer::Store, snapshot: &[u8]) -> Result<(), Error> {
+        snapshot::restore(store, &self.instance, snapshot)
+    }
+
+    fn fuel_exhausted(&self, store: &mut wasmer::Store) -> bool {
+        matches!(
+            metering::get_remaining_points(store, &self.instance),
+            MeteringPoints::Exhausted
+        )
     }
 }
 
 pub struct WasmRuntime {
     store: wasmer::Store,
+    config: RunConfig,
+    // Filesystems preopened for every guest, besides the root.
+    mounts: Vec<Mount>,
 }
 
 impl WasmRuntime {
     pub fn new() -> Self {
+        Self::with_config(RunConfig::default())
+    }
+
+    pub fn with_config(config: RunConfig) -> Self {
+        let mut compiler = wasmer::Cranelift::default();
+        if let Some(fuel) = config.fuel {
+            // Every instance starts with the whole budget.
+            let cost = |_: &Operator| 1;
+            compiler.push_middleware(Arc::new(Metering::new(fuel, cost)));
+        }
+        // Lets deadlines and interrupts stop guests that never make a syscall.
+        compiler.push_middleware(Arc::new(Preempt::default()));
+        let mut engine: wasmer::Engine = compiler.into();
+        let base = BaseTunables::for_target(&Target::default());
+        engine.set_tunables(LimitingTunables::new(
+            base,
+            wasmer::Pages(config.max_memory_pages),
+        ));
         Self {
-            store: wasmer::Store::default(),
+            store: wasmer::Store::new(engine),
+            config,
+            mounts: Vec::new(),
         }
     }
 
+    // Preopen what is under path in fs for guests at guest_path, e.g. an IpfsFs subtree. Fails
+    // if guest_path is at, above or under another mount.
+    pub fn mount(
+        &mut self,
+        guest_path: impl Into<PathBuf>,
+        fs: Arc<dyn virtual_fs::FileSystem + Send + Sync>,
+        path: impl Into<PathBuf>,
+    ) -> Result<(), Error> {
+        let mount = Mount {
+            guest_path: guest_path.into(),
+            fs,
+            path: path.into(),
+        };
+        mount.check(&self.mounts)?;
+        self.mounts.push(mount);
+        Ok(())
+    }
+
+    // Identifies how the runtime compiles modules, so modules compiled by another wasmer
+    // version, compiler, target or set of limits aren't run by it.
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "wasmer-{}/{}/fuel={:?}/pages={}/preempt",
+            wasmer::VERSION,
+            self.store.engine().deterministic_id(),
+            self.config.fuel,
+            self.config.max_memory_pages
+        )
+    }
+
+    pub fn store(&self) -> &wasmer::Store {
+        &self.store
+    }
+
     pub fn store_mut(&mut self) -> &mut wasmer::Store {
         &mut self.store
     }
@@ -47,15 +219,89 @@ impl WasmRuntime {
         // fs: Box<dyn virtual_fs::FileSystem + Send + Sync>,
     ) -> Result<WasmProcess, Box<dyn std::error::Error>> {
         let module = wasmer::Module::new(&self.store, bytecode).expect("couldn't load WASM module");
+        self.instantiate(&module, fs)
+    }
+
+    // Instantiate a compiled module, e.g. one from a Loader, with fs as its root filesystem and
+    // the mounts of the runtime under it.
+    pub fn instantiate(
+        &mut self,
+        module: &wasmer::Module,
+        fs: virtual_fs::TmpFileSystem,
+    ) -> Result<WasmProcess, Box<dyn std::error::Error>> {
+        self.instantiate_with(module, fs, None)
+    }
+
+    // Instantiate a compiled module like instantiate, with its stdin and stdout piped to the
+    // host through channels holding up to capacity chunks each, in place of the stdio of the
+    // runtime. Its stderr still goes where the stdio of the runtime says.
+    pub fn instantiate_piped(
+        &mut self,
+        module: &wasmer::Module,
+        fs: virtual_fs::TmpFileSystem,
+        capacity: usize,
+    ) -> Result<(WasmProcess, Pipes), Box<dyn std::error::Error>> {
+        let (pipes, stdin, stdout) = piped::pipes(capacity);
+        let process = self.instantiate_with(module, fs, Some((stdin, stdout)))?;
+        Ok((process, pipes))
+    }
+
+    fn instantiate_with(
+        &mut self,
+        module: &wasmer::Module,
+        fs: virtual_fs::TmpFileSystem,
+        piped: Option<(piped::Stdin, piped::Stdout)>,
+    ) -> Result<WasmProcess, Box<dyn std::error::Error>> {
+        self.config.validate()?;
         let uuid = Uuid::new_v4();
-        let pre_opens: Vec<String> = ["/", "/ipfs"].iter().map(|&s| s.to_string()).collect();
+        let mut pre_opens = vec!["/".to_owned()];
+        for mount in &self.mounts {
+            mount.apply(&fs)?;
+            pre_opens.push(mount.guest_path.to_string_lossy().into_owned());
+        }
         let mut wasi_env_builder = WasiEnv::builder(uuid);
-        wasi_env_builder = wasi_env_builder.sandbox_fs(fs);
+        wasi_env_builder = wasi_env_builder
+            .sandbox_fs(fs)
+            .args(&self.config.args)
+            .envs(self.config.env.iter().cloned());
+        let (stdout, stderr) = (Arc::default(), Arc::default());
+        let (stdio, span) = (self.config.stdio, tracing::info_span!("guest", id = %uuid));
+        let stdout_file: Box<dyn virtual_fs::VirtualFile + Send + Sync> = match piped {
+            Some((stdin_file, stdout_file)) => {
+                wasi_env_builder = wasi_env_builder.stdin(Box::new(stdin_file));
+                Box::new(stdout_file)
+            }
+            None => Box::new(Pipe::new(
+                "stdout",
+                stdio,
+                span.clone(),
+                Arc::clone(&stdout),
+            )),
+        };
+        wasi_env_builder = wasi_env_builder
+            .stdout(stdout_file)
+            .stderr(Box::new(Pipe::new(
+                "stderr",
+                stdio,
+                span,
+                Arc::clone(&stderr),
+            )));
         // wasi_env_builder = wasi_env_builder.fs(fs);
         wasi_env_builder.preopen_vfs_dirs(pre_opens).unwrap();
         let mut wasi_env = wasi_env_builder.finalize(self.store_mut())?;
-        let import_object = wasi_env.import_object(self.store_mut(), &module)?;
-        let instance = wasmer::Instance::new(self.store_mut(), &module, &import_object)?;
+        let mut import_object = wasi_env.import_object(self.store_mut(), module)?;
+        let deterministic = self
+            .config
+            .deterministic
+            .as_ref()
+            .map(|config| Deterministic::new(&mut self.store, config));
+        if let Some(deterministic) = &deterministic {
+            deterministic.define(&mut self.store, &mut import_object);
+        }
+        let instance = wasmer::Instance::new(self.store_mut(), module, &import_object)?;
+        if let Some(deterministic) = &deterministic {
+            deterministic.attach(&mut self.store, &instance)?;
+        }
 
         // // Attach the memory export
         // let memory = instance.exports.get_memory("memory")?;
@@ -65,6 +311,448 @@ impl WasmRuntime {
 
         let function = instance.exports.get_function("_start")?;
 
-        Ok(WasmProcess::new(wasi_env, function.to_owned()))
+        let mut process = WasmProcess::new(wasi_env, instance.clone(), function.to_owned());
+        process.config = self.config.clone();
+        process.stdout = stdout;
+        process.stderr = stderr;
+        process.flag = Flag::of(&self.store, &instance).map(Arc::new);
+        Ok(process)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+    use wasmer_wasix::virtual_fs::{FileSystem, RootFileSystemBuilder};
+
+    fn run(config: RunConfig, wat: &str) -> Result<i32, Error> {
+        let mut runtime = WasmRuntime::with_config(config);
+        let module = wasmer::Module::new(runtime.store(), wat).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        process.run(runtime.store_mut())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fuel_exhausted() {
+        let busy_loop = r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start") (loop (br 0))))"#;
+        let config = RunConfig {
+            fuel: Some(10_000),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let result = run(config, busy_loop);
+        assert!(matches!(result, Err(Error::ResourceLimit(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Guests that stay within budget run to completion.
+        let nop = r#"(module (memory (export "memory") 1) (func (export "_start")))"#;
+        let config = RunConfig {
+            fuel: Some(10_000),
+            ..Default::default()
+        };
+        assert!(run(config, nop).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memory_ceiling() {
+        // Grow memory a page at a time until it fails, then exit with its size in pages.
+        let grow = r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (block $full
+                    (loop $grow
+                        (br_if $full (i32.eq (memory.grow (i32.const 1)) (i32.const -1)))
+                        (br $grow)))
+                (call $proc_exit (memory.size))))"#;
+        let config = RunConfig {
+            max_memory_pages: 4,
+            ..Default::default()
+        };
+
+        assert_eq!(run(config, grow).unwrap(), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_exit_code() {
+        let exit = r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_start") (call $proc_exit (i32.const 3))))"#;
+        assert_eq!(run(RunConfig::default(), exit).unwrap(), 3);
+
+        let nop = r#"(module (memory (export "memory") 1) (func (export "_start")))"#;
+        assert_eq!(run(RunConfig::default(), nop).unwrap(), 0);
+
+        let trap = r#"(module (memory (export "memory") 1) (func (export "_start") unreachable))"#;
+        assert!(matches!(
+            run(RunConfig::default(), trap),
+            Err(Error::Trap(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deadline_exceeded() {
+        let sleep = r#"(module
+            (import "wasix_32v1" "thread_sleep" (func $sleep (param i64) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (drop (call $sleep (i64.const 60000000000)))))"#;
+        let config = RunConfig {
+            deadline: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let result = run(config, sleep);
+        assert!(matches!(result, Err(Error::DeadlineExceeded(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Guests spinning without syscalls are preempted, without a fuel budget.
+        let busy_loop = r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start") (loop (br 0))))"#;
+        let config = RunConfig {
+            deadline: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let result = run(config, busy_loop);
+        assert!(matches!(result, Err(Error::DeadlineExceeded(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Guests finishing in time aren't killed.
+        let nop = r#"(module (memory (export "memory") 1) (func (export "_start")))"#;
+        let config = RunConfig {
+            deadline: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        assert!(run(config, nop).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_interrupt() {
+        let sleep = r#"(module
+            (import "wasix_32v1" "thread_sleep" (func $sleep (param i64) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (drop (call $sleep (i64.const 60000000000)))))"#;
+        let mut runtime = WasmRuntime::new();
+        let module = wasmer::Module::new(runtime.store(), sleep).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        let interrupt = process.interrupt();
+        let start = Instant::now();
+        let guest = tokio::task::spawn_blocking(move || process.run(runtime.store_mut()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        interrupt.interrupt();
+        assert!(matches!(guest.await.unwrap(), Err(Error::Interrupted)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // So are guests spinning without syscalls.
+        let busy_loop = r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start") (loop (br 0))))"#;
+        let mut runtime = WasmRuntime::new();
+        let module = wasmer::Module::new(runtime.store(), busy_loop).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        let interrupt = process.interrupt();
+        let guest = tokio::task::spawn_blocking(move || process.run(runtime.store_mut()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        interrupt.interrupt();
+        assert!(matches!(guest.await.unwrap(), Err(Error::Interrupted)));
+
+        // Guests interrupted before they run don't run at all.
+        let mut runtime = WasmRuntime::new();
+        let module = wasmer::Module::new(runtime.store(), sleep).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        process.interrupt().interrupt();
+        let result = process.run(runtime.store_mut());
+        assert!(matches!(result, Err(Error::Interrupted)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_capture_stdio() {
+        // Write "out\n" to stdout and "err\n" to stderr, through iovecs at 0 and 8.
+        let print = r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\10\00\00\00\04\00\00\00\20\00\00\00\04\00\00\00")
+            (data (i32.const 16) "out\n")
+            (data (i32.const 32) "err\n")
+            (func (export "_start")
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 48)))
+                (drop (call $fd_write (i32.const 2) (i32.const 8) (i32.const 1) (i32.const 48))))
+            )"#;
+        let mut runtime = WasmRuntime::with_config(RunConfig {
+            stdio: Stdio::Capture,
+            ..Default::default()
+        });
+        let module = wasmer::Module::new(runtime.store(), print).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        process.run(runtime.store_mut()).unwrap();
+
+        assert_eq!(
+            process.output(),
+            Output {
+                stdout: b"out\n".to_vec(),
+                stderr: b"err\n".to_vec(),
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_args_and_env() {
+        // Print the first argument and the first environment variable, a line each.
+        let echo = r#"(module
+            (import "wasi_snapshot_preview1" "args_get"
+                (func $args_get (param i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "environ_get"
+                (func $environ_get (param i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func $puts (param $ptr i32)
+                (local $len i32)
+                (block $end
+                    (loop $scan
+                        (br_if $end (i32.eqz (i32.load8_u
+                            (i32.add (local.get $ptr) (local.get $len)))))
+                        (local.set $len (i32.add (local.get $len) (i32.const 1)))
+                        (br $scan)))
+                ;; Swap the NUL for a newline, and write the line through an iovec at 0.
+                (i32.store8 (i32.add (local.get $ptr) (local.get $len)) (i32.const 10))
+                (i32.store (i32.const 0) (local.get $ptr))
+                (i32.store (i32.const 4) (i32.add (local.get $len) (i32.const 1)))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))
+            (func (export "_start")
+                (drop (call $args_get (i32.const 1024) (i32.const 2048)))
+                (drop (call $environ_get (i32.const 4096) (i32.const 8192)))
+                (call $puts (i32.load (i32.const 1028)))
+                (call $puts (i32.load (i32.const 4096)))))"#;
+        let mut runtime = WasmRuntime::with_config(RunConfig {
+            stdio: Stdio::Capture,
+            args: vec!["dataset-a".to_owned()],
+            env: vec![("JOB".to_owned(), "42".to_owned())],
+            ..Default::default()
+        });
+        let module = wasmer::Module::new(runtime.store(), echo).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        process.run(runtime.store_mut()).unwrap();
+        assert_eq!(process.output().stdout, b"dataset-a\nJOB=42\n");
+
+        let config = RunConfig {
+            env: vec![("JOB=1".to_owned(), "42".to_owned())],
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(Error::Config(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_piped_stdio() {
+        // Copy stdin to stdout, through a 1 KiB buffer at 1024, until stdin ends.
+        let echo = r#"(module
+            (import "wasi_snapshot_preview1" "fd_read"
+                (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 1024))
+                (block $eof
+                    (loop $copy
+                        (i32.store (i32.const 4) (i32.const 1024))
+                        (drop (call $fd_read
+                            (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                        (br_if $eof (i32.eqz (i32.load (i32.const 8))))
+                        (i32.store (i32.const 4) (i32.load (i32.const 8)))
+                        (drop (call $fd_write
+                            (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                        (br $copy)))))"#;
+        let mut runtime = WasmRuntime::new();
+        let module = wasmer::Module::new(runtime.store(), echo).unwrap();
+        let (mut process, mut pipes) = runtime
+            .instantiate_piped(&module, RootFileSystemBuilder::new().build(), 1)
+            .unwrap();
+        let guest = tokio::task::spawn_blocking(move || process.run(runtime.store_mut()));
+
+        let mut stdout = Vec::new();
+        for chunk in ["hello ", "piped ", "world"] {
+            pipes.stdin.send(chunk.as_bytes().to_vec()).await.unwrap();
+            stdout.extend(pipes.stdout.recv().await.unwrap());
+        }
+        drop(pipes.stdin);
+        while let Some(chunk) = pipes.stdout.recv().await {
+            stdout.extend(chunk);
+        }
+        assert_eq!(stdout, b"hello piped world");
+        assert_eq!(guest.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mounts() {
+        // Copy /data/a.txt and then /models/b.txt to stdout, opening them under the root
+        // preopen.
+        let cat = r#"(module
+            (import "wasi_snapshot_preview1" "path_open"
+                (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_read"
+                (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 256) "data/a.txt")
+            (data (i32.const 272) "models/b.txt")
+            (func $cat (param $path i32) (param $len i32)
+                (drop (call $path_open (i32.const 3) (i32.const 0) (local.get $path)
+                    (local.get $len) (i32.const 0) (i64.const -1) (i64.const -1) (i32.const 0)
+                    (i32.const 0)))
+                ;; Read into 1 KiB at 1024 through an iovec at 8, then write what was read.
+                (i32.store (i32.const 8) (i32.const 1024))
+                (i32.store (i32.const 12) (i32.const 1024))
+                (drop (call $fd_read (i32.load (i32.const 0)) (i32.const 8) (i32.const 1)
+                    (i32.const 16)))
+                (i32.store (i32.const 12) (i32.load (i32.const 16)))
+                (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16))))
+            (func (export "_start")
+                (call $cat (i32.const 256) (i32.const 10))
+                (call $cat (i32.const 272) (i32.const 12))))"#;
+
+        let mut runtime = WasmRuntime::with_config(RunConfig {
+            stdio: Stdio::Capture,
+            ..Default::default()
+        });
+        let files = [("/data", "/a.txt", "a\n"), ("/models", "/b.txt", "b\n")];
+        for (guest_path, file, contents) in files {
+            let fs = virtual_fs::TmpFileSystem::new();
+            let mut f = fs
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .open(file)
+                .unwrap();
+            f.write_all(contents.as_bytes()).await.unwrap();
+            runtime.mount(guest_path, Arc::new(fs), "/").unwrap();
+        }
+        // Mounts can't overlap.
+        let fs = Arc::new(virtual_fs::TmpFileSystem::new());
+        assert!(matches!(
+            runtime.mount("/data", fs.clone(), "/"),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            runtime.mount("/data/nested", fs, "/"),
+            Err(Error::Config(_))
+        ));
+
+        let module = wasmer::Module::new(runtime.store(), cat).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        process.run(runtime.store_mut()).unwrap();
+        assert_eq!(process.output().stdout, b"a\nb\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deterministic() {
+        // Print 16 random bytes followed by the realtime clock.
+        let entropy = r#"(module
+            (import "wasi_snapshot_preview1" "random_get"
+                (func $random_get (param i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "clock_time_get"
+                (func $clock_time_get (param i32 i64 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (drop (call $random_get (i32.const 64) (i32.const 16)))
+                (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 80)))
+                (i32.store (i32.const 0) (i32.const 64))
+                (i32.store (i32.const 4) (i32.const 24))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#;
+        let output = |seed| {
+            let mut runtime = WasmRuntime::with_config(RunConfig {
+                stdio: Stdio::Capture,
+                deterministic: Some(DeterministicConfig {
+                    seed,
+                    timestamp: Duration::from_secs(1_700_000_000),
+                }),
+                ..Default::default()
+            });
+            let module = wasmer::Module::new(runtime.store(), entropy).unwrap();
+            let mut process = runtime
+                .instantiate(&module, RootFileSystemBuilder::new().build())
+                .unwrap();
+            process.run(runtime.store_mut()).unwrap();
+            process.output().stdout
+        };
+
+        let first = output(42);
+        assert_eq!(first, output(42));
+        assert_eq!(first[16..], 1_700_000_000_000_000_000u64.to_le_bytes());
+        assert_ne!(first[..16], output(43)[..16]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshot_restore() {
+        // Start sets a global and the first word of memory, which resume adds up.
+        let counter = r#"(module
+            (memory (export "memory") 1)
+            (global $count (export "count") (mut i32) (i32.const 0))
+            (func (export "_start")
+                (global.set $count (i32.const 5))
+                (i32.store (i32.const 0) (i32.const 7)))
+            (func (export "resume") (result i32)
+                (i32.add (global.get $count) (i32.load (i32.const 0)))))"#;
+        let mut runtime = WasmRuntime::new();
+        let module = wasmer::Module::new(runtime.store(), counter).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        process.run(runtime.store_mut()).unwrap();
+        let snapshot = process.snapshot(runtime.store_mut()).unwrap();
+
+        let mut restored = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        restored.restore(runtime.store_mut(), &snapshot).unwrap();
+        let resume = restored.instance.exports.get_function("resume").unwrap();
+        let result = resume.call(runtime.store_mut(), &[]).unwrap();
+        assert_eq!(result[0].unwrap_i32(), 12);
+
+        // Instances of other modules don't take the snapshot.
+        let other = r#"(module
+            (memory (export "memory") 1)
+            (global (export "count") (mut i64) (i64.const 0))
+            (func (export "_start")))"#;
+        let module = wasmer::Module::new(runtime.store(), other).unwrap();
+        let mut other = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        assert!(matches!(
+            other.restore(runtime.store_mut(), &snapshot),
+            Err(Error::Snapshot(_))
+        ));
+        assert!(matches!(
+            other.restore(runtime.store_mut(), b"garbage"),
+            Err(Error::Snapshot(_))
+        ));
     }
 }
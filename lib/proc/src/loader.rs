@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use futures::TryStreamExt;
+use net::ipfs::{self, Cid, Client};
+use uuid::Uuid;
+
+use crate::{Error, WasmRuntime};
+
+// Every WASM binary starts with the magic number followed by the version of the format.
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: &[u8] = &[1, 0, 0, 0];
+
+// Check that bytecode is a binary WASM module of a supported version, before handing it to
+// the compiler.
+pub fn validate(bytecode: &[u8]) -> Result<(), Error> {
+    if !bytecode.starts_with(MAGIC) {
+        return Err(Error::Invalid("missing \\0asm magic number".to_owned()));
+    }
+    let version = bytecode.get(MAGIC.len()..MAGIC.len() + VERSION.len());
+    if version != Some(VERSION) {
+        return Err(Error::Invalid(format!("unsupported version {version:?}")));
+    }
+    Ok(())
+}
+
+// Loads WASM modules from IPFS paths. Modules are compiled once and kept by the CID of their
+// file, as the content behind a CID never changes. Compiled modules depend on the runtime they
+// are compiled for, so they are also kept by the fingerprint of the runtime.
+pub struct Loader {
+    client: Client,
+    modules: Mutex<HashMap<(Cid, String), wasmer::Module>>,
+    // Directory compiled modules are stored in across runs, one file per CID.
+    cache_dir: Option<PathBuf>,
+}
+
+impl Loader {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            modules: Mutex::default(),
+            cache_dir: None,
+        }
+    }
+
+    // Client modules are fetched through.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    // Store compiled modules under dir, so later runs load them without compiling them again.
+    // Modules compiled for a runtime with another fingerprint are compiled again and replaced.
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    // Fetch, check and compile the module at an '/ipfs/<cid>/<path>' path for runtime. Modules
+    // loaded before are returned without being fetched again.
+    pub async fn load(&self, runtime: &WasmRuntime, path: &str) -> Result<wasmer::Module, Error> {
+        let cid = self.client.resolve_cid(path).await?;
+        let key = (cid, runtime.fingerprint());
+        if let Some(module) = self.modules.lock().unwrap().get(&key) {
+            tracing::debug!("using cached module {cid}");
+            return Ok(module.clone());
+        }
+
+        let module = match self.load_compiled(runtime, &cid) {
+            Some(module) => module,
+            None => {
+                let bytecode: Vec<u8> = self
+                    .client
+                    .get_file(&format!("/ipfs/{cid}"))
+                    .map_ok(|chunk| chunk.to_vec())
+                    .try_concat()
+                    .await
+                    .map_err(ipfs::Error::from)?;
+                self.compile(runtime, &cid, &bytecode)?
+            }
+        };
+        self.modules.lock().unwrap().insert(key, module.clone());
+        Ok(module)
+    }
+
+    // Read, check and compile the module in a local file, e.g. one being developed. Such
+    // modules have no CID, so they are compiled on every load.
+    pub fn load_file(&self, runtime: &WasmRuntime, path: &Path) -> Result<wasmer::Module, Error> {
+        let bytecode = fs::read(path).map_err(|e| Error::Read(path.to_owned(), e))?;
+        validate(&bytecode)?;
+        Ok(wasmer::Module::new(runtime.store(), bytecode)?)
+    }
+
+    // Check and compile bytecode, storing the compiled module in the cache directory.
+    fn compile(
+        &self,
+        runtime: &WasmRuntime,
+        cid: &Cid,
+        bytecode: &[u8],
+    ) -> Result<wasmer::Module, Error> {
+        validate(bytecode)?;
+        let module = wasmer::Module::new(runtime.store(), bytecode)?;
+        if let Some(path) = self.cache_path(cid) {
+            if let Err(e) = store_compiled(&path, &runtime.fingerprint(), &module) {
+                tracing::warn!("failed to cache module {cid}: {e}");
+            }
+        }
+        Ok(module)
+    }
+
+    // Load the module compiled from cid out of the cache directory, if it was compiled for a
+    // runtime with the same fingerprint. Stale entries are removed.
+    fn load_compiled(&self, runtime: &WasmRuntime, cid: &Cid) -> Option<wasmer::Module> {
+        let path = self.cache_path(cid)?;
+        let data = fs::read(&path).ok()?;
+        let fingerprint = runtime.fingerprint();
+        let artifact = data
+            .strip_prefix(fingerprint.as_bytes())
+            .and_then(|rest| rest.strip_prefix(b"\n"));
+        let Some(artifact) = artifact else {
+            tracing::debug!("removing module {cid} compiled for another runtime");
+            let _ = fs::remove_file(&path);
+            return None;
+        };
+        // Artifacts are only ever written by compile, so they are trusted like the compiler.
+        match unsafe { wasmer::Module::deserialize(runtime.store(), artifact) } {
+            Ok(module) => {
+                tracing::debug!("loaded compiled module {cid}");
+                Some(module)
+            }
+            Err(e) => {
+                tracing::warn!("removing unreadable compiled module {cid}: {e}");
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    fn cache_path(&self, cid: &Cid) -> Option<PathBuf> {
+        let dir = self.cache_dir.as_ref()?;
+        Some(dir.join(format!("{cid}.wasmu")))
+    }
+}
+
+// Write the fingerprint of the runtime a module was compiled for on a line of its own, followed
+// by the compiled module. The file is written aside and moved into place, so readers never see
+// it half written.
+fn store_compiled(path: &Path, fingerprint: &str, module: &wasmer::Module) -> io::Result<()> {
+    let artifact = module.serialize().map_err(io::Error::other)?;
+    let mut data = Vec::with_capacity(fingerprint.len() + 1 + artifact.len());
+    data.extend_from_slice(fingerprint.as_bytes());
+    data.push(b'\n');
+    data.extend_from_slice(&artifact);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RunConfig;
+    use bytes::Bytes;
+    use wasmer_wasix::virtual_fs::RootFileSystemBuilder;
+
+    const NOP: &[u8] = br#"(module (memory (export "memory") 1) (func (export "_start")))"#;
+
+    #[test]
+    fn test_validate() {
+        let module = wasmer::wat2wasm(b"(module)").unwrap();
+        assert!(validate(&module).is_ok());
+
+        assert!(matches!(validate(b"(module)"), Err(Error::Invalid(_))));
+        assert!(matches!(validate(b"\0asm"), Err(Error::Invalid(_))));
+        assert!(matches!(
+            validate(b"\0asm\x02\0\0\0"),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs an IPFS daemon listening on 127.0.0.1:5001"]
+    async fn test_load_by_cid() {
+        let client = Client::new("/ip4/127.0.0.1/tcp/5001".parse().unwrap());
+        let bytecode = wasmer::wat2wasm(NOP).unwrap();
+        let cid = client
+            .add_bytes(Bytes::from(bytecode.into_owned()))
+            .await
+            .unwrap();
+
+        let loader = Loader::new(client);
+        let mut runtime = WasmRuntime::new();
+        let path = format!("/ipfs/{cid}");
+        let module = loader.load(&runtime, &path).await.unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        process.run(runtime.store_mut()).unwrap();
+
+        // Loading again is served from the cache.
+        loader.load(&runtime, &path).await.unwrap();
+        assert_eq!(loader.modules.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_load_file() {
+        let path = std::env::temp_dir().join(format!("ww-test-{}.wasm", Uuid::new_v4()));
+        let client = Client::new("/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        let loader = Loader::new(client);
+        let mut runtime = WasmRuntime::new();
+        assert!(matches!(
+            loader.load_file(&runtime, &path),
+            Err(Error::Read(..))
+        ));
+
+        fs::write(&path, wasmer::wat2wasm(NOP).unwrap()).unwrap();
+        let module = loader.load_file(&runtime, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        assert_eq!(process.run(runtime.store_mut()).unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compiled_cache() {
+        let dir = std::env::temp_dir().join(format!("ww-test-{}", Uuid::new_v4()));
+        let bytecode = wasmer::wat2wasm(NOP).unwrap();
+        let cid: Cid = "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+            .parse()
+            .unwrap();
+        // The daemon is never contacted, as modules are compiled and loaded directly.
+        let client = Client::new("/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        let loader = Loader::new(client.clone()).with_cache_dir(&dir);
+        let mut runtime = WasmRuntime::new();
+
+        assert!(loader.load_compiled(&runtime, &cid).is_none());
+        loader.compile(&runtime, &cid, &bytecode).unwrap();
+
+        // A second run deserializes the module compiled by the first.
+        let loader = Loader::new(client).with_cache_dir(&dir);
+        let module = loader.load_compiled(&runtime, &cid).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+        assert_eq!(process.run(runtime.store_mut()).unwrap(), 0);
+
+        // Runtimes compiling differently don't use it, and drop it.
+        let metered = WasmRuntime::with_config(RunConfig {
+            fuel: Some(1000),
+            ..Default::default()
+        });
+        assert!(loader.load_compiled(&metered, &cid).is_none());
+        assert!(loader.load_compiled(&runtime, &cid).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
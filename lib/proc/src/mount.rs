@@ -0,0 +1,52 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use wasmer_wasix::virtual_fs::{self, FileSystem, FsError, TmpFileSystem};
+
+use crate::Error;
+
+// Filesystem preopened for guests at guest_path, showing what is under path in fs.
+#[derive(Clone)]
+pub struct Mount {
+    pub guest_path: PathBuf,
+    pub fs: Arc<dyn FileSystem + Send + Sync>,
+    pub path: PathBuf,
+}
+
+impl Mount {
+    // Check that the mount can go next to the others, which it can't if it is at, above or
+    // under one of them.
+    pub fn check(&self, others: &[Mount]) -> Result<(), Error> {
+        if !self.guest_path.is_absolute() || self.guest_path == Path::new("/") {
+            return Err(Error::Config(format!(
+                "cannot mount at {}",
+                self.guest_path.display()
+            )));
+        }
+        let conflict = others.iter().find(|other| {
+            self.guest_path.starts_with(&other.guest_path)
+                || other.guest_path.starts_with(&self.guest_path)
+        });
+        match conflict {
+            Some(other) => Err(Error::Config(format!(
+                "mount at {} conflicts with mount at {}",
+                self.guest_path.display(),
+                other.guest_path.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Mount under root, creating the directories above the guest path first.
+    pub fn apply(&self, root: &TmpFileSystem) -> virtual_fs::Result<()> {
+        let mut parents: Vec<&Path> = self.guest_path.ancestors().skip(1).collect();
+        parents.pop();
+        for parent in parents.into_iter().rev() {
+            match root.create_dir(parent) {
+                Ok(()) | Err(FsError::AlreadyExists) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        root.mount(self.guest_path.clone(), &self.fs, self.path.clone())
+    }
+}
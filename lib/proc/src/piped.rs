@@ -0,0 +1,284 @@
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+use wasmer_wasix::{virtual_fs, FsError};
+
+// Host ends of the stdin and stdout of a guest run with piped stdio. Both hold up to a bounded
+// number of chunks, so a host writing faster than the guest reads waits in send, and a guest
+// writing faster than the host reads is blocked in its writes. Dropping stdin ends the input of
+// the guest, and stdout ends once the guest exits.
+pub struct Pipes {
+    pub stdin: mpsc::Sender<Vec<u8>>,
+    pub stdout: mpsc::Receiver<Vec<u8>>,
+}
+
+// Create the host ends of piped stdio holding up to capacity chunks each, and the guest ends to
+// hand to the guest.
+pub(crate) fn pipes(capacity: usize) -> (Pipes, Stdin, Stdout) {
+    let (stdin, chunks) = mpsc::channel(capacity.max(1));
+    let (sender, stdout) = mpsc::channel(capacity.max(1));
+    let stdin_end = Stdin {
+        chunks: Mutex::new(chunks),
+        chunk: Vec::new(),
+    };
+    let stdout_end = Stdout {
+        chunks: Mutex::new(PollSender::new(sender)),
+    };
+    (Pipes { stdin, stdout }, stdin_end, stdout_end)
+}
+
+// Guest end of a piped stdin, which reads the chunks the host sends until the host drops its
+// end.
+pub struct Stdin {
+    // Behind a mutex only for the guest to hold it as Sync.
+    chunks: Mutex<mpsc::Receiver<Vec<u8>>>,
+    // What is left of the chunk being read.
+    chunk: Vec<u8>,
+}
+
+impl Stdin {
+    // Wait for input, returning whether there is any left.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        while self.chunk.is_empty() {
+            match ready!(self.chunks.get_mut().unwrap().poll_recv(cx)) {
+                Some(chunk) => self.chunk = chunk,
+                None => return Poll::Ready(false),
+            }
+        }
+        Poll::Ready(true)
+    }
+}
+
+impl fmt::Debug for Stdin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stdin").finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for Stdin {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Reading nothing is the end of the input.
+        if ready!(this.poll_fill(cx)) {
+            let n = this.chunk.len().min(buf.remaining());
+            buf.put_slice(&this.chunk[..n]);
+            this.chunk.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Stdin {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for Stdin {
+    fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+impl virtual_fs::VirtualFile for Stdin {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn set_times(&mut self, _: Option<u64>, _: Option<u64>) -> virtual_fs::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _: u64) -> virtual_fs::Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> virtual_fs::Result<()> {
+        Ok(())
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_fill(cx));
+        Poll::Ready(Ok(this.chunk.len()))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+    }
+}
+
+// Guest end of a piped stdout, which hands the host a chunk per write.
+pub struct Stdout {
+    // Behind a mutex only for the guest to hold it as Sync.
+    chunks: Mutex<PollSender<Vec<u8>>>,
+}
+
+impl Stdout {
+    // Wait for room for a chunk. Writing to a host that hung up fails as it would on a closed
+    // pipe.
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let reserved = ready!(self.chunks.get_mut().unwrap().poll_reserve(cx));
+        Poll::Ready(reserved.map_err(|_| io::ErrorKind::BrokenPipe.into()))
+    }
+}
+
+impl fmt::Debug for Stdout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stdout").finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for Stdout {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        _: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Stdout {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_reserve(cx))?;
+        match this.chunks.get_mut().unwrap().send_item(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().chunks.get_mut().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for Stdout {
+    fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+impl virtual_fs::VirtualFile for Stdout {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn set_times(&mut self, _: Option<u64>, _: Option<u64>) -> virtual_fs::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _: u64) -> virtual_fs::Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> virtual_fs::Result<()> {
+        Ok(())
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+
+    // Ready once there is room for a chunk, which stays reserved for the next write.
+    fn poll_write_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        ready!(self.get_mut().poll_reserve(cx))?;
+        Poll::Ready(Ok(8192))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::FutureExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_pipes() {
+        let (mut pipes, mut stdin, mut stdout) = pipes(2);
+
+        // The host waits once it sent as many chunks as the pipe holds.
+        pipes.stdin.send(b"ab".to_vec()).await.unwrap();
+        pipes.stdin.send(b"c".to_vec()).await.unwrap();
+        assert!(pipes.stdin.send(b"d".to_vec()).now_or_never().is_none());
+        let mut buf = [0; 1];
+        stdin.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"a");
+        drop(pipes.stdin);
+        let mut rest = Vec::new();
+        stdin.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"bc");
+
+        // So does the guest.
+        stdout.write_all(b"x").await.unwrap();
+        stdout.write_all(b"y").await.unwrap();
+        assert!(stdout.write_all(b"z").now_or_never().is_none());
+        assert_eq!(pipes.stdout.recv().await.unwrap(), b"x");
+        stdout.write_all(b"z").await.unwrap();
+        drop(stdout);
+        assert_eq!(pipes.stdout.recv().await.unwrap(), b"y");
+        assert_eq!(pipes.stdout.recv().await.unwrap(), b"z");
+        assert_eq!(pipes.stdout.recv().await, None);
+    }
+}
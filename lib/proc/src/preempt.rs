@@ -0,0 +1,125 @@
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+use wasmer::vm::{VMExtern, VMGlobalDefinition};
+use wasmer::wasmparser::{BlockType, Operator};
+use wasmer::{
+    AsStoreRef, ExportIndex, Extern, FunctionMiddleware, GlobalInit, GlobalType, Instance,
+    LocalFunctionIndex, MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Type,
+};
+use wasmer_types::{GlobalIndex, ModuleInfo};
+
+// Name the flag is exported under.
+const EXPORT: &str = "ww_preempted";
+
+// Middleware giving every module a flag the host can raise from another thread, checked on
+// entering each function and at the top of each loop iteration. The guest traps at the next
+// check once it is raised, so guests spinning without making syscalls can be stopped too.
+#[derive(Debug, Default)]
+pub struct Preempt {
+    // Index of the flag in the module being compiled.
+    global: Mutex<Option<GlobalIndex>>,
+}
+
+impl ModuleMiddleware for Preempt {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        let global = self
+            .global
+            .lock()
+            .unwrap()
+            .expect("functions are compiled after their module info is transformed");
+        Box::new(Check {
+            global,
+            entered: false,
+        })
+    }
+
+    fn transform_module_info(&self, info: &mut ModuleInfo) -> Result<(), MiddlewareError> {
+        let global = info
+            .globals
+            .push(GlobalType::new(Type::I32, Mutability::Var));
+        info.global_initializers.push(GlobalInit::I32Const(0));
+        info.exports
+            .insert(EXPORT.to_owned(), ExportIndex::Global(global));
+        *self.global.lock().unwrap() = Some(global);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Check {
+    global: GlobalIndex,
+    // Whether the check on entering the function was emitted.
+    entered: bool,
+}
+
+impl Check {
+    fn emit(&self, state: &mut MiddlewareReaderState) {
+        state.extend(&[
+            Operator::GlobalGet {
+                global_index: self.global.as_u32(),
+            },
+            Operator::If {
+                blockty: BlockType::Empty,
+            },
+            Operator::Unreachable,
+            Operator::End,
+        ]);
+    }
+}
+
+impl FunctionMiddleware for Check {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        if !self.entered {
+            self.entered = true;
+            self.emit(state);
+        }
+        let looping = matches!(operator, Operator::Loop { .. });
+        state.push_operator(operator);
+        if looping {
+            self.emit(state);
+        }
+        Ok(())
+    }
+}
+
+// Raises the flag of an instance from any thread. It points into the store of the instance, so
+// it must not be used once the instance stops running; the watchdog and the interrupt only hold
+// it while the guest runs.
+pub struct Flag(NonNull<VMGlobalDefinition>);
+
+// The flag is only ever written atomically through the pointer.
+unsafe impl Send for Flag {}
+unsafe impl Sync for Flag {}
+
+impl Flag {
+    // Find the flag of an instance, if its module was compiled with the middleware.
+    pub fn of(store: &impl AsStoreRef, instance: &Instance) -> Option<Self> {
+        let global = instance.exports.get_global(EXPORT).ok()?;
+        let VMExtern::Global(handle) = Extern::Global(global.clone()).to_vm_extern() else {
+            return None;
+        };
+        let definition = handle.get(store.as_store_ref().objects()).vmglobal();
+        Some(Self(definition))
+    }
+
+    pub fn raise(&self) {
+        self.value().store(1, Ordering::Relaxed);
+    }
+
+    // Lower the flag before the guest runs again, e.g. after being restored from a snapshot
+    // taken once it was stopped.
+    pub fn lower(&self) {
+        self.value().store(0, Ordering::Relaxed);
+    }
+
+    fn value(&self) -> &AtomicI32 {
+        // The definition outlives the flag, which is only used while the instance runs.
+        unsafe { AtomicI32::from_ptr(ptr::addr_of_mut!((*self.0.as_ptr()).val.i32)) }
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+
+use wasmer::{AsStoreMut, Extern, Instance, Mutability, Pages, Value};
+
+use crate::Error;
+
+// Snapshots start with the magic number followed by the version of the format.
+const MAGIC: &[u8] = b"wwsnap";
+const VERSION: u8 = 1;
+
+// Size of a WASM page.
+const PAGE_SIZE: usize = 64 * 1024;
+
+const MEMORY: u8 = 0;
+const GLOBAL: u8 = 1;
+
+// State of an instance that can be saved and restored, by export name. Only exported memories
+// and mutable globals are part of it, as nothing else can be reached from the host.
+enum Item {
+    Memory(Vec<u8>),
+    Global(Value),
+}
+
+fn items(
+    store: &mut impl AsStoreMut,
+    instance: &Instance,
+) -> Result<BTreeMap<String, Item>, Error> {
+    let mut items = BTreeMap::new();
+    for (name, export) in instance.exports.iter() {
+        let item = match export {
+            Extern::Memory(memory) => {
+                let data = memory
+                    .view(&*store)
+                    .copy_to_vec()
+                    .map_err(|e| Error::Snapshot(format!("reading memory {name}: {e}")))?;
+                Item::Memory(data)
+            }
+            Extern::Global(global) if global.ty(&*store).mutability == Mutability::Var => {
+                Item::Global(global.get(store))
+            }
+            _ => continue,
+        };
+        items.insert(name.clone(), item);
+    }
+    Ok(items)
+}
+
+// Save the exported memories and mutable globals of instance.
+pub fn snapshot(store: &mut impl AsStoreMut, instance: &Instance) -> Result<Vec<u8>, Error> {
+    let items = items(store, instance)?;
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+    for (name, item) in items {
+        out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        match item {
+            Item::Memory(data) => {
+                out.push(MEMORY);
+                out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                out.extend_from_slice(&data);
+            }
+            Item::Global(value) => {
+                let (ty, bits) = match value {
+                    Value::I32(v) => (0, v as u32 as u64),
+                    Value::I64(v) => (1, v as u64),
+                    Value::F32(v) => (2, v.to_bits() as u64),
+                    Value::F64(v) => (3, v.to_bits()),
+                    value => {
+                        return Err(Error::Snapshot(format!(
+                            "global {name} has unsupported type {:?}",
+                            value.ty()
+                        )))
+                    }
+                };
+                out.push(GLOBAL);
+                out.push(ty);
+                out.extend_from_slice(&bits.to_le_bytes());
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Reader over the bytes of a snapshot, failing on truncated input.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(Error::Snapshot("truncated".to_owned()));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+fn parse(data: &[u8]) -> Result<BTreeMap<String, Item>, Error> {
+    let mut reader = Reader(data);
+    if reader.take(MAGIC.len())? != MAGIC || reader.u8()? != VERSION {
+        return Err(Error::Snapshot("not a snapshot".to_owned()));
+    }
+    let mut items = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        let len = reader.u32()? as usize;
+        let name = String::from_utf8(reader.take(len)?.to_vec())
+            .map_err(|_| Error::Snapshot("export name is not utf-8".to_owned()))?;
+        let item = match reader.u8()? {
+            MEMORY => {
+                let len = reader.u64()? as usize;
+                Item::Memory(reader.take(len)?.to_vec())
+            }
+            GLOBAL => {
+                let ty = reader.u8()?;
+                let bits = reader.u64()?;
+                Item::Global(match ty {
+                    0 => Value::I32(bits as u32 as i32),
+                    1 => Value::I64(bits as i64),
+                    2 => Value::F32(f32::from_bits(bits as u32)),
+                    3 => Value::F64(f64::from_bits(bits)),
+                    ty => return Err(Error::Snapshot(format!("unknown global type {ty}"))),
+                })
+            }
+            kind => return Err(Error::Snapshot(format!("unknown item kind {kind}"))),
+        };
+        items.insert(name, item);
+    }
+    if !reader.0.is_empty() {
+        return Err(Error::Snapshot("trailing data".to_owned()));
+    }
+    Ok(items)
+}
+
+// Load a snapshot into instance. The snapshot must hold the same exported memories and mutable
+// globals as the instance, with globals of the same types and memories no smaller than the
+// instance has, so nothing is loaded into an instance of another module.
+pub fn restore(store: &mut impl AsStoreMut, instance: &Instance, data: &[u8]) -> Result<(), Error> {
+    let saved = parse(data)?;
+    let current = items(store, instance)?;
+    let mismatch = |name: &str| Error::Snapshot(format!("export {name} doesn't match the module"));
+    if saved.len() != current.len() {
+        return Err(Error::Snapshot(format!(
+            "holds {} exports, the module has {}",
+            saved.len(),
+            current.len()
+        )));
+    }
+    for (name, item) in &saved {
+        match (item, current.get(name)) {
+            (Item::Memory(data), Some(Item::Memory(now)))
+                if data.len() >= now.len() && data.len() % PAGE_SIZE == 0 => {}
+            (Item::Global(value), Some(Item::Global(now))) if value.ty() == now.ty() => {}
+            _ => return Err(mismatch(name)),
+        }
+    }
+
+    for (name, item) in saved {
+        match (item, instance.exports.get_extern(&name)) {
+            (Item::Memory(data), Some(Extern::Memory(memory))) => {
+                let grow = (data.len() - memory.view(&*store).data_size() as usize) / PAGE_SIZE;
+                if grow > 0 {
+                    memory
+                        .grow(store, Pages(grow as u32))
+                        .map_err(|e| Error::Snapshot(format!("growing memory {name}: {e}")))?;
+                }
+                memory
+                    .view(&*store)
+                    .write(0, &data)
+                    .map_err(|e| Error::Snapshot(format!("writing memory {name}: {e}")))?;
+            }
+            (Item::Global(value), Some(Extern::Global(global))) => {
+                global
+                    .set(store, value)
+                    .map_err(|e| Error::Snapshot(format!("setting global {name}: {e}")))?;
+            }
+            _ => return Err(mismatch(&name)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::io::{self, SeekFrom, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use wasmer_wasix::{virtual_fs, FsError};
+
+// Where the stdout and stderr of a guest go.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Stdio {
+    // Written to the stdout and stderr of the host a line at a time, as the guest writes them.
+    #[default]
+    Inherit,
+    // Logged line by line and kept for the caller to read back.
+    Capture,
+    // Logged line by line only, for guests whose output is too long-lived to keep.
+    Log,
+    // Written to the stdout of the host a line at a time, each wrapped in a JSON object tagged
+    // with the stream it came from.
+    Json,
+    // Discarded.
+    Null,
+}
+
+// What a guest wrote to its stdout and stderr, when captured.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+enum Sink {
+    // Logged through tracing in the span of the guest, and kept in a buffer shared with the
+    // host if any.
+    Log {
+        span: tracing::Span,
+        buffer: Option<Arc<Mutex<Vec<u8>>>>,
+    },
+    // Written through to the host, wrapped in JSON if json is set.
+    Host {
+        out: Box<dyn Write + Send + Sync>,
+        json: bool,
+    },
+    Null,
+}
+
+// Guest stdout or stderr handing each line written to it on as soon as it is complete. The
+// rest of the last line is handed on when the guest exits.
+pub struct Pipe {
+    stream: &'static str,
+    sink: Sink,
+    // Start of the line being written.
+    line: Vec<u8>,
+}
+
+impl Pipe {
+    // Pipe for stream, "stdout" or "stderr", of a guest running in span. The buffer is filled
+    // with what the guest writes when it is captured.
+    pub fn new(
+        stream: &'static str,
+        stdio: Stdio,
+        span: tracing::Span,
+        buffer: Arc<Mutex<Vec<u8>>>,
+    ) -> Self {
+        let host: Box<dyn Write + Send + Sync> = match stream {
+            "stderr" => Box::new(io::stderr()),
+            _ => Box::new(io::stdout()),
+        };
+        let sink = match stdio {
+            Stdio::Inherit => Sink::Host {
+                out: host,
+                json: false,
+            },
+            Stdio::Capture => Sink::Log {
+                span,
+                buffer: Some(buffer),
+            },
+            Stdio::Log => Sink::Log { span, buffer: None },
+            Stdio::Json => Sink::Host {
+                out: Box::new(io::stdout()),
+                json: true,
+            },
+            Stdio::Null => Sink::Null,
+        };
+        Self::with_sink(stream, sink)
+    }
+
+    fn with_sink(stream: &'static str, sink: Sink) -> Self {
+        Self {
+            stream,
+            sink,
+            line: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, line: &[u8]) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Log { span, .. } => {
+                let line = String::from_utf8_lossy(line);
+                span.in_scope(|| tracing::info!(stream = self.stream, "{}", line.trim_end()));
+                Ok(())
+            }
+            Sink::Host { out, json: false } => {
+                out.write_all(line)?;
+                out.flush()
+            }
+            Sink::Host { out, json: true } => {
+                let line = String::from_utf8_lossy(line);
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                writeln!(
+                    out,
+                    r#"{{"stream":"{}","line":"{}"}}"#,
+                    self.stream,
+                    escape(line)
+                )?;
+                out.flush()
+            }
+            Sink::Null => Ok(()),
+        }
+    }
+}
+
+// Escape s to go between the quotes of a JSON string.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pipe")
+            .field("stream", &self.stream)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // Hand on the last line even if the guest never ended it.
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            let _ = self.emit(&line);
+        }
+    }
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        _: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for Pipe {
+    fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match &this.sink {
+            Sink::Log {
+                buffer: Some(buffer),
+                ..
+            } => buffer.lock().unwrap().extend_from_slice(buf),
+            Sink::Null => return Poll::Ready(Ok(buf.len())),
+            Sink::Log { buffer: None, .. } | Sink::Host { .. } => {}
+        }
+        this.line.extend_from_slice(buf);
+        while let Some(end) = this.line.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = this.line.drain(..=end).collect();
+            this.emit(&line)?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl virtual_fs::VirtualFile for Pipe {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn set_times(&mut self, _: Option<u64>, _: Option<u64>) -> virtual_fs::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _: u64) -> virtual_fs::Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> virtual_fs::Result<()> {
+        Ok(())
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        // Writes go to memory, so they never have to wait.
+        Poll::Ready(Ok(8192))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    // Host stream the tests can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn pipe(host: &Shared, json: bool) -> Pipe {
+        let out = Box::new(host.clone());
+        Pipe::with_sink("stdout", Sink::Host { out, json })
+    }
+
+    #[tokio::test]
+    async fn test_stream_lines() {
+        let host = Shared::default();
+        let mut pipe = pipe(&host, false);
+
+        // Lines reach the host as soon as they are complete, while the guest still runs.
+        pipe.write_all(b"one\ntw").await.unwrap();
+        assert_eq!(host.contents(), "one\n");
+        pipe.write_all(b"o\nthree").await.unwrap();
+        assert_eq!(host.contents(), "one\ntwo\n");
+        drop(pipe);
+        assert_eq!(host.contents(), "one\ntwo\nthree");
+    }
+
+    #[tokio::test]
+    async fn test_log() {
+        // Captured output is kept for the host, logged output isn't.
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let span = tracing::Span::none();
+        let mut captured = Pipe::new("stderr", Stdio::Capture, span.clone(), buffer.clone());
+        captured.write_all(b"kept\n").await.unwrap();
+        let mut logged = Pipe::new("stderr", Stdio::Log, span, buffer.clone());
+        logged.write_all(b"dropped\n").await.unwrap();
+        assert!(logged.line.is_empty());
+        assert_eq!(*buffer.lock().unwrap(), b"kept\n");
+    }
+
+    #[tokio::test]
+    async fn test_stream_json() {
+        let host = Shared::default();
+        let mut pipe = pipe(&host, true);
+        pipe.write_all(b"say \"hi\"\n\tbye").await.unwrap();
+        drop(pipe);
+        assert_eq!(
+            host.contents(),
+            "{\"stream\":\"stdout\",\"line\":\"say \\\"hi\\\"\"}\n\
+             {\"stream\":\"stdout\",\"line\":\"\\tbye\"}\n"
+        );
+    }
+}
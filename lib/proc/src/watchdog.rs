@@ -0,0 +1,42 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use wasmer_wasix::types::wasi::Signal;
+use wasmer_wasix::WasiProcess;
+
+use crate::preempt::Flag;
+
+// Kills a WASI process once its deadline passes, unless cancelled before. The process is sent
+// SIGKILL, which interrupts the guest in whatever syscall it is blocked in, and its preemption
+// flag is raised, which traps it at the next call or loop iteration if it is busy in WASM.
+pub struct Watchdog {
+    cancel: Sender<()>,
+    handle: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    pub fn spawn(deadline: Duration, process: WasiProcess, flag: Option<Arc<Flag>>) -> Self {
+        let (cancel, cancelled) = mpsc::channel();
+        let handle = thread::spawn(move || match cancelled.recv_timeout(deadline) {
+            Err(RecvTimeoutError::Timeout) => {
+                tracing::debug!("killing process {} past its deadline", process.pid());
+                if let Some(flag) = &flag {
+                    flag.raise();
+                }
+                process.signal_process(Signal::Sigkill);
+                true
+            }
+            // Cancelled, or the watchdog was dropped.
+            _ => false,
+        });
+        Self { cancel, handle }
+    }
+
+    // Stop the watchdog and wait for it to exit, returning whether it fired first.
+    pub fn cancel(self) -> bool {
+        let _ = self.cancel.send(());
+        self.handle.join().unwrap_or(false)
+    }
+}
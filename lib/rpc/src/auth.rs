@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+
+use capnp::capability::Client;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::{twoparty, RpcSystem};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use libp2p_identity::PeerId;
+
+use crate::call;
+
+// Prefixes the errors of calls made by callers a server doesn't let through, so they can be told
+// from other failures once they come back over the connection. No other error starts with it.
+const DENIED: &str = "ww.denied:";
+
+// Peer at the other end of the connection a call came over, as verified by the handshake that
+// secured the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Caller {
+    pub peer: PeerId,
+}
+
+// Error of a call caller isn't allowed to make. It reaches the caller as call::Error::Denied.
+pub fn denied(caller: &Caller) -> capnp::Error {
+    capnp::Error::failed(format!("{DENIED} permission denied to {}", caller.peer))
+}
+
+// Whether e is the error of a call the server didn't let through.
+pub fn is_denied(e: &capnp::Error) -> bool {
+    call::marked(e, DENIED).is_some()
+}
+
+// Peers allowed to call the methods it guards.
+#[derive(Clone, Debug, Default)]
+pub struct Allowlist {
+    peers: HashSet<PeerId>,
+}
+
+impl Allowlist {
+    pub fn new(peers: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            peers: peers.into_iter().collect(),
+        }
+    }
+
+    // Let caller through if it is allowed, or else fail with denied.
+    pub fn check(&self, caller: &Caller) -> Result<(), capnp::Error> {
+        match self.peers.contains(&caller.peer) {
+            true => Ok(()),
+            false => Err(denied(caller)),
+        }
+    }
+}
+
+// Run an RPC system over stream, a connection secured with peer, serving it the capability
+// bootstrap makes for it. Methods of that capability know who calls them through the Caller
+// they were made with.
+pub fn serve<S>(
+    stream: S,
+    peer: PeerId,
+    bootstrap: impl FnOnce(Caller) -> Client,
+) -> RpcSystem<Side>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let (read, write) = stream.split();
+    let network = twoparty::VatNetwork::new(read, write, Side::Server, Default::default());
+    RpcSystem::new(Box::new(network), Some(bootstrap(Caller { peer })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use capnp::capability::Promise;
+    use capnp_rpc::pry;
+    use tokio::task::LocalSet;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use crate::call::{self, Call};
+    use crate::cap::wrap;
+    use crate::proc_capnp::proc_;
+    use crate::testing::connect;
+
+    // Takes deliveries from the peers allowed to make them.
+    struct Guarded {
+        caller: Caller,
+        allowed: Allowlist,
+    }
+
+    impl proc_::Server for Guarded {
+        fn deliver(
+            &mut self,
+            _: proc_::DeliverParams,
+            _: proc_::DeliverResults,
+        ) -> Promise<(), capnp::Error> {
+            pry!(self.allowed.check(&self.caller));
+            Promise::ok(())
+        }
+    }
+
+    // Deliver to a guarded proc, over a connection secured with peer.
+    async fn deliver_as(peer: PeerId, allowed: &Allowlist) -> Result<(), call::Error> {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let allowed = allowed.clone();
+        let rpc_b = serve(b.compat(), peer, |caller| {
+            wrap::<proc_::Client>(capnp_rpc::new_client(Guarded { caller, allowed }))
+        });
+        tokio::task::spawn_local(rpc_b);
+        let mut rpc_a = connect(a, Side::Client, None);
+        let remote: proc_::Client = rpc_a.bootstrap(Side::Server);
+        tokio::task::spawn_local(rpc_a);
+        let request = remote.deliver_request();
+        Call::new(request.send().promise).run().await.map(|_| ())
+    }
+
+    #[test]
+    fn test_is_denied() {
+        let caller = Caller {
+            peer: PeerId::random(),
+        };
+        assert!(is_denied(&denied(&caller)));
+        let remote = capnp::Error::failed(format!("remote exception: {}", denied(&caller).extra));
+        assert!(is_denied(&remote));
+        let plain = capnp::Error::failed("open: permission denied".to_string());
+        assert!(!is_denied(&plain));
+        assert!(!is_denied(&capnp::Error::failed(format!("open: {DENIED}"))));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_allowlist() {
+        LocalSet::new()
+            .run_until(async {
+                let (trusted, stranger) = (PeerId::random(), PeerId::random());
+                let allowed = Allowlist::new([trusted]);
+                deliver_as(trusted, &allowed).await.unwrap();
+                let result = deliver_as(stranger, &allowed).await;
+                assert!(matches!(result, Err(call::Error::Denied(_))), "{result:?}");
+            })
+            .await;
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+
+use capnp::capability::{Client, Promise};
+use capnp::Error;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::{pry, twoparty, RpcSystem};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, Stream, StreamExt};
+use libp2p_identity::PeerId;
+
+use crate::auth;
+use crate::cap::wrap;
+use crate::proc_capnp::node;
+
+// Capabilities a node advertises to the peers that connect to it, by name.
+#[derive(Clone, Default)]
+pub struct Capabilities {
+    caps: BTreeMap<String, Client>,
+}
+
+impl Capabilities {
+    // Advertise cap under name, in place of any capability advertised under it before.
+    pub fn with(mut self, name: &str, cap: Client) -> Self {
+        self.caps.insert(name.to_owned(), cap);
+        self
+    }
+}
+
+// Bootstrap capability of a connection, handing the peer the capabilities of the node.
+pub struct Bootstrap {
+    caps: Capabilities,
+}
+
+impl Bootstrap {
+    pub fn new(caps: Capabilities) -> Self {
+        Self { caps }
+    }
+}
+
+impl node::Server for Bootstrap {
+    fn list(&mut self, _: node::ListParams, mut results: node::ListResults) -> Promise<(), Error> {
+        let caps = &self.caps.caps;
+        let mut names = results.get().init_names(caps.len() as u32);
+        for (i, name) in caps.keys().enumerate() {
+            names.set(i as u32, name.as_str().into());
+        }
+        Promise::ok(())
+    }
+
+    fn get(
+        &mut self,
+        params: node::GetParams,
+        mut results: node::GetResults,
+    ) -> Promise<(), Error> {
+        let name = pry!(pry!(pry!(params.get()).get_name()).to_str());
+        match self.caps.caps.get(name) {
+            Some(cap) => {
+                results.get().set_cap(cap.clone());
+                Promise::ok(())
+            }
+            None => Promise::err(Error::failed(format!("no capability named {name}"))),
+        }
+    }
+}
+
+// Serve caps over stream, a connection secured with peer, through a Bootstrap.
+pub fn serve<S>(stream: S, peer: PeerId, caps: Capabilities) -> RpcSystem<Side>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    auth::serve(stream, peer, |_| {
+        wrap::<node::Client>(capnp_rpc::new_client(Bootstrap::new(caps)))
+    })
+}
+
+// Serve caps over each stream coming from incoming, with the peer it was opened by, e.g. the RPC
+// streams a node accepts over the swarm:
+//
+//     rpc::bootstrap::listen(streams.accept(net::RPC_PROTOCOL)?, caps).await;
+//
+// Each connection is served on the current LocalSet until it drops, and streams are taken until
+// incoming ends.
+pub async fn listen<S>(incoming: impl Stream<Item = (PeerId, S)>, caps: Capabilities)
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let mut incoming = std::pin::pin!(incoming);
+    while let Some((peer, stream)) = incoming.next().await {
+        tokio::task::spawn_local(serve(stream, peer, caps.clone()));
+    }
+}
+
+// Bootstrap capability of the node at the other end of stream, e.g. one opened over the swarm:
+//
+//     let node = rpc::bootstrap::connect(streams.open_stream(peer, net::RPC_PROTOCOL).await?);
+//
+// The RPC system runs on the current LocalSet until the connection drops.
+pub fn connect<S>(stream: S) -> node::Client
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let (read, write) = stream.split();
+    let network = twoparty::VatNetwork::new(read, write, Side::Client, Default::default());
+    let mut rpc = RpcSystem::new(Box::new(network), None);
+    let node = rpc.bootstrap(Side::Server);
+    tokio::task::spawn_local(rpc);
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::task::LocalSet;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use crate::cap::{unwrap, Registry};
+    use crate::proc_capnp::{proc_, registry};
+
+    struct Executor;
+
+    impl proc_::Server for Executor {
+        fn deliver(
+            &mut self,
+            _: proc_::DeliverParams,
+            _: proc_::DeliverResults,
+        ) -> Promise<(), Error> {
+            Promise::ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_bootstrap() {
+        LocalSet::new()
+            .run_until(async {
+                let executor: proc_::Client = capnp_rpc::new_client(Executor);
+                let storage: registry::Client = capnp_rpc::new_client(Registry::default());
+                let caps = Capabilities::default()
+                    .with("storage", wrap(storage))
+                    .with("executor", wrap(executor));
+                let (a, b) = tokio::io::duplex(1 << 16);
+                tokio::task::spawn_local(serve(b.compat(), PeerId::random(), caps));
+
+                let node = connect(a.compat());
+                let reply = node.list_request().send().promise.await.unwrap();
+                let names: Vec<&str> = reply
+                    .get()
+                    .unwrap()
+                    .get_names()
+                    .unwrap()
+                    .iter()
+                    .map(|name| name.unwrap().to_str().unwrap())
+                    .collect();
+                assert_eq!(names, ["executor", "storage"]);
+
+                let mut request = node.get_request();
+                request.get().set_name("executor");
+                let reply = request.send().promise.await.unwrap();
+                let executor: proc_::Client = unwrap(reply.get().unwrap().get_cap().unwrap());
+                executor.deliver_request().send().promise.await.unwrap();
+
+                let mut request = node.get_request();
+                request.get().set_name("missing");
+                assert!(request.send().promise.await.is_err());
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_listen() {
+        LocalSet::new()
+            .run_until(async {
+                let storage: registry::Client = capnp_rpc::new_client(Registry::default());
+                let caps = Capabilities::default().with("storage", wrap(storage));
+                let (a, b) = tokio::io::duplex(1 << 16);
+                let (c, d) = tokio::io::duplex(1 << 16);
+                let incoming = [
+                    (PeerId::random(), b.compat()),
+                    (PeerId::random(), d.compat()),
+                ];
+                tokio::task::spawn_local(listen(futures::stream::iter(incoming), caps));
+
+                // Every peer gets the capabilities of the node.
+                for stream in [a, c] {
+                    let node = connect(stream.compat());
+                    let reply = node.list_request().send().promise.await.unwrap();
+                    let names = reply.get().unwrap().get_names().unwrap();
+                    assert_eq!(names.len(), 1);
+                    assert_eq!(names.get(0).unwrap().to_str().unwrap(), "storage");
+                }
+            })
+            .await;
+    }
+}
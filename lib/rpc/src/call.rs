@@ -0,0 +1,221 @@
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use futures::future::{AbortHandle, AbortRegistration, Abortable};
+use prometheus_client::metrics::histogram::Histogram;
+
+use crate::auth;
+
+#[derive(Debug)]
+pub enum Error {
+    // The call didn't return within its timeout.
+    Timeout(Duration),
+    // The call was canceled before it returned.
+    Canceled,
+    // The server didn't let the caller make the call.
+    Denied(String),
+    // The call failed.
+    Rpc(capnp::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Timeout(timeout) => write!(f, "call timed out after {timeout:?}"),
+            Error::Canceled => write!(f, "call canceled"),
+            Error::Denied(msg) => write!(f, "call denied: {msg}"),
+            Error::Rpc(e) => write!(f, "call failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<capnp::Error> for Error {
+    fn from(e: capnp::Error) -> Self {
+        match auth::is_denied(&e) {
+            true => Error::Denied(e.extra),
+            false => Error::Rpc(e),
+        }
+    }
+}
+
+// Prefix capnp-rpc puts before the reason of the errors it gets back from the remote.
+const REMOTE_EXCEPTION: &str = "remote exception: ";
+
+// Rest of the reason of e if it's a failure whose reason starts with marker, be it raised here or
+// by the remote.
+pub(crate) fn marked<'a>(e: &'a capnp::Error, marker: &str) -> Option<&'a str> {
+    if e.kind != capnp::ErrorKind::Failed {
+        return None;
+    }
+    let reason = e.extra.strip_prefix(REMOTE_EXCEPTION).unwrap_or(&e.extra);
+    reason.strip_prefix(marker).map(str::trim_start)
+}
+
+// Cancels the call it was taken from, from anywhere.
+#[derive(Clone, Debug)]
+pub struct Canceler(AbortHandle);
+
+impl Canceler {
+    pub fn cancel(&self) {
+        self.0.abort();
+    }
+}
+
+// An outbound call that gives up when it times out or is canceled. Giving up drops the promise
+// of the call, which finishes its question, so the remote drops its side of the call too and
+// stops working on it.
+pub struct Call<F> {
+    promise: F,
+    timeout: Option<Duration>,
+    abort: AbortHandle,
+    registration: AbortRegistration,
+    // Where the time the call took to return is observed, if anywhere.
+    latency: Option<Histogram>,
+}
+
+impl<F, T> Call<F>
+where
+    F: Future<Output = Result<T, capnp::Error>>,
+{
+    pub fn new(promise: F) -> Self {
+        let (abort, registration) = AbortHandle::new_pair();
+        Self {
+            promise,
+            timeout: None,
+            abort,
+            registration,
+            latency: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Observe in latency how long the call takes, in seconds, if it returns.
+    pub fn with_latency(mut self, latency: Histogram) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    pub fn canceler(&self) -> Canceler {
+        Canceler(self.abort.clone())
+    }
+
+    pub async fn run(self) -> Result<T, Error> {
+        let start = Instant::now();
+        let call = Abortable::new(self.promise, self.registration);
+        let result = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, call)
+                .await
+                .map_err(|_| Error::Timeout(timeout))?,
+            None => call.await,
+        };
+        let result = result.map_err(|_| Error::Canceled)?;
+        if let Some(latency) = &self.latency {
+            latency.observe(start.elapsed().as_secs_f64());
+        }
+        result.map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    use capnp::capability::Promise;
+    use capnp_rpc::rpc_twoparty_capnp::Side;
+    use tokio::task::LocalSet;
+
+    use crate::cap::wrap;
+    use crate::proc_capnp::proc_;
+    use crate::testing::connect;
+
+    // Never returns from a delivery, and records when the remote drops it.
+    struct Stall(Rc<Cell<bool>>);
+
+    struct Dropped(Rc<Cell<bool>>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    impl proc_::Server for Stall {
+        fn deliver(
+            &mut self,
+            _: proc_::DeliverParams,
+            _: proc_::DeliverResults,
+        ) -> Promise<(), capnp::Error> {
+            let dropped = Dropped(self.0.clone());
+            Promise::from_future(async move {
+                let _dropped = dropped;
+                futures::future::pending::<()>().await;
+                Ok(())
+            })
+        }
+    }
+
+    // Connect to a stalling proc, returning it and whether its remote side dropped the call.
+    fn stalled() -> (proc_::Client, Rc<Cell<bool>>) {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let dropped = Rc::new(Cell::new(false));
+        let stall: proc_::Client = capnp_rpc::new_client(Stall(dropped.clone()));
+        tokio::task::spawn_local(connect(b, Side::Server, Some(wrap(stall))));
+        let mut rpc_a = connect(a, Side::Client, None);
+        let remote = rpc_a.bootstrap(Side::Server);
+        tokio::task::spawn_local(rpc_a);
+        (remote, dropped)
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+
+        LocalSet::new()
+            .run_until(async {
+                let (remote, dropped) = stalled();
+                let request = remote.deliver_request();
+                let start = Instant::now();
+                let result = Call::new(request.send().promise)
+                    .with_timeout(TIMEOUT)
+                    .run()
+                    .await;
+                assert!(matches!(result, Err(Error::Timeout(_))));
+                assert!(start.elapsed() >= TIMEOUT);
+
+                // The remote hears the call is finished and drops it.
+                tokio::time::sleep(TIMEOUT).await;
+                assert!(dropped.get());
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_cancel() {
+        LocalSet::new()
+            .run_until(async {
+                let (remote, dropped) = stalled();
+                let request = remote.deliver_request();
+                let call = Call::new(request.send().promise);
+                let canceler = call.canceler();
+                tokio::task::spawn_local(async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    canceler.cancel();
+                });
+                assert!(matches!(call.run().await, Err(Error::Canceled)));
+
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(dropped.get());
+            })
+            .await;
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use capnp::capability::{Client, FromClientHook, Promise};
+use capnp::Error;
+use capnp_rpc::pry;
+
+use crate::proc_capnp::registry;
+
+// Erase the interface of cap, to put it in a Capability field. The RPC system exports it in the
+// cap table of the message the field is written to.
+//
+// Filling in cap tables can't race with the reuse of the arena of a message, as it can in the Go
+// implementation. Every message of a connection, cap table included, is built on the thread that
+// runs its RpcSystem. Clients, hooks and cap tables are held in Rc and RefCell, which aren't Send,
+// so the compiler rejects touching them from another thread, and each return is built in a
+// message and arena of its own, never reused while it is in flight.
+pub fn wrap<T: FromClientHook>(cap: T) -> Client {
+    Client::new(cap.into_client_hook())
+}
+
+// Give back its interface to a capability read from a Capability field. Calls on it go to the
+// node that exported it.
+pub fn unwrap<T: FromClientHook>(cap: Client) -> T {
+    cap.cast_to()
+}
+
+// Holds the capabilities peers put in it, to hand them to the peers that get them.
+#[derive(Default)]
+pub struct Registry {
+    caps: HashMap<String, Client>,
+}
+
+impl registry::Server for Registry {
+    fn put(&mut self, params: registry::PutParams, _: registry::PutResults) -> Promise<(), Error> {
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str());
+        let cap = pry!(params.get_cap());
+        self.caps.insert(name.to_string(), cap);
+        Promise::ok(())
+    }
+
+    fn get(
+        &mut self,
+        params: registry::GetParams,
+        mut results: registry::GetResults,
+    ) -> Promise<(), Error> {
+        let name = pry!(pry!(pry!(params.get()).get_name()).to_str());
+        match self.caps.get(name) {
+            Some(cap) => {
+                results.get().set_cap(cap.clone());
+                Promise::ok(())
+            }
+            None => Promise::err(Error::failed(format!("no capability named {name}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use capnp_rpc::rpc_twoparty_capnp::Side;
+    use tokio::task::LocalSet;
+
+    use crate::proc_capnp::proc_;
+    use crate::testing::connect;
+
+    // Records the deliveries made to it.
+    struct Recorder(Rc<RefCell<Vec<(String, Vec<u8>)>>>);
+
+    impl proc_::Server for Recorder {
+        fn deliver(
+            &mut self,
+            params: proc_::DeliverParams,
+            _: proc_::DeliverResults,
+        ) -> Promise<(), Error> {
+            let params = pry!(params.get());
+            let method = pry!(pry!(params.get_method()).to_str());
+            let event = pry!(params.get_event());
+            self.0
+                .borrow_mut()
+                .push((method.to_string(), event.to_vec()));
+            Promise::ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_pass_capability() {
+        LocalSet::new()
+            .run_until(async {
+                let (a, b) = tokio::io::duplex(1 << 16);
+
+                // B serves a registry, A puts a capability to its recorder in it.
+                let registry: registry::Client = capnp_rpc::new_client(Registry::default());
+                let rpc_b = connect(b, Side::Server, Some(wrap(registry.clone())));
+                tokio::task::spawn_local(rpc_b);
+                let mut rpc_a = connect(a, Side::Client, None);
+                let remote: registry::Client = rpc_a.bootstrap(Side::Server);
+                tokio::task::spawn_local(rpc_a);
+
+                let calls = Rc::new(RefCell::new(Vec::new()));
+                let recorder: proc_::Client = capnp_rpc::new_client(Recorder(calls.clone()));
+                let mut request = remote.put_request();
+                request.get().set_name("proc");
+                request.get().set_cap(wrap(recorder));
+                request.send().promise.await.unwrap();
+
+                // B gets the capability back out and calls it, which runs on A.
+                let mut request = registry.get_request();
+                request.get().set_name("proc");
+                let reply = request.send().promise.await.unwrap();
+                let proc_: proc_::Client = unwrap(reply.get().unwrap().get_cap().unwrap());
+                let mut request = proc_.deliver_request();
+                request.get().set_method("hello");
+                request.get().set_event(b"world");
+                request.send().promise.await.unwrap();
+                assert_eq!(
+                    *calls.borrow(),
+                    vec![("hello".to_string(), b"world".to_vec())]
+                );
+
+                let mut request = registry.get_request();
+                request.get().set_name("missing");
+                assert!(request.send().promise.await.is_err());
+            })
+            .await;
+    }
+
+    // Many returns carrying the same capability are in flight at once. They interleave on the
+    // thread of the RPC system, the only one able to build them (see wrap), and each gets its own
+    // message, so none of their cap tables are filled in while another return is being built.
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_concurrent_returns() {
+        const CALLS: usize = 256;
+
+        LocalSet::new()
+            .run_until(async {
+                let (a, b) = tokio::io::duplex(1 << 16);
+                let registry: registry::Client = capnp_rpc::new_client(Registry::default());
+                tokio::task::spawn_local(connect(a, Side::Server, Some(wrap(registry.clone()))));
+                let mut rpc_b = connect(b, Side::Client, None);
+                let remote: registry::Client = rpc_b.bootstrap(Side::Server);
+                tokio::task::spawn_local(rpc_b);
+
+                let calls = Rc::new(RefCell::new(Vec::new()));
+                let recorder: proc_::Client = capnp_rpc::new_client(Recorder(calls.clone()));
+                let mut request = registry.put_request();
+                request.get().set_name("proc");
+                request.get().set_cap(wrap(recorder));
+                request.send().promise.await.unwrap();
+
+                let delivered = (0..CALLS).map(|i| {
+                    let remote = remote.clone();
+                    async move {
+                        let mut request = remote.get_request();
+                        request.get().set_name("proc");
+                        let reply = request.send().promise.await?;
+                        let proc_: proc_::Client = unwrap(reply.get()?.get_cap()?);
+                        let mut request = proc_.deliver_request();
+                        request.get().set_method(&i.to_string());
+                        request.send().promise.await?;
+                        Ok::<_, Error>(())
+                    }
+                });
+                for result in futures::future::join_all(delivered).await {
+                    result.unwrap();
+                }
+
+                let mut methods: Vec<usize> = calls
+                    .borrow()
+                    .iter()
+                    .map(|(method, _)| method.parse().unwrap())
+                    .collect();
+                methods.sort();
+                assert_eq!(methods, (0..CALLS).collect::<Vec<_>>());
+            })
+            .await;
+    }
+}
@@ -0,0 +1,832 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use capnp::capability::Promise;
+use capnp_rpc::pry;
+use futures::channel::oneshot;
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
+use futures::StreamExt;
+use net::ipfs::{Cid, Client};
+use proc::{Loader, Pipes, RunConfig, Stdio, WasmRuntime};
+use wasmer_wasix::virtual_fs::{FileSystem, RootFileSystemBuilder, TmpFileSystem};
+
+use crate::proc_capnp::{executor, limits, process};
+use crate::{call, stream};
+
+// Prefix the errors of jobs stopped by their limits, so they can be told from other failures once
+// they come back over the connection. No other error starts with them.
+const RESOURCE_LIMIT: &str = "ww.resource_limit:";
+const DEADLINE_EXCEEDED: &str = "ww.deadline_exceeded:";
+
+#[derive(Debug)]
+pub enum Error {
+    // The job ran out of fuel or memory.
+    ResourceLimit(String),
+    // The job didn't finish within its deadline.
+    DeadlineExceeded(String),
+    // The job couldn't be run, or the call failed.
+    Call(call::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ResourceLimit(msg) => write!(f, "job stopped: resource limit exceeded: {msg}"),
+            Error::DeadlineExceeded(msg) => write!(f, "job killed: deadline exceeded {msg}"),
+            Error::Call(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<call::Error> for Error {
+    fn from(e: call::Error) -> Self {
+        match e {
+            call::Error::Rpc(e) => Error::from(e),
+            e => Error::Call(e),
+        }
+    }
+}
+
+impl From<capnp::Error> for Error {
+    fn from(e: capnp::Error) -> Self {
+        if let Some(msg) = call::marked(&e, RESOURCE_LIMIT) {
+            return Error::ResourceLimit(msg.to_owned());
+        }
+        if let Some(msg) = call::marked(&e, DEADLINE_EXCEEDED) {
+            return Error::DeadlineExceeded(msg.to_owned());
+        }
+        Error::Call(call::Error::from(e))
+    }
+}
+
+// Error of a job that failed to run, as sent back to the peer that submitted it.
+fn failed(e: proc::Error) -> capnp::Error {
+    match e {
+        proc::Error::ResourceLimit(msg) => capnp::Error::failed(format!("{RESOURCE_LIMIT} {msg}")),
+        proc::Error::DeadlineExceeded(deadline) => {
+            capnp::Error::failed(format!("{DEADLINE_EXCEEDED} after {deadline:?}"))
+        }
+        e => capnp::Error::failed(e.to_string()),
+    }
+}
+
+// Limits a job runs under. Those left unset are the ones of the executor running it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub max_memory_pages: Option<u32>,
+    pub deadline: Option<Duration>,
+}
+
+impl Limits {
+    // Limits no looser than ceiling, which fills in those left unset.
+    pub fn within(&self, ceiling: &Limits) -> Limits {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        Limits {
+            fuel: min(self.fuel, ceiling.fuel),
+            max_memory_pages: min(self.max_memory_pages, ceiling.max_memory_pages),
+            deadline: min(self.deadline, ceiling.deadline),
+        }
+    }
+
+    fn read(reader: limits::Reader) -> Self {
+        Self {
+            fuel: Some(reader.get_fuel()).filter(|&fuel| fuel > 0),
+            max_memory_pages: Some(reader.get_max_memory_pages()).filter(|&pages| pages > 0),
+            deadline: Some(reader.get_deadline_ms())
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+        }
+    }
+
+    fn write(&self, mut builder: limits::Builder) {
+        builder.set_fuel(self.fuel.unwrap_or(0));
+        builder.set_max_memory_pages(self.max_memory_pages.unwrap_or(0));
+        let deadline = self.deadline.map_or(0, |d| d.as_millis() as u64);
+        builder.set_deadline_ms(deadline);
+    }
+}
+
+// A module to run, by its '/ipfs/<cid>' path, with what it runs with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Job {
+    pub module: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub limits: Limits,
+}
+
+impl Job {
+    // Job from the fields of a run or spawn request, limited to ceiling.
+    fn read(
+        module: capnp::text::Reader,
+        args: capnp::text_list::Reader,
+        env: capnp::text_list::Reader,
+        limits: limits::Reader,
+        ceiling: &Limits,
+    ) -> Result<Self, capnp::Error> {
+        let mut job = Job {
+            module: module.to_string()?,
+            limits: Limits::read(limits).within(ceiling),
+            ..Default::default()
+        };
+        for arg in args {
+            job.args.push(arg?.to_string()?);
+        }
+        for var in env {
+            let var = var?.to_str()?;
+            let Some((key, value)) = var.split_once('=') else {
+                let e = format!("invalid environment variable {var:?}");
+                return Err(capnp::Error::failed(e));
+            };
+            job.env.push((key.to_owned(), value.to_owned()));
+        }
+        Ok(job)
+    }
+}
+
+// Fill the fields of a run or spawn request with a job.
+macro_rules! write_job {
+    ($params:expr, $job:expr) => {{
+        let (mut params, job): (_, &Job) = ($params, $job);
+        params.set_module(job.module.as_str().into());
+        let mut args = params.reborrow().init_args(job.args.len() as u32);
+        for (i, arg) in job.args.iter().enumerate() {
+            args.set(i as u32, arg.as_str().into());
+        }
+        let mut env = params.reborrow().init_env(job.env.len() as u32);
+        for (i, (key, value)) in job.env.iter().enumerate() {
+            env.set(i as u32, format!("{key}={value}").as_str().into());
+        }
+        job.limits.write(params.init_limits());
+    }};
+}
+
+// How a job exited, and what it wrote to its stdout.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Outcome {
+    pub exit_code: i32,
+    pub stdout: Vec<u8>,
+}
+
+// Where the output of a job is stored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Storage {
+    // Path of the file holding the output in the filesystem of the job, or None for its stdout.
+    pub output: Option<String>,
+    // Whether the node of the executor pins the output.
+    pub pin: bool,
+}
+
+// How a job exited, and the root CID of its stored output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stored {
+    pub exit_code: i32,
+    pub cid: Cid,
+}
+
+// Where an executor gets the modules named by the jobs it runs.
+pub trait Modules {
+    // Module at path, an '/ipfs/<cid>/<path>' path, compiled for runtime.
+    fn load<'a>(
+        &'a self,
+        runtime: &'a WasmRuntime,
+        path: &'a str,
+    ) -> LocalBoxFuture<'a, Result<wasmer::Module, proc::Error>>;
+}
+
+impl Modules for Loader {
+    fn load<'a>(
+        &'a self,
+        runtime: &'a WasmRuntime,
+        path: &'a str,
+    ) -> LocalBoxFuture<'a, Result<wasmer::Module, proc::Error>> {
+        Box::pin(Loader::load(self, runtime, path))
+    }
+}
+
+// Runs the jobs peers submit on the local runtime, each with a runtime of its own built for its
+// limits, which are kept within the ceiling of the executor. Jobs are run on the blocking
+// threads of the tokio runtime, so the RPC system keeps serving calls while they run.
+pub struct Executor {
+    modules: Arc<dyn Modules>,
+    // Client outputs are stored through.
+    client: Client,
+    ceiling: Limits,
+}
+
+impl Executor {
+    pub fn new(loader: Arc<Loader>, ceiling: Limits) -> Self {
+        Self {
+            client: loader.client().clone(),
+            modules: loader,
+            ceiling,
+        }
+    }
+
+    // Take the modules of jobs from modules rather than from the loader.
+    pub fn with_modules(mut self, modules: Arc<dyn Modules>) -> Self {
+        self.modules = modules;
+        self
+    }
+
+    // Runtime built for the limits of job, and its module compiled for it.
+    async fn load(
+        modules: &dyn Modules,
+        job: Job,
+        stdio: Stdio,
+    ) -> Result<(WasmRuntime, wasmer::Module), proc::Error> {
+        let pages = job.limits.max_memory_pages;
+        let config = RunConfig {
+            fuel: job.limits.fuel,
+            max_memory_pages: pages.unwrap_or(proc::DEFAULT_MAX_MEMORY_PAGES),
+            deadline: job.limits.deadline,
+            stdio,
+            args: job.args,
+            env: job.env,
+            ..Default::default()
+        };
+        config.validate()?;
+        let runtime = WasmRuntime::with_config(config);
+        let module = modules.load(&runtime, &job.module).await?;
+        Ok((runtime, module))
+    }
+
+    // Run job with fs as its root filesystem, which is left as the job left it.
+    async fn run(
+        modules: &dyn Modules,
+        job: Job,
+        fs: TmpFileSystem,
+    ) -> Result<Outcome, proc::Error> {
+        let (mut runtime, module) = Self::load(modules, job, Stdio::Capture).await?;
+        let run = move || {
+            let mut process = runtime
+                .instantiate(&module, fs)
+                .map_err(|e| proc::Error::Config(e.to_string()))?;
+            let exit_code = process.run(runtime.store_mut())?;
+            let stdout = process.output().stdout;
+            Ok(Outcome { exit_code, stdout })
+        };
+        tokio::task::spawn_blocking(run)
+            .await
+            .map_err(|e| proc::Error::Config(e.to_string()))?
+    }
+
+    // Run job, and store its output in IPFS.
+    async fn store(
+        modules: &dyn Modules,
+        client: &Client,
+        job: Job,
+        storage: Storage,
+    ) -> Result<Stored, capnp::Error> {
+        let fs = RootFileSystemBuilder::new().build();
+        let outcome = Self::run(modules, job, fs.clone()).await.map_err(failed)?;
+        let added = match &storage.output {
+            Some(path) => {
+                let file = match fs.new_open_options().read(true).open(path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        let e = format!("cannot open output {path}: {e}");
+                        return Err(capnp::Error::failed(e));
+                    }
+                };
+                client.add_file(file).await
+            }
+            None => client.add_file(&outcome.stdout[..]).await,
+        };
+        let cid = added.map_err(|e| capnp::Error::failed(e.to_string()))?;
+        if storage.pin {
+            let pinned = client.pin(&cid, true).await;
+            pinned.map_err(|e| capnp::Error::failed(e.to_string()))?;
+        }
+        Ok(Stored {
+            exit_code: outcome.exit_code,
+            cid,
+        })
+    }
+
+    // Run job with its stdin taken from items and its stdout pushed through stdout, returning
+    // its exit code once all of its stdout was taken. Items are only taken while the pipe to
+    // the guest has room for them, and the guest is blocked in its writes while stdout has no
+    // credit left, so neither side runs ahead of the other.
+    async fn stream(
+        modules: Arc<dyn Modules>,
+        job: Job,
+        mut items: stream::Items,
+        mut stdout: stream::Sender,
+    ) -> Result<i32, capnp::Error> {
+        // Stderr is only logged, as stdout is the only stream handed back and the job may run
+        // for as long as its input lasts.
+        let (mut runtime, module) = Self::load(&*modules, job, Stdio::Log)
+            .await
+            .map_err(failed)?;
+        let fs = RootFileSystemBuilder::new().build();
+        let (mut process, pipes) = runtime
+            .instantiate_piped(&module, fs, stream::DEFAULT_WINDOW)
+            .map_err(|e| capnp::Error::failed(e.to_string()))?;
+        let Pipes {
+            stdin,
+            stdout: mut output,
+        } = pipes;
+
+        // Dropping stdin is the end of the input of the guest.
+        let feed = tokio::task::spawn_local(async move {
+            while let Some(item) = items.next().await {
+                if stdin.send(item).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let guest = tokio::task::spawn_blocking(move || process.run(runtime.store_mut()));
+        // Dropping output when stdout fails breaks the pipe of the guest, rather than leaving
+        // it blocked in a write.
+        let pump = async move {
+            while let Some(chunk) = output.recv().await {
+                stdout.send(&chunk).await?;
+            }
+            stdout.finish().await
+        };
+        let (exit, pumped) = futures::join!(guest, pump);
+        feed.abort();
+        let exit_code = exit
+            .map_err(|e| capnp::Error::failed(e.to_string()))?
+            .map_err(failed)?;
+        pumped?;
+        Ok(exit_code)
+    }
+}
+
+impl executor::Server for Executor {
+    fn run(
+        &mut self,
+        params: executor::RunParams,
+        mut results: executor::RunResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let job = pry!(Job::read(
+            pry!(params.get_module()),
+            pry!(params.get_args()),
+            pry!(params.get_env()),
+            pry!(params.get_limits()),
+            &self.ceiling,
+        ));
+
+        let modules = self.modules.clone();
+        Promise::from_future(async move {
+            let fs = RootFileSystemBuilder::new().build();
+            let outcome = Self::run(&*modules, job, fs).await.map_err(failed)?;
+            let mut results = results.get();
+            results.set_exit_code(outcome.exit_code);
+            results.set_stdout(&outcome.stdout);
+            Ok(())
+        })
+    }
+
+    fn spawn(
+        &mut self,
+        params: executor::SpawnParams,
+        mut results: executor::SpawnResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let job = pry!(Job::read(
+            pry!(params.get_module()),
+            pry!(params.get_args()),
+            pry!(params.get_env()),
+            pry!(params.get_limits()),
+            &self.ceiling,
+        ));
+        let stdout = stream::Sender::new(pry!(params.get_stdout()));
+
+        let (stdin, items) = stream::channel();
+        let (exited, exit) = oneshot::channel();
+        let modules = self.modules.clone();
+        tokio::task::spawn_local(async move {
+            let _ = exited.send(Self::stream(modules, job, items, stdout).await);
+        });
+        let mut results = results.get();
+        results.set_stdin(stdin);
+        results.set_process(capnp_rpc::new_client(Guest {
+            exit: exit.shared(),
+        }));
+        Promise::ok(())
+    }
+
+    fn store(
+        &mut self,
+        params: executor::StoreParams,
+        mut results: executor::StoreResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let job = pry!(Job::read(
+            pry!(params.get_module()),
+            pry!(params.get_args()),
+            pry!(params.get_env()),
+            pry!(params.get_limits()),
+            &self.ceiling,
+        ));
+        let output = pry!(pry!(params.get_output()).to_string());
+        let storage = Storage {
+            output: Some(output).filter(|output| !output.is_empty()),
+            pin: params.get_pin(),
+        };
+
+        let (modules, client) = (self.modules.clone(), self.client.clone());
+        Promise::from_future(async move {
+            let stored = Self::store(&*modules, &client, job, storage).await?;
+            let mut results = results.get();
+            results.set_exit_code(stored.exit_code);
+            results.set_cid(stored.cid.to_string().as_str().into());
+            Ok(())
+        })
+    }
+}
+
+// Serves the process of a job started with spawn.
+struct Guest {
+    exit: Shared<oneshot::Receiver<Result<i32, capnp::Error>>>,
+}
+
+impl process::Server for Guest {
+    fn wait(
+        &mut self,
+        _: process::WaitParams,
+        mut results: process::WaitResults,
+    ) -> Promise<(), capnp::Error> {
+        let exit = self.exit.clone();
+        Promise::from_future(async move {
+            match exit.await {
+                Ok(exit) => results.get().set_exit_code(exit?),
+                Err(_) => return Err(capnp::Error::failed("job lost".to_string())),
+            }
+            Ok(())
+        })
+    }
+}
+
+// Run job on the node serving executor, e.g. one found through its bootstrap capability.
+pub async fn run(executor: &executor::Client, job: &Job) -> Result<Outcome, Error> {
+    let mut request = executor.run_request();
+    write_job!(request.get(), job);
+
+    let reply = call::Call::new(request.send().promise).run().await?;
+    let reply = reply.get()?;
+    Ok(Outcome {
+        exit_code: reply.get_exit_code(),
+        stdout: reply.get_stdout()?.to_vec(),
+    })
+}
+
+// Run job on the node serving executor, which stores its output in IPFS as storage says. The
+// output can then be read from its CID, e.g. through an IpfsFs.
+pub async fn store(
+    executor: &executor::Client,
+    job: &Job,
+    storage: &Storage,
+) -> Result<Stored, Error> {
+    let mut request = executor.store_request();
+    write_job!(request.get(), job);
+    let output = storage.output.as_deref().unwrap_or_default();
+    request.get().set_output(output.into());
+    request.get().set_pin(storage.pin);
+
+    let reply = call::Call::new(request.send().promise).run().await?;
+    let reply = reply.get()?;
+    let cid = reply.get_cid()?.to_str()?;
+    let cid = cid
+        .parse()
+        .map_err(|e| capnp::Error::failed(format!("invalid CID {cid}: {e}")))?;
+    Ok(Stored {
+        exit_code: reply.get_exit_code(),
+        cid,
+    })
+}
+
+// A job started with spawn. Its stdin is pushed through stdin, and ends once stdin is finished.
+// Its stdout is taken from stdout, which ends once the job exits.
+pub struct Spawned {
+    pub stdin: stream::Sender,
+    pub stdout: stream::Items,
+    pub process: Process,
+}
+
+// Process of a spawned job.
+pub struct Process(process::Client);
+
+impl Process {
+    // Wait for the job to exit, and for its stdout to be taken, returning its exit code.
+    pub async fn wait(&self) -> Result<i32, Error> {
+        let request = self.0.wait_request();
+        let reply = call::Call::new(request.send().promise).run().await?;
+        Ok(reply.get()?.get_exit_code())
+    }
+}
+
+// Start job on the node serving executor, with its stdin and stdout streamed.
+pub async fn spawn(executor: &executor::Client, job: &Job) -> Result<Spawned, Error> {
+    let (sink, stdout) = stream::channel();
+    let mut request = executor.spawn_request();
+    write_job!(request.get(), job);
+    request.get().set_stdout(sink);
+
+    let reply = call::Call::new(request.send().promise).run().await?;
+    let reply = reply.get()?;
+    Ok(Spawned {
+        stdin: stream::Sender::new(reply.get_stdin()?),
+        stdout,
+        process: Process(reply.get_process()?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fs::IpfsFs;
+    use futures::TryStreamExt;
+    use libp2p_identity::PeerId;
+    use net::testing::Daemon;
+    use tokio::io::AsyncReadExt;
+    use tokio::task::LocalSet;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use crate::bootstrap::{self, Capabilities};
+    use crate::cap::{unwrap, wrap};
+
+    // Modules compiled from the WAT source kept under their path, with no daemon to fetch from.
+    struct Wat(Vec<(String, String)>);
+
+    impl Modules for Wat {
+        fn load<'a>(
+            &'a self,
+            runtime: &'a WasmRuntime,
+            path: &'a str,
+        ) -> LocalBoxFuture<'a, Result<wasmer::Module, proc::Error>> {
+            let wat = self.0.iter().find(|(p, _)| p == path).map(|(_, wat)| wat);
+            let module = match wat {
+                Some(wat) => {
+                    let bytecode = wasmer::wat2wasm(wat.as_bytes()).unwrap();
+                    wasmer::Module::new(runtime.store(), bytecode).map_err(proc::Error::from)
+                }
+                None => Err(net::ipfs::Error::NotFound(path.to_owned()).into()),
+            };
+            Box::pin(async move { module })
+        }
+    }
+
+    // Executor of a node running modules compiled from WAT source and storing outputs through
+    // client, as found by a peer connected to it.
+    async fn serve_wat(client: Client, ceiling: Limits, modules: Wat) -> executor::Client {
+        let executor = Executor::new(Arc::new(Loader::new(client)), ceiling);
+        serve(executor.with_modules(Arc::new(modules))).await
+    }
+
+    // Executor, as found by a peer connected to the node serving it.
+    async fn serve(executor: Executor) -> executor::Client {
+        let executor: executor::Client = capnp_rpc::new_client(executor);
+        let caps = Capabilities::default().with("executor", wrap(executor));
+        let (a, b) = tokio::io::duplex(1 << 16);
+        tokio::task::spawn_local(bootstrap::serve(b.compat(), PeerId::random(), caps));
+
+        let node = bootstrap::connect(a.compat());
+        let mut request = node.get_request();
+        request.get().set_name("executor");
+        let reply = request.send().promise.await.unwrap();
+        unwrap(reply.get().unwrap().get_cap().unwrap())
+    }
+
+    #[test]
+    fn test_limits() {
+        let ceiling = Limits {
+            fuel: Some(1_000),
+            max_memory_pages: None,
+            deadline: Some(Duration::from_secs(1)),
+        };
+        let asked = Limits {
+            fuel: Some(10_000),
+            max_memory_pages: Some(4),
+            deadline: Some(Duration::from_millis(100)),
+        };
+        let within = Limits {
+            fuel: Some(1_000),
+            max_memory_pages: Some(4),
+            deadline: Some(Duration::from_millis(100)),
+        };
+        assert_eq!(asked.within(&ceiling), within);
+        assert_eq!(Limits::default().within(&ceiling), ceiling);
+    }
+
+    // Outcome of a job spinning forever on an executor with ceiling, served from WAT source.
+    async fn spin(ceiling: Limits) -> Result<Outcome, Error> {
+        let spin = r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start") (loop $spin (br $spin))))"#;
+        let modules = Wat(vec![("/ipfs/spin".to_owned(), spin.to_owned())]);
+        // Modules aren't fetched and outputs aren't stored, so the client is never used.
+        let client = Client::new("/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        let executor = serve_wat(client, ceiling, modules).await;
+        let job = Job {
+            module: "/ipfs/spin".to_owned(),
+            ..Default::default()
+        };
+        run(&executor, &job).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_limits_exceeded() {
+        LocalSet::new()
+            .run_until(async {
+                let ceiling = Limits {
+                    fuel: Some(1_000_000),
+                    ..Default::default()
+                };
+                let result = spin(ceiling).await;
+                assert!(matches!(result, Err(Error::ResourceLimit(_))), "{result:?}");
+
+                let ceiling = Limits {
+                    deadline: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                };
+                let result = spin(ceiling).await;
+                let killed = matches!(result, Err(Error::DeadlineExceeded(_)));
+                assert!(killed, "{result:?}");
+            })
+            .await;
+    }
+
+    #[test]
+    fn test_markers() {
+        let limit = failed(proc::Error::ResourceLimit("out of fuel".to_owned()));
+        let remote = capnp::Error::failed(format!("remote exception: {}", limit.extra));
+        assert!(matches!(Error::from(remote), Error::ResourceLimit(msg) if msg == "out of fuel"));
+        let plain = capnp::Error::failed("open: deadline exceeded".to_owned());
+        assert!(matches!(Error::from(plain), Error::Call(_)));
+        let missing = failed(net::ipfs::Error::NotFound("/ipfs/missing".to_owned()).into());
+        assert!(matches!(Error::from(missing), Error::Call(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_remote_run() {
+        // Print the first argument and exit with 3, or spin forever when given no argument.
+        let wat = r#"(module
+            (import "wasi_snapshot_preview1" "args_sizes_get"
+                (func $args_sizes_get (param i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "args_get"
+                (func $args_get (param i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (drop (call $args_sizes_get (i32.const 0) (i32.const 4)))
+                (if (i32.lt_u (i32.load (i32.const 0)) (i32.const 2))
+                    (then (loop $spin (br $spin))))
+                (drop (call $args_get (i32.const 1024) (i32.const 2048)))
+                ;; Write the 5 bytes of "hello" through an iovec at 16.
+                (i32.store (i32.const 16) (i32.load (i32.const 1028)))
+                (i32.store (i32.const 20) (i32.const 5))
+                (drop (call $fd_write (i32.const 1) (i32.const 16) (i32.const 1) (i32.const 24)))
+                (call $proc_exit (i32.const 3))))"#;
+        let module = "/ipfs/hello".to_owned();
+        let modules = Wat(vec![(module.clone(), wat.to_owned())]);
+        let client = Client::new("/ip4/127.0.0.1/tcp/1".parse().unwrap());
+
+        LocalSet::new()
+            .run_until(async {
+                let ceiling = Limits {
+                    fuel: Some(1_000_000),
+                    ..Default::default()
+                };
+                let executor = serve_wat(client, ceiling, modules).await;
+
+                let mut job = Job {
+                    module,
+                    args: vec!["hello".to_owned()],
+                    ..Default::default()
+                };
+                let outcome = run(&executor, &job).await.unwrap();
+                assert_eq!(outcome.exit_code, 3);
+                assert_eq!(outcome.stdout, b"hello");
+
+                // Jobs are stopped once they burn the fuel of the executor, asked for or not.
+                job.args.clear();
+                job.limits.fuel = Some(u64::MAX);
+                let result = run(&executor, &job).await;
+                assert!(matches!(result, Err(Error::ResourceLimit(_))), "{result:?}");
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_remote_stream() {
+        // Copy stdin to stdout, through a 1 KiB buffer at 1024, until stdin ends.
+        let echo = r#"(module
+            (import "wasi_snapshot_preview1" "fd_read"
+                (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 1024))
+                (block $eof
+                    (loop $copy
+                        (i32.store (i32.const 4) (i32.const 1024))
+                        (drop (call $fd_read
+                            (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                        (br_if $eof (i32.eqz (i32.load (i32.const 8))))
+                        (i32.store (i32.const 4) (i32.load (i32.const 8)))
+                        (drop (call $fd_write
+                            (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                        (br $copy)))))"#;
+        let module = "/ipfs/echo".to_owned();
+        let modules = Wat(vec![(module.clone(), echo.to_owned())]);
+        let client = Client::new("/ip4/127.0.0.1/tcp/1".parse().unwrap());
+
+        LocalSet::new()
+            .run_until(async {
+                let executor = serve_wat(client, Limits::default(), modules).await;
+                let job = Job {
+                    module,
+                    ..Default::default()
+                };
+                let Spawned {
+                    mut stdin,
+                    stdout,
+                    process,
+                } = spawn(&executor, &job).await.unwrap();
+
+                let input: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i; 100]).collect();
+                let producer = tokio::task::spawn_local({
+                    let input = input.clone();
+                    async move {
+                        for chunk in &input {
+                            stdin.send(chunk).await?;
+                        }
+                        // Finishing stdin is what lets the guest exit.
+                        stdin.finish().await
+                    }
+                });
+                let output: Vec<Vec<u8>> = stdout.collect().await;
+                assert_eq!(output.concat(), input.concat());
+                producer.await.unwrap().unwrap();
+                assert_eq!(process.wait().await.unwrap(), 0);
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_remote_store() {
+        // Write "stored\n" to stdout, through an iovec at 0.
+        let print = r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\10\00\00\00\07\00\00\00")
+            (data (i32.const 16) "stored\n")
+            (func (export "_start")
+                (drop (call $fd_write
+                    (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 32)))))"#;
+        let module = "/ipfs/print".to_owned();
+        let modules = Wat(vec![(module.clone(), print.to_owned())]);
+        // Outputs are stored on the node of the executor.
+        let daemon = Daemon::start();
+        let client = daemon.client();
+
+        let stored = LocalSet::new()
+            .run_until({
+                let client = client.clone();
+                async move {
+                    let executor = serve_wat(client, Limits::default(), modules).await;
+                    let job = Job {
+                        module,
+                        ..Default::default()
+                    };
+                    let storage = Storage {
+                        output: None,
+                        pin: true,
+                    };
+                    store(&executor, &job, &storage).await.unwrap()
+                }
+            })
+            .await;
+        assert_eq!(stored.exit_code, 0);
+        let pins: Vec<Cid> = client.pins().try_collect().await.unwrap();
+        assert!(pins.contains(&stored.cid));
+
+        // Opens fetch blocks with blocking calls.
+        let fs = IpfsFs::new(client);
+        let path = format!("/ipfs/{}", stored.cid);
+        let mut file =
+            tokio::task::block_in_place(|| fs.new_open_options().read(true).open(path).unwrap());
+        let mut output = Vec::new();
+        file.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, b"stored\n");
+    }
+}
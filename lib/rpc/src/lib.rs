@@ -13,8 +13,36 @@ pub mod proc_capnp {
     include!(concat!(env!("OUT_DIR"), "/proc_capnp.rs"));
 }
 
+pub mod auth;
+pub mod bootstrap;
+pub mod call;
+pub mod cap;
 pub mod client;
+pub mod executor;
+pub mod reconnect;
 pub mod server;
+pub mod stream;
+
+#[cfg(test)]
+mod testing {
+    use capnp::capability::Client;
+    use capnp_rpc::rpc_twoparty_capnp::Side;
+    use capnp_rpc::{twoparty, RpcSystem};
+    use tokio::io::DuplexStream;
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    // Run an RPC system over stream, serving bootstrap to the other side.
+    pub fn connect(stream: DuplexStream, side: Side, bootstrap: Option<Client>) -> RpcSystem<Side> {
+        let (read, write) = tokio::io::split(stream);
+        let network = twoparty::VatNetwork::new(
+            read.compat(),
+            write.compat_write(),
+            side,
+            Default::default(),
+        );
+        RpcSystem::new(Box::new(network), bootstrap)
+    }
+}
 
 
 // // cost_function function will be called for each `Operator` encountered during
@@ -0,0 +1,228 @@
+use std::future::Future;
+use std::time::Duration;
+
+use capnp::ErrorKind;
+
+use crate::call::Error;
+
+// How often to try connecting again while waiting out an outage.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// What calls do while the peer can't be reached.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Outage {
+    // Fail as soon as connecting fails.
+    #[default]
+    FailFast,
+    // Keep trying to connect for up to this long.
+    Wait(Duration),
+}
+
+// A capability that outlives the connection it came over. Once a call fails because the
+// connection dropped, the next call connects again and gets the capability anew, through
+// connect.
+pub struct Reconnect<T, C> {
+    connect: C,
+    cap: Option<T>,
+    outage: Outage,
+}
+
+impl<T, C, F> Reconnect<T, C>
+where
+    T: Clone,
+    C: FnMut() -> F,
+    F: Future<Output = Result<T, capnp::Error>>,
+{
+    pub fn new(connect: C) -> Self {
+        Self {
+            connect,
+            cap: None,
+            outage: Outage::default(),
+        }
+    }
+
+    pub fn with_outage(mut self, outage: Outage) -> Self {
+        self.outage = outage;
+        self
+    }
+
+    // Call f on the capability. A call that fails because the connection dropped isn't retried,
+    // since it may have run, but the capability is forgotten so the next call reconnects.
+    pub async fn call<R, G, Fut>(&mut self, f: G) -> Result<R, Error>
+    where
+        G: FnOnce(T) -> Fut,
+        Fut: Future<Output = Result<R, capnp::Error>>,
+    {
+        let cap = self.resolve().await?;
+        let result = f(cap).await;
+        if let Err(e) = &result {
+            if e.kind == ErrorKind::Disconnected {
+                self.cap = None;
+            }
+        }
+        result.map_err(Error::from)
+    }
+
+    async fn resolve(&mut self) -> Result<T, Error> {
+        if let Some(cap) = &self.cap {
+            return Ok(cap.clone());
+        }
+        let cap = match self.outage {
+            Outage::FailFast => (self.connect)().await?,
+            Outage::Wait(wait) => tokio::time::timeout(wait, self.retry())
+                .await
+                .map_err(|_| Error::Timeout(wait))?,
+        };
+        self.cap = Some(cap.clone());
+        Ok(cap)
+    }
+
+    async fn retry(&mut self) -> T {
+        loop {
+            match (self.connect)().await {
+                Ok(cap) => return cap,
+                Err(_) => tokio::time::sleep(RETRY_INTERVAL).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use capnp::capability::Promise;
+    use capnp_rpc::rpc_twoparty_capnp::Side;
+    use futures::future::{self, Ready};
+    use tokio::task::{JoinHandle, LocalSet};
+
+    use crate::cap::wrap;
+    use crate::proc_capnp::proc_;
+    use crate::testing::connect;
+
+    // Counts the deliveries made to it.
+    struct Counter(Rc<Cell<usize>>);
+
+    impl proc_::Server for Counter {
+        fn deliver(
+            &mut self,
+            _: proc_::DeliverParams,
+            _: proc_::DeliverResults,
+        ) -> Promise<(), capnp::Error> {
+            self.0.set(self.0.get() + 1);
+            Promise::ok(())
+        }
+    }
+
+    // A peer serving a counter, which can go down and come back.
+    #[derive(Clone)]
+    struct Peer {
+        up: Rc<Cell<bool>>,
+        counter: proc_::Client,
+        delivered: Rc<Cell<usize>>,
+        // The side of the current connection the peer runs.
+        conn: Rc<RefCell<Option<JoinHandle<Result<(), capnp::Error>>>>>,
+    }
+
+    impl Peer {
+        fn new() -> Self {
+            let delivered = Rc::new(Cell::new(0));
+            Self {
+                up: Rc::new(Cell::new(true)),
+                counter: capnp_rpc::new_client(Counter(delivered.clone())),
+                delivered,
+                conn: Rc::default(),
+            }
+        }
+
+        fn connect(&self) -> Ready<Result<proc_::Client, capnp::Error>> {
+            if !self.up.get() {
+                return future::ready(Err(capnp::Error::disconnected("peer down".to_string())));
+            }
+            let (a, b) = tokio::io::duplex(1 << 16);
+            let conn = connect(b, Side::Server, Some(wrap(self.counter.clone())));
+            *self.conn.borrow_mut() = Some(tokio::task::spawn_local(conn));
+            let mut rpc = connect(a, Side::Client, None);
+            let cap = rpc.bootstrap(Side::Server);
+            tokio::task::spawn_local(rpc);
+            future::ready(Ok(cap))
+        }
+
+        // Go down, dropping the current connection.
+        async fn crash(&self) {
+            self.up.set(false);
+            if let Some(conn) = self.conn.borrow_mut().take() {
+                conn.abort();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn deliver(cap: proc_::Client) -> Result<(), capnp::Error> {
+        cap.deliver_request().send().promise.await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_reconnect() {
+        LocalSet::new()
+            .run_until(async {
+                let peer = Peer::new();
+                let mut cap = Reconnect::new({
+                    let peer = peer.clone();
+                    move || peer.connect()
+                });
+                cap.call(deliver).await.unwrap();
+
+                // Calls fail while the peer is down, the first one on the lost connection.
+                peer.crash().await;
+                let lost = cap.call(deliver).await;
+                assert!(matches!(lost, Err(Error::Rpc(e)) if e.kind == ErrorKind::Disconnected));
+                assert!(cap.call(deliver).await.is_err());
+
+                // Once the peer is back, the next call goes through a new connection.
+                peer.up.set(true);
+                cap.call(deliver).await.unwrap();
+                assert_eq!(peer.delivered.get(), 2);
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_wait() {
+        const WAIT: Duration = Duration::from_millis(500);
+
+        LocalSet::new()
+            .run_until(async {
+                let peer = Peer::new();
+                let mut cap = Reconnect::new({
+                    let peer = peer.clone();
+                    move || peer.connect()
+                })
+                .with_outage(Outage::Wait(WAIT));
+                cap.call(deliver).await.unwrap();
+                peer.crash().await;
+                assert!(cap.call(deliver).await.is_err());
+
+                // A call waits for the peer to come back...
+                tokio::task::spawn_local({
+                    let peer = peer.clone();
+                    async move {
+                        tokio::time::sleep(WAIT / 5).await;
+                        peer.up.set(true);
+                    }
+                });
+                cap.call(deliver).await.unwrap();
+                assert_eq!(peer.delivered.get(), 2);
+
+                // ...but not longer than it may.
+                peer.crash().await;
+                assert!(cap.call(deliver).await.is_err());
+                let result = cap.call(deliver).await;
+                assert!(matches!(result, Err(Error::Timeout(_))));
+            })
+            .await;
+    }
+}
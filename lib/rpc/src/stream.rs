@@ -0,0 +1,167 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use capnp::capability::{Promise, Response};
+use capnp::Error;
+use capnp_rpc::pry;
+use futures::channel::{mpsc, oneshot};
+use futures::stream::FuturesUnordered;
+use futures::{Stream, StreamExt};
+
+use crate::proc_capnp::sink;
+
+// Pushes a sender may have in flight before it waits for the receiver to take one.
+pub const DEFAULT_WINDOW: usize = 8;
+
+// Streams items to a sink. Each push in flight holds a credit, which comes back once the
+// receiver takes the item, so a sender never runs more than its window ahead of the receiver.
+pub struct Sender {
+    sink: sink::Client,
+    window: usize,
+    in_flight: FuturesUnordered<Promise<Response<sink::push_results::Owned>, Error>>,
+}
+
+impl Sender {
+    pub fn new(sink: sink::Client) -> Self {
+        Self {
+            sink,
+            window: DEFAULT_WINDOW,
+            in_flight: FuturesUnordered::new(),
+        }
+    }
+
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    // Push item to the sink, first waiting for a credit if the window is used up.
+    pub async fn send(&mut self, item: &[u8]) -> Result<(), Error> {
+        while self.in_flight.len() >= self.window {
+            if let Some(result) = self.in_flight.next().await {
+                result?;
+            }
+        }
+        let mut request = self.sink.push_request();
+        request.get().set_item(item);
+        self.in_flight.push(request.send().promise);
+        Ok(())
+    }
+
+    // Wait for the receiver to take the items in flight, then end the stream.
+    pub async fn finish(mut self) -> Result<(), Error> {
+        while let Some(result) = self.in_flight.next().await {
+            result?;
+        }
+        self.sink.done_request().send().promise.await?;
+        Ok(())
+    }
+}
+
+type Item = (Vec<u8>, oneshot::Sender<()>);
+
+// Serves a sink, handing what is pushed to it to an Items stream.
+struct Receiver {
+    items: mpsc::UnboundedSender<Item>,
+}
+
+impl sink::Server for Receiver {
+    fn push(&mut self, params: sink::PushParams, _: sink::PushResults) -> Promise<(), Error> {
+        let item = pry!(pry!(params.get()).get_item()).to_vec();
+        let (taken, credit) = oneshot::channel();
+        if self.items.unbounded_send((item, taken)).is_err() {
+            return Promise::err(Error::failed("stream closed".to_string()));
+        }
+        // Returning is what grants the sender its credit back.
+        Promise::from_future(async move {
+            credit
+                .await
+                .map_err(|_| Error::failed("stream closed".to_string()))
+        })
+    }
+
+    fn done(&mut self, _: sink::DoneParams, _: sink::DoneResults) -> Promise<(), Error> {
+        self.items.close_channel();
+        Promise::ok(())
+    }
+}
+
+// Items pushed to a sink, in the order they were sent. Taking an item grants its sender a credit.
+pub struct Items {
+    items: mpsc::UnboundedReceiver<Item>,
+}
+
+impl Stream for Items {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        self.items.poll_next_unpin(cx).map(|item| {
+            item.map(|(item, taken)| {
+                let _ = taken.send(());
+                item
+            })
+        })
+    }
+}
+
+// Create a sink to hand to a sender, and the stream of the items it pushes.
+pub fn channel() -> (sink::Client, Items) {
+    let (sender, items) = mpsc::unbounded();
+    let sink = capnp_rpc::new_client(Receiver { items: sender });
+    (sink, Items { items })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use capnp_rpc::rpc_twoparty_capnp::Side;
+    use tokio::task::LocalSet;
+
+    use crate::cap::wrap;
+    use crate::testing::connect;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_flow_control() {
+        const SETTLE: Duration = Duration::from_millis(100);
+
+        LocalSet::new()
+            .run_until(async {
+                let (a, b) = tokio::io::duplex(1 << 16);
+                let (sink, mut items) = channel();
+                tokio::task::spawn_local(connect(b, Side::Server, Some(wrap(sink))));
+                let mut rpc_a = connect(a, Side::Client, None);
+                let remote: sink::Client = rpc_a.bootstrap(Side::Server);
+                tokio::task::spawn_local(rpc_a);
+
+                let sent = Rc::new(Cell::new(0));
+                let producer = tokio::task::spawn_local({
+                    let sent = sent.clone();
+                    async move {
+                        let mut sender = Sender::new(remote).with_window(4);
+                        for i in 0..16u8 {
+                            sender.send(&[i]).await?;
+                            sent.set(sent.get() + 1);
+                        }
+                        sender.finish().await
+                    }
+                });
+
+                // The producer stops once it used up its window, until the consumer takes an
+                // item.
+                tokio::time::sleep(SETTLE).await;
+                assert_eq!(sent.get(), 4);
+                assert_eq!(items.next().await, Some(vec![0]));
+                tokio::time::sleep(SETTLE).await;
+                assert_eq!(sent.get(), 5);
+
+                let rest: Vec<_> = items.collect().await;
+                assert_eq!(rest, (1..16u8).map(|i| vec![i]).collect::<Vec<_>>());
+                producer.await.unwrap().unwrap();
+            })
+            .await;
+    }
+}
@@ -1,36 +1,357 @@
-use clap::Parser;
-use libp2p::{identity, kad, Multiaddr};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use libp2p::{identity, kad, Multiaddr, PeerId};
+use net::access::AccessPolicy;
+use proc::Stdio;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::logging::{self, LogFormat};
+
+// Exit codes of the CLI besides the ones of the WASM program, matching those in main.
+const EXIT_CODES: &str = "\
+Exit codes:
+  The exit code of the WASM program, or:
+  124  the program ran past its deadline
+  125  the host failed, e.g. to start the swarm or reach IPFS
+  126  the module is invalid or failed to compile or instantiate
+  127  the module was not found
+  130  the program was interrupted with Ctrl-C
+  134  the program trapped
+  137  the program ran out of fuel or memory";
 
 /// Run a WASM program from IPFS.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = EXIT_CODES,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Log the stdout and stderr of the WASM module instead of writing them
+    /// to those of the host.
+    #[arg(long, default_value_t = false)]
+    capture_stdio: bool,
+
+    /// Discard the stdout and stderr of the WASM module.
+    #[arg(short, long, default_value_t = false, conflicts_with_all = ["capture_stdio", "log_json"])]
+    quiet: bool,
+
+    /// Write each line of the stdout and stderr of the WASM module to stdout
+    /// as a JSON object tagged with the stream it came from.
+    #[arg(long, default_value_t = false, conflicts_with = "capture_stdio")]
+    log_json: bool,
+
+    /// Format of the logs of the host, written to stderr.
+    #[arg(long, value_enum, env = "WW_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Logs of the host to write, as comma-separated TARGET=LEVEL directives,
+    /// e.g. 'info,net::ipfs=debug,fs=trace'. A bare LEVEL applies to every
+    /// target, and a target covers the modules below it.
+    ///
+    /// Targets:
+    ///   ww          the CLI and its tools
+    ///   fs          the IPFS file system mounted in the WASM module
+    ///   net::ipfs   the client of the IPFS daemon
+    ///   net::swarm  the libp2p swarm, with net::dht, net::pubsub, ...
+    ///   proc        the WASM runtime, with proc::loader, ...
+    ///   proc::stdio the output of the WASM module, with --capture-stdio
+    #[arg(
+        long,
+        env = "RUST_LOG",
+        default_value = logging::DEFAULT_FILTER,
+        value_parser = parse_log_filter,
+        verbatim_doc_comment
+    )]
+    log_filter: String,
+
     /// IPFS path of the WASM module to run, e.g.
-    /// '/ipfs/Qm...YR/main.wasm'.
-    #[arg(short, long)]
-    load: String,
+    /// '/ipfs/Qm...YR/main.wasm', or path of a local file.
+    #[arg(short, long, required = true)]
+    load: Option<String>,
 
     /// Kad client (true) or server (false) mode.
     #[arg(short, long, default_value_t = false)]
     kad_client: bool,
+
+    /// Seconds the WASM module may run before it is killed. Unlimited if
+    /// unset.
+    #[arg(long)]
+    deadline: Option<u64>,
+
+    /// Fuel the WASM module may burn before it is stopped, one unit per
+    /// instruction. Unlimited if unset.
+    #[arg(long)]
+    fuel: Option<u64>,
+
+    /// Directory compiled WASM modules are kept in between runs.
+    #[arg(long)]
+    module_cache: Option<PathBuf>,
+
+    /// Most linear memory the WASM module may use, in 64 KiB pages.
+    #[arg(long, default_value_t = proc::DEFAULT_MAX_MEMORY_PAGES)]
+    max_memory_pages: u32,
+
+    /// Most inbound connections handshaking at once. Any more are refused.
+    #[arg(long, default_value_t = net::swarm::DEFAULT_MAX_PENDING_INBOUND)]
+    max_pending_inbound: u32,
+
+    /// Most outbound dials in flight at once. Any more wait their turn.
+    #[arg(long, default_value_t = net::swarm::DEFAULT_MAX_PENDING_OUTBOUND)]
+    max_pending_outbound: u32,
+
+    /// Argument passed to the WASM module. Repeat for more.
+    #[arg(long = "arg")]
+    args: Vec<String>,
+
+    /// Environment variable of the WASM module, as KEY=VALUE. Repeat for more.
+    #[arg(long = "env", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Multiaddress to listen on, over TCP or QUIC. Repeat for more. Listens
+    /// on all interfaces with every enabled transport if unset.
+    #[arg(long = "listen")]
+    listen_addrs: Vec<Multiaddr>,
+
+    /// Ports to pick from, as START-END, for listen addresses with port 0.
+    #[arg(long, value_parser = parse_port_range)]
+    port_range: Option<RangeInclusive<u16>>,
+
+    /// Only let this peer connect. Repeat for more.
+    #[arg(long = "allow-peer", conflicts_with = "deny_peers")]
+    allow_peers: Vec<PeerId>,
+
+    /// Never let this peer connect. Repeat for more.
+    #[arg(long = "deny-peer")]
+    deny_peers: Vec<PeerId>,
+
+    /// Disable the TCP transport.
+    #[arg(long, default_value_t = false)]
+    no_tcp: bool,
+
+    /// Disable the QUIC transport.
+    #[arg(long, default_value_t = false)]
+    no_quic: bool,
+
+    /// Address to serve Prometheus metrics of the node on, at /metrics, e.g.
+    /// '127.0.0.1:9090'. Metrics aren't collected if unset.
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Discover and dial other nodes on the local network through mDNS.
+    #[arg(long, default_value_t = false)]
+    mdns: bool,
+
+    /// File the addresses of known peers are kept in between runs.
+    #[arg(long)]
+    peerstore: Option<PathBuf>,
+
+    /// Seconds known peers are kept for after they were last seen.
+    #[arg(long, default_value_t = net::swarm::DEFAULT_PEER_STALENESS.as_secs())]
+    peer_staleness: u64,
+
+    /// Multiaddress of a relay to reserve a slot on while the node isn't
+    /// reachable, ending with /p2p/<peer ID>. Repeat for more.
+    #[arg(long = "relay")]
+    relays: Vec<Multiaddr>,
+
+    /// Seconds a connection no protocol uses is kept open for.
+    #[arg(long, default_value_t = net::swarm::DEFAULT_IDLE_TIMEOUT.as_secs())]
+    idle_timeout: u64,
+
+    /// Keep connections to this peer open however long they stay idle.
+    /// Repeat for more.
+    #[arg(long = "pin-peer")]
+    pinned_peers: Vec<PeerId>,
+
+    /// Score pubsub peers, pruning those that send invalid messages from the
+    /// mesh.
+    #[arg(long, default_value_t = false)]
+    peer_scoring: bool,
+
+    /// Run the WASM jobs peers submit over RPC, within the fuel, memory and
+    /// deadline limits of the WASM program.
+    #[arg(long, default_value_t = false)]
+    executor: bool,
+}
+
+// Tools run instead of a WASM program.
+#[derive(Subcommand, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Import a local file into IPFS and print its CID.
+    Add {
+        /// Local path of the file, or of the directory with -r.
+        path: PathBuf,
+
+        /// Import a directory with everything under it, as a UnixFS
+        /// directory. Symlinks and special files in it are skipped.
+        #[arg(short, long, default_value_t = false)]
+        recursive: bool,
+
+        /// Pin what was imported on the IPFS daemon.
+        #[arg(long, default_value_t = false)]
+        pin: bool,
+    },
+    /// Write a file in IPFS to stdout.
+    Cat {
+        /// IPFS path of the file, e.g. '/ipfs/Qm...YR/data.bin'.
+        path: String,
+
+        /// Byte of the file to start at.
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+
+        /// Most bytes to write. Up to the end of the file if unset.
+        #[arg(long)]
+        length: Option<u64>,
+
+        /// Show how much of the file was fetched on stderr.
+        #[arg(long, default_value_t = false)]
+        progress: bool,
+    },
+    /// List a directory in IPFS, with the type and size of each entry.
+    Ls {
+        /// IPFS path of the directory, e.g. '/ipfs/Qm...YR/data'.
+        path: String,
+
+        /// Also show the content type of files, guessed from their first
+        /// block.
+        #[arg(short, long, default_value_t = false)]
+        long: bool,
+
+        /// Write each entry as a JSON object on a line of its own.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Start the node and print its peer ID, then the addresses it can be
+    /// dialed at.
+    Id,
+}
+
+// Split a KEY=VALUE environment variable.
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {s}"))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+// Check a filter of the logs of the host parses, keeping it as is since filters can't be cloned.
+fn parse_log_filter(s: &str) -> Result<String, String> {
+    EnvFilter::try_new(s).map_err(|e| format!("bad log filter {s}: {e}"))?;
+    Ok(s.to_owned())
+}
+
+// Parse a START-END port range.
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected START-END, got {s}"))?;
+    let port = |p: &str| p.parse::<u16>().map_err(|e| format!("bad port {p}: {e}"));
+    let (start, end) = (port(start)?, port(end)?);
+    if start > end {
+        return Err(format!("start {start} is past end {end}"));
+    }
+    Ok(start..=end)
+}
+
+impl Args {
+    // Check what clap can't tell from each argument alone, before anything is started, so bad
+    // arguments never leave a half-built node behind.
+    fn validate(&self) -> Result<(), clap::Error> {
+        let mut command = Args::command();
+        if self.command.is_none() && self.load.as_deref().unwrap_or_default().trim().is_empty() {
+            return Err(command.error(ErrorKind::InvalidValue, "--load must not be empty"));
+        }
+        if self.no_tcp && self.no_quic {
+            return Err(command.error(
+                ErrorKind::ArgumentConflict,
+                "--no-tcp and --no-quic leave no transport to listen on",
+            ));
+        }
+        Ok(())
+    }
 }
 
 // Configuration
 pub trait Cfg {
+    // Peers that may connect to the node.
+    fn access(&self) -> AccessPolicy;
+    // Arguments passed to the WASM program.
+    fn args(&self) -> Vec<String>;
+    // Tool to run instead of the WASM program, if any.
+    fn command(&self) -> Option<Command>;
+    // Wall-clock time the WASM program may run for, None for unlimited.
+    fn deadline(&self) -> Option<Duration>;
+    // Environment variables of the WASM program.
+    fn env(&self) -> Vec<(String, String)>;
+    // Whether the node runs the WASM jobs peers submit over RPC.
+    fn executor(&self) -> bool;
+    // Fuel the WASM program runs with, None for unlimited.
+    fn fuel(&self) -> Option<u64>;
     // ID keys uniqely identifying the node.
     fn id_keys(&self) -> identity::Keypair;
     // Name of the protocol used to identify the node through libpb Identify.
     fn identify_protocol(&self) -> String;
+    // How long connections no protocol uses are kept open for.
+    fn idle_timeout(&self) -> Duration;
     // Multiaddress of the IPFS node.
     fn ipfs_addr(&self) -> Multiaddr;
     // Server or Client. Defaults to server.
     fn kad_mode(&self) -> kad::Mode;
-    // Multiaddress the node listens on.
-    fn listen_addr(&self) -> Multiaddr;
+    // Multiaddresses the node listens on.
+    fn listen_addrs(&self) -> Vec<Multiaddr>;
     // IPFS path of the WASM program to run.
     fn load(&self) -> String;
+    // Logs of the host to write, by target and level.
+    fn log_filter(&self) -> EnvFilter;
+    // Format of the logs of the host.
+    fn log_format(&self) -> LogFormat;
+    // Most linear memory the WASM program may use, in pages.
+    fn max_memory_pages(&self) -> u32;
+    // Most inbound connections handshaking at once.
+    fn max_pending_inbound(&self) -> u32;
+    // Most outbound dials in flight at once.
+    fn max_pending_outbound(&self) -> u32;
+    // Whether other nodes on the local network are discovered through mDNS.
+    fn mdns(&self) -> bool;
+    // Address metrics are served on, if any.
+    fn metrics_addr(&self) -> Option<SocketAddr>;
+    // Directory compiled WASM programs are cached in, if any.
+    fn module_cache(&self) -> Option<PathBuf>;
     // Peer ID of the node. Derived from the public key in id_keys().
     fn peer_id(&self) -> identity::PeerId;
+    // Whether pubsub peers are scored, to keep misbehaving ones out of the mesh.
+    fn peer_scoring(&self) -> bool;
+    // How long known peers are kept for after they were last seen.
+    fn peer_staleness(&self) -> Duration;
+    // File the addresses of known peers are kept in, if any.
+    fn peerstore(&self) -> Option<PathBuf>;
+    // Peers whose connections are kept open however long they stay idle.
+    fn pinned_peers(&self) -> HashSet<PeerId>;
+    // Ports listen addresses with port 0 pick from, if restricted.
+    fn port_range(&self) -> Option<RangeInclusive<u16>>;
+    // Whether the QUIC transport is enabled.
+    fn quic(&self) -> bool;
+    // Relays the node reserves a slot on while it isn't reachable.
+    fn relays(&self) -> Vec<Multiaddr>;
+    // Where the stdout and stderr of the WASM program go.
+    fn stdio(&self) -> Stdio;
+    // Whether the TCP transport is enabled.
+    fn tcp(&self) -> bool;
 }
 
 // Default node configuration.
@@ -39,18 +360,20 @@ pub struct DefaultCfg {
     id_keys: identity::Keypair,
     identify_protocol: String,
     ipfs_addr: Multiaddr,
-    listen_addr: Multiaddr,
 }
 
 impl DefaultCfg {
     // Default node configuration.
     pub fn new() -> Self {
+        let args = Args::parse();
+        if let Err(e) = args.validate() {
+            e.exit();
+        }
         Self {
-            args: Args::parse(),
+            args,
             id_keys: identity::Keypair::generate_ed25519(),
             identify_protocol: "/ww/identify/0.0.1".to_owned(),
             ipfs_addr: "/ip4/127.0.0.1/tcp/5001".to_owned().parse().unwrap(),
-            listen_addr: "/ip4/0.0.0.0/tcp/0".to_owned().parse().unwrap(),
         }
     }
 
@@ -61,6 +384,40 @@ impl DefaultCfg {
 }
 
 impl Cfg for DefaultCfg {
+    fn access(&self) -> AccessPolicy {
+        if !self.args.allow_peers.is_empty() {
+            return AccessPolicy::Allow(self.args.allow_peers.iter().copied().collect());
+        }
+        if !self.args.deny_peers.is_empty() {
+            return AccessPolicy::Deny(self.args.deny_peers.iter().copied().collect());
+        }
+        AccessPolicy::Open
+    }
+
+    fn args(&self) -> Vec<String> {
+        self.args.args.to_owned()
+    }
+
+    fn command(&self) -> Option<Command> {
+        self.args.command.to_owned()
+    }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.args.deadline.map(Duration::from_secs)
+    }
+
+    fn env(&self) -> Vec<(String, String)> {
+        self.args.env.to_owned()
+    }
+
+    fn executor(&self) -> bool {
+        self.args.executor
+    }
+
+    fn fuel(&self) -> Option<u64> {
+        self.args.fuel
+    }
+
     fn id_keys(&self) -> identity::Keypair {
         self.id_keys.clone()
     }
@@ -69,6 +426,10 @@ impl Cfg for DefaultCfg {
         self.identify_protocol.to_owned()
     }
 
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.args.idle_timeout)
+    }
+
     fn ipfs_addr(&self) -> Multiaddr {
         self.ipfs_addr.to_owned()
     }
@@ -80,15 +441,154 @@ impl Cfg for DefaultCfg {
         kad::Mode::Server
     }
 
-    fn listen_addr(&self) -> Multiaddr {
-        self.listen_addr.to_owned()
+    fn listen_addrs(&self) -> Vec<Multiaddr> {
+        if !self.args.listen_addrs.is_empty() {
+            return self.args.listen_addrs.to_owned();
+        }
+        let mut addrs = Vec::new();
+        if self.tcp() {
+            addrs.push("/ip4/0.0.0.0/tcp/0".parse().unwrap());
+        }
+        if self.quic() {
+            addrs.push("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap());
+        }
+        addrs
     }
 
     fn load(&self) -> String {
-        self.args.load.to_owned()
+        self.args.load.to_owned().unwrap_or_default()
+    }
+
+    fn log_filter(&self) -> EnvFilter {
+        EnvFilter::new(&self.args.log_filter)
+    }
+
+    fn log_format(&self) -> LogFormat {
+        self.args.log_format
+    }
+
+    fn max_memory_pages(&self) -> u32 {
+        self.args.max_memory_pages
+    }
+
+    fn max_pending_inbound(&self) -> u32 {
+        self.args.max_pending_inbound
+    }
+
+    fn max_pending_outbound(&self) -> u32 {
+        self.args.max_pending_outbound
+    }
+
+    fn mdns(&self) -> bool {
+        self.args.mdns
+    }
+
+    fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.args.metrics_addr
+    }
+
+    fn module_cache(&self) -> Option<PathBuf> {
+        self.args.module_cache.to_owned()
     }
 
     fn peer_id(&self) -> identity::PeerId {
         identity::PeerId::from(self.id_keys().public())
     }
+
+    fn peer_scoring(&self) -> bool {
+        self.args.peer_scoring
+    }
+
+    fn peer_staleness(&self) -> Duration {
+        Duration::from_secs(self.args.peer_staleness)
+    }
+
+    fn peerstore(&self) -> Option<PathBuf> {
+        self.args.peerstore.to_owned()
+    }
+
+    fn pinned_peers(&self) -> HashSet<PeerId> {
+        self.args.pinned_peers.iter().copied().collect()
+    }
+
+    fn port_range(&self) -> Option<RangeInclusive<u16>> {
+        self.args.port_range.to_owned()
+    }
+
+    fn quic(&self) -> bool {
+        !self.args.no_quic
+    }
+
+    fn relays(&self) -> Vec<Multiaddr> {
+        self.args.relays.to_owned()
+    }
+
+    fn stdio(&self) -> Stdio {
+        if self.args.quiet {
+            return Stdio::Null;
+        }
+        if self.args.log_json {
+            return Stdio::Json;
+        }
+        if self.args.capture_stdio {
+            return Stdio::Capture;
+        }
+        Stdio::Inherit
+    }
+
+    fn tcp(&self) -> bool {
+        !self.args.no_tcp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let args = Args::try_parse_from([&["ww"][..], args].concat())?;
+        args.validate()?;
+        Ok(args)
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(parse(&["--load", "/ipfs/Qm/main.wasm"]).is_ok());
+        let cat = parse(&["cat", "/ipfs/Qm/data.bin", "--offset", "4"]).unwrap();
+        assert_eq!(
+            cat.command,
+            Some(Command::Cat {
+                path: "/ipfs/Qm/data.bin".to_owned(),
+                offset: 4,
+                length: None,
+                progress: false,
+            })
+        );
+
+        let add = parse(&["add", "-r", "data", "--pin"]).unwrap();
+        assert_eq!(
+            add.command,
+            Some(Command::Add {
+                path: PathBuf::from("data"),
+                recursive: true,
+                pin: true,
+            })
+        );
+
+        let kind = |args: &[&str]| parse(args).err().map(|e| e.kind());
+        assert_eq!(kind(&[]), Some(ErrorKind::MissingRequiredArgument));
+        assert_eq!(kind(&["--load", " "]), Some(ErrorKind::InvalidValue));
+        assert_eq!(
+            kind(&["--load", "main.wasm", "--port-range", "9000-8000"]),
+            Some(ErrorKind::ValueValidation)
+        );
+        assert_eq!(
+            kind(&["--load", "main.wasm", "--log-filter", "fs=loud"]),
+            Some(ErrorKind::ValueValidation)
+        );
+        assert_eq!(
+            kind(&["--load", "main.wasm", "--no-tcp", "--no-quic"]),
+            Some(ErrorKind::ArgumentConflict)
+        );
+    }
 }
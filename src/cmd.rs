@@ -0,0 +1,490 @@
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use libp2p::multiaddr::Protocol;
+use serde_json::json;
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use wasmer_wasix::virtual_fs::FileSystem;
+use wasmer_wasix::FsError;
+
+use fs::IpfsFs;
+use net::ipfs::{self, Cid, Client};
+use net::unixfs::Link;
+use net::SwarmService;
+
+use crate::cfg::Command;
+
+// How long id waits for the node to listen on an address.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Error {
+    // The IPFS daemon can't be reached.
+    Ipfs(ipfs::Error),
+    // Nothing is at the path.
+    NotFound(String),
+    // A directory was expected at the path, but something else is there.
+    NotADirectory(String),
+    // The entry at the path can't be read.
+    Fs(String, FsError),
+    // The local file at the path can't be read.
+    Local(String, io::Error),
+    // A file was expected at the path, but a directory is there.
+    IsADirectory(String),
+    // Something other than a file or directory is at the path, like a socket or a device.
+    Unsupported(String),
+    // Writing the output failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Ipfs(e) => write!(f, "{e}"),
+            Error::NotFound(path) => write!(f, "{path}: not found"),
+            Error::NotADirectory(path) => write!(f, "{path}: not a directory"),
+            Error::Fs(path, e) => write!(f, "{path}: {e}"),
+            Error::Local(path, e) => write!(f, "{path}: {e}"),
+            Error::IsADirectory(path) => write!(f, "{path}: is a directory, add it with -r"),
+            Error::Unsupported(path) => write!(f, "{path}: not a file or directory"),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ipfs::Error> for Error {
+    fn from(e: ipfs::Error) -> Self {
+        Error::Ipfs(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl Error {
+    fn fs(path: &str, e: FsError) -> Self {
+        match e {
+            // Paths going through a file don't exist either.
+            FsError::EntryNotFound | FsError::BaseNotDirectory => Error::NotFound(path.to_owned()),
+            e => Error::Fs(path.to_owned(), e),
+        }
+    }
+}
+
+// Run a tool against the IPFS daemon of client, writing what it prints to stdout.
+pub async fn run(command: Command, client: Client) -> Result<(), Error> {
+    client.health_check().await?;
+    let mut fs = IpfsFs::new(client.clone());
+    if let Command::Cat { progress: true, .. } = command {
+        fs = fs.with_progress(Arc::new(show_progress));
+    }
+    let fs = Arc::new(fs);
+    let mut stdout = tokio::io::stdout();
+    match command {
+        Command::Add {
+            path,
+            recursive,
+            pin,
+        } => {
+            add(&client, &path, recursive, pin, &mut stdout).await?;
+        }
+        Command::Cat {
+            path,
+            offset,
+            length,
+            progress,
+        } => {
+            cat(fs, &path, offset, length, &mut stdout).await?;
+            if progress {
+                eprintln!();
+            }
+        }
+        Command::Ls { path, long, json } => {
+            let mut out = String::new();
+            for entry in ls(fs, &path, long).await? {
+                match json {
+                    true => out.push_str(&format!("{}\n", entry.to_json())),
+                    false => out.push_str(&entry.line(long)),
+                }
+            }
+            stdout.write_all(out.as_bytes()).await?;
+            stdout.flush().await?;
+        }
+        // Printing the identity of the node takes running one rather than a daemon.
+        Command::Id => unreachable!("id is run with a swarm"),
+    }
+    Ok(())
+}
+
+// Import the local file at path into IPFS, or the directory at path with everything under it if
+// recursive, and write the CID of its root to out. Symlinks and special files, like sockets or
+// devices, under the directory are skipped with a warning. Directories too large for a block are
+// sharded as kubo shards them. The root is pinned along with everything under it if pin is
+// set.
+pub async fn add(
+    client: &Client,
+    path: &Path,
+    recursive: bool,
+    pin: bool,
+    out: &mut (impl AsyncWrite + Unpin),
+) -> Result<Cid, Error> {
+    let name = path.display().to_string();
+    // The path itself is followed if it is a symlink.
+    let metadata = tokio::fs::metadata(path).await;
+    let metadata = metadata.map_err(|e| Error::Local(name.clone(), e))?;
+    if metadata.is_dir() && !recursive {
+        return Err(Error::IsADirectory(name));
+    }
+    let Some((cid, _)) = import(client, path, metadata).await? else {
+        return Err(Error::Unsupported(name));
+    };
+    if pin {
+        client.pin(&cid, true).await?;
+    }
+    out.write_all(format!("{cid}\n").as_bytes()).await?;
+    out.flush().await?;
+    Ok(cid)
+}
+
+// Import the file or directory at path, returning its CID and the cumulative size of its
+// blocks, or None if it is something else.
+fn import<'a>(
+    client: &'a Client,
+    path: &'a Path,
+    metadata: std::fs::Metadata,
+) -> BoxFuture<'a, Result<Option<(Cid, u64)>, Error>> {
+    Box::pin(async move {
+        let local = |e| Error::Local(path.display().to_string(), e);
+        if metadata.is_file() {
+            let file = tokio::fs::File::open(path).await.map_err(local)?;
+            return Ok(Some(client.add_file_sized(file).await?));
+        }
+        if !metadata.is_dir() {
+            return Ok(None);
+        }
+
+        let mut links = Vec::new();
+        let mut entries = tokio::fs::read_dir(path).await.map_err(local)?;
+        while let Some(entry) = entries.next_entry().await.map_err(local)? {
+            let child = entry.path();
+            let Ok(name) = entry.file_name().into_string() else {
+                tracing::warn!("skipping {}: name is not UTF-8", child.display());
+                continue;
+            };
+            // Entries aren't followed, so links can't lead out of the directory or loop.
+            let metadata = entry.metadata().await;
+            let metadata = metadata.map_err(|e| Error::Local(child.display().to_string(), e))?;
+            if metadata.is_symlink() {
+                tracing::warn!("skipping {}: symlink", child.display());
+                continue;
+            }
+            match import(client, &child, metadata).await? {
+                Some((cid, tsize)) => links.push(Link { cid, name, tsize }),
+                None => tracing::warn!("skipping {}: not a file or directory", child.display()),
+            }
+        }
+        Ok(Some(client.put_directory(links).await?))
+    })
+}
+
+// Write the peer ID of the node swarm runs to out, then the addresses it listens on and those
+// it was confirmed to be reachable at, with the peer ID appended so they can be dialed as is.
+// Waits for the node to listen on an address first, up to LISTEN_TIMEOUT.
+pub async fn id(swarm: &SwarmService, out: &mut (impl AsyncWrite + Unpin)) -> Result<(), Error> {
+    let peer = swarm.peer_id();
+    let mut events = Box::pin(swarm.subscribe());
+    let listening = async {
+        loop {
+            let addrs = swarm.listen_addrs().await;
+            if !addrs.is_empty() || events.next().await.is_none() {
+                return addrs;
+            }
+        }
+    };
+    let mut addrs = tokio::time::timeout(LISTEN_TIMEOUT, listening)
+        .await
+        .unwrap_or_default();
+    for addr in swarm.external_addrs().await {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    let mut text = format!("{peer}\n");
+    for addr in addrs {
+        text.push_str(&format!("{}\n", addr.with(Protocol::P2p(peer))));
+    }
+    out.write_all(text.as_bytes()).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+// Write the file at an '/ipfs/<cid>/<path>' path to out, from offset on and at most length
+// bytes of it, returning how many bytes were written. The file is streamed through IpfsFs, so
+// only the blocks overlapping the range are fetched.
+pub async fn cat(
+    fs: Arc<IpfsFs>,
+    path: &str,
+    offset: u64,
+    length: Option<u64>,
+    out: &mut (impl AsyncWrite + Unpin),
+) -> Result<u64, Error> {
+    // Opening resolves the path with blocking fetches.
+    let owned = path.to_owned();
+    let file = tokio::task::spawn_blocking(move || fs.new_open_options().read(true).open(owned))
+        .await
+        .map_err(io::Error::other)?;
+    let mut file = file.map_err(|e| Error::fs(path, e))?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut range = file.take(length.unwrap_or(u64::MAX));
+    let n = tokio::io::copy(&mut range, out).await?;
+    out.flush().await?;
+    Ok(n)
+}
+
+// Show the bytes of a file fetched so far on stderr, over the line shown before.
+fn show_progress(fetched: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => {
+            let percent = fetched * 100 / total;
+            eprint!("\rfetched {fetched}/{total} bytes ({percent}%)");
+        }
+        _ => eprint!("\rfetched {fetched} bytes"),
+    }
+}
+
+// Entry of a directory, as ls lists it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    // "dir", "file" or "symlink".
+    pub kind: &'static str,
+    pub size: u64,
+    // Content type of files guessed from their first block, when listed long.
+    pub content_type: Option<String>,
+}
+
+impl Entry {
+    fn line(&self, long: bool) -> String {
+        match long {
+            true => {
+                let content_type = self.content_type.as_deref().unwrap_or("-");
+                format!(
+                    "{:<7} {:>12} {content_type:<24} {}\n",
+                    self.kind, self.size, self.name
+                )
+            }
+            false => format!("{:<7} {:>12} {}\n", self.kind, self.size, self.name),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut entry = json!({"name": self.name, "type": self.kind, "size": self.size});
+        if let Some(content_type) = &self.content_type {
+            entry["content_type"] = json!(content_type);
+        }
+        entry
+    }
+}
+
+// List the directory at an '/ipfs/<cid>/<path>' path. Guessing content types fetches the first
+// block of every file, so it is only done when long is set.
+pub async fn ls(fs: Arc<IpfsFs>, path: &str, long: bool) -> Result<Vec<Entry>, Error> {
+    // Listing resolves the path and its entries with blocking fetches.
+    let owned = path.to_owned();
+    tokio::task::spawn_blocking(move || list(&fs, &owned, long))
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn list(fs: &IpfsFs, path: &str, long: bool) -> Result<Vec<Entry>, Error> {
+    let fs_error = |e| Error::fs(path, e);
+    if !fs.metadata(Path::new(path)).map_err(fs_error)?.ft.is_dir() {
+        return Err(Error::NotADirectory(path.to_owned()));
+    }
+    let mut entries = Vec::new();
+    for entry in fs.read_dir(Path::new(path)).map_err(fs_error)? {
+        let entry = entry.map_err(fs_error)?;
+        let metadata = entry.metadata.clone().map_err(fs_error)?;
+        let kind = if metadata.ft.is_dir() {
+            "dir"
+        } else if metadata.ft.is_symlink() {
+            "symlink"
+        } else {
+            "file"
+        };
+        let content_type = match long && metadata.ft.is_file() {
+            true => fs.content_type(&entry.path).map_err(fs_error)?,
+            false => None,
+        };
+        entries.push(Entry {
+            name: entry
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            kind,
+            size: metadata.len,
+            content_type,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use libp2p::identity;
+    use net::testing::Daemon;
+    use net::unixfs::Node;
+    use net::SwarmConfig;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add() {
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let root = std::env::temp_dir().join(format!("ww-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.txt"), "hello\n").unwrap();
+        std::fs::write(root.join("sub/b.txt"), "world").unwrap();
+        // Symlinks under the directory are skipped.
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("link")).unwrap();
+
+        let result = add(&client, &root, false, false, &mut Vec::new()).await;
+        assert!(matches!(result, Err(Error::IsADirectory(_))));
+
+        let mut out = Vec::new();
+        let added = add(&client, &root, true, false, &mut out).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        let cid = added.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{cid}\n"));
+
+        let fs = Arc::new(IpfsFs::new(client));
+        let entries = ls(fs.clone(), &format!("/ipfs/{cid}"), false)
+            .await
+            .unwrap();
+        let listed: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.kind, entry.size))
+            .collect();
+        assert_eq!(listed, vec![("a.txt", "file", 6), ("sub", "dir", 1)]);
+        let entries = ls(fs.clone(), &format!("/ipfs/{cid}/sub"), false)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].name.as_str(), entries[0].size), ("b.txt", 5));
+
+        let mut out = Vec::new();
+        cat(fs, &format!("/ipfs/{cid}/sub/b.txt"), 0, None, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"world");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cat() {
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let data: Vec<u8> = (0..=255).cycle().take(1 << 20).collect();
+        let cid = client.add_bytes(Bytes::from(data.clone())).await.unwrap();
+        let fs = Arc::new(IpfsFs::new(client));
+        let path = format!("/ipfs/{cid}");
+
+        let mut out = Vec::new();
+        let n = cat(fs.clone(), &path, 0, None, &mut out).await.unwrap();
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(out, data);
+
+        let mut out = Vec::new();
+        cat(fs.clone(), &path, 1000, Some(300_000), &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, data[1000..301_000]);
+
+        let missing = format!("{path}/missing");
+        let result = cat(fs, &missing, 0, None, &mut Vec::new()).await;
+        assert!(matches!(result, Err(Error::NotFound(p)) if p == missing));
+    }
+
+    #[tokio::test]
+    async fn test_id() {
+        let config = SwarmConfig {
+            listen_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            ..Default::default()
+        };
+        let swarm = SwarmService::new(identity::Keypair::generate_ed25519(), config).unwrap();
+        let mut out = Vec::new();
+        id(&swarm, &mut out).await.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        let peer = swarm.peer_id();
+        assert_eq!(lines[0], peer.to_string());
+        assert_eq!(lines.len(), 2, "{out}");
+        assert!(lines[1].starts_with("/ip4/127.0.0.1/tcp/"), "{out}");
+        assert!(lines[1].ends_with(&format!("/p2p/{peer}")), "{out}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ls() {
+        let daemon = Daemon::start();
+        let client = daemon.client();
+        let mut links = Vec::new();
+        for (name, data) in [("a.txt", "hello\n"), ("b.json", "{}")] {
+            let node = Node::file(Bytes::from(data));
+            let cid = client.put_node(&node).await.unwrap();
+            let tsize = data.len() as u64;
+            links.push(Link {
+                cid,
+                name: name.to_owned(),
+                tsize,
+            });
+        }
+        let file = links[0].cid;
+        let sub = client.put_node(&Node::directory(Vec::new())).await.unwrap();
+        links.push(Link {
+            cid: sub,
+            name: "sub".to_owned(),
+            tsize: 0,
+        });
+        let dir = client.put_node(&Node::directory(links)).await.unwrap();
+        let fs = Arc::new(IpfsFs::new(client));
+
+        let entries = ls(fs.clone(), &format!("/ipfs/{dir}"), false)
+            .await
+            .unwrap();
+        let listed: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.kind, entry.size))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("a.txt", "file", 6),
+                ("b.json", "file", 2),
+                ("sub", "dir", 0)
+            ]
+        );
+        assert_eq!(
+            entries[0].to_json(),
+            json!({"name": "a.txt", "type": "file", "size": 6})
+        );
+
+        let result = ls(fs, &format!("/ipfs/{file}"), false).await;
+        assert!(matches!(result, Err(Error::NotADirectory(_))));
+    }
+}
@@ -0,0 +1,126 @@
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+// Logs written when no filter is set.
+pub const DEFAULT_FILTER: &str = "info";
+
+// Format of the logs of the host.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Abbreviated lines, for people.
+    #[default]
+    Text,
+    /// A JSON object per event, for log aggregators.
+    Json,
+}
+
+// Subscriber writing the logs of the host passing filter to writer, in format. Filters are
+// TARGET=LEVEL directives, where targets are module paths, e.g. 'net::ipfs=debug,fs=trace'.
+// In JSON, the fields of an event are top-level keys, and those of the spans it happened in,
+// e.g. path, peer or cid, are under "span" for the innermost one and "spans" for all of
+// them, so they can be queried as is.
+pub fn subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.compact().finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(true)
+                .finish(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+
+    // Writes to a buffer the test reads back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn lines(out: &Shared) -> Vec<Value> {
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        out.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_json() {
+        let out = Shared::default();
+        let filter = EnvFilter::new(DEFAULT_FILTER);
+        let subscriber = subscriber(LogFormat::Json, filter, {
+            let out = out.clone();
+            move || out.clone()
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("open", path = "/ipfs/bafy/a.txt", cid = "bafy");
+            span.in_scope(|| tracing::info!(peer = "12D3Koo", "fetched block"));
+            tracing::info!("done");
+        });
+
+        let lines = lines(&out);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["message"], "fetched block");
+        assert_eq!(lines[0]["peer"], "12D3Koo");
+        assert_eq!(lines[0]["span"]["name"], "open");
+        assert_eq!(lines[0]["span"]["path"], "/ipfs/bafy/a.txt");
+        assert_eq!(lines[0]["span"]["cid"], "bafy");
+        assert_eq!(lines[0]["spans"][0]["cid"], "bafy");
+        assert_eq!(lines[1]["message"], "done");
+        assert!(lines[1].get("span").is_none());
+    }
+
+    #[test]
+    fn test_filter() {
+        let out = Shared::default();
+        let filter = EnvFilter::new("warn,net::ipfs=trace");
+        let subscriber = subscriber(LogFormat::Json, filter, {
+            let out = out.clone();
+            move || out.clone()
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            let fs = tracing::trace_span!(target: "fs", "open", path = "/ipfs/bafy");
+            let ipfs = tracing::trace_span!(target: "net::ipfs", "get", cid = "bafy");
+            assert!(fs.is_disabled());
+            assert!(!ipfs.is_disabled());
+            fs.in_scope(|| tracing::debug!(target: "fs::cache", "hit"));
+            ipfs.in_scope(|| tracing::debug!(target: "net::ipfs", "fetched"));
+            tracing::info!(target: "net::swarm", "dialing");
+        });
+
+        let lines = lines(&out);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["target"], "net::ipfs");
+        assert_eq!(lines[0]["span"]["cid"], "bafy");
+    }
+}
@@ -1,133 +1,367 @@
-use std::{error::Error, sync::Arc, time::Duration};
+use std::path::Path;
+use std::{error::Error, fmt, sync::Arc, time::Duration};
 
-use anyhow::Result;
-use futures::TryStreamExt;
-use libp2p::{identify, kad, mdns, noise, ping, swarm, tcp, yamux};
-use tracing_subscriber::EnvFilter;
+use futures::{Stream, StreamExt};
+use tokio::task::LocalSet;
 use wasmer_wasix::virtual_fs::{self, RootFileSystemBuilder};
 
 use fs::IpfsFs;
-use net::{DefaultBehaviour, DefaultBehaviourEvent, DefaultSwarm};
-use proc::{self, WasmRuntime};
+use net::metrics::Metrics;
+use net::{PeerEvent, Reachability, SwarmConfig, SwarmService};
+use proc::{self, Loader, RunConfig, WasmRuntime};
+use rpc::bootstrap::{self, Capabilities};
+use rpc::executor::{self, Executor};
 
 pub mod cfg;
+pub mod cmd;
+pub mod logging;
+
+// How long the swarm has to close its connections once the WASM module exits.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Exit codes of the CLI when the guest doesn't exit by itself, listed in cfg::EXIT_CODES.
+// Guests exiting with one of these can't be told apart from the host failing.
+const EXIT_DEADLINE: i32 = 124;
+const EXIT_HOST: i32 = 125;
+const EXIT_INVALID: i32 = 126;
+const EXIT_NOT_FOUND: i32 = 127;
+// 128 + SIGINT, as shells report programs killed by Ctrl-C.
+const EXIT_INTERRUPTED: i32 = 130;
+const EXIT_TRAP: i32 = 134;
+const EXIT_RESOURCE_LIMIT: i32 = 137;
+
+// Error the CLI exits on, with its exit code.
+struct Failure {
+    code: i32,
+    error: Box<dyn Error>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl<E: Into<Box<dyn Error>>> From<E> for Failure {
+    fn from(e: E) -> Self {
+        Self {
+            code: EXIT_HOST,
+            error: e.into(),
+        }
+    }
+}
+
+impl Failure {
+    // Failure to load or run the guest, with the code telling why.
+    fn proc(e: proc::Error) -> Self {
+        let code = match e {
+            proc::Error::Fetch(_) | proc::Error::Read(..) => EXIT_NOT_FOUND,
+            proc::Error::Invalid(_) | proc::Error::Config(_) | proc::Error::Compile(_) => {
+                EXIT_INVALID
+            }
+            proc::Error::Trap(_) => EXIT_TRAP,
+            proc::Error::ResourceLimit(_) => EXIT_RESOURCE_LIMIT,
+            proc::Error::DeadlineExceeded(_) => EXIT_DEADLINE,
+            proc::Error::Interrupted => EXIT_INTERRUPTED,
+            proc::Error::Snapshot(_) => EXIT_HOST,
+        };
+        Self {
+            code,
+            error: e.into(),
+        }
+    }
+
+    // Failure of a tool, with the code telling why.
+    fn cmd(e: cmd::Error) -> Self {
+        let code = match e {
+            cmd::Error::NotFound(_) => EXIT_NOT_FOUND,
+            _ => EXIT_HOST,
+        };
+        Self {
+            code,
+            error: e.into(),
+        }
+    }
+
+    fn invalid(e: Box<dyn Error>) -> Self {
+        Self {
+            code: EXIT_INVALID,
+            error: e,
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() {
     // Use the default configuration.
     let config: &dyn cfg::Cfg = &cfg::DefaultCfg::new();
 
-    // Start configuring a `fmt` subscriber
-    let subscriber = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .compact() // use abbreviated log format
-        // .with_max_level(tracing::Level::TRACE)
-        .with_max_level(tracing::Level::INFO)
-        .finish();
+    // Keep stdout for the output of the guest and of tools, e.g. cat.
+    let subscriber = logging::subscriber(config.log_format(), config.log_filter(), std::io::stderr);
 
     // Set the subscriber as global default
-    tracing::subscriber::set_global_default(subscriber)?;
-
-    // Create a MDNS network behaviour.
-    let mdns_behaviour = mdns::tokio::Behaviour::new(mdns::Config::default(), config.peer_id())?;
-
-    // Create Stream behaviour.
-    let ping_behaviour = ping::Behaviour::default();
-
-    // Create Kademlia and Identify behaviours.
-    let kad_cfg = kad::Config::new(swarm::StreamProtocol::new("/ww")); // TODO custom protocol name, cfg
-    let kad_store = kad::store::MemoryStore::new(config.id_keys().public().to_peer_id());
-    let kad_behaviour =
-        kad::Behaviour::with_config(config.id_keys().public().to_peer_id(), kad_store, kad_cfg);
-    let identify_behaviour = identify::Behaviour::new(identify::Config::new(
-        config.identify_protocol(),
-        config.id_keys().public(),
-    ));
-
-    // Combine behaviours.
-    let behaviour = DefaultBehaviour {
-        mdns: mdns_behaviour,
-        ping: ping_behaviour,
-        kad: kad_behaviour,
-        identify: identify_behaviour,
-    };
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        eprintln!("{e}");
+        std::process::exit(EXIT_HOST);
+    }
 
-    let raw_swarm = libp2p::SwarmBuilder::with_existing_identity(config.id_keys())
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|_| behaviour)?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
-        .build();
+    // Exit with the code of the guest, so scripts can branch on it.
+    match run(config).await {
+        Ok(0) => {}
+        Ok(exit_code) => std::process::exit(exit_code),
+        Err(failure) => {
+            tracing::error!("{failure}");
+            std::process::exit(failure.code);
+        }
+    }
+}
 
-    // Wrap the swarm in our custom type to overwrite its behaviour and event management.
-    let mut swarm = DefaultSwarm(raw_swarm);
+// Run the tool or the WASM module the node is configured with, and return its exit code.
+async fn run(config: &dyn cfg::Cfg) -> Result<i32, Failure> {
+    match config.command() {
+        Some(cfg::Command::Id) => {
+            let mut swarm_service =
+                SwarmService::new(config.id_keys(), swarm_config(config, None))?;
+            let result = cmd::id(&swarm_service, &mut tokio::io::stdout()).await;
+            swarm_service.shutdown(SHUTDOWN_TIMEOUT).await?;
+            result.map_err(Failure::cmd)?;
+            return Ok(0);
+        }
+        Some(command) => {
+            let client = net::ipfs::Client::new(config.ipfs_addr());
+            cmd::run(command, client).await.map_err(Failure::cmd)?;
+            return Ok(0);
+        }
+        None => {}
+    }
 
-    // Set the Kademlia mode.
-    swarm.behaviour_mut().kad.set_mode(Some(config.kad_mode()));
+    let metrics = match config.metrics_addr() {
+        Some(addr) => {
+            let metrics = Metrics::new();
+            let addr = net::metrics::serve(addr, metrics.clone())?;
+            tracing::info!("serving metrics on http://{addr}/metrics");
+            Some(metrics)
+        }
+        None => None,
+    };
 
+    // Run behaviour loop in the background, for as long as the service is alive.
     tracing::info!("Initialize swarm...");
-    // Tell the swarm to listen on all interfaces and a random, OS-assigned port.
-    swarm.listen_on(config.listen_addr())?;
-
-    // Run behaviour loop in the background.
-    tracing::info!("Spawn behaviour thread...");
+    let mut swarm_service =
+        SwarmService::new(config.id_keys(), swarm_config(config, metrics.clone()))?;
+    // Tell users when peers find the node can't be dialed, e.g. as it is behind a NAT.
+    let mut events = Box::pin(swarm_service.subscribe());
     tokio::spawn(async move {
-        loop {
-            match swarm.select_next_some().await {
-                swarm::SwarmEvent::NewListenAddr { address, .. } => {
-                    // TODO:  seal & sign a PeerRecord and announce it to the DHT,
-                    // using our PeerID as the key.
-                    tracing::info!("listening on {address:?}")
-                }
-                swarm::SwarmEvent::Behaviour(DefaultBehaviourEvent::Mdns(event)) => {
-                    net::dial::default_mdns_handler(&mut swarm, event);
-                }
-                swarm::SwarmEvent::Behaviour(DefaultBehaviourEvent::Ping(event)) => {
-                    tracing::debug!("got PING event: {event:?}");
-                }
-                swarm::SwarmEvent::Behaviour(DefaultBehaviourEvent::Kad(event)) => {
-                    tracing::debug!("got KAD event: {event:?}");
-                }
-                swarm::SwarmEvent::Behaviour(DefaultBehaviourEvent::Identify(event)) => {
-                    tracing::debug!("got IDENTIFY event: {event:?}");
-                }
-                event => {
-                    tracing::debug!("got event: {event:?}");
-                }
+        let private = PeerEvent::ReachabilityChanged {
+            reachability: Reachability::Private,
+        };
+        while let Some(event) = events.next().await {
+            if event == private {
+                tracing::warn!("peers can't dial this node back, it may be behind a NAT");
             }
         }
     });
 
     tracing::debug!("Initialize IPFS client...");
     // The IPFS library we are using, ferristseng/rust-ipfs-api, requires multiformats::Multiaddr.
-    let ipfs_client = net::ipfs::Client::new(config.ipfs_addr());
-
-    tracing::info!("Fetch bytecode from {}...", config.load());
-    let bytecode = ipfs_client
-        .get_file(config.load().as_str())
-        .map_ok(|chunk| chunk.to_vec())
-        .try_concat()
-        .await?;
+    let mut ipfs_client = net::ipfs::Client::new(config.ipfs_addr());
+    if let Some(metrics) = &metrics {
+        ipfs_client = ipfs_client.with_metrics(metrics.clone());
+    }
+    // Modules in local files run without the daemon, only failing to read from IPFS.
+    let local = !config.load().starts_with("/ipfs/") && !config.load().starts_with("/ipns/");
+    if let Err(e) = ipfs_client.health_check().await {
+        if !local {
+            return Err(e.into());
+        }
+        tracing::warn!("{e}");
+    }
 
     // Initialize WASM runtime.
     tracing::info!("Initialize WASM runtime...");
-    let mut wasm_runtime = WasmRuntime::new();
+    let mut wasm_runtime = WasmRuntime::with_config(RunConfig {
+        fuel: config.fuel(),
+        max_memory_pages: config.max_memory_pages(),
+        deadline: config.deadline(),
+        stdio: config.stdio(),
+        args: config.args(),
+        env: config.env(),
+        deterministic: None,
+        metrics,
+    });
+
+    tracing::info!("Load WASM module from {}...", config.load());
+    let mut loader = Loader::new(ipfs_client.clone());
+    if let Some(dir) = config.module_cache() {
+        loader = loader.with_cache_dir(dir);
+    }
+    let loader = Arc::new(loader);
+    // Jobs peers submit get no more than the WASM program of the node.
+    let ceiling = executor::Limits {
+        fuel: config.fuel(),
+        max_memory_pages: Some(config.max_memory_pages()),
+        deadline: config.deadline(),
+    };
+    let executor = config.executor().then(|| (loader.clone(), ceiling));
+    serve_rpc(&swarm_service, executor)?;
+
+    let module = match local {
+        true => loader.load_file(&wasm_runtime, Path::new(&config.load())),
+        false => loader.load(&wasm_runtime, config.load().as_str()).await,
+    }
+    .map_err(Failure::proc)?;
 
     tracing::info!("Initialize WASM module instance...");
     let ipfs_fs = IpfsFs::new(ipfs_client);
     let ipfs_path = ipfs_fs.path();
+    let ipns_path = ipfs_fs.ipns_path();
     // TODO: now that we have everything we need, we can set up an RPC listener that can be invoked
     // an arbitrary number of time and keep server/client functionality appart.
     let shared_ipfs_fs = Arc::new(ipfs_fs) as Arc<dyn virtual_fs::FileSystem + Send + Sync>;
+    wasm_runtime.mount(ipfs_path.clone(), shared_ipfs_fs.clone(), ipfs_path)?;
+    wasm_runtime.mount(ipns_path.clone(), shared_ipfs_fs, ipns_path)?;
     let root_fs = RootFileSystemBuilder::new().build();
-    root_fs.mount(ipfs_path.clone(), &shared_ipfs_fs, ipfs_path)?;
-    let mut wasm_process = wasm_runtime.build(bytecode, root_fs)?;
+    let mut wasm_process = wasm_runtime
+        .instantiate(&module, root_fs)
+        .map_err(Failure::invalid)?;
     // let mut wasm_process = wasm_runtime.build(bytecode, Box::new(ipfs_fs))?;
-    wasm_process.run(wasm_runtime.store_mut())?;
-    tracing::info!("WASM module executed successfully.");
+    // The first Ctrl-C stops the guest, so the node tears down as it does when the guest exits.
+    // Another one during the teardown exits right away.
+    let force = || std::process::exit(EXIT_INTERRUPTED);
+    let signals = tokio::spawn(interrupt_on(wasm_process.interrupt(), ctrl_c(), force));
+    let result = wasm_process.run(wasm_runtime.store_mut());
+    let shutdown = swarm_service.shutdown(SHUTDOWN_TIMEOUT).await;
+    signals.abort();
+    shutdown?;
+    let exit_code = result.map_err(Failure::proc)?;
+    tracing::info!("WASM module exited with code {exit_code}.");
+    Ok(exit_code)
+}
+
+// Serve the bootstrap capability of the node to the peers opening RPC streams to it, listing an
+// executor running modules from its loader within its ceiling, if any. RPC systems aren't Send,
+// so they run on a thread of their own, until the swarm stops handing it streams.
+fn serve_rpc(
+    swarm_service: &SwarmService,
+    executor: Option<(Arc<Loader>, executor::Limits)>,
+) -> Result<(), Failure> {
+    let incoming = swarm_service.streams().accept(net::RPC_PROTOCOL)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::spawn(move || {
+        let mut caps = Capabilities::default();
+        if let Some((loader, ceiling)) = executor {
+            let executor: rpc::proc_capnp::executor::Client =
+                capnp_rpc::new_client(Executor::new(loader, ceiling));
+            caps = caps.with("executor", rpc::cap::wrap(executor));
+        }
+        LocalSet::new().block_on(&runtime, bootstrap::listen(incoming, caps));
+    });
     Ok(())
 }
+
+// Ctrl-Cs the CLI gets, once it starts listening for them.
+fn ctrl_c() -> impl Stream<Item = ()> {
+    futures::stream::unfold((), |()| async {
+        tokio::signal::ctrl_c().await.ok().map(|()| ((), ()))
+    })
+}
+
+// Interrupt the guest on the first of signals, so the node tears down once the guest stops, and
+// force an exit on the second, for when the teardown hangs.
+async fn interrupt_on(
+    interrupt: proc::Interrupt,
+    signals: impl Stream<Item = ()>,
+    force: impl FnOnce(),
+) {
+    let mut signals = std::pin::pin!(signals);
+    if signals.next().await.is_none() {
+        return;
+    }
+    tracing::warn!("interrupting the guest, press Ctrl-C again to exit right away");
+    interrupt.interrupt();
+    if signals.next().await.is_some() {
+        force();
+    }
+}
+
+// Configuration of the swarm of the node, recording into metrics if any.
+fn swarm_config(config: &dyn cfg::Cfg, metrics: Option<Metrics>) -> SwarmConfig {
+    SwarmConfig {
+        listen_addrs: config.listen_addrs(),
+        port_range: config.port_range(),
+        tcp: config.tcp(),
+        quic: config.quic(),
+        identify_protocol: config.identify_protocol(),
+        kad_mode: Some(config.kad_mode()),
+        peerstore: config.peerstore(),
+        peer_staleness: config.peer_staleness(),
+        max_pending_inbound: config.max_pending_inbound(),
+        max_pending_outbound: config.max_pending_outbound(),
+        handshake_threads: net::swarm::DEFAULT_HANDSHAKE_THREADS,
+        idle_timeout: config.idle_timeout(),
+        pinned: config.pinned_peers(),
+        access: config.access(),
+        provider_republish: net::swarm::DEFAULT_PROVIDER_REPUBLISH,
+        mdns: config.mdns(),
+        metrics,
+        autonat: libp2p::autonat::Config::default(),
+        relays: config.relays(),
+        dns: net::dns::Dns::default(),
+        message_id: net::pubsub::MessageIdFn::default(),
+        dedup_window: net::swarm::DEFAULT_DEDUP_WINDOW,
+        dedup_capacity: net::swarm::DEFAULT_DEDUP_CAPACITY,
+        peer_scoring: config.peer_scoring().then(net::pubsub::Scoring::default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_interrupt_on() {
+        let sleep = r#"(module
+            (import "wasix_32v1" "thread_sleep" (func $sleep (param i64) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (drop (call $sleep (i64.const 60000000000)))))"#;
+        let path = std::env::temp_dir().join(format!("ww-{:x}.wasm", rand::random::<u64>()));
+        std::fs::write(&path, wat::parse_str(sleep).unwrap()).unwrap();
+        let client = net::ipfs::Client::new("/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        let mut runtime = WasmRuntime::new();
+        let module = Loader::new(client).load_file(&runtime, &path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut process = runtime
+            .instantiate(&module, RootFileSystemBuilder::new().build())
+            .unwrap();
+
+        let (signal, signals) = mpsc::unbounded();
+        let forced = Arc::new(AtomicBool::new(false));
+        let force = {
+            let forced = forced.clone();
+            move || forced.store(true, Ordering::SeqCst)
+        };
+        let handler = tokio::spawn(interrupt_on(process.interrupt(), signals, force));
+        let start = Instant::now();
+        let guest = tokio::task::spawn_blocking(move || process.run(runtime.store_mut()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The first signal stops the guest, which the CLI exits on as interrupted.
+        signal.unbounded_send(()).unwrap();
+        let failure = Failure::proc(guest.await.unwrap().unwrap_err());
+        assert_eq!(failure.code, EXIT_INTERRUPTED);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!forced.load(Ordering::SeqCst));
+
+        // The second one forces the exit.
+        signal.unbounded_send(()).unwrap();
+        handler.await.unwrap();
+        assert!(forced.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Write a module to a temporary file, to run it without IPFS.
+fn module(wat: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ww-cli-{:x}.wasm", rand::random::<u64>()));
+    fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+    path
+}
+
+// Run the CLI on the module at path, listening on loopback only, and return its exit code.
+fn ww(path: &str) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_ww"))
+        .args([
+            "--load",
+            path,
+            "--listen",
+            "/ip4/127.0.0.1/tcp/0",
+            "--no-quic",
+            "--quiet",
+        ])
+        .status()
+        .unwrap()
+        .code()
+}
+
+#[test]
+fn test_exit_code() {
+    let exit = module(
+        r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_start") (call $proc_exit (i32.const 42))))"#,
+    );
+    let trap =
+        module(r#"(module (memory (export "memory") 1) (func (export "_start") unreachable))"#);
+
+    assert_eq!(ww(exit.to_str().unwrap()), Some(42));
+    assert_eq!(ww(trap.to_str().unwrap()), Some(134));
+    assert_eq!(ww("/nonexistent/main.wasm"), Some(127));
+    fs::remove_file(exit).unwrap();
+    fs::remove_file(trap).unwrap();
+}
+
+#[test]
+fn test_missing_args() {
+    // Usage errors exit before any service is started, without panicking.
+    let output = Command::new(env!("CARGO_BIN_EXE_ww")).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr.contains("--load"), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ww"))
+        .args(["--load", "main.wasm", "--no-tcp", "--no-quic"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}